@@ -7,14 +7,15 @@ async fn main() {
     env_logger::init();
 
     // Create server with user authentication
-    let mut server_options = ServerOptions::default();
-
     // Enable authentication and add some credentials
-    server_options.auth_required = true;
-    server_options.credentials = Some(vec![
-        ("user1".to_string(), "password1".to_string()),
-        ("user2".to_string(), "password2".to_string()),
-    ]);
+    let server_options = ServerOptions {
+        auth_required: true,
+        credentials: Some(vec![
+            ("user1".to_string(), "password1".to_string()),
+            ("user2".to_string(), "password2".to_string()),
+        ]),
+        ..Default::default()
+    };
 
     let server = Server::from_options(server_options);
 
@@ -0,0 +1,303 @@
+// Throughput and handshake latency benchmark for the relay path.
+//
+// Starts an echo destination and (unless --proxy is given) a server on
+// loopback, then runs --connections concurrent workers. Each one performs
+// --requests CONNECTs in a row and pushes --payload bytes through every
+// tunnel and back. Reports relayed throughput, handshake latency percentiles
+// and the CPU time the process used, which includes the in-process server.
+//
+//     cargo run --release --example charon-bench -- --connections 64 --payload 1M
+
+use std::io;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use socks5_rs::Client;
+use socks5_rs::server::Server;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+
+const USAGE: &str =
+    "Usage: charon-bench [--connections N] [--requests N] [--payload SIZE] [--proxy HOST:PORT]
+
+  --connections N    concurrent workers (default 64)
+  --requests N       CONNECTs per worker, one after another (default 10)
+  --payload SIZE     bytes echoed through each tunnel, with an optional K or M
+                     suffix (default 1M)
+  --proxy HOST:PORT  benchmark this proxy instead of an in-process server; it
+                     must be able to reach the echo destination on loopback";
+
+struct Config {
+    connections: usize,
+    requests: usize,
+    payload: usize,
+    proxy: Option<String>,
+}
+
+// What one worker measured
+#[derive(Default)]
+struct Results {
+    handshakes: Vec<Duration>,
+    bytes: u64,
+    failures: usize,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    let config = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Benchmark failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// `None` if help was asked for
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Config>, String> {
+    let mut config = Config {
+        connections: 64,
+        requests: 10,
+        payload: 1024 * 1024,
+        proxy: None,
+    };
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Ok(None);
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?;
+        let count = || {
+            value
+                .parse::<usize>()
+                .ok()
+                .filter(|&n| n > 0)
+                .ok_or_else(|| format!("Invalid {} {:?}", arg, value))
+        };
+        match arg.as_str() {
+            "--connections" => config.connections = count()?,
+            "--requests" => config.requests = count()?,
+            "--payload" => {
+                config.payload =
+                    parse_size(&value).ok_or_else(|| format!("Invalid size {:?}", value))?
+            }
+            "--proxy" => config.proxy = Some(value),
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    Ok(Some(config))
+}
+
+fn parse_size(value: &str) -> Option<usize> {
+    let (digits, unit) = match value.to_ascii_uppercase() {
+        v if v.ends_with('K') => (v[..v.len() - 1].to_string(), 1024),
+        v if v.ends_with('M') => (v[..v.len() - 1].to_string(), 1024 * 1024),
+        v => (v, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(unit)
+}
+
+async fn run(config: Config) -> io::Result<()> {
+    let destination = echo_server().await?;
+    let client = match &config.proxy {
+        Some(proxy) => {
+            let (host, port) = proxy
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid --proxy"))?;
+            Client::new(host.trim_matches(['[', ']']).to_string(), port)
+        }
+        None => {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let server = Server::new(addr.to_string());
+            tokio::spawn(async move { server.run_with_listener(listener).await });
+            Client::new(addr.ip().to_string(), addr.port())
+        }
+    };
+
+    println!(
+        "{} workers x {} CONNECTs, {} bytes each way per tunnel",
+        config.connections, config.requests, config.payload
+    );
+    let payload: Arc<[u8]> = (0..config.payload).map(|i| i as u8).collect();
+    let cpu_before = cpu_time();
+    let started = Instant::now();
+
+    let mut workers = JoinSet::new();
+    for _ in 0..config.connections {
+        let client = client.clone();
+        let payload = Arc::clone(&payload);
+        let requests = config.requests;
+        workers.spawn(async move { worker(&client, destination, &payload, requests).await });
+    }
+    let mut total = Results::default();
+    while let Some(results) = workers.join_next().await {
+        let results = results.map_err(io::Error::other)?;
+        total.handshakes.extend(results.handshakes);
+        total.bytes += results.bytes;
+        total.failures += results.failures;
+    }
+
+    let elapsed = started.elapsed();
+    let cpu = cpu_before
+        .zip(cpu_time())
+        .map(|(before, after)| after - before);
+    report(&mut total, elapsed, cpu);
+    Ok(())
+}
+
+// CONNECT `requests` times, one after another, echoing `payload` through
+// each tunnel
+async fn worker(
+    client: &Client,
+    destination: SocketAddr,
+    payload: &[u8],
+    requests: usize,
+) -> Results {
+    let mut results = Results::default();
+    for _ in 0..requests {
+        let started = Instant::now();
+        let stream = match client.connect_to_target(destination).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("CONNECT failed: {}", e);
+                results.failures += 1;
+                continue;
+            }
+        };
+        results.handshakes.push(started.elapsed());
+
+        match echo(stream, payload).await {
+            Ok(()) => results.bytes += 2 * payload.len() as u64,
+            Err(e) => {
+                log::warn!("Transfer failed: {}", e);
+                results.failures += 1;
+            }
+        }
+    }
+    results
+}
+
+// Send `payload` and read it back, writing and reading at the same time so
+// large payloads do not fill both directions' buffers
+async fn echo<S>(stream: S, payload: &[u8]) -> io::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let send = async {
+        writer.write_all(payload).await?;
+        writer.shutdown().await
+    };
+    let receive = async {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        while received < payload.len() {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Tunnel closed early",
+                ));
+            }
+            received += n;
+        }
+        Ok(())
+    };
+    tokio::try_join!(send, receive)?;
+    Ok(())
+}
+
+async fn echo_server() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.into_split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+fn report(results: &mut Results, elapsed: Duration, cpu: Option<Duration>) {
+    let tunnels = results.handshakes.len();
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "{} tunnels, {} failures in {:.2}s ({:.0} CONNECTs/s)",
+        tunnels,
+        results.failures,
+        secs,
+        tunnels as f64 / secs
+    );
+    println!(
+        "Relayed {:.1} MiB: {:.1} MiB/s",
+        results.bytes as f64 / (1024.0 * 1024.0),
+        results.bytes as f64 / (1024.0 * 1024.0) / secs
+    );
+
+    results.handshakes.sort();
+    if !results.handshakes.is_empty() {
+        println!(
+            "Handshake latency: p50 {:.2?}, p99 {:.2?}, max {:.2?}",
+            percentile(&results.handshakes, 50.0),
+            percentile(&results.handshakes, 99.0),
+            results.handshakes[tunnels - 1]
+        );
+    }
+    match cpu {
+        Some(cpu) => println!(
+            "CPU time: {:.2}s ({:.0}% of one core)",
+            cpu.as_secs_f64(),
+            100.0 * cpu.as_secs_f64() / secs
+        ),
+        None => println!("CPU time: not available on this platform"),
+    }
+}
+
+// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// User plus system CPU time of this process so far
+#[cfg(unix)]
+fn cpu_time() -> Option<Duration> {
+    let to_duration = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    // SAFETY: getrusage only writes to the struct we pass
+    let usage = unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+            return None;
+        }
+        usage
+    };
+    Some(to_duration(usage.ru_utime) + to_duration(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Option<Duration> {
+    None
+}
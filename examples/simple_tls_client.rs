@@ -14,7 +14,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let tcp_stream = tokio::net::TcpStream::connect("127.0.0.1:1081").await?;
     
     // Convert the domain to DNS name
-    let domain = rustls::ServerName::try_from("localhost")
+    let domain = rustls::pki_types::ServerName::try_from("localhost")
         .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid domain name"))?;
     
     let mut tls_stream = tls_connector.connect(domain, tcp_stream).await?;
@@ -39,12 +39,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let target = "example.com";
     let port = 80;
     
-    let mut request = Vec::new();
-    request.push(0x05); // SOCKS version
-    request.push(0x01); // CONNECT command
-    request.push(0x00); // Reserved
-    request.push(0x03); // Domain name address type
-    request.push(target.len() as u8); // Domain name length
+    let mut request = vec![
+        0x05,               // SOCKS version
+        0x01,               // CONNECT command
+        0x00,               // Reserved
+        0x03,               // Domain name address type
+        target.len() as u8, // Domain name length
+    ];
     request.extend_from_slice(target.as_bytes()); // Domain name
     request.push((port >> 8) as u8); // Port high byte
     request.push(port as u8); // Port low byte
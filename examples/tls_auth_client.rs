@@ -1,4 +1,4 @@
-use log::{debug, info};
+use log::info;
 use socks5_rs::tls_client::TlsClient;
 use std::error::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -14,7 +14,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
         1081,
         "user1".to_string(),
         "password1".to_string(),
-    );
+    )
+    // Trust the self-signed certificate the tls_auth_server example generated
+    .with_ca_file("cert.pem")?;
 
     info!("Connecting to example.com via authenticated TLS SOCKS5 proxy...");
 
@@ -15,21 +15,24 @@ async fn main() -> Result<()> {
     }
 
     // Create server options with authentication
-    let mut server_options = ServerOptions::default();
-    server_options.bind_addr = "127.0.0.1:1081".to_string(); // Use a different port for TLS
-
-    // Enable authentication and add credentials
-    server_options.auth_required = true;
-    server_options.credentials = Some(vec![
-        ("user1".to_string(), "password1".to_string()),
-        ("user2".to_string(), "password2".to_string()),
-    ]);
+    let server_options = ServerOptions {
+        bind_addr: "127.0.0.1:1081".to_string(), // Use a different port for TLS
+
+        // Enable authentication and add credentials
+        auth_required: true,
+        credentials: Some(vec![
+            ("user1".to_string(), "password1".to_string()),
+            ("user2".to_string(), "password2".to_string()),
+        ]),
+        ..Default::default()
+    };
 
     // Create TLS server options
     let tls_options = TlsServerOptions {
         server_options,
         cert_path: "cert.pem".to_string(),
         key_path: "key.pem".to_string(),
+        ..Default::default()
     };
 
     // Create TLS server
@@ -48,7 +51,7 @@ async fn main() -> Result<()> {
     // Run the server
     info!("Starting server on 127.0.0.1:1081");
 
-    match server.run("127.0.0.1:1081").await {
+    match server.run().await {
         Ok(_) => Ok(()),
         Err(e) => {
             error!("Server error: {}", e);
@@ -1,4 +1,4 @@
-use log::{debug, info};
+use log::info;
 use socks5_rs::tls_client::TlsClient;
 use std::error::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -9,8 +9,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
     // Create a TLS client that connects to our secure SOCKS5 proxy
-    // Note: Using localhost:1081 (the TLS server port)
-    let client = TlsClient::new("localhost".to_string(), 1081);
+    // Note: Using localhost:1081 (the TLS server port), trusting the
+    // self-signed certificate the tls_server example generated
+    let client = TlsClient::new("localhost".to_string(), 1081).with_ca_file("cert.pem")?;
 
     // Connect to example.com through the TLS-secured SOCKS5 proxy
     info!("Connecting to example.com via TLS-secured SOCKS5 proxy...");
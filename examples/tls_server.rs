@@ -2,7 +2,6 @@ use anyhow::Result;
 use log::{error, info};
 use socks5_rs::server::ServerOptions;
 use socks5_rs::tls::{TlsServer, TlsServerOptions, generate_self_signed_cert};
-use std::error::Error;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -16,14 +15,17 @@ async fn main() -> Result<()> {
     }
 
     // Create server options with custom bind address for TLS
-    let mut server_options = ServerOptions::default();
-    server_options.bind_addr = "127.0.0.1:1081".to_string(); // Use a different port for TLS
+    let server_options = ServerOptions {
+        bind_addr: "127.0.0.1:1081".to_string(), // Use a different port for TLS
+        ..Default::default()
+    };
 
     // Create TLS server options
     let tls_options = TlsServerOptions {
         server_options,
         cert_path: "cert.pem".to_string(),
         key_path: "key.pem".to_string(),
+        ..Default::default()
     };
 
     // Create TLS server
@@ -38,7 +40,7 @@ async fn main() -> Result<()> {
     info!("Starting SOCKS5 TLS server on 127.0.0.1:1081");
 
     // Run the server
-    match server.run("127.0.0.1:1081").await {
+    match server.run().await {
         Ok(_) => Ok(()),
         Err(e) => {
             error!("Server error: {}", e);
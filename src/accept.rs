@@ -0,0 +1,155 @@
+// Recovery from failing accept() calls.
+//
+// A failed accept usually means the process or system is out of file
+// descriptors, and the pending connection stays queued, so retrying at once
+// just spins. Failures back off exponentially, and on descriptor exhaustion
+// a descriptor reserved up front is released so the pending connection can
+// be accepted and closed, clearing the queue instead of leaving clients
+// hanging until they time out.
+
+use std::fs::File;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::{error, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// How long to wait for the connection to shed once the reserve is released
+const SHED_WAIT: Duration = Duration::from_millis(10);
+
+/// Counters exposed for monitoring.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AcceptStats {
+    /// Failed accept calls of any kind.
+    pub errors: u64,
+    /// Failures caused by running out of file descriptors.
+    pub fd_exhaustion: u64,
+    /// Connections accepted and closed immediately to drain the queue while
+    /// out of descriptors.
+    pub shed_connections: u64,
+}
+
+/// Accept failure state shared by every accept loop of a server.
+#[derive(Debug)]
+pub(crate) struct AcceptErrors {
+    errors: AtomicU64,
+    fd_exhaustion: AtomicU64,
+    shed: AtomicU64,
+    reserve: Mutex<Option<File>>,
+}
+
+impl AcceptErrors {
+    pub(crate) fn new() -> Self {
+        AcceptErrors {
+            errors: AtomicU64::new(0),
+            fd_exhaustion: AtomicU64::new(0),
+            shed: AtomicU64::new(0),
+            reserve: Mutex::new(open_reserve()),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> AcceptStats {
+        AcceptStats {
+            errors: self.errors.load(Ordering::Relaxed),
+            fd_exhaustion: self.fd_exhaustion.load(Ordering::Relaxed),
+            shed_connections: self.shed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Handle a failed accept: count it, shed one pending connection with
+    /// `accept` if descriptors ran out, then wait out the backoff.
+    pub(crate) async fn recover<F, Fut, T>(&self, e: &io::Error, backoff: &mut Backoff, accept: F)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+
+        if is_fd_exhaustion(e) {
+            self.fd_exhaustion.fetch_add(1, Ordering::Relaxed);
+            warn!("Out of file descriptors, shedding a pending connection");
+            self.shed(accept).await;
+        } else {
+            error!("Failed to accept connection: {}", e);
+        }
+
+        tokio::time::sleep(backoff.next()).await;
+    }
+
+    async fn shed<F, Fut, T>(&self, accept: F)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let released = self.reserve.lock().unwrap().take();
+        if released.is_none() {
+            return;
+        }
+        drop(released);
+
+        // Dropping the accepted connection closes it right away
+        if let Ok(Ok(_)) = tokio::time::timeout(SHED_WAIT, accept()).await {
+            self.shed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut reserve = self.reserve.lock().unwrap();
+        if reserve.is_none() {
+            *reserve = open_reserve();
+        }
+    }
+}
+
+impl Default for AcceptErrors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Exponential delay between retries of one accept loop.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Backoff {
+            delay: INITIAL_BACKOFF,
+        }
+    }
+
+    /// Call after a successful accept.
+    pub(crate) fn reset(&mut self) {
+        self.delay = INITIAL_BACKOFF;
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+#[cfg(unix)]
+fn open_reserve() -> Option<File> {
+    File::open("/dev/null").ok()
+}
+
+#[cfg(not(unix))]
+fn open_reserve() -> Option<File> {
+    None
+}
+
+#[cfg(unix)]
+fn is_fd_exhaustion(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+fn is_fd_exhaustion(_: &io::Error) -> bool {
+    false
+}
@@ -0,0 +1,259 @@
+// Persistent per-user bandwidth accounting.
+//
+// `Accounting` keeps an upload and a download counter per user in memory,
+// updated while relays run, and periodically saves all of them to an
+// `AccountingStore`. The saved counters are loaded back at startup, so usage
+// survives restarts. Accounting only records usage; quotas are enforced
+// separately through `QuotaStore`.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error};
+
+use crate::relay::RelayCounters;
+
+/// How often `Accounting::run` saves the counters unless overridden.
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Cumulative bytes relayed for one user.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes sent to destinations.
+    pub upload: u64,
+    /// Bytes sent back to the user.
+    pub download: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.upload.saturating_add(self.download)
+    }
+}
+
+/// Where accounting counters are persisted between restarts.
+#[async_trait]
+pub trait AccountingStore: Send + Sync {
+    /// Counters saved by an earlier run.
+    async fn load(&self) -> io::Result<HashMap<String, Usage>>;
+
+    /// Replace the saved counters with `usage`, which holds every user.
+    async fn save(&self, usage: &HashMap<String, Usage>) -> io::Result<()>;
+}
+
+/// Live per-user counters with periodic persistence.
+pub struct Accounting {
+    store: Arc<dyn AccountingStore>,
+    users: Mutex<HashMap<String, Arc<RelayCounters>>>,
+    // What the store last accepted; also keeps flushes from overlapping
+    saved: tokio::sync::Mutex<HashMap<String, Usage>>,
+    flush_interval: Duration,
+}
+
+impl Accounting {
+    /// Load the counters saved in `store` and keep accounting from there.
+    pub async fn load(store: Arc<dyn AccountingStore>) -> io::Result<Self> {
+        let saved = store.load().await?;
+        debug!("Loaded bandwidth accounting for {} users", saved.len());
+        let users = saved
+            .iter()
+            .map(|(username, usage)| {
+                let counters = RelayCounters::starting_at(usage.upload, usage.download);
+                (username.clone(), Arc::new(counters))
+            })
+            .collect();
+
+        Ok(Accounting {
+            store,
+            users: Mutex::new(users),
+            saved: tokio::sync::Mutex::new(saved),
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+        })
+    }
+
+    /// Save every `interval` instead of `DEFAULT_FLUSH_INTERVAL`.
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    pub fn usage(&self, username: &str) -> Usage {
+        self.users
+            .lock()
+            .unwrap()
+            .get(username)
+            .map(|counters| usage_of(counters))
+            .unwrap_or_default()
+    }
+
+    /// Current counters of every user.
+    pub fn snapshot(&self) -> HashMap<String, Usage> {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(username, counters)| (username.clone(), usage_of(counters)))
+            .collect()
+    }
+
+    /// Zero a user's counters (e.g. at the start of a billing period); the
+    /// store is updated on the next flush.
+    pub fn reset(&self, username: &str) {
+        if let Some(counters) = self.users.lock().unwrap().get(username) {
+            counters.clear();
+        }
+    }
+
+    /// Save the counters if they changed since the last save.
+    pub async fn flush(&self) -> io::Result<()> {
+        let mut saved = self.saved.lock().await;
+        let usage = self.snapshot();
+        if usage == *saved {
+            return Ok(());
+        }
+        self.store.save(&usage).await?;
+        *saved = usage;
+        Ok(())
+    }
+
+    /// Flush every flush interval, forever. Errors are logged and retried
+    /// on the next tick.
+    pub async fn run(&self) {
+        let mut ticks = tokio::time::interval(self.flush_interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if let Err(e) = self.flush().await {
+                error!("Failed to save bandwidth accounting: {}", e);
+            }
+        }
+    }
+
+    // The live counters of `username`, created on first use
+    pub(crate) fn counters(&self, username: &str) -> Arc<RelayCounters> {
+        let mut users = self.users.lock().unwrap();
+        Arc::clone(users.entry(username.to_string()).or_default())
+    }
+}
+
+fn usage_of(counters: &RelayCounters) -> Usage {
+    Usage {
+        upload: counters.upload(),
+        download: counters.download(),
+    }
+}
+
+/// Counters saved to a text file, one `username<TAB>upload<TAB>download`
+/// line per user. Saves write a temporary file and rename it over the old
+/// one, so a crash never leaves a half-written file behind.
+#[derive(Debug, Clone)]
+pub struct FileAccountingStore {
+    path: PathBuf,
+}
+
+impl FileAccountingStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileAccountingStore { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl AccountingStore for FileAccountingStore {
+    async fn load(&self) -> io::Result<HashMap<String, Usage>> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+                Err(e) => return Err(e),
+            };
+
+            let mut usage = HashMap::new();
+            for (number, line) in BufReader::new(file).lines().enumerate() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let invalid = || {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid accounting line {}", number + 1),
+                    )
+                };
+                let mut fields = line.split('\t');
+                let (Some(username), Some(upload), Some(download), None) =
+                    (fields.next(), fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(invalid());
+                };
+                usage.insert(
+                    unescape(username).ok_or_else(invalid)?,
+                    Usage {
+                        upload: upload.parse().map_err(|_| invalid())?,
+                        download: download.parse().map_err(|_| invalid())?,
+                    },
+                );
+            }
+            Ok(usage)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    async fn save(&self, usage: &HashMap<String, Usage>) -> io::Result<()> {
+        let path = self.path.clone();
+        let mut contents = String::new();
+        for (username, usage) in usage {
+            contents.push_str(&format!(
+                "{}\t{}\t{}\n",
+                escape(username),
+                usage.upload,
+                usage.download
+            ));
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let mut temp = path.clone().into_os_string();
+            temp.push(".tmp");
+            let mut file = File::create(&temp)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temp, &path)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+// Usernames may contain anything, including the separators
+fn escape(username: &str) -> String {
+    username
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(field: &str) -> Option<String> {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
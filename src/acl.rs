@@ -0,0 +1,247 @@
+// Destination access control lists.
+//
+// Rules are evaluated in order against the CONNECT target and the first match
+// decides; when nothing matches the ACL's default action applies. Log rules
+// only record the match and evaluation continues past them. Domain rules
+// match domain targets and IP/CIDR/ASN rules match IP-literal targets. A
+// domain target dialed directly is checked again for each address it resolves
+// to, with CIDR and ASN rules matching the address, so a name cannot smuggle a
+// connection into a denied network.
+
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use log::info;
+
+use crate::asn::AsnDatabase;
+use crate::protocol::SocksAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+    /// Log the match and keep evaluating. As a default action it allows.
+    Log,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclTarget {
+    Any,
+    /// Exact domain name (case-insensitive).
+    Domain(String),
+    /// The domain itself and every subdomain of it.
+    DomainSuffix(String),
+    /// IP network in CIDR form: address and prefix length.
+    Cidr(IpAddr, u8),
+    /// Addresses announced by any of these autonomous systems, according to
+    /// `db`.
+    Asn {
+        asns: Vec<u32>,
+        db: Arc<AsnDatabase>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclRule {
+    pub action: AclAction,
+    pub target: AclTarget,
+    /// Restrict the rule to these destination ports; `None` matches any port.
+    pub ports: Option<RangeInclusive<u16>>,
+}
+
+impl AclRule {
+    pub fn allow(target: AclTarget) -> Self {
+        AclRule {
+            action: AclAction::Allow,
+            target,
+            ports: None,
+        }
+    }
+
+    pub fn deny(target: AclTarget) -> Self {
+        AclRule {
+            action: AclAction::Deny,
+            target,
+            ports: None,
+        }
+    }
+
+    pub fn log(target: AclTarget) -> Self {
+        AclRule {
+            action: AclAction::Log,
+            target,
+            ports: None,
+        }
+    }
+
+    pub fn with_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports = Some(ports);
+        self
+    }
+
+    pub fn matches(&self, addr: &SocksAddr) -> bool {
+        if let Some(ports) = &self.ports
+            && !ports.contains(&addr.port())
+        {
+            return false;
+        }
+        self.target.matches(addr)
+    }
+
+    /// Whether the rule matches `ip`, an address the domain target `addr`
+    /// resolved to.
+    pub fn matches_resolved(&self, addr: &SocksAddr, ip: IpAddr) -> bool {
+        if let Some(ports) = &self.ports
+            && !ports.contains(&addr.port())
+        {
+            return false;
+        }
+        self.target.matches_resolved(addr, ip)
+    }
+}
+
+impl AclTarget {
+    /// Whether `addr` is one of the target's destinations, on any port.
+    pub fn matches(&self, addr: &SocksAddr) -> bool {
+        let ip = addr_ip(addr);
+        let domain = match addr {
+            SocksAddr::Domain(domain, _) => Some(domain.as_str()),
+            _ => None,
+        };
+
+        match self {
+            AclTarget::Any => true,
+            AclTarget::Domain(name) => domain.is_some_and(|d| normalize(d) == normalize(name)),
+            AclTarget::DomainSuffix(suffix) => domain.is_some_and(|d| {
+                let d = normalize(d);
+                let suffix = normalize(suffix.trim_start_matches('.'));
+                d == suffix || d.ends_with(&format!(".{}", suffix))
+            }),
+            AclTarget::Cidr(network, prefix) => {
+                ip.is_some_and(|ip| cidr_contains(*network, *prefix, ip))
+            }
+            AclTarget::Asn { asns, db } => ip
+                .and_then(|ip| db.lookup(ip))
+                .is_some_and(|record| asns.contains(&record.asn)),
+        }
+    }
+
+    /// Whether `ip`, an address the domain target `addr` resolved to, is one
+    /// of the target's destinations. Domain targets match the name, CIDR and
+    /// ASN targets the address.
+    pub fn matches_resolved(&self, addr: &SocksAddr, ip: IpAddr) -> bool {
+        match self {
+            AclTarget::Cidr(..) | AclTarget::Asn { .. } => {
+                self.matches(&SocksAddr::from(SocketAddr::new(ip, addr.port())))
+            }
+            target => target.matches(addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    pub rules: Vec<AclRule>,
+    pub default_action: AclAction,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Acl {
+            rules: Vec::new(),
+            default_action: AclAction::Allow,
+        }
+    }
+}
+
+impl Acl {
+    /// An ACL that only allows the listed rules' targets.
+    pub fn allow_only(targets: Vec<AclTarget>) -> Self {
+        Acl {
+            rules: targets.into_iter().map(AclRule::allow).collect(),
+            default_action: AclAction::Deny,
+        }
+    }
+
+    pub fn check(&self, addr: &SocksAddr) -> AclAction {
+        self.decide(addr, addr_ip(addr), |rule| rule.matches(addr))
+    }
+
+    pub fn allows(&self, addr: &SocksAddr) -> bool {
+        self.check(addr) == AclAction::Allow
+    }
+
+    /// The action for `ip`, one of the addresses the domain target `addr`
+    /// resolved to. Domain rules still match the name, so an allowed name
+    /// resolving into a denied network is denied only if the network's rule
+    /// comes first.
+    pub fn check_resolved(&self, addr: &SocksAddr, ip: IpAddr) -> AclAction {
+        self.decide(addr, Some(ip), |rule| rule.matches_resolved(addr, ip))
+    }
+
+    pub fn allows_resolved(&self, addr: &SocksAddr, ip: IpAddr) -> bool {
+        self.check_resolved(addr, ip) == AclAction::Allow
+    }
+
+    // `ip` is the address ASN log rules look up
+    fn decide(
+        &self,
+        addr: &SocksAddr,
+        ip: Option<IpAddr>,
+        matches: impl Fn(&AclRule) -> bool,
+    ) -> AclAction {
+        for rule in self.rules.iter().filter(|rule| matches(rule)) {
+            match rule.action {
+                AclAction::Log => log_match(rule, addr, ip),
+                action => return action,
+            }
+        }
+        match self.default_action {
+            AclAction::Log => AclAction::Allow,
+            action => action,
+        }
+    }
+}
+
+fn log_match(rule: &AclRule, addr: &SocksAddr, ip: Option<IpAddr>) {
+    let dest = match (addr, ip) {
+        (SocksAddr::Domain(..), Some(ip)) => format!("{} at {}", addr, ip),
+        _ => addr.to_string(),
+    };
+    match (&rule.target, ip) {
+        (AclTarget::Asn { db, .. }, Some(ip)) => match db.lookup(ip) {
+            Some(record) => info!("ACL log rule matched {} ({})", dest, record),
+            None => info!("ACL log rule matched {}", dest),
+        },
+        _ => info!("ACL log rule matched {}", dest),
+    }
+}
+
+fn addr_ip(addr: &SocksAddr) -> Option<IpAddr> {
+    match addr {
+        SocksAddr::Ipv4(ip, _) => Some(IpAddr::V4(*ip)),
+        SocksAddr::Ipv6(ip, _) => Some(IpAddr::V6(*ip)),
+        SocksAddr::Domain(..) => None,
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn cidr_contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let prefix = prefix.min(32) as u32;
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let prefix = prefix.min(128) as u32;
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,95 @@
+// Automatic certificate provisioning via ACME (e.g. Let's Encrypt).
+//
+// Certificates are obtained and renewed with the TLS-ALPN-01 challenge, which
+// is answered on the TLS listener itself: the CA connects to the configured
+// domains on port 443 with the `acme-tls/1` ALPN protocol, so the server must
+// be reachable there. Account keys and certificates are cached on disk so
+// restarts do not trigger new orders.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures_util::StreamExt;
+use log::{error, info};
+use rustls::ServerConfig;
+use rustls_acme::AcmeConfig;
+use rustls_acme::caches::DirCache;
+
+use crate::server::{Server, ServerOptions};
+use crate::tls::{RustlsBackend, TlsServer, crypto_provider};
+
+pub struct AcmeOptions {
+    /// Hostnames to request the certificate for.
+    pub domains: Vec<String>,
+    /// Account contacts, e.g. `mailto:admin@example.com`.
+    pub contact: Vec<String>,
+    /// Directory where the account key and certificates are stored.
+    pub cache_dir: PathBuf,
+    /// Use the Let's Encrypt production directory instead of staging.
+    pub production: bool,
+    /// Custom ACME directory URL; overrides `production`.
+    pub directory_url: Option<String>,
+}
+
+impl Default for AcmeOptions {
+    fn default() -> Self {
+        AcmeOptions {
+            domains: Vec::new(),
+            contact: Vec::new(),
+            cache_dir: PathBuf::from("acme-cache"),
+            production: false,
+            directory_url: None,
+        }
+    }
+}
+
+impl TlsServer {
+    /// Create a TLS server whose certificate is provisioned and renewed via
+    /// ACME. Until the first certificate is available, handshakes fail.
+    ///
+    /// Must be called from within a Tokio runtime: the ACME state machine is
+    /// spawned as a background task.
+    pub fn with_acme(server_options: ServerOptions, acme: AcmeOptions) -> io::Result<Self> {
+        if acme.domains.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ACME needs at least one domain",
+            ));
+        }
+
+        let config = AcmeConfig::new_with_provider(&acme.domains, crypto_provider())
+            .contact(&acme.contact)
+            .cache(DirCache::new(acme.cache_dir));
+        let config = match &acme.directory_url {
+            Some(url) => config.directory(url),
+            None => config.directory_lets_encrypt(acme.production),
+        };
+        let mut state = config.state();
+
+        let tls_config = Arc::new(
+            ServerConfig::builder_with_provider(crypto_provider())
+                .with_safe_default_protocol_versions()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+                .with_no_client_auth()
+                .with_cert_resolver(state.resolver()),
+        );
+        let challenge_config = state.challenge_rustls_config_with_provider(crypto_provider());
+
+        tokio::spawn(async move {
+            while let Some(event) = state.next().await {
+                match event {
+                    Ok(ok) => info!("ACME: {:?}", ok),
+                    Err(e) => error!("ACME: {}", e),
+                }
+            }
+        });
+
+        let backend =
+            RustlsBackend::with_config(tls_config).with_challenge_config(challenge_config);
+        Ok(TlsServer::with_backend(
+            Server::from_options(server_options),
+            Arc::new(backend),
+        ))
+    }
+}
@@ -0,0 +1,129 @@
+// IP-to-ASN lookups for ACL rules.
+//
+// The database is the tab-separated format published by iptoasn.com
+// (`ip2asn-v4.tsv`, `ip2asn-v6.tsv` or `ip2asn-combined.tsv`): one range per
+// line as `range_start range_end as_number country_code as_description`.
+// Ranges with AS number 0 are unrouted space and are skipped.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The autonomous system an address belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsnRecord {
+    pub asn: u32,
+    /// Holder description, e.g. "AMAZON-02".
+    pub name: Arc<str>,
+}
+
+impl fmt::Display for AsnRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AS{} {}", self.asn, self.name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Range {
+    // Both ends inclusive; IPv4 addresses are stored as IPv4-mapped IPv6
+    start: u128,
+    end: u128,
+    record: AsnRecord,
+}
+
+/// Address ranges and the autonomous systems announcing them, sorted for
+/// binary search.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct AsnDatabase {
+    ranges: Vec<Range>,
+}
+
+impl fmt::Debug for AsnDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsnDatabase")
+            .field("ranges", &self.ranges.len())
+            .finish()
+    }
+}
+
+impl AsnDatabase {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    pub fn parse<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut names: HashMap<u32, Arc<str>> = HashMap::new();
+        let mut ranges = Vec::new();
+
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid ASN database line {}", number + 1),
+                )
+            };
+
+            let mut fields = line.split('\t');
+            let start: IpAddr = fields
+                .next()
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(invalid)?;
+            let end: IpAddr = fields
+                .next()
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(invalid)?;
+            let asn: u32 = fields
+                .next()
+                .and_then(|f| f.parse().ok())
+                .ok_or_else(invalid)?;
+            let name = fields.nth(1).unwrap_or_default();
+            if asn == 0 {
+                continue;
+            }
+            if start.is_ipv4() != end.is_ipv4() {
+                return Err(invalid());
+            }
+
+            let name = names.entry(asn).or_insert_with(|| Arc::from(name)).clone();
+            ranges.push(Range {
+                start: to_u128(start),
+                end: to_u128(end),
+                record: AsnRecord { asn, name },
+            });
+        }
+
+        ranges.sort_by_key(|range| range.start);
+        Ok(AsnDatabase { ranges })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<&AsnRecord> {
+        let ip = to_u128(ip);
+        // Last range starting at or before `ip`
+        let index = self.ranges.partition_point(|range| range.start <= ip);
+        let range = self.ranges.get(index.checked_sub(1)?)?;
+        (ip <= range.end).then_some(&range.record)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
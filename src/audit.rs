@@ -0,0 +1,59 @@
+// Audit trail of security-relevant decisions: authentication outcomes and
+// whether each request was allowed. The server hands events to an
+// `AuditSink`; with the `audit` feature, `audit_log::HashChainLog` writes
+// them to a tamper-evident file.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    AuthSuccess,
+    AuthFailure,
+    /// The request passed the ACLs, quotas and session limits.
+    Allowed,
+    /// The connection or request was refused; `detail` says why.
+    Denied,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::AuthSuccess => "auth_success",
+            AuditAction::AuthFailure => "auth_failure",
+            AuditAction::Allowed => "allowed",
+            AuditAction::Denied => "denied",
+        }
+    }
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub time: SystemTime,
+    /// `ConnectionInfo::id` of the connection concerned.
+    pub connection: u64,
+    pub peer: Option<SocketAddr>,
+    /// The authenticated user, or the username tried for failed attempts.
+    pub username: Option<String>,
+    pub action: AuditAction,
+    /// Requested destination, once known.
+    pub target: Option<String>,
+    pub detail: Option<String>,
+}
+
+/// Where audit events go. Failing to record an event is logged but does not
+/// affect the connection.
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn record(&self, event: &AuditEvent) -> io::Result<()>;
+}
@@ -0,0 +1,268 @@
+// Append-only, hash-chained audit log file (`audit` feature).
+//
+// Each event is one JSON line. Its hash covers the previous line's hash and
+// the event itself, so editing, removing or reordering records breaks the
+// chain from that point on, which `verify` detects. The hash is an HMAC
+// keyed with a secret that should live off the host (a secrets manager, an
+// operator's vault), so that someone who can rewrite the file cannot forge
+// a matching chain. The chain continues across restarts from the last
+// record in the file; a partial record a crash left behind is cut off.
+//
+// A line is the event object followed by the chain fields:
+// {"seq":1,"time":1700000000.123,"connection":7,...,"prev":"<hex>","hash":"<hex>"}
+// where hash = HMAC-SHA256(key, prev || event object without the chain fields).
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use async_trait::async_trait;
+use log::warn;
+use ring::hmac;
+
+use crate::audit::{AuditEvent, AuditSink};
+
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// `,"prev":"` + 64 hex + `","hash":"` + 64 hex + `"}`
+const CHAIN_SUFFIX_LEN: usize = 9 + 64 + 10 + 64 + 2;
+
+struct Chain {
+    file: File,
+    key: hmac::Key,
+    seq: u64,
+    prev: String,
+}
+
+/// Tamper-evident audit file.
+pub struct HashChainLog {
+    chain: Arc<Mutex<Chain>>,
+    sync: bool,
+}
+
+impl HashChainLog {
+    /// Open or create the log at `path`, continuing the chain of any
+    /// existing records, with hashes keyed by `key`. Fails if `key` is
+    /// empty.
+    pub fn open<P: AsRef<Path>>(path: P, key: &[u8]) -> io::Result<Self> {
+        let key = chain_key(key)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+
+        let (seq, prev) = match last_line(&mut file)? {
+            Some(line) => {
+                let (seq, hash) = parse_chain(&line).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Last audit record is corrupt")
+                })?;
+                (seq, hash.to_string())
+            }
+            None => (0, GENESIS.to_string()),
+        };
+
+        Ok(HashChainLog {
+            chain: Arc::new(Mutex::new(Chain {
+                file,
+                key,
+                seq,
+                prev,
+            })),
+            sync: false,
+        })
+    }
+
+    /// fsync after every record, so an acknowledged event survives a crash.
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+}
+
+#[async_trait]
+impl AuditSink for HashChainLog {
+    async fn record(&self, event: &AuditEvent) -> io::Result<()> {
+        let chain = Arc::clone(&self.chain);
+        let event = event.clone();
+        let sync = self.sync;
+
+        tokio::task::spawn_blocking(move || {
+            let mut chain = chain
+                .lock()
+                .map_err(|_| io::Error::other("Audit log poisoned"))?;
+            let seq = chain.seq + 1;
+            let body = encode(seq, &event);
+            let hash = chain_hash(&chain.key, &chain.prev, &body);
+            let line = format!(
+                "{},\"prev\":\"{}\",\"hash\":\"{}\"}}\n",
+                &body[..body.len() - 1],
+                chain.prev,
+                hash
+            );
+
+            chain.file.write_all(line.as_bytes())?;
+            if sync {
+                chain.file.sync_data()?;
+            }
+            chain.seq = seq;
+            chain.prev = hash;
+            Ok(())
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
+
+/// Check the whole chain in `path` against `key`, returning the number of
+/// records. Fails with `ErrorKind::InvalidData` naming the first record that
+/// does not match.
+pub fn verify<P: AsRef<Path>>(path: P, key: &[u8]) -> io::Result<u64> {
+    let key = chain_key(key)?;
+    let mut prev = GENESIS.to_string();
+    let mut count = 0;
+
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let broken = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Audit chain broken at line {}", index + 1),
+            )
+        };
+
+        let (seq, hash) = parse_chain(&line).ok_or_else(broken)?;
+        let body = format!("{}}}", &line[..line.len() - CHAIN_SUFFIX_LEN]);
+        let recorded_prev = &line[line.len() - CHAIN_SUFFIX_LEN + 9..][..64];
+        if seq != count + 1 || recorded_prev != prev || chain_hash(&key, &prev, &body) != hash {
+            return Err(broken());
+        }
+
+        prev = hash.to_string();
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn chain_key(key: &[u8]) -> io::Result<hmac::Key> {
+    if key.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Audit log key must not be empty",
+        ));
+    }
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, key))
+}
+
+fn chain_hash(key: &hmac::Key, prev: &str, body: &str) -> String {
+    let mut context = hmac::Context::with_key(key);
+    context.update(prev.as_bytes());
+    context.update(body.as_bytes());
+    context
+        .sign()
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// The event as a JSON object, without the chain fields
+fn encode(seq: u64, event: &AuditEvent) -> String {
+    let time = event
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!(
+        "{{\"seq\":{},\"time\":{}.{:03},\"connection\":{},\"peer\":{},\"user\":{},\"action\":\"{}\",\"target\":{},\"detail\":{}}}",
+        seq,
+        time / 1000,
+        time % 1000,
+        event.connection,
+        json_string(event.peer.map(|addr| addr.to_string()).as_deref()),
+        json_string(event.username.as_deref()),
+        event.action,
+        json_string(event.target.as_deref()),
+        json_string(event.detail.as_deref()),
+    )
+}
+
+fn json_string(value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return "null".to_string();
+    };
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Sequence number and hash of a record line
+fn parse_chain(line: &str) -> Option<(u64, &str)> {
+    if line.len() < CHAIN_SUFFIX_LEN || !line.is_char_boundary(line.len() - CHAIN_SUFFIX_LEN) {
+        return None;
+    }
+    let suffix = &line[line.len() - CHAIN_SUFFIX_LEN..];
+    if !suffix.starts_with(",\"prev\":\"") || !suffix.ends_with("\"}") {
+        return None;
+    }
+    let hash = &suffix[9 + 64 + 10..][..64];
+
+    let seq = line
+        .strip_prefix("{\"seq\":")?
+        .split(',')
+        .next()?
+        .parse()
+        .ok()?;
+    Some((seq, hash))
+}
+
+// The last complete line of the file, read from the end. A partial line
+// after it, from a crash in the middle of a write, is truncated away.
+fn last_line(file: &mut File) -> io::Result<Option<String>> {
+    const CHUNK: u64 = 64 * 1024;
+
+    let len = file.seek(SeekFrom::End(0))?;
+    let start = len.saturating_sub(CHUNK);
+    file.seek(SeekFrom::Start(start))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+
+    if tail.last().is_some_and(|&byte| byte != b'\n') {
+        let complete = match tail.iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => newline + 1,
+            None if start == 0 => 0,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Last audit record is corrupt",
+                ));
+            }
+        };
+        warn!(
+            "Truncating {} bytes of a partial audit record",
+            tail.len() - complete
+        );
+        file.set_len(start + complete as u64)?;
+        tail.truncate(complete);
+    }
+
+    // The chunk may start inside a character, but never inside the last line
+    Ok(String::from_utf8_lossy(&tail)
+        .lines()
+        .rfind(|line| !line.is_empty())
+        .map(str::to_string))
+}
@@ -0,0 +1,84 @@
+// Pluggable authentication backends for RFC 1929 username/password auth.
+// The server hands the credentials it reads off the wire to an `Authenticator`
+// and uses the returned `Identity` for everything that happens afterwards.
+
+use std::io;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+/// Bounds on the username/password sub-negotiation. Each connection gets a
+/// single attempt; oversized or malformed credentials are rejected with a
+/// failure status before the rest is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthLimits {
+    /// Longest username accepted, in bytes (at most 255 on the wire).
+    pub max_username_len: usize,
+    /// Longest password accepted, in bytes (at most 255 on the wire).
+    pub max_password_len: usize,
+    /// Wait this long before answering a failed attempt, slowing down
+    /// password guessing.
+    pub failure_delay: Duration,
+}
+
+impl Default for AuthLimits {
+    fn default() -> Self {
+        AuthLimits {
+            max_username_len: 255,
+            max_password_len: 255,
+            failure_delay: Duration::ZERO,
+        }
+    }
+}
+
+/// The identity established by a successful authentication.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    pub username: String,
+    /// Groups the backend reported for this user (e.g. LDAP `memberOf`).
+    pub groups: Vec<String>,
+    /// Name of the policy this user maps to, if the backend assigns one.
+    pub policy: Option<String>,
+}
+
+impl Identity {
+    pub fn new(username: impl Into<String>) -> Self {
+        Identity {
+            username: username.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Validates username/password pairs received during the SOCKS5 sub-negotiation.
+///
+/// Return `Ok(Some(identity))` to accept, `Ok(None)` to reject the credentials,
+/// and `Err` when the backend itself could not be reached. The server treats
+/// errors as a rejection but logs them separately.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, username: &str, password: &str) -> io::Result<Option<Identity>>;
+}
+
+/// Authenticator backed by a fixed list of username/password pairs.
+#[derive(Debug, Clone, Default)]
+pub struct StaticAuthenticator {
+    credentials: Vec<(String, String)>,
+}
+
+impl StaticAuthenticator {
+    pub fn new(credentials: Vec<(String, String)>) -> Self {
+        StaticAuthenticator { credentials }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    async fn authenticate(&self, username: &str, password: &str) -> io::Result<Option<Identity>> {
+        let found = self
+            .credentials
+            .iter()
+            .any(|(u, p)| u == username && p == password);
+        Ok(found.then(|| Identity::new(username)))
+    }
+}
@@ -0,0 +1,220 @@
+// Fail2ban-style protection against credential stuffing.
+// Failed RFC 1929 authentications are counted per source IP; an IP that fails
+// too often within the window is refused outright for the ban duration.
+// IPv6 sources are counted per /64, since a single host usually has a whole
+// /64 to pick addresses from.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+// Expired entries are pruned at most this often
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Share of the table evicted at once when it is full, so the scan that picks
+// the oldest entries is paid once per many insertions
+const EVICT_FRACTION: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct BanOptions {
+    /// Failed attempts allowed within `window` before the IP is banned.
+    pub max_failures: u32,
+    pub window: Duration,
+    pub ban_duration: Duration,
+    /// Most sources tracked at once. When the table is full, the oldest
+    /// entries that are not banned are forgotten first.
+    pub max_entries: usize,
+}
+
+impl Default for BanOptions {
+    fn default() -> Self {
+        BanOptions {
+            max_failures: 5,
+            window: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(600),
+            max_entries: 65536,
+        }
+    }
+}
+
+/// Counters exposed for monitoring.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BanStats {
+    pub auth_failures: u64,
+    pub bans: u64,
+    pub rejected_connections: u64,
+    pub currently_banned: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    failures: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+struct Table {
+    entries: HashMap<IpAddr, Entry>,
+    last_prune: Instant,
+}
+
+pub struct BanList {
+    options: BanOptions,
+    table: Mutex<Table>,
+    auth_failures: AtomicU64,
+    bans: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl BanList {
+    pub fn new(options: BanOptions) -> Self {
+        BanList {
+            options,
+            table: Mutex::new(Table {
+                entries: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+            auth_failures: AtomicU64::new(0),
+            bans: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Check whether `ip` is currently banned, counting the rejection if so.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let table = self.table.lock().unwrap();
+        let banned = table
+            .entries
+            .get(&source(ip))
+            .and_then(|e| e.banned_until)
+            .is_some_and(|until| until > now);
+        if banned {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        banned
+    }
+
+    /// Record a failed authentication; returns true if this failure banned the IP.
+    pub fn record_failure(&self, ip: IpAddr) -> bool {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+
+        if now.duration_since(table.last_prune) >= PRUNE_INTERVAL {
+            self.prune(&mut table.entries, now);
+            table.last_prune = now;
+        }
+        let key = source(ip);
+        if !table.entries.contains_key(&key) && table.entries.len() >= self.options.max_entries {
+            self.evict(&mut table.entries, now);
+        }
+
+        let entry = table.entries.entry(key).or_insert(Entry {
+            failures: 0,
+            window_start: now,
+            banned_until: None,
+        });
+
+        if now.duration_since(entry.window_start) > self.options.window {
+            entry.failures = 0;
+            entry.window_start = now;
+        }
+        entry.failures += 1;
+
+        if entry.failures >= self.options.max_failures {
+            entry.failures = 0;
+            entry.window_start = now;
+            entry.banned_until = Some(now + self.options.ban_duration);
+            self.bans.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Banning {} for {:?} after repeated authentication failures",
+                ip, self.options.ban_duration
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Forget past failures for `ip` after a successful authentication.
+    pub fn record_success(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let key = source(ip);
+        let mut table = self.table.lock().unwrap();
+        if table
+            .entries
+            .get(&key)
+            .is_some_and(|e| e.banned_until.is_none_or(|until| until <= now))
+        {
+            table.entries.remove(&key);
+        }
+    }
+
+    /// Lift a ban (or pending failure count) for `ip`, or for its whole /64
+    /// if it is an IPv6 address.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.table
+            .lock()
+            .unwrap()
+            .entries
+            .remove(&source(ip))
+            .is_some()
+    }
+
+    pub fn stats(&self) -> BanStats {
+        let now = Instant::now();
+        let currently_banned = self
+            .table
+            .lock()
+            .unwrap()
+            .entries
+            .values()
+            .filter(|e| e.banned_until.is_some_and(|until| until > now))
+            .count();
+        BanStats {
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            bans: self.bans.load(Ordering::Relaxed),
+            rejected_connections: self.rejected.load(Ordering::Relaxed),
+            currently_banned,
+        }
+    }
+
+    fn prune(&self, entries: &mut HashMap<IpAddr, Entry>, now: Instant) {
+        entries.retain(|_, e| match e.banned_until {
+            Some(until) => until > now,
+            None => now.duration_since(e.window_start) <= self.options.window,
+        });
+    }
+
+    // Make room in a full table: forget the oldest share of it, picking
+    // sources that are not banned before bans closest to expiry
+    fn evict(&self, entries: &mut HashMap<IpAddr, Entry>, now: Instant) {
+        let mut ages: Vec<_> = entries
+            .iter()
+            .map(|(ip, e)| match e.banned_until {
+                Some(until) if until > now => (true, until, *ip),
+                _ => (false, e.window_start, *ip),
+            })
+            .collect();
+        let count = (ages.len() / EVICT_FRACTION).max(1).min(ages.len());
+        if count < ages.len() {
+            ages.select_nth_unstable(count);
+        }
+        for (_, _, ip) in &ages[..count] {
+            entries.remove(ip);
+        }
+    }
+}
+
+// The key a source is counted under: IPv4 addresses (including IPv4-mapped
+// IPv6 ones) as they are, IPv6 addresses by their /64
+fn source(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from_bits(v6.to_bits() & (u128::MAX << 64))),
+        v4 => v4,
+    }
+}
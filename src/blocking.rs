@@ -0,0 +1,65 @@
+// Blocking client (`blocking` feature).
+//
+// For CLI tools and build scripts that want one proxied connection without
+// running an async runtime themselves. `Client` drives the async client on a
+// private single-threaded runtime and hands back a plain
+// `std::net::TcpStream`, so reads and writes afterwards are ordinary
+// blocking I/O with no runtime involved.
+
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+
+use tokio::runtime::Runtime;
+
+use crate::client::{self, SocksStream};
+
+/// Blocking wrapper around `client::Client`. Must not be used from async
+/// code: connecting blocks the calling thread.
+pub struct Client {
+    inner: client::Client,
+    runtime: Runtime,
+}
+
+impl Client {
+    /// Wrap a configured async client; timeouts, retries, DNS mode and
+    /// socket options all carry over.
+    pub fn new(inner: client::Client) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Client { inner, runtime })
+    }
+
+    /// See `client::Client::from_url`.
+    pub fn from_url(url: &str) -> io::Result<Self> {
+        Self::new(client::Client::from_url(url)?)
+    }
+
+    /// Connect to `host:port` (see `client::Client::connect`).
+    pub fn connect(&self, target: &str) -> io::Result<SocksStream<TcpStream>> {
+        into_std(self.runtime.block_on(self.inner.connect(target))?)
+    }
+
+    pub fn connect_to_domain(&self, domain: &str, port: u16) -> io::Result<SocksStream<TcpStream>> {
+        into_std(
+            self.runtime
+                .block_on(self.inner.connect_to_domain(domain, port))?,
+        )
+    }
+
+    pub fn connect_to_target(&self, target: SocketAddr) -> io::Result<SocksStream<TcpStream>> {
+        into_std(
+            self.runtime
+                .block_on(self.inner.connect_to_target(target))?,
+        )
+    }
+}
+
+// Detach the connection from the runtime and make it blocking again
+fn into_std(stream: SocksStream<tokio::net::TcpStream>) -> io::Result<SocksStream<TcpStream>> {
+    stream.try_map(|stream| {
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    })
+}
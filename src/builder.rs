@@ -0,0 +1,383 @@
+// Fluent construction of `Server` and `TlsServer`, validated at build time.
+// `ServerOptions` and `TlsServerOptions` remain for configuration loaded from
+// files; a builder can also start from them.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::accounting::Accounting;
+use crate::acl::Acl;
+use crate::audit::AuditSink;
+use crate::auth::{AuthLimits, Authenticator};
+use crate::ban::BanOptions;
+use crate::capture::CaptureOptions;
+#[cfg(feature = "chaos")]
+use crate::chaos::ChaosOptions;
+use crate::dialer::{DEFAULT_CONNECT_TIMEOUT, Dialer, OutboundBind};
+use crate::health::HealthOptions;
+use crate::hook::ConnectionHook;
+use crate::pac::PacOptions;
+use crate::policy::{BandwidthLimit, PolicyTable};
+use crate::privdrop::PrivilegeDrop;
+use crate::quota::QuotaStore;
+use crate::resolver::Resolver;
+use crate::routing::RoutingTable;
+use crate::server::{
+    DEFAULT_DRAIN_TIMEOUT, HandshakeTimeouts, ListenerOptions, Server, ServerOptions,
+    validate_bind_addr,
+};
+use crate::sniff::SniffOptions;
+use crate::sockopt::SocketOptions;
+use crate::tls::{ClientCertIdentity, TlsServer, TlsServerOptions, TlsSettings};
+use crate::udp_relay::UdpRelayOptions;
+
+/// Timeouts set together through `ServerBuilder::timeouts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Per-address connect timeout of the default direct dialer.
+    pub connect: Duration,
+    /// Close relays idle for this long.
+    pub idle: Option<Duration>,
+    /// Grace period `Server::shutdown` gives in-flight connections.
+    pub drain: Duration,
+    /// Per-phase deadlines for the client's handshake messages.
+    pub handshake: HandshakeTimeouts,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            idle: None,
+            drain: DEFAULT_DRAIN_TIMEOUT,
+            handshake: HandshakeTimeouts::default(),
+        }
+    }
+}
+
+/// Builds a `Server`, checking the configuration in `build`.
+#[derive(Default)]
+pub struct ServerBuilder {
+    options: ServerOptions,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+impl ServerBuilder {
+    /// Start from existing options, e.g. loaded from a configuration file.
+    pub fn from_options(options: ServerOptions) -> Self {
+        ServerBuilder { options }
+    }
+
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.options.bind_addr = addr.into();
+        self
+    }
+
+    /// Require username/password authentication against a fixed list.
+    pub fn credentials(mut self, credentials: Vec<(String, String)>) -> Self {
+        self.options.credentials = Some(credentials);
+        self.options.auth_required = true;
+        self
+    }
+
+    /// Require authentication against a custom backend.
+    pub fn auth(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.options.authenticator = Some(authenticator);
+        self.options.auth_required = true;
+        self
+    }
+
+    /// Whether clients must authenticate; `credentials` and `auth` turn this
+    /// on.
+    pub fn auth_required(mut self, required: bool) -> Self {
+        self.options.auth_required = required;
+        self
+    }
+
+    pub fn auth_limits(mut self, limits: AuthLimits) -> Self {
+        self.options.auth_limits = limits;
+        self
+    }
+
+    pub fn auth_ban(mut self, options: BanOptions) -> Self {
+        self.options.auth_ban = Some(options);
+        self
+    }
+
+    pub fn policies(mut self, policies: PolicyTable) -> Self {
+        self.options.policies = policies;
+        self
+    }
+
+    pub fn global_bandwidth(mut self, limit: BandwidthLimit) -> Self {
+        self.options.global_bandwidth = Some(limit);
+        self
+    }
+
+    pub fn connection_bandwidth(mut self, limit: BandwidthLimit) -> Self {
+        self.options.connection_bandwidth = Some(limit);
+        self
+    }
+
+    pub fn quota_store(mut self, store: Arc<dyn QuotaStore>) -> Self {
+        self.options.quota_store = Some(store);
+        self
+    }
+
+    pub fn max_sessions_per_user(mut self, limit: usize) -> Self {
+        self.options.max_sessions_per_user = Some(limit);
+        self
+    }
+
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.options.acl = Some(acl);
+        self
+    }
+
+    pub fn dialer(mut self, dialer: Arc<dyn Dialer>) -> Self {
+        self.options.dialer = Some(dialer);
+        self
+    }
+
+    pub fn routing(mut self, routing: RoutingTable) -> Self {
+        self.options.routing = Some(routing);
+        self
+    }
+
+    /// Serve UDP ASSOCIATE through a relay configured by `options`.
+    pub fn udp_relay(mut self, options: UdpRelayOptions) -> Self {
+        self.options.udp_relay = Some(options);
+        self
+    }
+
+    /// Also answer SOCKS6 draft requests, told apart by their version byte.
+    pub fn socks6(mut self, enabled: bool) -> Self {
+        self.options.socks6 = enabled;
+        self
+    }
+
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.options.resolver = Some(resolver);
+        self
+    }
+
+    pub fn outbound_bind(mut self, bind: OutboundBind) -> Self {
+        self.options.outbound_bind = Some(bind);
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.options.connect_timeout = timeouts.connect;
+        self.options.idle_timeout = timeouts.idle;
+        self.options.drain_timeout = timeouts.drain;
+        self.options.handshake_timeouts = timeouts.handshake;
+        self
+    }
+
+    pub fn max_handshaking(mut self, max: usize) -> Self {
+        self.options.max_handshaking = Some(max);
+        self
+    }
+
+    pub fn client_socket(mut self, options: SocketOptions) -> Self {
+        self.options.client_socket = options;
+        self
+    }
+
+    pub fn outbound_socket(mut self, options: SocketOptions) -> Self {
+        self.options.outbound_socket = options;
+        self
+    }
+
+    pub fn acceptors(mut self, acceptors: usize) -> Self {
+        self.options.acceptors = acceptors;
+        self
+    }
+
+    /// Also serve on a Unix socket, with permission bits `mode` if given.
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>, mode: Option<u32>) -> Self {
+        self.options.unix_socket_path = Some(path.into());
+        self.options.unix_socket_mode = mode;
+        self
+    }
+
+    /// Add a listener besides the bind address; may be called repeatedly.
+    pub fn listener(mut self, listener: ListenerOptions) -> Self {
+        self.options.listeners.push(listener);
+        self
+    }
+
+    pub fn privileges(mut self, privileges: PrivilegeDrop) -> Self {
+        self.options.privileges = Some(privileges);
+        self
+    }
+
+    /// Add a lifecycle hook; may be called repeatedly, hooks run in order.
+    pub fn hook(mut self, hook: Arc<dyn ConnectionHook>) -> Self {
+        self.options.hooks.push(hook);
+        self
+    }
+
+    /// Record authentication outcomes and allow/deny decisions.
+    pub fn audit(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.options.audit = Some(sink);
+        self
+    }
+
+    /// Serve `/healthz` and `/readyz` alongside the SOCKS listeners.
+    pub fn health(mut self, health: HealthOptions) -> Self {
+        self.options.health = Some(health);
+        self
+    }
+
+    pub fn pac(mut self, pac: PacOptions) -> Self {
+        self.options.pac = Some(pac);
+        self
+    }
+
+    /// Capture the plaintext of the relays `capture` selects.
+    pub fn capture(mut self, capture: CaptureOptions) -> Self {
+        self.options.capture = Some(capture);
+        self
+    }
+
+    /// Inject faults to test clients against (`chaos` feature).
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: ChaosOptions) -> Self {
+        self.options.chaos = Some(chaos);
+        self
+    }
+
+    /// Look for the TLS SNI or HTTP Host in relayed traffic, for the logs
+    /// and the ACLs.
+    pub fn sniff(mut self, sniff: SniffOptions) -> Self {
+        self.options.sniff = Some(sniff);
+        self
+    }
+
+    /// Keep persistent per-user byte counters.
+    pub fn accounting(mut self, accounting: Arc<Accounting>) -> Self {
+        self.options.accounting = Some(accounting);
+        self
+    }
+
+    /// Validate the configuration and create the server.
+    pub fn build(self) -> io::Result<Server> {
+        self.options.validate()?;
+        Ok(Server::from_options(self.options))
+    }
+
+    pub fn into_options(self) -> ServerOptions {
+        self.options
+    }
+}
+
+/// Builds a `TlsServer` with rustls and certificate files, checking the
+/// configuration in `build`.
+#[derive(Default)]
+pub struct TlsServerBuilder {
+    options: TlsServerOptions,
+}
+
+impl TlsServer {
+    pub fn builder() -> TlsServerBuilder {
+        TlsServerBuilder::default()
+    }
+}
+
+impl TlsServerBuilder {
+    /// Start from existing options, e.g. loaded from a configuration file.
+    pub fn from_options(options: TlsServerOptions) -> Self {
+        TlsServerBuilder { options }
+    }
+
+    /// The wrapped SOCKS5 server; its bind address is the TLS listener's.
+    pub fn server(mut self, server: ServerBuilder) -> Self {
+        self.options.server_options = server.into_options();
+        self
+    }
+
+    pub fn cert(mut self, cert_path: impl Into<String>, key_path: impl Into<String>) -> Self {
+        self.options.cert_path = cert_path.into();
+        self.options.key_path = key_path.into();
+        self
+    }
+
+    pub fn tls(mut self, settings: TlsSettings) -> Self {
+        self.options.tls = settings;
+        self
+    }
+
+    /// Require client certificates issued by the CAs in this bundle.
+    pub fn client_ca(mut self, path: impl Into<String>) -> Self {
+        self.options.client_ca_path = Some(path.into());
+        self
+    }
+
+    pub fn client_cert_identity(mut self, source: ClientCertIdentity) -> Self {
+        self.options.client_cert_identity = Some(source);
+        self
+    }
+
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        self.options.reload_interval = Some(interval);
+        self
+    }
+
+    pub fn plaintext_listener(mut self, addr: impl Into<String>) -> Self {
+        self.options.plaintext_bind_addr = Some(addr.into());
+        self
+    }
+
+    pub fn http2_connect(mut self) -> Self {
+        self.options.http2_connect = true;
+        self
+    }
+
+    /// Validate the configuration, load the certificates and create the
+    /// server.
+    pub fn build(self) -> io::Result<TlsServer> {
+        let options = &self.options;
+        options.server_options.validate()?;
+        if let Some(addr) = &options.plaintext_bind_addr {
+            validate_bind_addr(addr)?;
+            if *addr == options.server_options.bind_addr {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The plaintext listener cannot share the TLS bind address",
+                ));
+            }
+            if let Some(health) = &options.server_options.health
+                && health.bind_addr == *addr
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The plaintext listener cannot share the health bind address",
+                ));
+            }
+        }
+        if options.client_cert_identity.is_some() && options.client_ca_path.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "client_cert_identity needs client_ca_path, or no certificates are requested",
+            ));
+        }
+        if options
+            .reload_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "reload_interval must not be zero",
+            ));
+        }
+        TlsServer::new(self.options)
+    }
+}
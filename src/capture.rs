@@ -0,0 +1,536 @@
+// Packet captures of relayed connections, for troubleshooting.
+//
+// Relays matching a capture filter (by user or destination) get their
+// outbound stream tapped, and the plaintext both ways is written to a pcapng
+// file per connection. There are no packets to record at this level, so the
+// file holds a synthesized TCP conversation between the client and the
+// destination (handshake, data segments and FINs) that Wireshark and tcpdump
+// dissect like a real one. Domain targets have no address yet when the relay
+// starts; their packets use 0.0.0.0 and the file's comment names the target.
+//
+// The relay never waits for the file: data goes to the writer through a
+// bounded queue, and if the disk falls that far behind, the capture stops
+// there and its last packets carry a comment saying it was truncated.
+//
+// Captures contain everything users send and receive in the clear, TLS
+// payloads aside, so they are only taken when explicitly configured.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::acl::AclTarget;
+use crate::dialer::{Outbound, ProxyStream};
+use crate::hook::ConnectionInfo;
+use crate::protocol::SocksAddr;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+// Raw IPv4/IPv6 packets, no link layer
+const LINKTYPE_RAW: u16 = 101;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+// Payload per synthesized segment
+const SEGMENT_SIZE: usize = 16 * 1024;
+// Reads and writes queued for the writer before the capture is truncated
+const QUEUE_LEN: usize = 1024;
+
+/// Which relays to capture and where to write them.
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    /// Directory for the capture files, one `.pcapng` per connection.
+    pub dir: PathBuf,
+    /// Capture only these authenticated users' relays.
+    pub users: Option<Vec<String>>,
+    /// Capture only relays to these destinations (matched like ACL rules).
+    /// `AclTarget::Any` captures every relay.
+    pub destinations: Option<Vec<AclTarget>>,
+    /// Stop recording a connection after this many payload bytes; the relay
+    /// itself carries on.
+    pub max_bytes: Option<u64>,
+}
+
+impl CaptureOptions {
+    /// Capture nothing until `with_users` or `with_destinations` selects
+    /// what to capture.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        CaptureOptions {
+            dir: dir.into(),
+            users: None,
+            destinations: None,
+            max_bytes: None,
+        }
+    }
+
+    pub fn with_users(mut self, users: Vec<String>) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    pub fn with_destinations(mut self, destinations: Vec<AclTarget>) -> Self {
+        self.destinations = Some(destinations);
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Whether a relay to `addr` for `username` is captured: it has to pass
+    /// every filter that is set, and at least one must be.
+    pub fn matches(&self, addr: &SocksAddr, username: Option<&str>) -> bool {
+        if self.users.is_none() && self.destinations.is_none() {
+            return false;
+        }
+        if let Some(users) = &self.users
+            && !username.is_some_and(|username| users.iter().any(|user| user == username))
+        {
+            return false;
+        }
+        self.destinations
+            .as_ref()
+            .is_none_or(|targets| targets.iter().any(|target| target.matches(addr)))
+    }
+
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if self.users.is_none() && self.destinations.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "capture needs users or destinations to select connections \
+                 (AclTarget::Any captures all of them)",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Tap `outbound` so its traffic is written to a new capture file. A file
+/// that cannot be created is logged and the relay goes ahead untapped.
+pub(crate) async fn tap(
+    options: &CaptureOptions,
+    info: &ConnectionInfo,
+    addr: &SocksAddr,
+    outbound: Outbound,
+) -> Outbound {
+    let path = options.dir.join(file_name(info, addr));
+    let file = match create(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to create capture file {}: {}", path.display(), e);
+            return outbound;
+        }
+    };
+    info!(
+        "Capturing connection {} to {} in {}",
+        info.id,
+        addr,
+        path.display()
+    );
+
+    let client = info
+        .peer
+        .addr
+        .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+    let server = match addr {
+        SocksAddr::Ipv4(ip, port) => SocketAddr::from((*ip, *port)),
+        SocksAddr::Ipv6(ip, port) => SocketAddr::from((*ip, *port)),
+        SocksAddr::Domain(_, port) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, *port)),
+    };
+    let mut comment = format!("Connection {} to {}", info.id, addr);
+    if let Some(identity) = &info.identity {
+        comment.push_str(&format!(" for user {}", identity.username));
+    }
+
+    let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+    let truncated = Arc::new(AtomicBool::new(false));
+    let writer = Writer::new(Flow::new(client, server), comment, truncated.clone());
+    tokio::spawn(async move {
+        if let Err(e) = writer.run(file, receiver).await {
+            warn!("Failed to write capture file {}: {}", path.display(), e);
+        }
+    });
+
+    Outbound {
+        stream: Box::new(Tapped {
+            stream: outbound.stream,
+            sender,
+            remaining: options.max_bytes.unwrap_or(u64::MAX),
+            truncated,
+        }),
+        bound_addr: outbound.bound_addr,
+    }
+}
+
+fn file_name(info: &ConnectionInfo, addr: &SocksAddr) -> String {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let target: String = addr
+        .to_string()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}-{}-{}.pcapng", started, info.id, target)
+}
+
+// Capture files are readable by their owner only
+async fn create(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path).await
+}
+
+enum Event {
+    // Data from the client to the destination
+    Upload(SystemTime, Vec<u8>),
+    Download(SystemTime, Vec<u8>),
+}
+
+// The outbound stream, copying what passes through to the writer
+struct Tapped {
+    stream: Box<dyn ProxyStream>,
+    sender: mpsc::Sender<Event>,
+    remaining: u64,
+    truncated: Arc<AtomicBool>,
+}
+
+impl Tapped {
+    // Once the writer's queue is full, nothing more is recorded, so the file
+    // holds an unbroken start of the conversation
+    fn record(&mut self, data: &[u8], event: fn(SystemTime, Vec<u8>) -> Event) {
+        let len = (data.len() as u64).min(self.remaining) as usize;
+        if len == 0 {
+            return;
+        }
+        self.remaining -= len as u64;
+        let event = event(SystemTime::now(), data[..len].to_vec());
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(event) {
+            self.truncated.store(true, Ordering::Relaxed);
+            self.remaining = 0;
+        }
+    }
+}
+
+impl AsyncRead for Tapped {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.record(&buf.filled()[filled..], Event::Download);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Tapped {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.record(&buf[..n], Event::Upload);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Writes one connection's pcapng file as events arrive, and closes the
+// conversation once the tapped stream is gone
+struct Writer {
+    flow: Flow,
+    comment: String,
+    truncated: Arc<AtomicBool>,
+}
+
+impl Writer {
+    fn new(flow: Flow, comment: String, truncated: Arc<AtomicBool>) -> Self {
+        Writer {
+            flow,
+            comment,
+            truncated,
+        }
+    }
+
+    async fn run(mut self, file: File, mut events: mpsc::Receiver<Event>) -> io::Result<()> {
+        let mut file = BufWriter::new(file);
+        file.write_all(&section_header(&self.comment)).await?;
+        file.write_all(&interface_description()).await?;
+
+        let now = SystemTime::now();
+        for (from_client, flags) in [(true, TCP_SYN), (false, TCP_SYN | TCP_ACK), (true, TCP_ACK)] {
+            let packet = self.flow.segment(from_client, flags, &[]);
+            file.write_all(&enhanced_packet(now, &packet, None)).await?;
+        }
+
+        while let Some(event) = events.recv().await {
+            let (time, from_client, data) = match event {
+                Event::Upload(time, data) => (time, true, data),
+                Event::Download(time, data) => (time, false, data),
+            };
+            for chunk in data.chunks(SEGMENT_SIZE) {
+                let packet = self.flow.segment(from_client, TCP_PSH | TCP_ACK, chunk);
+                file.write_all(&enhanced_packet(time, &packet, None))
+                    .await?;
+            }
+            // Keep the file usable if the process goes away mid-relay
+            if events.is_empty() {
+                file.flush().await?;
+            }
+        }
+
+        let now = SystemTime::now();
+        let comment = self
+            .truncated
+            .load(Ordering::Relaxed)
+            .then_some("Capture truncated: the file could not keep up with the relay");
+        if comment.is_some() {
+            warn!("{}: capture truncated, writing fell behind", self.comment);
+        }
+        for from_client in [true, false] {
+            let packet = self.flow.segment(from_client, TCP_FIN | TCP_ACK, &[]);
+            file.write_all(&enhanced_packet(now, &packet, comment))
+                .await?;
+        }
+        file.flush().await
+    }
+}
+
+// The synthesized TCP conversation
+struct Flow {
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl Flow {
+    fn new(client: SocketAddr, server: SocketAddr) -> Self {
+        // Both ends have to be of the same family
+        let (client, server) = match (client, server) {
+            (SocketAddr::V4(_), SocketAddr::V6(_)) => (mapped(client), server),
+            (SocketAddr::V6(_), SocketAddr::V4(_)) => (client, mapped(server)),
+            _ => (client, server),
+        };
+        Flow {
+            client,
+            server,
+            client_seq: 0,
+            server_seq: 0,
+        }
+    }
+
+    // One IP packet carrying a TCP segment, advancing the sender's sequence
+    // number
+    fn segment(&mut self, from_client: bool, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let (src, dst, seq, ack) = if from_client {
+            (self.client, self.server, self.client_seq, self.server_seq)
+        } else {
+            (self.server, self.client, self.server_seq, self.client_seq)
+        };
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&src.port().to_be_bytes());
+        tcp.extend_from_slice(&dst.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.extend_from_slice(&[5 << 4, flags]);
+        tcp.extend_from_slice(&u16::MAX.to_be_bytes()); // window
+        tcp.extend_from_slice(&[0, 0, 0, 0]); // checksum, urgent pointer
+        tcp.extend_from_slice(payload);
+
+        // SYN and FIN take up a sequence number each
+        let advance = payload.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+        if from_client {
+            self.client_seq = self.client_seq.wrapping_add(advance);
+        } else {
+            self.server_seq = self.server_seq.wrapping_add(advance);
+        }
+
+        ip_packet(src.ip(), dst.ip(), tcp)
+    }
+}
+
+fn mapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port())),
+        v6 => v6,
+    }
+}
+
+// Wrap a TCP segment in an IP header and fill in the checksums
+fn ip_packet(src: IpAddr, dst: IpAddr, mut tcp: Vec<u8>) -> Vec<u8> {
+    let tcp_len = tcp.len();
+    let mut pseudo = Vec::with_capacity(40);
+    let mut packet = Vec::with_capacity(40 + tcp_len);
+
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, 6]);
+            pseudo.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]); // DF, TTL, TCP
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (src, dst) => {
+            let src = ipv6(src);
+            let dst = ipv6(dst);
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&(tcp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, 6]);
+
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[6, 64]); // TCP, hop limit
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+        }
+    }
+
+    let checksum = checksum(&[&pseudo, &tcp]);
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&tcp);
+    packet
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+// The Internet checksum (RFC 1071) over the concatenated parts
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd = None;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        match odd.take() {
+            None => odd = Some(byte),
+            Some(high) => sum += u32::from(u16::from_be_bytes([high, byte])),
+        }
+    }
+    if let Some(high) = odd {
+        sum += u32::from(high) << 8;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn section_header(comment: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes()); // major version
+    body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+    body.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+    option(&mut body, OPT_COMMENT, comment.as_bytes());
+    option(
+        &mut body,
+        OPT_SHB_USERAPPL,
+        concat!("charon ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    option(&mut body, OPT_END, &[]);
+    block(BLOCK_SECTION_HEADER, &body)
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+    body.extend_from_slice(&0u32.to_le_bytes()); // no snap length
+    block(BLOCK_INTERFACE, &body)
+}
+
+// Timestamps use the default resolution of microseconds
+fn enhanced_packet(time: SystemTime, packet: &[u8], comment: Option<&str>) -> Vec<u8> {
+    let micros = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut body = Vec::with_capacity(20 + packet.len() + 3);
+    body.extend_from_slice(&0u32.to_le_bytes()); // interface
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original
+    body.extend_from_slice(packet);
+    pad(&mut body);
+    if let Some(comment) = comment {
+        option(&mut body, OPT_COMMENT, comment.as_bytes());
+        option(&mut body, OPT_END, &[]);
+    }
+    block(BLOCK_ENHANCED_PACKET, &body)
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = (12 + body.len()) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
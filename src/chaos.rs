@@ -0,0 +1,351 @@
+// Fault injection for testing clients against a misbehaving proxy (`chaos`
+// feature).
+//
+// Each rule names a point in the server's handling of a SOCKS5 connection and
+// a fault to inject there: latency, a reset, a reply cut short, or replies
+// trickled out slowly. Rules fire with a probability drawn from a generator
+// seeded per connection (from the configured seed and the connection's number
+// in accept order), so a test that opens connections one after another sees
+// the same faults on every run. A rule can also be limited to its first few
+// hits, e.g. to reset the first two connections and let a retrying client
+// through on the third.
+//
+// Faults apply to SOCKS5 connections (`Server::handle_connection` and every
+// listener built on it), not to HTTP/2 CONNECT streams.
+
+use std::any::Any;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use log::debug;
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Sleep;
+
+// Weyl increment of splitmix64
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+tokio::task_local! {
+    static CURRENT: Arc<Connection>;
+}
+
+/// Where in a connection a fault is injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultPoint {
+    /// Before the client's greeting is read.
+    Accept,
+    /// Before the server answers the greeting with its method choice.
+    MethodReply,
+    /// Before the server answers username/password authentication.
+    AuthReply,
+    /// Before the server answers the request, whether it succeeds or not.
+    ConnectReply,
+    /// After the CONNECT reply, before relaying starts.
+    Relay,
+}
+
+/// What happens at a `FaultPoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Wait this long before going on.
+    Latency(Duration),
+    /// Abort the connection: a TCP reset for TCP clients, a plain close for
+    /// other transports.
+    Reset,
+    /// Send only this many more bytes to the client, then reset. At a reply
+    /// point this cuts the reply short; at `Relay`, the relayed data.
+    Truncate(usize),
+    /// From here on, send to the client at most `chunk` bytes at a time with
+    /// `delay` between chunks, like a congested link.
+    SlowRead { chunk: usize, delay: Duration },
+}
+
+/// A fault and where and how often to inject it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    pub point: FaultPoint,
+    pub fault: Fault,
+    /// Chance of firing each time a connection reaches `point`, from 0 to 1.
+    pub probability: f64,
+    /// Fire at most this many times over the server's lifetime.
+    pub limit: Option<u64>,
+}
+
+impl FaultRule {
+    /// A rule that fires every time.
+    pub fn new(point: FaultPoint, fault: Fault) -> Self {
+        FaultRule {
+            point,
+            fault,
+            probability: 1.0,
+            limit: None,
+        }
+    }
+
+    pub fn with_probability(mut self, probability: f64) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    pub fn with_limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// Fault injection settings (`ServerOptions::chaos`). For tests only.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChaosOptions {
+    /// Seed for the per-connection generators deciding whether rules fire.
+    pub seed: u64,
+    /// Checked in order at each point; every rule that fires applies.
+    pub rules: Vec<FaultRule>,
+}
+
+impl ChaosOptions {
+    pub fn new(seed: u64) -> Self {
+        ChaosOptions {
+            seed,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn with_rule(mut self, rule: FaultRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        for rule in &self.rules {
+            if !(0.0..=1.0).contains(&rule.probability) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Fault probability must be between 0 and 1",
+                ));
+            }
+            if let Fault::SlowRead { chunk: 0, .. } = rule.fault {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "SlowRead chunk size must not be zero",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// A server's rules and the state shared by its connections
+pub(crate) struct Chaos {
+    options: ChaosOptions,
+    connections: AtomicU64,
+    hits: Vec<AtomicU64>,
+}
+
+impl Chaos {
+    pub(crate) fn new(options: ChaosOptions) -> Self {
+        let hits = options.rules.iter().map(|_| AtomicU64::new(0)).collect();
+        Chaos {
+            options,
+            connections: AtomicU64::new(0),
+            hits,
+        }
+    }
+
+    // State for the next accepted connection
+    pub(crate) fn connection(self: &Arc<Self>) -> Arc<Connection> {
+        let number = self.connections.fetch_add(1, Ordering::Relaxed);
+        let rng = self
+            .options
+            .seed
+            .wrapping_add(number.wrapping_mul(GOLDEN_GAMMA));
+        Arc::new(Connection {
+            chaos: Arc::clone(self),
+            state: Mutex::new(State {
+                rng,
+                remaining: None,
+                slow: None,
+                reset: false,
+            }),
+        })
+    }
+}
+
+// One connection's generator and the faults armed on its client stream
+pub(crate) struct Connection {
+    chaos: Arc<Chaos>,
+    state: Mutex<State>,
+}
+
+struct State {
+    rng: u64,
+    // Bytes still to send before a truncation resets the connection
+    remaining: Option<usize>,
+    slow: Option<(usize, Duration)>,
+    reset: bool,
+}
+
+impl Connection {
+    async fn inject(&self, point: FaultPoint) -> io::Result<()> {
+        for fault in self.draw(point) {
+            debug!("Injecting {:?} at {:?}", fault, point);
+            match fault {
+                Fault::Latency(delay) => tokio::time::sleep(delay).await,
+                Fault::Reset => {
+                    self.state.lock().unwrap().reset = true;
+                    return Err(reset_error("Connection reset by fault injection"));
+                }
+                Fault::Truncate(bytes) => self.state.lock().unwrap().remaining = Some(bytes),
+                Fault::SlowRead { chunk, delay } => {
+                    self.state.lock().unwrap().slow = Some((chunk, delay));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The faults of the rules for `point` that fire this time
+    fn draw(&self, point: FaultPoint) -> Vec<Fault> {
+        let mut state = self.state.lock().unwrap();
+        let mut faults = Vec::new();
+        for (rule, hits) in self.chaos.options.rules.iter().zip(&self.chaos.hits) {
+            if rule.point != point {
+                continue;
+            }
+            // Draw for every rule, so one rule's limit running out does not
+            // change what the others draw
+            let roll = (splitmix64(&mut state.rng) >> 11) as f64 / (1u64 << 53) as f64;
+            if roll >= rule.probability {
+                continue;
+            }
+            if let Some(limit) = rule.limit
+                && hits.fetch_add(1, Ordering::Relaxed) >= limit
+            {
+                continue;
+            }
+            faults.push(rule.fault);
+        }
+        faults
+    }
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(GOLDEN_GAMMA);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn reset_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, msg)
+}
+
+// Run `future`, a connection's handling, with `connection` as the target of
+// `inject`
+pub(crate) async fn scope<F: Future>(connection: Arc<Connection>, future: F) -> F::Output {
+    CURRENT.scope(connection, future).await
+}
+
+// Inject the faults that fire at `point` into the current connection, if
+// fault injection is on. Fails if the connection is to be reset.
+pub(crate) async fn inject(point: FaultPoint) -> io::Result<()> {
+    match CURRENT.try_with(Arc::clone) {
+        Ok(connection) => connection.inject(point).await,
+        Err(_) => Ok(()),
+    }
+}
+
+// The client side of a connection, applying the truncation and slow-down
+// faults to what the server writes
+pub(crate) struct ChaosStream<S: Any> {
+    inner: S,
+    connection: Arc<Connection>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S: Any> ChaosStream<S> {
+    pub(crate) fn new(inner: S, connection: Arc<Connection>) -> Self {
+        ChaosStream {
+            inner,
+            connection,
+            delay: None,
+        }
+    }
+}
+
+impl<S: Any> Drop for ChaosStream<S> {
+    fn drop(&mut self) {
+        // Close with RST rather than FIN
+        if self.connection.state.lock().unwrap().reset
+            && let Some(stream) = (&self.inner as &dyn Any).downcast_ref::<TcpStream>()
+        {
+            let _ = SockRef::from(stream).set_linger(Some(Duration::ZERO));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin + Any> AsyncRead for ChaosStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin + Any> AsyncWrite for ChaosStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let (remaining, slow) = {
+            let mut state = this.connection.state.lock().unwrap();
+            if state.reset {
+                return Poll::Ready(Err(reset_error("Connection reset by fault injection")));
+            }
+            if state.remaining == Some(0) {
+                state.reset = true;
+                return Poll::Ready(Err(reset_error("Reply truncated by fault injection")));
+            }
+            (state.remaining, state.slow)
+        };
+
+        let mut len = buf.len();
+        if let Some(remaining) = remaining {
+            len = len.min(remaining);
+        }
+        if let Some((chunk, _)) = slow {
+            if let Some(delay) = &mut this.delay {
+                ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+            }
+            len = len.min(chunk);
+        }
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        if let Some(remaining) = remaining {
+            this.connection.state.lock().unwrap().remaining = Some(remaining - written);
+        }
+        if let Some((_, delay)) = slow {
+            this.delay = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,173 @@
+// hyper connector (`hyper-connector` feature).
+//
+// `SocksConnector` is a `tower::Service<Uri>`, which is what hyper-util's
+// legacy client accepts as a connector: every request's host and port become
+// a CONNECT through the configured proxy. The connection to the target is
+// plain TCP; wrap the connector in an HTTPS connector (e.g. hyper-rustls)
+// for `https://` URIs.
+
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+use crate::client::{Client, SocksStream};
+use crate::protocol::SocksAddr;
+use crate::tls_client::TlsClient;
+
+/// Connects hyper clients to their targets through a SOCKS5 proxy.
+#[derive(Clone)]
+pub struct SocksConnector {
+    proxy: Arc<Proxy>,
+}
+
+// Built once and shared behind an Arc, so the variants' sizes do not matter
+#[allow(clippy::large_enum_variant)]
+enum Proxy {
+    Plain(Client),
+    Tls(TlsClient),
+}
+
+impl SocksConnector {
+    pub fn new(client: Client) -> Self {
+        SocksConnector {
+            proxy: Arc::new(Proxy::Plain(client)),
+        }
+    }
+
+    /// Reach the proxy itself over TLS.
+    pub fn tls(client: TlsClient) -> Self {
+        SocksConnector {
+            proxy: Arc::new(Proxy::Tls(client)),
+        }
+    }
+}
+
+impl From<Client> for SocksConnector {
+    fn from(client: Client) -> Self {
+        SocksConnector::new(client)
+    }
+}
+
+impl From<TlsClient> for SocksConnector {
+    fn from(client: TlsClient) -> Self {
+        SocksConnector::tls(client)
+    }
+}
+
+impl tower_service::Service<Uri> for SocksConnector {
+    type Response = TokioIo<SocksConnection>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let proxy = Arc::clone(&self.proxy);
+        Box::pin(async move {
+            let target = target_of(&uri)?;
+            let connection = match (&*proxy, target) {
+                (Proxy::Plain(client), SocksAddr::Domain(domain, port)) => {
+                    SocksConnection::Plain(client.connect_to_domain(&domain, port).await?)
+                }
+                (Proxy::Plain(client), target) => {
+                    let addr = target.to_socket_addr().expect("IP target");
+                    SocksConnection::Plain(client.connect_to_target(addr).await?)
+                }
+                (Proxy::Tls(client), SocksAddr::Domain(domain, port)) => {
+                    SocksConnection::Tls(Box::new(client.connect_to_domain(&domain, port).await?))
+                }
+                (Proxy::Tls(client), target) => {
+                    let addr = target.to_socket_addr().expect("IP target");
+                    SocksConnection::Tls(Box::new(client.connect_to_target(addr).await?))
+                }
+            };
+            Ok(TokioIo::new(connection))
+        })
+    }
+}
+
+// The CONNECT target for a request URI, with the scheme's default port
+fn target_of(uri: &Uri) -> io::Result<SocksAddr> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_string());
+
+    let host = uri.host().ok_or_else(|| invalid("URI has no host"))?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("http")) => 80,
+        (None, Some("https")) => 443,
+        _ => return Err(invalid("URI has no port and no known scheme")),
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok(match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => SocksAddr::Ipv4(ip, port),
+        Ok(IpAddr::V6(ip)) => SocksAddr::Ipv6(ip, port),
+        Err(_) => SocksAddr::Domain(host.to_string(), port),
+    })
+}
+
+/// A connection made by `SocksConnector`.
+#[derive(Debug)]
+pub enum SocksConnection {
+    Plain(SocksStream<TcpStream>),
+    Tls(Box<SocksStream<TlsStream<TcpStream>>>),
+}
+
+impl Connection for SocksConnection {
+    fn connected(&self) -> Connected {
+        // Tunneled, so requests use origin-form like a direct connection
+        Connected::new()
+    }
+}
+
+impl AsyncRead for SocksConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SocksConnection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            SocksConnection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for SocksConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SocksConnection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            SocksConnection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SocksConnection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            SocksConnection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SocksConnection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            SocksConnection::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+// Outbound connection establishment.
+//
+// A `Dialer` turns a CONNECT target into a connected byte stream. The server
+// uses `DirectDialer` by default; per-user policies can swap in another one,
+// e.g. `UpstreamDialer` to egress through a further SOCKS5 proxy, or
+// `upstream::UpstreamPool` to spread connections over several.
+
+use std::any::Any;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::debug;
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::client::{ChainedClient, Client};
+use crate::happy_eyeballs;
+use crate::protocol::SocksAddr;
+use crate::resolver::{Resolver, SystemResolver};
+use crate::sockopt::SocketOptions;
+
+/// Per-address connect timeout used by `DirectDialer` unless overridden.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Any bidirectional byte stream the relay can drive. `Any` lets the relay
+/// recognise plain TCP sockets it can splice.
+pub trait ProxyStream: AsyncRead + AsyncWrite + Unpin + Send + Any {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Any> ProxyStream for T {}
+
+/// A connected outbound stream.
+pub struct Outbound {
+    pub stream: Box<dyn ProxyStream>,
+    /// Local address of the outbound socket, reported to the client as BND.ADDR.
+    pub bound_addr: Option<SocketAddr>,
+}
+
+#[async_trait]
+pub trait Dialer: Send + Sync {
+    /// Connect to `target`. Errors should carry an `io::ErrorKind` describing
+    /// the failure (refused, host unreachable, ...) so the server can pick the
+    /// matching SOCKS reply code.
+    async fn dial(&self, target: &SocksAddr) -> io::Result<Outbound>;
+
+    /// Connect to `target` like `dial`, but only to resolved addresses
+    /// `check` accepts. Dialers resolving domain targets locally should check
+    /// every address and fail with `PermissionDenied` when none is left; the
+    /// default passes the target to `dial`, for dialers that hand names on.
+    async fn dial_checked(
+        &self,
+        target: &SocksAddr,
+        check: &(dyn Fn(SocketAddr) -> bool + Sync),
+    ) -> io::Result<Outbound> {
+        let _ = check;
+        self.dial(target).await
+    }
+}
+
+/// Where direct outbound connections egress from on multi-homed hosts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboundBind {
+    /// Bind to this source address. Only destinations of the same address
+    /// family can be reached.
+    Address(IpAddr),
+    /// Bind to this network interface with `SO_BINDTODEVICE` (Linux only;
+    /// needs `CAP_NET_RAW`).
+    Interface(String),
+}
+
+impl FromStr for OutboundBind {
+    type Err = io::Error;
+
+    /// Parse an IP address, or otherwise take the string as an interface name.
+    fn from_str(s: &str) -> io::Result<Self> {
+        if s.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Empty outbound bind address",
+            ));
+        }
+        Ok(match s.parse() {
+            Ok(ip) => OutboundBind::Address(ip),
+            Err(_) => OutboundBind::Interface(s.to_string()),
+        })
+    }
+}
+
+impl From<IpAddr> for OutboundBind {
+    fn from(ip: IpAddr) -> Self {
+        OutboundBind::Address(ip)
+    }
+}
+
+/// Connects straight to the destination, resolving domain names locally and
+/// racing IPv6 and IPv4 addresses (Happy Eyeballs).
+#[derive(Clone)]
+pub struct DirectDialer {
+    resolver: Arc<dyn Resolver>,
+    connect_timeout: Duration,
+    outbound_bind: Option<OutboundBind>,
+    socket_options: SocketOptions,
+}
+
+impl Default for DirectDialer {
+    fn default() -> Self {
+        Self::with_resolver(Arc::new(SystemResolver::new()))
+    }
+}
+
+impl DirectDialer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve domain targets with `resolver` instead of the system resolver.
+    pub fn with_resolver(resolver: Arc<dyn Resolver>) -> Self {
+        DirectDialer {
+            resolver,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            outbound_bind: None,
+            socket_options: SocketOptions::default(),
+        }
+    }
+
+    /// Give up on each resolved address after `timeout` and move on to the
+    /// next one.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Egress from a specific source address or interface.
+    pub fn with_outbound_bind(mut self, bind: OutboundBind) -> Self {
+        self.outbound_bind = Some(bind);
+        self
+    }
+
+    /// Tune outbound sockets (Nagle, keepalive, buffer sizes).
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    async fn resolve(&self, target: &SocksAddr) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addr) = target.to_socket_addr() {
+            return Ok(vec![addr]);
+        }
+
+        let SocksAddr::Domain(domain, port) = target else {
+            unreachable!("IP targets always convert to a socket address");
+        };
+        let resolved = self.resolver.resolve(domain).await?;
+        Ok(resolved
+            .addrs
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, *port))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl Dialer for DirectDialer {
+    async fn dial(&self, target: &SocksAddr) -> io::Result<Outbound> {
+        self.dial_checked(target, &|_| true).await
+    }
+
+    async fn dial_checked(
+        &self,
+        target: &SocksAddr,
+        check: &(dyn Fn(SocketAddr) -> bool + Sync),
+    ) -> io::Result<Outbound> {
+        let resolve = self.resolve(target);
+        #[cfg(feature = "otel")]
+        let resolve = async {
+            match target {
+                SocksAddr::Domain(..) => {
+                    crate::otel::traced(crate::otel::Phase::Dns, resolve).await
+                }
+                _ => resolve.await,
+            }
+        };
+        let mut addrs = resolve.await?;
+        addrs.retain(|addr| check(*addr));
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Every address of {} is denied", target),
+            ));
+        }
+        if let Some(OutboundBind::Address(source)) = &self.outbound_bind {
+            addrs.retain(|addr| addr.is_ipv4() == source.is_ipv4());
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NetworkUnreachable,
+                    "Destination has no address in the outbound bind address family",
+                ));
+            }
+        }
+
+        let bind = self.outbound_bind.clone();
+        let options = self.socket_options.clone();
+        let connect = happy_eyeballs::connect_with(&addrs, self.connect_timeout, move |addr| {
+            connect_from(addr, bind.clone(), options.clone())
+        });
+        #[cfg(feature = "otel")]
+        let connect = crate::otel::traced(crate::otel::Phase::Connect, connect);
+        let stream = connect.await?;
+        Ok(Outbound {
+            bound_addr: stream.local_addr().ok(),
+            stream: Box::new(stream),
+        })
+    }
+}
+
+pub(crate) async fn connect_from(
+    addr: SocketAddr,
+    bind: Option<OutboundBind>,
+    options: SocketOptions,
+) -> io::Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    options.apply(SockRef::from(&socket))?;
+    match bind {
+        Some(OutboundBind::Address(ip)) => socket.bind(SocketAddr::new(ip, 0))?,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(OutboundBind::Interface(name)) => socket.bind_device(Some(name.as_bytes()))?,
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        Some(OutboundBind::Interface(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Binding to an interface is only supported on Linux",
+            ));
+        }
+        None => {}
+    }
+    socket.connect(addr).await
+}
+
+/// Connects through another SOCKS5 proxy, or a chain of them.
+pub struct UpstreamDialer {
+    hops: Hops,
+}
+
+enum Hops {
+    Proxy(Box<Client>),
+    Chain(ChainedClient),
+}
+
+impl UpstreamDialer {
+    pub fn new(client: Client) -> Self {
+        UpstreamDialer {
+            hops: Hops::Proxy(Box::new(client)),
+        }
+    }
+
+    /// Connect through every proxy of `chain` in turn; the target sees the
+    /// last one.
+    pub fn chained(chain: ChainedClient) -> Self {
+        UpstreamDialer {
+            hops: Hops::Chain(chain),
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for UpstreamDialer {
+    async fn dial(&self, target: &SocksAddr) -> io::Result<Outbound> {
+        debug!("Dialing {} through upstream proxy", target);
+        let stream = match (&self.hops, target) {
+            (Hops::Chain(chain), _) => chain.connect(target.clone()).await?,
+            (Hops::Proxy(client), SocksAddr::Domain(domain, port)) => {
+                client.connect_to_domain(domain, *port).await?
+            }
+            (Hops::Proxy(client), _) => {
+                let addr = target.to_socket_addr().expect("IP target");
+                client.connect_to_target(addr).await?
+            }
+        };
+        // Pass on the upstream's BND.ADDR, which is where the target sees the
+        // connection come from; fall back to our side of the hop when the
+        // upstream reports a name or the unspecified address
+        let bound_addr = stream
+            .bound_addr()
+            .to_socket_addr()
+            .filter(|addr| !addr.ip().is_unspecified())
+            .or_else(|| stream.get_ref().local_addr().ok());
+        Ok(Outbound {
+            bound_addr,
+            stream: Box::new(stream),
+        })
+    }
+}
@@ -0,0 +1,196 @@
+// In-process cache for resolved CONNECT domains.
+// Answers are kept for their TTL (clamped to the configured bounds; resolvers
+// that report no TTL get `default_ttl`). When the cache is full, the entry
+// closest to expiry is dropped, which is an expired one if there are any;
+// entries are also kept in expiry order so that takes no scan.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::resolver::{Resolved, Resolver};
+
+#[derive(Debug, Clone)]
+pub struct DnsCacheOptions {
+    /// Maximum number of domains kept at once.
+    pub max_entries: usize,
+    /// Lifetime for answers whose TTL is unknown (e.g. from the system resolver).
+    pub default_ttl: Duration,
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+}
+
+impl Default for DnsCacheOptions {
+    fn default() -> Self {
+        DnsCacheOptions {
+            max_entries: 10_000,
+            default_ttl: Duration::from_secs(60),
+            min_ttl: Duration::from_secs(5),
+            max_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Counters exposed for monitoring.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DnsCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+}
+
+impl DnsCacheStats {
+    /// Fraction of lookups answered from the cache, or 0 before any lookup.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+struct Entry {
+    resolved: Resolved,
+    expires_at: Instant,
+}
+
+// The cached answers, indexed by domain and by expiry
+#[derive(Default)]
+struct Entries {
+    by_domain: HashMap<String, Entry>,
+    by_expiry: BTreeSet<(Instant, String)>,
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        self.by_domain.len()
+    }
+
+    fn insert(&mut self, domain: String, entry: Entry) {
+        // The old key goes first, in case it equals the new one
+        self.remove(&domain);
+        self.by_expiry.insert((entry.expires_at, domain.clone()));
+        self.by_domain.insert(domain, entry);
+    }
+
+    fn remove(&mut self, domain: &str) {
+        if let Some(entry) = self.by_domain.remove(domain) {
+            self.by_expiry
+                .remove(&(entry.expires_at, domain.to_string()));
+        }
+    }
+
+    // Drop the entry closest to expiry
+    fn evict(&mut self) -> bool {
+        match self.by_expiry.pop_first() {
+            Some((_, domain)) => self.by_domain.remove(&domain).is_some(),
+            None => false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.by_domain.clear();
+        self.by_expiry.clear();
+    }
+}
+
+/// Wraps another resolver and caches its answers.
+pub struct CachingResolver {
+    inner: Arc<dyn Resolver>,
+    options: DnsCacheOptions,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CachingResolver {
+    pub fn new(inner: Arc<dyn Resolver>, options: DnsCacheOptions) -> Self {
+        CachingResolver {
+            inner,
+            options,
+            entries: Mutex::default(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Drop every cached answer.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn stats(&self) -> DnsCacheStats {
+        DnsCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+
+    fn lookup(&self, domain: &str, now: Instant) -> Option<Resolved> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.by_domain.get(domain)?;
+        if entry.expires_at <= now {
+            entries.remove(domain);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Resolved {
+            addrs: entry.resolved.addrs.clone(),
+            ttl: Some(entry.expires_at - now),
+        })
+    }
+
+    fn insert(&self, domain: String, resolved: Resolved, now: Instant) {
+        if self.options.max_entries == 0 {
+            return;
+        }
+
+        let ttl = resolved
+            .ttl
+            .unwrap_or(self.options.default_ttl)
+            .clamp(self.options.min_ttl, self.options.max_ttl);
+        let mut entries = self.entries.lock().unwrap();
+
+        if !entries.by_domain.contains_key(&domain)
+            && entries.len() >= self.options.max_entries
+            && entries.evict()
+        {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        entries.insert(
+            domain,
+            Entry {
+                resolved,
+                expires_at: now + ttl,
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl Resolver for CachingResolver {
+    async fn resolve(&self, domain: &str) -> io::Result<Resolved> {
+        let domain = domain.to_ascii_lowercase();
+        if let Some(resolved) = self.lookup(&domain, Instant::now()) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(resolved);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let resolved = self.inner.resolve(&domain).await?;
+        self.insert(domain, resolved.clone(), Instant::now());
+        Ok(resolved)
+    }
+}
@@ -0,0 +1,257 @@
+// Local DNS forwarder that resolves through a SOCKS5 proxy.
+//
+// Listens for DNS on UDP and TCP and sends every query to a remote resolver
+// over a CONNECT tunnel through the proxy, so lookups leave from the proxy
+// instead of the local network. A TCP client's connection becomes one tunnel
+// carrying its queries as they are. Each UDP query gets a tunnel of its own
+// and travels as DNS over TCP (RFC 7766); answers too large for the client's
+// UDP buffer come back truncated, and the client retries over TCP.
+//
+// Queries in flight and TCP clients share a cap; beyond it UDP queries are
+// dropped, as a busy resolver would, and TCP clients are closed.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::Semaphore;
+
+use crate::accept::{AcceptErrors, Backoff};
+use crate::client::Client;
+use crate::protocol::SocksAddr;
+
+/// How long a UDP query may take, tunnel setup included, unless overridden.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// UDP queries and TCP clients served at once, unless overridden.
+pub const DEFAULT_MAX_QUERIES: usize = 256;
+
+// Largest UDP answer for clients that do not advertise a buffer size (RFC 1035)
+const CLASSIC_UDP_SIZE: usize = 512;
+const HEADER_LEN: usize = 12;
+const TYPE_OPT: u16 = 41;
+
+/// Forwards DNS queries to a resolver through a proxy.
+#[derive(Clone)]
+pub struct DnsForwarder {
+    client: Client,
+    resolver: String,
+    query_timeout: Duration,
+    // Shared by clones, so a forwarder serving UDP and TCP has one cap
+    queries: Arc<Semaphore>,
+}
+
+impl DnsForwarder {
+    /// Forward to `resolver` (`host:port`, e.g. `1.1.1.1:53`), which must
+    /// answer DNS over TCP, through the proxy `client` points at.
+    pub fn new(client: Client, resolver: &str) -> io::Result<Self> {
+        resolver.parse::<SocksAddr>()?;
+        Ok(DnsForwarder {
+            client,
+            resolver: resolver.to_string(),
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            queries: Arc::new(Semaphore::new(DEFAULT_MAX_QUERIES)),
+        })
+    }
+
+    pub fn with_query_timeout(mut self, timeout: Duration) -> Self {
+        self.query_timeout = timeout;
+        self
+    }
+
+    /// How many UDP queries and TCP clients are served at once; more UDP
+    /// queries are dropped and more TCP clients closed.
+    pub fn with_max_queries(mut self, max_queries: usize) -> Self {
+        self.queries = Arc::new(Semaphore::new(max_queries));
+        self
+    }
+
+    /// Serve DNS on `bind_addr` over both UDP and TCP.
+    pub async fn run(&self, bind_addr: &str) -> io::Result<()> {
+        let socket = UdpSocket::bind(bind_addr).await?;
+        let listener = TcpListener::bind(socket.local_addr()?).await?;
+        tokio::try_join!(self.run_udp(socket), self.run_tcp(listener))?;
+        Ok(())
+    }
+
+    /// Answer queries arriving on an already bound UDP socket.
+    pub async fn run_udp(&self, socket: UdpSocket) -> io::Result<()> {
+        info!("DNS forwarder listening on {}/udp", socket.local_addr()?);
+        let socket = Arc::new(socket);
+        let mut buf = vec![0u8; u16::MAX as usize];
+
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // ICMP errors for earlier replies surface here on some
+                // platforms; they concern that reply only
+                Err(e) => {
+                    debug!("DNS forwarder receive failed: {}", e);
+                    continue;
+                }
+            };
+            if len < HEADER_LEN {
+                continue;
+            }
+
+            let Ok(permit) = Arc::clone(&self.queries).try_acquire_owned() else {
+                debug!("Too many DNS queries in flight, dropping one from {}", peer);
+                continue;
+            };
+            let query = buf[..len].to_vec();
+            let forwarder = self.clone();
+            let socket = Arc::clone(&socket);
+            tokio::spawn(async move {
+                let _permit = permit;
+                let answer =
+                    match tokio::time::timeout(forwarder.query_timeout, forwarder.query(&query))
+                        .await
+                    {
+                        Ok(Ok(answer)) => answer,
+                        Ok(Err(e)) => {
+                            warn!("DNS query from {} failed: {}", peer, e);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("DNS query from {} timed out", peer);
+                            return;
+                        }
+                    };
+                let answer = truncate(&query, answer);
+                if let Err(e) = socket.send_to(&answer, peer).await {
+                    debug!("Failed to answer DNS query from {}: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Relay DNS-over-TCP clients on an already bound listener, one tunnel
+    /// per connection.
+    pub async fn run_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        info!("DNS forwarder listening on {}/tcp", listener.local_addr()?);
+        let accept_errors = AcceptErrors::new();
+        let mut backoff = Backoff::new();
+
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(accepted) => {
+                    backoff.reset();
+                    accepted
+                }
+                Err(e) => {
+                    accept_errors
+                        .recover(&e, &mut backoff, || listener.accept())
+                        .await;
+                    continue;
+                }
+            };
+
+            let Ok(permit) = Arc::clone(&self.queries).try_acquire_owned() else {
+                debug!("Too many DNS clients, closing {}", peer);
+                continue;
+            };
+            let forwarder = self.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let mut tunnel = match forwarder.client.connect(&forwarder.resolver).await {
+                    Ok(tunnel) => tunnel,
+                    Err(e) => {
+                        warn!("DNS tunnel for {} failed: {}", peer, e);
+                        return;
+                    }
+                };
+                if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut tunnel).await {
+                    debug!("DNS relay for {} ended: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Send one DNS message to the resolver through a new tunnel and return
+    /// the answer.
+    pub async fn query(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let length = u16::try_from(message.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "DNS message too long"))?;
+        let mut tunnel = self.client.connect(&self.resolver).await?;
+
+        let mut request = Vec::with_capacity(2 + message.len());
+        request.extend_from_slice(&length.to_be_bytes());
+        request.extend_from_slice(message);
+        tunnel.write_all(&request).await?;
+
+        let length = tunnel.read_u16().await?;
+        let mut answer = vec![0u8; length as usize];
+        tunnel.read_exact(&mut answer).await?;
+        Ok(answer)
+    }
+}
+
+// Fit an answer into the client's UDP buffer: answers that are too large
+// become the header and question with the TC bit set
+fn truncate(query: &[u8], answer: Vec<u8>) -> Vec<u8> {
+    if answer.len() <= udp_limit(query) || answer.len() < HEADER_LEN {
+        return answer;
+    }
+    let question_end = skip_questions(query).unwrap_or(HEADER_LEN);
+    let mut truncated = answer[..HEADER_LEN].to_vec();
+    truncated[2] |= 0x02; // TC
+    truncated[4..6].copy_from_slice(&query[4..6]); // QDCOUNT
+    truncated[6..12].fill(0); // ANCOUNT, NSCOUNT, ARCOUNT
+    truncated.extend_from_slice(&query[HEADER_LEN..question_end]);
+    truncated
+}
+
+// The UDP payload size the client advertises in an EDNS OPT record, or the
+// classic 512 bytes
+fn udp_limit(query: &[u8]) -> usize {
+    advertised_udp_size(query)
+        .unwrap_or(0)
+        .max(CLASSIC_UDP_SIZE)
+}
+
+fn advertised_udp_size(query: &[u8]) -> Option<usize> {
+    let count = |at: usize| Some(usize::from(read_u16(query, at)?));
+    let records = count(6)? + count(8)? + count(10)?;
+    let mut offset = skip_questions(query)?;
+    for _ in 0..records {
+        offset = skip_name(query, offset)?;
+        // TYPE, CLASS (the payload size for OPT), TTL, RDLENGTH
+        if read_u16(query, offset)? == TYPE_OPT {
+            return Some(usize::from(read_u16(query, offset + 2)?));
+        }
+        offset += 10 + usize::from(read_u16(query, offset + 8)?);
+    }
+    None
+}
+
+fn read_u16(message: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *message.get(at)?,
+        *message.get(at + 1)?,
+    ]))
+}
+
+// Offset just past the question section
+fn skip_questions(message: &[u8]) -> Option<usize> {
+    let count = read_u16(message, 4)?;
+    let mut offset = HEADER_LEN;
+    for _ in 0..count {
+        offset = skip_name(message, offset)? + 4;
+    }
+    (offset <= message.len()).then_some(offset)
+}
+
+// Offset just past the (possibly compressed) name at `offset`
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xC0 == 0xC0 => return Some(offset + 2),
+            len => offset += 1 + usize::from(len),
+        }
+    }
+}
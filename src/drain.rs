@@ -0,0 +1,141 @@
+// Graceful shutdown: stop accepting, let in-flight connections finish within
+// a grace period, then cut whatever is left.
+
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::sync::Notify;
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+// How long a cut connection may take to wind down (relays stop on their own
+// and charge their usage) before it is dropped outright
+const CUT_WAIT: Duration = Duration::from_secs(1);
+
+/// Outcome of `Server::shutdown`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrainReport {
+    /// Connections that finished on their own during the grace period.
+    pub drained: u64,
+    /// Connections still open when the grace period ran out.
+    pub cut: u64,
+}
+
+/// Shutdown state shared by a server's accept loops and connections.
+#[derive(Debug, Default)]
+pub(crate) struct Drain {
+    stop: CancellationToken,
+    cut: CancellationToken,
+    active: AtomicU64,
+    closed: AtomicU64,
+    changed: Notify,
+    listening: AtomicUsize,
+}
+
+impl Drain {
+    /// Resolves once accept loops should stop.
+    pub(crate) fn stopped(&self) -> WaitForCancellationFuture<'_> {
+        self.stop.cancelled()
+    }
+
+    /// Cancelled when the grace period runs out; relays watch it.
+    pub(crate) fn cut_token(&self) -> CancellationToken {
+        self.cut.clone()
+    }
+
+    pub(crate) fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stop.is_cancelled()
+    }
+
+    /// Count an accept loop as running until the returned guard is dropped.
+    pub(crate) fn listen(self: &Arc<Self>) -> Listening {
+        self.listening.fetch_add(1, Ordering::Relaxed);
+        Listening(Arc::clone(self))
+    }
+
+    /// Accept loops currently running.
+    pub(crate) fn listeners(&self) -> usize {
+        self.listening.load(Ordering::Relaxed)
+    }
+
+    /// Wrap a connection so it counts as in flight until it finishes, and is
+    /// dropped shortly after being cut.
+    pub(crate) fn track<F>(self: &Arc<Self>, connection: F) -> impl Future<Output = ()> + use<F>
+    where
+        F: Future<Output = ()>,
+    {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let guard = Tracked(Arc::clone(self));
+        let cut = self.cut.clone();
+
+        async move {
+            let _guard = guard;
+            let mut connection = pin!(connection);
+            tokio::select! {
+                _ = &mut connection => return,
+                _ = cut.cancelled() => {}
+            }
+            let _ = tokio::time::timeout(CUT_WAIT, connection).await;
+        }
+    }
+
+    /// Stop accepting, wait up to `grace` for in-flight connections, then
+    /// cut the rest and wait for them to close.
+    pub(crate) async fn run(&self, grace: Duration) -> DrainReport {
+        let closed_before = self.closed.load(Ordering::Relaxed);
+        self.stop.cancel();
+        info!(
+            "Shutting down, draining {} connections for up to {:?}",
+            self.active(),
+            grace
+        );
+
+        let idle = tokio::time::timeout(grace, self.wait_idle()).await.is_ok();
+        let cut = if idle { 0 } else { self.active() };
+        let drained = self.closed.load(Ordering::Relaxed) - closed_before;
+        if cut > 0 {
+            warn!("Grace period over, cutting {} connections", cut);
+            self.cut.cancel();
+            self.wait_idle().await;
+        }
+
+        info!("Shutdown complete: {} drained, {} cut", drained, cut);
+        DrainReport { drained, cut }
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let mut changed = pin!(self.changed.notified());
+            changed.as_mut().enable();
+            if self.active() == 0 {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+// Counts a connection as in flight for as long as it lives
+struct Tracked(Arc<Drain>);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.closed.fetch_add(1, Ordering::Relaxed);
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+        self.0.changed.notify_waiters();
+    }
+}
+
+pub(crate) struct Listening(Arc<Drain>);
+
+impl Drop for Listening {
+    fn drop(&mut self) {
+        self.0.listening.fetch_sub(1, Ordering::Relaxed);
+    }
+}
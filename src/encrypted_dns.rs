@@ -0,0 +1,123 @@
+// DNS-over-HTTPS and DNS-over-TLS resolution.
+//
+// Keeps destination lookups off the wire in plaintext on the proxy host and
+// lets operators choose the upstream resolver independently of the system
+// configuration.
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use hickory_resolver::TokioResolver;
+use hickory_resolver::config::{
+    CLOUDFLARE, GOOGLE, NameServerConfig, QUAD9, ResolverConfig, ServerGroup,
+};
+use hickory_resolver::net::runtime::TokioRuntimeProvider;
+use log::debug;
+
+use crate::resolver::{Resolved, Resolver, unresolved};
+
+/// Encrypted transport used to reach the upstream resolver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsTransport {
+    /// DNS-over-HTTPS (RFC 8484), port 443.
+    Https,
+    /// DNS-over-TLS (RFC 7858), port 853.
+    Tls,
+}
+
+/// Upstream resolver to query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsUpstream {
+    Cloudflare,
+    Google,
+    Quad9,
+    Custom {
+        /// Addresses of the resolver.
+        ips: Vec<IpAddr>,
+        /// Name the resolver's certificate is verified against.
+        server_name: String,
+        /// DoH query path; defaults to `/dns-query`.
+        path: Option<String>,
+    },
+}
+
+/// Resolves domains over DoH or DoT.
+pub struct EncryptedResolver {
+    resolver: TokioResolver,
+}
+
+impl EncryptedResolver {
+    pub fn new(upstream: DnsUpstream, transport: DnsTransport) -> io::Result<Self> {
+        let name_servers = match &upstream {
+            DnsUpstream::Cloudflare => name_servers(&CLOUDFLARE, transport),
+            DnsUpstream::Google => name_servers(&GOOGLE, transport),
+            DnsUpstream::Quad9 => name_servers(&QUAD9, transport),
+            DnsUpstream::Custom {
+                ips,
+                server_name,
+                path,
+            } => {
+                let group = ServerGroup {
+                    ips,
+                    server_name,
+                    path: path.as_deref().unwrap_or("/dns-query"),
+                };
+                name_servers(&group, transport)
+            }
+        };
+        if name_servers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No DNS upstream addresses configured",
+            ));
+        }
+
+        let config = ResolverConfig::from_name_servers(name_servers);
+        let resolver = TokioResolver::builder_with_config(config, TokioRuntimeProvider::default())
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(EncryptedResolver { resolver })
+    }
+}
+
+fn name_servers(group: &ServerGroup<'_>, transport: DnsTransport) -> Vec<NameServerConfig> {
+    let server_name: Arc<str> = Arc::from(group.server_name);
+    group
+        .ips
+        .iter()
+        .map(|ip| match transport {
+            DnsTransport::Https => {
+                NameServerConfig::https(*ip, Arc::clone(&server_name), Some(Arc::from(group.path)))
+            }
+            DnsTransport::Tls => NameServerConfig::tls(*ip, Arc::clone(&server_name)),
+        })
+        .collect()
+}
+
+#[async_trait]
+impl Resolver for EncryptedResolver {
+    async fn resolve(&self, domain: &str) -> io::Result<Resolved> {
+        let lookup = self.resolver.lookup_ip(domain).await.map_err(|e| {
+            debug!("Encrypted DNS lookup for {} failed: {}", domain, e);
+            unresolved()
+        })?;
+
+        let addrs: Vec<IpAddr> = lookup.iter().collect();
+        if addrs.is_empty() {
+            return Err(unresolved());
+        }
+
+        Ok(Resolved {
+            addrs,
+            ttl: Some(
+                lookup
+                    .valid_until()
+                    .saturating_duration_since(Instant::now()),
+            ),
+        })
+    }
+}
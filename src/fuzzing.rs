@@ -0,0 +1,160 @@
+// Support for fuzzing the protocol parsers (`fuzzing` feature).
+//
+// cargo-fuzz targets can feed raw bytes to the `parse_*` functions, which run
+// the same parsers the server and client use, or generate well-formed
+// messages through the `arbitrary::Arbitrary` impls and check that
+// `encode_*` followed by `parse_*` gives them back. Generated messages stay
+// within what the wire format can carry (domains of 1 to 255 bytes, 1 to 255
+// methods, credentials of at most 255 bytes), so a round trip only fails on a
+// real bug.
+//
+//     fuzz_target!(|data: &[u8]| {
+//         let _ = socks5_rs::fuzzing::parse_request(data);
+//     });
+
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use crate::protocol::{HandshakeRequest, Request, SOCKS_VERSION, SocksAddr, UserPassAuth};
+use crate::socks6::Socks6Request;
+
+impl<'a> Arbitrary<'a> for SocksAddr {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let port = u.arbitrary()?;
+        Ok(match u.int_in_range(0..=2)? {
+            0 => SocksAddr::Ipv4(Ipv4Addr::from(u.arbitrary::<[u8; 4]>()?), port),
+            1 => SocksAddr::Ipv6(Ipv6Addr::from(u.arbitrary::<[u8; 16]>()?), port),
+            _ => {
+                let mut domain = bounded_string(u)?;
+                if domain.is_empty() {
+                    domain.push('a');
+                }
+                SocksAddr::Domain(domain, port)
+            }
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for HandshakeRequest {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let len = u.int_in_range(1..=255)?;
+        let methods = u.bytes(len)?.to_vec();
+        Ok(HandshakeRequest {
+            version: SOCKS_VERSION,
+            methods,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Request {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Request {
+            version: SOCKS_VERSION,
+            command: u.arbitrary()?,
+            addr: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for UserPassAuth {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(UserPassAuth {
+            username: bounded_string(u)?,
+            password: bounded_string(u)?,
+        })
+    }
+}
+
+// A string of at most 255 bytes, cut at a character boundary
+fn bounded_string(u: &mut Unstructured<'_>) -> arbitrary::Result<String> {
+    let mut s: String = u.arbitrary()?;
+    let mut end = s.len().min(255);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s.truncate(end);
+    Ok(s)
+}
+
+/// Parse a greeting as the server does.
+pub fn parse_handshake(data: &[u8]) -> io::Result<HandshakeRequest> {
+    let mut data = data;
+    ready(HandshakeRequest::read_from(&mut data))
+}
+
+/// Parse a request as the server does.
+pub fn parse_request(data: &[u8]) -> io::Result<Request> {
+    let mut data = data;
+    ready(Request::read_from(&mut data))
+}
+
+/// Parse an address (address type, address and port) as found in requests,
+/// replies and UDP headers.
+pub fn parse_addr(data: &[u8]) -> io::Result<SocksAddr> {
+    let mut data = data;
+    ready(SocksAddr::read_from(&mut data))
+}
+
+/// Parse a SOCKS6 request, version byte included, as the server does.
+pub fn parse_socks6_request(data: &[u8]) -> io::Result<Socks6Request> {
+    let mut data = data;
+    ready(Socks6Request::read_from(&mut data))
+}
+
+/// Parse username/password credentials as the server does with the RFC 1929
+/// limits.
+pub fn parse_user_pass(data: &[u8]) -> io::Result<UserPassAuth> {
+    let mut data = data;
+    ready(UserPassAuth::read_from(&mut data))
+}
+
+pub fn encode_handshake(handshake: &HandshakeRequest) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    ready(handshake.write_to(&mut buf))?;
+    Ok(buf)
+}
+
+pub fn encode_request(request: &Request) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    ready(request.write_to(&mut buf))?;
+    Ok(buf)
+}
+
+pub fn encode_addr(addr: &SocksAddr) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    ready(addr.write_to(&mut buf))?;
+    Ok(buf)
+}
+
+pub fn encode_user_pass(auth: &UserPassAuth) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    ready(auth.write_to(&mut buf))?;
+    Ok(buf)
+}
+
+/// Encode a SOCKS6 request. Option data comes back from
+/// `parse_socks6_request` padded as it travels, to a multiple of four bytes.
+pub fn encode_socks6_request(request: &Socks6Request) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    ready(request.write_to(&mut buf))?;
+    Ok(buf)
+}
+
+// Run a parser or encoder over an in-memory buffer to completion. Byte slices
+// and vectors never wait, so the future finishes on its first poll and no
+// runtime is needed.
+fn ready<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    match future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("in-memory I/O does not wait"),
+    }
+}
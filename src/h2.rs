@@ -0,0 +1,211 @@
+// HTTP/2 CONNECT gateway.
+//
+// Clients that negotiate "h2" on the TLS listener speak HTTP/2 instead of
+// SOCKS5, and every CONNECT request on the connection opens a tunnel: the
+// request's authority names the destination, and the stream carries the data
+// once the server has answered 200. Tunnels go through the same pipeline as
+// SOCKS5 CONNECTs (ban list, hooks, authentication, ACLs, quotas, rate limits
+// and the relay). Credentials come from a `Proxy-Authorization: Basic`
+// header, and SOCKS reply codes become HTTP statuses.
+
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::body::Incoming;
+use hyper::header::{HeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use hyper::http::uri::Authority;
+use hyper::server::conn::http2;
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use log::{debug, error};
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::sync::oneshot;
+
+use crate::dialer::ProxyStream;
+use crate::protocol::{
+    REP_ADDRESS_TYPE_NOT_SUPPORTED, REP_COMMAND_NOT_SUPPORTED, REP_CONNECTION_NOT_ALLOWED,
+    REP_SUCCEEDED, REP_TTL_EXPIRED, Reply, SocksAddr, UserPassAuth,
+};
+use crate::server::{PeerInfo, Server, Tunnel};
+
+/// ALPN identifier of HTTP/2 over TLS.
+pub(crate) const ALPN_H2: &[u8] = b"h2";
+
+/// Add "h2" to the ALPN protocols offered, after the existing ones.
+pub(crate) fn offer_h2(alpn_protocols: &mut Vec<Vec<u8>>) {
+    if !alpn_protocols.iter().any(|protocol| protocol == ALPN_H2) {
+        alpn_protocols.push(ALPN_H2.to_vec());
+    }
+}
+
+impl Server {
+    /// Serve HTTP/2 on `stream`, e.g. a TLS connection that negotiated "h2",
+    /// and handle every CONNECT request on it like a SOCKS5 CONNECT. Returns
+    /// once the client closes the connection, or after the open streams
+    /// finish when the server shuts down.
+    pub async fn handle_h2_connection<S>(&self, stream: S, peer: PeerInfo) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.serve_h2(stream, peer, None).await
+    }
+
+    // `handle_h2_connection`, optionally pinging the client every
+    // `keepalive` and dropping the connection when it stops answering
+    pub(crate) async fn serve_h2<S>(
+        &self,
+        stream: S,
+        peer: PeerInfo,
+        keepalive: Option<Duration>,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let server = self.clone();
+        let service = service_fn(move |request| {
+            let server = server.clone();
+            let peer = peer.clone();
+            async move { Ok::<_, Infallible>(server.h2_response(request, peer).await) }
+        });
+        let connection = http2::Builder::new(TokioExecutor::new())
+            .timer(TokioTimer::new())
+            .keep_alive_interval(keepalive)
+            .serve_connection(TokioIo::new(stream), service);
+        tokio::pin!(connection);
+
+        tokio::select! {
+            result = &mut connection => return result.map_err(io::Error::other),
+            _ = self.drain().stopped() => {}
+        }
+        // Relays are cut by the drain; this only stops new streams
+        connection.as_mut().graceful_shutdown();
+        connection.await.map_err(io::Error::other)
+    }
+
+    // Start a tunnel for a CONNECT request and answer once the pipeline has
+    // decided
+    async fn h2_response(
+        &self,
+        mut request: Request<Incoming>,
+        peer: PeerInfo,
+    ) -> Response<Empty<Bytes>> {
+        if request.method() != Method::CONNECT {
+            return status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let Some(target) = request.uri().authority().and_then(target_of) else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        debug!("HTTP/2 CONNECT to {}", target);
+
+        let (respond, response) = oneshot::channel();
+        let tunnel = H2Tunnel {
+            credentials: basic_credentials(&request),
+            upgrade: hyper::upgrade::on(&mut request),
+            respond,
+        };
+        let server = self.clone();
+        tokio::spawn(self.drain().track(async move {
+            if let Err(e) = server.handle_tunnel(Box::new(tunnel), target, peer).await {
+                error!("Error handling HTTP/2 tunnel: {}", e);
+            }
+        }));
+
+        // The pipeline gave up before answering, e.g. for a banned address
+        // or a hook that rejected the connection
+        response
+            .await
+            .unwrap_or_else(|_| status(StatusCode::FORBIDDEN))
+    }
+}
+
+// A CONNECT stream waiting for the pipeline's answer
+struct H2Tunnel {
+    credentials: Option<UserPassAuth>,
+    upgrade: OnUpgrade,
+    respond: oneshot::Sender<Response<Empty<Bytes>>>,
+}
+
+#[async_trait]
+impl Tunnel for H2Tunnel {
+    fn credentials(&self) -> Option<UserPassAuth> {
+        self.credentials.clone()
+    }
+
+    async fn reject_auth(self: Box<Self>) {
+        let mut response = status(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        response.headers_mut().insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"charon\""),
+        );
+        let _ = self.respond.send(response);
+    }
+
+    async fn refuse(self: Box<Self>, reply: Reply) {
+        let _ = self.respond.send(status(status_for(reply.reply)));
+    }
+
+    async fn open(self: Box<Self>, reply: Reply) -> io::Result<Box<dyn ProxyStream>> {
+        if self.respond.send(status(status_for(reply.reply))).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "HTTP/2 stream closed before the tunnel opened",
+            ));
+        }
+        let upgraded = self.upgrade.await.map_err(io::Error::other)?;
+        Ok(Box::new(TokioIo::new(upgraded)))
+    }
+}
+
+fn status(status: StatusCode) -> Response<Empty<Bytes>> {
+    let mut response = Response::new(Empty::new());
+    *response.status_mut() = status;
+    response
+}
+
+// The HTTP equivalent of a SOCKS reply code
+fn status_for(reply: u8) -> StatusCode {
+    match reply {
+        REP_SUCCEEDED => StatusCode::OK,
+        REP_CONNECTION_NOT_ALLOWED => StatusCode::FORBIDDEN,
+        REP_TTL_EXPIRED => StatusCode::GATEWAY_TIMEOUT,
+        REP_COMMAND_NOT_SUPPORTED | REP_ADDRESS_TYPE_NOT_SUPPORTED => StatusCode::NOT_IMPLEMENTED,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+// The destination named by a CONNECT authority, which must have a port
+fn target_of(authority: &Authority) -> Option<SocksAddr> {
+    let port = authority.port_u16()?;
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    Some(match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => SocksAddr::Ipv4(ip, port),
+        Ok(IpAddr::V6(ip)) => SocksAddr::Ipv6(ip, port),
+        Err(_) => SocksAddr::Domain(host.to_string(), port),
+    })
+}
+
+// `Proxy-Authorization: Basic` credentials; a malformed header counts as none
+fn basic_credentials(request: &Request<Incoming>) -> Option<UserPassAuth> {
+    let header = request.headers().get(PROXY_AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+    Some(UserPassAuth::new(
+        username.to_string(),
+        password.to_string(),
+    ))
+}
@@ -0,0 +1,122 @@
+// Happy Eyeballs (RFC 8305) connection racing for dual-stack destinations.
+//
+// Addresses are interleaved by family, starting with the family of the first
+// resolved address, and a new attempt is started every 250 ms (or as soon as
+// the previous one fails) until one connects. A destination with broken IPv6
+// therefore costs a short stagger rather than a full connect timeout.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::debug;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+/// Delay before starting the next attempt while the previous is pending
+/// (RFC 8305 section 5 recommends 250 ms).
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first of `addrs` that answers, racing attempts with the
+/// standard stagger. Each attempt gives up after `attempt_timeout`. If every
+/// attempt fails, the most specific error is returned: a refusal from any
+/// address wins over unreachability, which wins over a timeout.
+pub async fn connect(addrs: &[SocketAddr], attempt_timeout: Duration) -> io::Result<TcpStream> {
+    connect_with(addrs, attempt_timeout, TcpStream::connect).await
+}
+
+/// Like `connect`, but each attempt is made by `connect_one`, e.g. to bind
+/// the socket or set options before connecting.
+pub async fn connect_with<F, Fut>(
+    addrs: &[SocketAddr],
+    attempt_timeout: Duration,
+    connect_one: F,
+) -> io::Result<TcpStream>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<TcpStream>> + Send + 'static,
+{
+    let mut pending = interleave(addrs).into_iter();
+    // Dropping the set aborts the attempts that lost the race
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = pending.next() {
+            debug!("Connection attempt to {}", addr);
+            let attempt = connect_one(addr);
+            attempts.spawn(async move {
+                tokio::time::timeout(attempt_timeout, attempt)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("Connection to {} timed out", addr),
+                        ))
+                    })
+            });
+        } else if attempts.is_empty() {
+            break;
+        }
+
+        let finished = if pending.len() > 0 {
+            tokio::select! {
+                finished = attempts.join_next() => finished,
+                _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => continue,
+            }
+        } else {
+            attempts.join_next().await
+        };
+
+        match finished {
+            Some(Ok(Ok(stream))) => return Ok(stream),
+            Some(Ok(Err(e))) => {
+                debug!("Connection attempt failed: {}", e);
+                if last_error
+                    .as_ref()
+                    .is_none_or(|last| specificity(&e) >= specificity(last))
+                {
+                    last_error = Some(e);
+                }
+            }
+            Some(Err(e)) => last_error = last_error.or(Some(io::Error::other(e))),
+            None => {}
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::HostUnreachable, "No addresses to connect to")
+    }))
+}
+
+// How much an error says about the destination, for picking the one to report
+fn specificity(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => 3,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => 2,
+        io::ErrorKind::TimedOut => 1,
+        _ => 0,
+    }
+}
+
+// Alternate address families, keeping the resolver's order within each and
+// starting with the family it put first
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut ordered = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+    ordered
+}
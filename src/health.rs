@@ -0,0 +1,191 @@
+// Health and readiness endpoints for orchestrators and load balancers.
+//
+// A small HTTP/1 listener answers `GET /healthz` with 200 whenever the
+// process is serving, and `GET /readyz` with 200 only while the server should
+// receive traffic. When it should not, `/readyz` returns 503 with one reason
+// per line.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, info};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::accept::Backoff;
+use crate::server::Server;
+
+/// Settings for the health listener started by `Server::run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthOptions {
+    pub bind_addr: String,
+    /// `host:port` addresses that must accept a TCP connection for the
+    /// server to be ready, e.g. the upstream proxies policies route through.
+    pub upstreams: Vec<String>,
+    /// How long each upstream probe may take.
+    pub probe_timeout: Duration,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        HealthOptions {
+            bind_addr: "127.0.0.1:8080".to_string(),
+            upstreams: Vec::new(),
+            probe_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Result of a readiness check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Readiness {
+    /// Why the server should not receive traffic; empty when it should.
+    pub problems: Vec<String>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Server {
+    /// Check whether the server should receive traffic: it is accepting on
+    /// at least one listener and not shutting down, has room for new
+    /// handshakes, and every configured upstream accepts connections.
+    pub async fn readiness(&self) -> Readiness {
+        let mut problems = Vec::new();
+        if self.drain().is_stopped() {
+            problems.push("Shutting down".to_string());
+        } else if self.drain().listeners() == 0 {
+            problems.push("No listener is accepting connections".to_string());
+        }
+        if self.handshakes_exhausted() {
+            problems.push("Handshake limit reached".to_string());
+        }
+
+        let options = self.health_options();
+        let mut probes = JoinSet::new();
+        for upstream in &options.upstreams {
+            let upstream = upstream.clone();
+            let timeout = options.probe_timeout;
+            probes.spawn(async move {
+                let result =
+                    match tokio::time::timeout(timeout, TcpStream::connect(&upstream)).await {
+                        Ok(Ok(_)) => Ok(()),
+                        Ok(Err(e)) => Err(e.to_string()),
+                        Err(_) => Err("timed out".to_string()),
+                    };
+                result.map_err(|e| format!("Upstream {} unreachable: {}", upstream, e))
+            });
+        }
+        while let Some(probe) = probes.join_next().await {
+            match probe {
+                Ok(Ok(())) => {}
+                Ok(Err(problem)) => problems.push(problem),
+                Err(e) => problems.push(format!("Upstream probe failed: {}", e)),
+            }
+        }
+
+        Readiness { problems }
+    }
+
+    /// Serve `/healthz` and `/readyz` on an already bound listener until the
+    /// server shuts down.
+    pub async fn run_health_with_listener(&self, listener: TcpListener) -> io::Result<()> {
+        info!("Health endpoints listening on {}", listener.local_addr()?);
+        self.serve_http(listener, |server, request| async move {
+            server.health_response(&request).await
+        })
+        .await
+    }
+
+    // Answer HTTP/1 requests on `listener` with `respond` until the server
+    // shuts down
+    pub(crate) async fn serve_http<F, Fut>(
+        &self,
+        listener: TcpListener,
+        respond: F,
+    ) -> io::Result<()>
+    where
+        F: Fn(Arc<Server>, Request<Incoming>) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = Response<Full<Bytes>>> + Send,
+    {
+        let server = Arc::new(self.clone());
+        let mut backoff = Backoff::new();
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.drain().stopped() => return Ok(()),
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => {
+                    backoff.reset();
+                    stream
+                }
+                Err(e) => {
+                    self.accept_errors()
+                        .recover(&e, &mut backoff, || listener.accept())
+                        .await;
+                    continue;
+                }
+            };
+
+            let server = Arc::clone(&server);
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request| {
+                    let response = respond(Arc::clone(&server), request);
+                    async move { Ok::<_, Infallible>(response.await) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("HTTP request failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn health_response(&self, request: &Request<Incoming>) -> Response<Full<Bytes>> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed\n");
+        }
+        match request.uri().path() {
+            "/healthz" => text(StatusCode::OK, "ok\n"),
+            "/readyz" => {
+                let readiness = self.readiness().await;
+                if readiness.is_ready() {
+                    text(StatusCode::OK, "ready\n")
+                } else {
+                    let mut body = readiness.problems.join("\n");
+                    body.push('\n');
+                    text(StatusCode::SERVICE_UNAVAILABLE, body)
+                }
+            }
+            _ => text(StatusCode::NOT_FOUND, "Not found\n"),
+        }
+    }
+}
+
+pub(crate) fn text(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
@@ -0,0 +1,94 @@
+// Connection lifecycle callbacks for embedders: custom logging, billing or
+// policy without touching the connection handler.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::auth::Identity;
+use crate::protocol::SocksAddr;
+use crate::server::PeerInfo;
+use crate::sniff::ServerName;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What is known about a connection so far; filled in as it progresses.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Unique within the process.
+    pub id: u64,
+    pub peer: PeerInfo,
+    /// Set once authentication succeeded (and stays `None` for anonymous
+    /// clients).
+    pub identity: Option<Identity>,
+    /// Set once the request has been read.
+    pub target: Option<SocksAddr>,
+    /// The host name the client's first bytes name, when sniffing is
+    /// enabled and found one.
+    pub server_name: Option<ServerName>,
+    pub started: Instant,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(peer: PeerInfo) -> Self {
+        ConnectionInfo {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            identity: None,
+            target: None,
+            server_name: None,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// How a connection ended, passed to `ConnectionHook::on_close`.
+#[derive(Debug)]
+pub struct ConnectionSummary<'a> {
+    /// Bytes sent to the destination.
+    pub upload: u64,
+    /// Bytes sent back to the client.
+    pub download: u64,
+    pub duration: Duration,
+    /// Why the connection failed, if it did.
+    pub error: Option<&'a io::Error>,
+}
+
+/// Callbacks invoked at each stage of a connection, in registration order.
+/// Every method has a no-op default, so implement only what you need.
+/// Returning an error from `on_accept`, `on_auth` or `on_request` rejects the
+/// connection at that stage.
+#[async_trait]
+pub trait ConnectionHook: Send + Sync {
+    /// A connection arrived, before the SOCKS handshake.
+    async fn on_accept(&self, _info: &ConnectionInfo) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Authentication succeeded; `info.identity` is `None` if the client
+    /// did not need to authenticate.
+    async fn on_auth(&self, _info: &ConnectionInfo) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The client sent a request for `info.target`. An error is reported to
+    /// the client as "connection not allowed".
+    async fn on_request(&self, _info: &ConnectionInfo, _command: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The outbound connection was established, with its local address if
+    /// known, or failed.
+    async fn on_connect_result(
+        &self,
+        _info: &ConnectionInfo,
+        _result: Result<Option<SocketAddr>, &io::Error>,
+    ) {
+    }
+
+    /// The connection is over, whether it completed or failed at any stage.
+    async fn on_close(&self, _info: &ConnectionInfo, _summary: &ConnectionSummary<'_>) {}
+}
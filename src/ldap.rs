@@ -0,0 +1,209 @@
+// LDAP / Active Directory authentication backend.
+//
+// Users are located with a search (optionally as a service account), then
+// verified by binding as the user's DN. Group membership from the user entry
+// is mapped onto policy names so directory groups drive per-user policies.
+
+use std::io;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry, ldap_escape};
+use log::{debug, warn};
+use tokio::sync::Mutex;
+
+use crate::auth::{Authenticator, Identity};
+
+// LDAP result code for a failed bind with bad credentials
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+
+pub struct LdapAuthOptions {
+    /// Server URL, e.g. `ldap://dc1.example.com:389` or `ldaps://...`.
+    pub url: String,
+    /// Service account used for searching; anonymous search when `None`.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    /// Search base for user entries.
+    pub base_dn: String,
+    /// Filter locating the user; `{username}` is replaced with the escaped name.
+    pub user_filter: String,
+    /// Attribute listing the user's groups.
+    pub group_attribute: String,
+    /// When non-empty, the user must be a member of at least one of these groups.
+    pub required_groups: Vec<String>,
+    /// Ordered (group DN, policy name) pairs; the first group the user belongs to wins.
+    pub group_policies: Vec<(String, String)>,
+    /// Maximum number of idle connections kept for reuse.
+    pub pool_size: usize,
+    pub timeout: Duration,
+}
+
+impl Default for LdapAuthOptions {
+    fn default() -> Self {
+        LdapAuthOptions {
+            url: "ldap://127.0.0.1:389".to_string(),
+            bind_dn: None,
+            bind_password: None,
+            base_dn: String::new(),
+            user_filter: "(sAMAccountName={username})".to_string(),
+            group_attribute: "memberOf".to_string(),
+            required_groups: Vec::new(),
+            group_policies: Vec::new(),
+            pool_size: 4,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+pub struct LdapAuthenticator {
+    options: LdapAuthOptions,
+    pool: Mutex<Vec<Ldap>>,
+}
+
+impl LdapAuthenticator {
+    pub fn new(options: LdapAuthOptions) -> Self {
+        LdapAuthenticator {
+            options,
+            pool: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Take an idle connection bound as the service account, or open a new one
+    async fn checkout(&self) -> io::Result<Ldap> {
+        while let Some(mut ldap) = self.pool.lock().await.pop() {
+            if !ldap.is_closed() {
+                return Ok(ldap);
+            }
+        }
+
+        let settings = LdapConnSettings::new().set_conn_timeout(self.options.timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.options.url)
+            .await
+            .map_err(ldap_error)?;
+        ldap3::drive!(conn);
+        ldap.with_timeout(self.options.timeout);
+        self.bind_service(&mut ldap).await?;
+        debug!("Opened LDAP connection to {}", self.options.url);
+        Ok(ldap)
+    }
+
+    async fn checkin(&self, ldap: Ldap) {
+        let mut pool = self.pool.lock().await;
+        if pool.len() < self.options.pool_size {
+            pool.push(ldap);
+        }
+    }
+
+    async fn bind_service(&self, ldap: &mut Ldap) -> io::Result<()> {
+        if let Some(bind_dn) = &self.options.bind_dn {
+            let password = self.options.bind_password.as_deref().unwrap_or("");
+            ldap.simple_bind(bind_dn, password)
+                .await
+                .and_then(|r| r.success())
+                .map_err(ldap_error)?;
+        }
+        Ok(())
+    }
+
+    async fn find_user(&self, ldap: &mut Ldap, username: &str) -> io::Result<Option<SearchEntry>> {
+        let filter = self
+            .options
+            .user_filter
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(
+                &self.options.base_dn,
+                Scope::Subtree,
+                &filter,
+                vec![self.options.group_attribute.as_str()],
+            )
+            .await
+            .and_then(|r| r.success())
+            .map_err(ldap_error)?;
+
+        if entries.len() > 1 {
+            warn!("LDAP filter {} matched {} entries", filter, entries.len());
+            return Ok(None);
+        }
+        Ok(entries.into_iter().next().map(SearchEntry::construct))
+    }
+
+    fn policy_for(&self, groups: &[String]) -> Option<String> {
+        self.options
+            .group_policies
+            .iter()
+            .find(|(group, _)| groups.iter().any(|g| g.eq_ignore_ascii_case(group)))
+            .map(|(_, policy)| policy.clone())
+    }
+
+    async fn authenticate_with(
+        &self,
+        ldap: &mut Ldap,
+        username: &str,
+        password: &str,
+    ) -> io::Result<Option<Identity>> {
+        let Some(entry) = self.find_user(ldap, username).await? else {
+            debug!("LDAP user {} not found", username);
+            return Ok(None);
+        };
+
+        let groups = entry
+            .attrs
+            .get(&self.options.group_attribute)
+            .cloned()
+            .unwrap_or_default();
+
+        if !self.options.required_groups.is_empty()
+            && !self
+                .options
+                .required_groups
+                .iter()
+                .any(|req| groups.iter().any(|g| g.eq_ignore_ascii_case(req)))
+        {
+            debug!("LDAP user {} is not in any required group", username);
+            return Ok(None);
+        }
+
+        // Verify the password by binding as the user, then restore the
+        // service binding so the connection can go back into the pool
+        let result = ldap
+            .simple_bind(&entry.dn, password)
+            .await
+            .map_err(ldap_error)?;
+        self.bind_service(ldap).await?;
+
+        if result.rc == LDAP_INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        result.success().map_err(ldap_error)?;
+
+        let policy = self.policy_for(&groups);
+        Ok(Some(Identity {
+            username: username.to_string(),
+            groups,
+            policy,
+        }))
+    }
+}
+
+#[async_trait]
+impl Authenticator for LdapAuthenticator {
+    async fn authenticate(&self, username: &str, password: &str) -> io::Result<Option<Identity>> {
+        // An empty password turns a simple bind into an unauthenticated bind,
+        // which most directories accept
+        if username.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+
+        let mut ldap = self.checkout().await?;
+        let result = self.authenticate_with(&mut ldap, username, password).await;
+        if result.is_ok() {
+            self.checkin(ldap).await;
+        }
+        result
+    }
+}
+
+fn ldap_error(e: ldap3::LdapError) -> io::Error {
+    io::Error::other(format!("LDAP error: {}", e))
+}
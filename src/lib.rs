@@ -3,36 +3,116 @@
 //! This crate provides implementation of SOCKS5 proxy protocol (RFC 1928)
 //! with both client and server components, including TLS support.
 
+pub mod accept;
+pub mod accounting;
+pub mod acl;
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod asn;
+pub mod audit;
+#[cfg(feature = "audit")]
+pub mod audit_log;
+pub mod auth;
+pub mod ban;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod builder;
+pub mod capture;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 pub mod client;
+#[cfg(feature = "hyper-connector")]
+pub mod connector;
+pub mod dialer;
+pub mod dns_cache;
+pub mod dns_forward;
+pub mod drain;
+#[cfg(feature = "encrypted-dns")]
+pub mod encrypted_dns;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod h2;
+pub mod happy_eyeballs;
+pub mod health;
+pub mod hook;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+#[cfg(feature = "native-tls")]
+pub mod native_tls;
+#[cfg(feature = "obfs")]
+pub mod obfs;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod pac;
+pub mod policy;
+pub mod pool;
+pub mod privdrop;
 pub mod protocol;
+pub mod quota;
+pub mod ratelimit;
+#[cfg(feature = "redis")]
+pub mod redis_auth;
+pub mod relay;
+pub mod resolver;
+pub mod reverse;
+pub mod routing;
 pub mod server;
+pub mod session;
+pub mod sniff;
+pub mod sockopt;
+pub mod socks6;
+#[cfg(all(feature = "splice", target_os = "linux"))]
+mod splice;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+pub mod syslog;
+pub mod testing;
 pub mod tls;
 pub mod tls_client;
+pub mod transport;
+#[cfg(feature = "udp-aead")]
+pub mod udp_aead;
+pub mod udp_client;
+pub mod udp_relay;
+#[cfg(unix)]
+mod unix;
+pub mod upstream;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+#[cfg(feature = "ws")]
+pub mod ws;
 
 // Re-exports
+pub use crate::auth::{Authenticator, Identity};
 pub use crate::client::Client;
 pub use crate::protocol::SocksAddr;
 pub use crate::server::Server;
 pub use crate::tls::TlsServer;
-pub use crate::tls::generate_self_signed_cert;
+pub use crate::tls::{generate_self_signed_cert, generate_self_signed_cert_for};
 pub use crate::tls_client::TlsClient;
 
 // Helper functions
+
+/// A client configuration that accepts any server certificate.
+///
+/// **Test-only.** Certificate chains and hostnames are not checked, so anyone
+/// on the path can impersonate the proxy. Requires the `danger-insecure`
+/// feature.
+#[cfg(feature = "danger-insecure")]
 pub fn create_insecure_client_config() -> std::sync::Arc<rustls::ClientConfig> {
-    use std::sync::Arc;
     use rustls::ClientConfig;
-    
-    // Create a configuration that accepts all certificates (DANGEROUS!)
-    let mut config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(rustls::RootCertStore::empty())
+    use std::sync::Arc;
+
+    let provider = crate::tls::crypto_provider();
+    let config = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .expect("default protocol versions are supported")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(
+            tls_client::danger::NoCertificateVerification::new(provider),
+        ))
         .with_no_client_auth();
-        
-    // Disable certificate verification
-    // Only for development/testing - NOT for production!
-    // config.dangerous().set_certificate_verifier(Arc::new(
-    //     rustls::dangerous_configuration::NoCertificateVerifier {}
-    // ));
-    
+
     Arc::new(config)
 }
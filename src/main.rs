@@ -1,10 +1,54 @@
-use log::error;
+use log::{LevelFilter, error};
+use socks5_rs::Client;
+use socks5_rs::dns_forward::DnsForwarder;
 use socks5_rs::server::{Server, ServerOptions};
+use socks5_rs::syslog::{SyslogLogger, SyslogOptions};
 
 #[tokio::main]
 async fn main() {
-    // Initialize the logger
-    env_logger::init();
+    // Initialize the logger: syslog when CHARON_SYSLOG names a destination
+    // (unix:/dev/log, udp://host:514 or tcp://host:601), stderr otherwise
+    match std::env::var("CHARON_SYSLOG") {
+        Ok(destination) => {
+            let level = std::env::var("RUST_LOG")
+                .ok()
+                .and_then(|level| level.parse::<LevelFilter>().ok())
+                .unwrap_or(LevelFilter::Info);
+            let options = destination.parse().map(|transport| SyslogOptions {
+                transport,
+                level,
+                ..Default::default()
+            });
+            if let Err(e) = options.and_then(SyslogLogger::init) {
+                eprintln!("Failed to set up syslog: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Err(_) => env_logger::init(),
+    }
+
+    // Forward DNS through a proxy when CHARON_DNS_LISTEN names an address
+    // (e.g. 127.0.0.1:53): queries go to CHARON_DNS_RESOLVER (1.1.1.1:53 by
+    // default) through CHARON_DNS_PROXY (this server by default)
+    if let Ok(listen) = std::env::var("CHARON_DNS_LISTEN") {
+        let proxy = std::env::var("CHARON_DNS_PROXY")
+            .unwrap_or_else(|_| "socks5://127.0.0.1:1080".to_string());
+        let resolver =
+            std::env::var("CHARON_DNS_RESOLVER").unwrap_or_else(|_| "1.1.1.1:53".to_string());
+        match Client::from_url(&proxy).and_then(|client| DnsForwarder::new(client, &resolver)) {
+            Ok(forwarder) => {
+                tokio::spawn(async move {
+                    if let Err(e) = forwarder.run(&listen).await {
+                        error!("DNS forwarder error: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Failed to set up the DNS forwarder: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
     println!("Starting SOCKS5 server on localhost:1080");
     // Create server with default options (localhost:1080)
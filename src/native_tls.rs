@@ -0,0 +1,285 @@
+// TLS via the platform's own stack (OpenSSL on Linux, SChannel on Windows,
+// Security.framework on macOS).
+//
+// Useful where the TLS implementation is mandated (FIPS-validated OpenSSL) or
+// trust anchors must come from the OS certificate store. Features that rely
+// on rustls internals, such as client certificate verification and cipher
+// suite selection, are not available with this backend.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::net::TcpStream;
+use tokio_native_tls::TlsStream;
+
+use crate::client::{Client, SocksStream};
+use crate::h2::offer_h2;
+use crate::protocol::SocksAddr;
+use crate::server::Server;
+use crate::sockopt::SocketOptions;
+use crate::tls::{TlsAccepted, TlsBackend, TlsServer, TlsServerOptions, TlsSettings, TlsVersion};
+
+/// Server-side TLS backed by `native-tls`.
+pub struct NativeTlsBackend {
+    cert_path: String,
+    key_path: String,
+    settings: TlsSettings,
+    acceptor: RwLock<tokio_native_tls::TlsAcceptor>,
+}
+
+impl NativeTlsBackend {
+    /// Load a PEM certificate chain and PKCS#8 private key.
+    pub fn new(cert_path: String, key_path: String, settings: TlsSettings) -> io::Result<Self> {
+        check_settings(&settings)?;
+        let acceptor = load_acceptor(&cert_path, &key_path, &settings)?;
+
+        Ok(NativeTlsBackend {
+            cert_path,
+            key_path,
+            settings,
+            acceptor: RwLock::new(acceptor),
+        })
+    }
+}
+
+#[async_trait]
+impl TlsBackend for NativeTlsBackend {
+    async fn accept(&self, stream: TcpStream) -> io::Result<Option<TlsAccepted>> {
+        let acceptor = self.acceptor.read().unwrap().clone();
+        let tls_stream = acceptor.accept(stream).await.map_err(io::Error::other)?;
+        let peer_certificate = tls_stream
+            .get_ref()
+            .peer_certificate()
+            .ok()
+            .flatten()
+            .and_then(|cert| cert.to_der().ok());
+        let alpn_protocol = tls_stream.get_ref().negotiated_alpn().ok().flatten();
+
+        Ok(Some(TlsAccepted {
+            stream: Box::new(tls_stream),
+            peer_certificate,
+            alpn_protocol,
+            // native-tls has no exporter interface
+            #[cfg(feature = "udp-aead")]
+            udp_keys: None,
+        }))
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let acceptor = load_acceptor(&self.cert_path, &self.key_path, &self.settings)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    fn watched_files(&self) -> Vec<String> {
+        vec![self.cert_path.clone(), self.key_path.clone()]
+    }
+}
+
+impl TlsServer {
+    /// Create a TLS server that uses the system TLS stack instead of rustls.
+    /// `client_ca_path` is not supported by this backend.
+    pub fn with_native_tls(mut options: TlsServerOptions) -> io::Result<Self> {
+        if options.client_ca_path.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Client certificate verification is not supported by the native-tls backend",
+            ));
+        }
+
+        if options.http2_connect {
+            offer_h2(&mut options.tls.alpn_protocols);
+        }
+        let backend = NativeTlsBackend::new(options.cert_path, options.key_path, options.tls)?;
+        let mut server = TlsServer::with_backend(
+            Server::from_options(options.server_options),
+            Arc::new(backend),
+        );
+        if let Some(interval) = options.reload_interval {
+            server = server.with_reload_interval(interval);
+        }
+        if let Some(addr) = options.plaintext_bind_addr {
+            server = server.with_plaintext_listener(addr);
+        }
+        if options.http2_connect {
+            server = server.with_http2_connect();
+        }
+        Ok(server)
+    }
+}
+
+// Reject settings only rustls can honor
+fn check_settings(settings: &TlsSettings) -> io::Result<()> {
+    if !settings.cipher_suites.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Cipher suite selection is not supported by the native-tls backend",
+        ));
+    }
+    if settings.key_log {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Key logging is not supported by the native-tls backend",
+        ));
+    }
+    Ok(())
+}
+
+fn load_acceptor(
+    cert_path: &str,
+    key_path: &str,
+    settings: &TlsSettings,
+) -> io::Result<tokio_native_tls::TlsAcceptor> {
+    let cert = std::fs::read(cert_path)?;
+    let key = std::fs::read(key_path)?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut builder = native_tls::TlsAcceptor::builder(identity);
+    builder
+        .min_protocol_version(Some(protocol(
+            settings.min_version.unwrap_or(TlsVersion::Tls12),
+        )))
+        .max_protocol_version(settings.max_version.map(protocol))
+        .accept_alpn(&alpn_protocols(settings)?);
+    let acceptor = builder.build().map_err(io::Error::other)?;
+
+    Ok(acceptor.into())
+}
+
+/// SOCKS5 client that reaches the proxy over the system TLS stack, trusting
+/// the operating system's certificate store.
+pub struct NativeTlsClient {
+    proxy_host: String,
+    proxy_port: u16,
+    client: Client,
+    connector: tokio_native_tls::TlsConnector,
+}
+
+impl NativeTlsClient {
+    pub fn new(proxy_host: String, proxy_port: u16) -> io::Result<Self> {
+        let client = Client::new(proxy_host.clone(), proxy_port);
+        Self::with_client(proxy_host, proxy_port, client)
+    }
+
+    pub fn with_auth(
+        proxy_host: String,
+        proxy_port: u16,
+        username: String,
+        password: String,
+    ) -> io::Result<Self> {
+        let client = Client::with_auth(proxy_host.clone(), proxy_port, username, password);
+        Self::with_client(proxy_host, proxy_port, client)
+    }
+
+    fn with_client(proxy_host: String, proxy_port: u16, client: Client) -> io::Result<Self> {
+        let connector = connector(&TlsSettings::default())?;
+        Ok(NativeTlsClient {
+            proxy_host,
+            proxy_port,
+            client,
+            connector,
+        })
+    }
+
+    /// Use the given ALPN protocols and TLS version bounds when connecting
+    /// to the proxy. Cipher suites and key logging are not available with
+    /// this backend.
+    pub fn with_tls_settings(mut self, settings: &TlsSettings) -> io::Result<Self> {
+        self.connector = connector(settings)?;
+        Ok(self)
+    }
+
+    /// Use a caller-built connector, e.g. with extra root certificates.
+    pub fn with_connector(mut self, connector: native_tls::TlsConnector) -> Self {
+        self.connector = connector.into();
+        self
+    }
+
+    /// Tune the socket to the proxy (Nagle, keepalive, buffer sizes). The
+    /// options are set before connecting, so they already apply to the TLS
+    /// and SOCKS handshakes. Nagle is disabled by default.
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.client = self.client.with_socket_options(options);
+        self
+    }
+
+    pub async fn connect_to_domain(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> io::Result<SocksStream<TlsStream<TcpStream>>> {
+        self.connect(SocksAddr::Domain(domain.to_string(), port))
+            .await
+    }
+
+    pub async fn connect_to_target(
+        &self,
+        target: SocketAddr,
+    ) -> io::Result<SocksStream<TlsStream<TcpStream>>> {
+        self.connect(target.into()).await
+    }
+
+    async fn connect(&self, addr: SocksAddr) -> io::Result<SocksStream<TlsStream<TcpStream>>> {
+        self.client.connect_via(addr, || self.dial()).await
+    }
+
+    async fn dial(&self) -> io::Result<TlsStream<TcpStream>> {
+        let tcp_stream = self.client.dial_tcp().await?;
+        debug!(
+            "Connected to SOCKS5 proxy at {}:{}",
+            self.proxy_host, self.proxy_port
+        );
+
+        let tls_stream = self
+            .connector
+            .connect(self.client.proxy_host(), tcp_stream)
+            .await
+            .map_err(io::Error::other)?;
+        debug!("TLS connection established to proxy");
+        Ok(tls_stream)
+    }
+}
+
+fn connector(settings: &TlsSettings) -> io::Result<tokio_native_tls::TlsConnector> {
+    check_settings(settings)?;
+
+    let alpn = alpn_protocols(settings)?;
+    let alpn: Vec<&str> = alpn.iter().map(String::as_str).collect();
+    let connector = native_tls::TlsConnector::builder()
+        .min_protocol_version(Some(protocol(
+            settings.min_version.unwrap_or(TlsVersion::Tls12),
+        )))
+        .max_protocol_version(settings.max_version.map(protocol))
+        .request_alpns(&alpn)
+        .build()
+        .map_err(io::Error::other)?;
+
+    Ok(connector.into())
+}
+
+fn protocol(version: TlsVersion) -> native_tls::Protocol {
+    match version {
+        TlsVersion::Tls12 => native_tls::Protocol::Tlsv12,
+        TlsVersion::Tls13 => native_tls::Protocol::Tlsv13,
+    }
+}
+
+// native-tls takes ALPN protocol names as strings
+fn alpn_protocols(settings: &TlsSettings) -> io::Result<Vec<String>> {
+    settings
+        .alpn_protocols
+        .iter()
+        .map(|protocol| {
+            String::from_utf8(protocol.clone()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "ALPN protocol names must be UTF-8 with the native-tls backend",
+                )
+            })
+        })
+        .collect()
+}
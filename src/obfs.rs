@@ -0,0 +1,552 @@
+// Obfuscated transport with a pre-shared key (`obfs` feature).
+//
+// An alternative to TLS for networks that block TLS on unusual ports but let
+// unknown protocols through. Each direction starts with a random salt, from
+// which both ends derive a session key, followed by AEAD-sealed chunks: a
+// sealed two-byte length, then the sealed payload, with a counter as nonce.
+// Without the password the stream looks like random bytes, with no plaintext
+// headers or magic values to match on. SOCKS5, authentication included, runs
+// inside unchanged.
+//
+// The password is stretched with Argon2id once per config, and each session
+// key comes from HKDF-SHA256 over the result, with a label per direction so
+// that a client's own stream reflected back to it does not authenticate. The
+// client writes before it has the server's salt, so its key is bound to its
+// own salt only; the server writes once it has read the client's, and binds
+// its key to both salts, so a recorded server stream cannot be replayed to
+// another connection.
+//
+// The server remembers recent salts and refuses replays. A connection whose
+// first chunk does not authenticate is not closed straight away but read
+// until the peer gives up, so probing with random data learns nothing.
+
+use std::collections::{HashSet, VecDeque};
+use std::future::poll_fn;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use argon2::Argon2;
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use log::{debug, warn};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::io::poll_read_buf;
+
+use crate::client::Client;
+use crate::server::{PeerInfo, Server};
+use crate::transport::{Accepted, Acceptor, Transport, TransportClient};
+
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const LENGTH_LEN: usize = 2;
+// Largest payload per chunk
+const MAX_PAYLOAD: usize = 0x3FFF;
+const CLIENT_INFO: &[u8] = b"charon-obfs client subkey";
+const SERVER_INFO: &[u8] = b"charon-obfs server subkey";
+// Both ends stretch the password alike, so the Argon2 salt is fixed
+const STRETCH_SALT: &[u8] = b"charon-obfs password";
+const KEY_LEN: usize = 32;
+// Salts the server remembers to detect replays
+const REPLAY_CACHE_SIZE: usize = 64 * 1024;
+// How long a connection that failed the handshake is read before closing
+const PROBE_HOLD: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ObfsCipher {
+    #[default]
+    ChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl ObfsCipher {
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            ObfsCipher::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+            ObfsCipher::Aes256Gcm => &aead::AES_256_GCM,
+        }
+    }
+}
+
+/// The shared secret and cipher both ends must agree on. Clones share the
+/// server's replay cache.
+#[derive(Clone)]
+pub struct ObfsConfig {
+    // The stretched password
+    key: Arc<[u8; KEY_LEN]>,
+    cipher: ObfsCipher,
+    handshake_timeout: Duration,
+    salts: Arc<Mutex<SaltCache>>,
+}
+
+impl ObfsConfig {
+    /// Derive keys from `password`. Use a long random string: anyone who
+    /// guesses it can use the proxy and read the traffic. Stretching the
+    /// password takes a moment, so build the config once and clone it.
+    pub fn new(password: &str) -> io::Result<Self> {
+        if password.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Obfuscation password must not be empty",
+            ));
+        }
+        let mut key = [0u8; KEY_LEN];
+        Argon2::default()
+            .hash_password_into(password.as_bytes(), STRETCH_SALT, &mut key)
+            .map_err(|e| io::Error::other(format!("Key derivation failed: {}", e)))?;
+        Ok(ObfsConfig {
+            key: Arc::new(key),
+            cipher: ObfsCipher::default(),
+            handshake_timeout: Duration::from_secs(10),
+            salts: Arc::default(),
+        })
+    }
+
+    pub fn with_cipher(mut self, cipher: ObfsCipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// How long the server waits for a client's salt and first chunk.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    // The session key the client seals with
+    fn client_key(&self, client_salt: &[u8]) -> io::Result<LessSafeKey> {
+        self.key(CLIENT_INFO, client_salt)
+    }
+
+    // The session key the server seals with
+    fn server_key(&self, server_salt: &[u8], client_salt: &[u8]) -> io::Result<LessSafeKey> {
+        self.key(SERVER_INFO, &[server_salt, client_salt].concat())
+    }
+
+    fn key(&self, info: &[u8], salt: &[u8]) -> io::Result<LessSafeKey> {
+        let algorithm = self.cipher.algorithm();
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(&self.key[..]);
+        let info = [info];
+        let okm = prk
+            .expand(&info, algorithm)
+            .map_err(|_| io::Error::other("Key derivation failed"))?;
+        Ok(LessSafeKey::new(UnboundKey::from(okm)))
+    }
+}
+
+#[derive(Default)]
+struct SaltCache {
+    seen: HashSet<[u8; SALT_LEN]>,
+    order: VecDeque<[u8; SALT_LEN]>,
+}
+
+impl SaltCache {
+    // Remember `salt`; false if it was already seen
+    fn insert(&mut self, salt: [u8; SALT_LEN]) -> bool {
+        if !self.seen.insert(salt) {
+            return false;
+        }
+        self.order.push_back(salt);
+        if self.order.len() > REPLAY_CACHE_SIZE
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        true
+    }
+}
+
+// One direction's key and nonce counter
+struct Chunker {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Chunker {
+    fn new(key: LessSafeKey) -> Self {
+        Chunker { key, counter: 0 }
+    }
+
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn seal(&mut self, plaintext: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        let mut block = plaintext.to_vec();
+        let nonce = self.nonce();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut block)
+            .map_err(|_| io::Error::other("Encryption failed"))?;
+        out.extend_from_slice(&block);
+        Ok(())
+    }
+
+    // Decrypt a sealed block in place, leaving the plaintext
+    fn open(&mut self, block: &mut BytesMut) -> io::Result<()> {
+        let nonce = self.nonce();
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::empty(), block)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Obfuscated stream failed authentication",
+                )
+            })?
+            .len();
+        block.truncate(len);
+        Ok(())
+    }
+}
+
+/// A byte stream carried over the obfuscated transport.
+pub struct ObfsStream<S> {
+    inner: S,
+    config: ObfsConfig,
+    server: bool,
+    salt: [u8; SALT_LEN],
+    // Set once the peer's salt has arrived
+    reader: Option<Chunker>,
+    peer_salt: [u8; SALT_LEN],
+    // The server's is also set once the client's salt has arrived
+    writer: Option<Chunker>,
+    // Received bytes not decrypted yet
+    received: BytesMut,
+    // Payload length of the next chunk, once its length block is opened
+    next_len: Option<usize>,
+    // Decrypted payload not read yet
+    pending: BytesMut,
+    // Sealed bytes not written yet, starting with our salt
+    unsent: BytesMut,
+}
+
+impl<S> ObfsStream<S> {
+    /// Start the client side of the transport over `inner`. Nothing is sent
+    /// until the first write.
+    pub fn new(inner: S, config: ObfsConfig) -> io::Result<Self> {
+        let mut stream = Self::start(inner, config, false)?;
+        stream.writer = Some(Chunker::new(stream.config.client_key(&stream.salt)?));
+        Ok(stream)
+    }
+
+    // The server side, which can only write once the client's salt is read
+    fn accept(inner: S, config: ObfsConfig) -> io::Result<Self> {
+        Self::start(inner, config, true)
+    }
+
+    fn start(inner: S, config: ObfsConfig, server: bool) -> io::Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| io::Error::other("No randomness for the salt"))?;
+        Ok(ObfsStream {
+            inner,
+            config,
+            server,
+            salt,
+            reader: None,
+            peer_salt: [0; SALT_LEN],
+            writer: None,
+            received: BytesMut::new(),
+            next_len: None,
+            pending: BytesMut::new(),
+            unsent: BytesMut::from(&salt[..]),
+        })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ObfsStream<S> {
+    // Read until `received` holds `n` bytes; false at EOF
+    fn poll_fill(&mut self, cx: &mut Context<'_>, n: usize) -> Poll<io::Result<bool>> {
+        while self.received.len() < n {
+            self.received.reserve(n - self.received.len());
+            if ready!(poll_read_buf(
+                Pin::new(&mut self.inner),
+                cx,
+                &mut self.received
+            ))? == 0
+            {
+                return Poll::Ready(Ok(false));
+            }
+        }
+        Poll::Ready(Ok(true))
+    }
+
+    // Read the peer's salt, if still due, and the next chunk's length;
+    // false if the stream ended cleanly
+    fn poll_header(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        if self.reader.is_none() {
+            if !ready!(self.poll_fill(cx, SALT_LEN))? {
+                return Poll::Ready(self.end_of_stream());
+            }
+            self.peer_salt
+                .copy_from_slice(&self.received.split_to(SALT_LEN));
+            let key = if self.server {
+                let writer = self.config.server_key(&self.salt, &self.peer_salt)?;
+                self.writer = Some(Chunker::new(writer));
+                self.config.client_key(&self.peer_salt)?
+            } else {
+                self.config.server_key(&self.peer_salt, &self.salt)?
+            };
+            self.reader = Some(Chunker::new(key));
+        }
+        if self.next_len.is_none() {
+            if !ready!(self.poll_fill(cx, LENGTH_LEN + TAG_LEN))? {
+                return Poll::Ready(self.end_of_stream());
+            }
+            let mut block = self.received.split_to(LENGTH_LEN + TAG_LEN);
+            if let Some(reader) = &mut self.reader {
+                reader.open(&mut block)?;
+            }
+            let len = usize::from(u16::from_be_bytes([block[0], block[1]]));
+            if len > MAX_PAYLOAD {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Obfuscated chunk too long",
+                )));
+            }
+            self.next_len = Some(len);
+        }
+        Poll::Ready(Ok(true))
+    }
+
+    // EOF is only clean between chunks
+    fn end_of_stream(&self) -> io::Result<bool> {
+        if self.received.is_empty() {
+            Ok(false)
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    // Write out the sealed bytes
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.unsent))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ObfsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.pending.is_empty() {
+            if !ready!(this.poll_header(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+            let len = this.next_len.unwrap_or_default();
+            if !ready!(this.poll_fill(cx, len + TAG_LEN))? {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            let mut block = this.received.split_to(len + TAG_LEN);
+            if let Some(reader) = &mut this.reader {
+                reader.open(&mut block)?;
+            }
+            this.pending = block;
+            this.next_len = None;
+        }
+        let len = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending[..len]);
+        this.pending.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ObfsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let Some(writer) = &mut this.writer else {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Obfuscated server stream written before the client's salt",
+            )));
+        };
+        let len = buf.len().min(MAX_PAYLOAD);
+        writer.seal(&(len as u16).to_be_bytes(), &mut this.unsent)?;
+        writer.seal(&buf[..len], &mut this.unsent)?;
+        // Send it right away like a socket would, since callers such as the
+        // SOCKS handshake do not always flush. A chunk that cannot go out
+        // yet is sent by the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl Server {
+    /// Serve SOCKS5 over the obfuscated transport on an already bound
+    /// listener.
+    pub async fn run_with_obfs_listener(
+        &self,
+        listener: TcpListener,
+        config: ObfsConfig,
+    ) -> io::Result<()> {
+        self.run_with_acceptor(listener, Arc::new(ObfsAcceptor { config }))
+            .await
+    }
+
+    /// Verify the client's first chunk on `stream`, then serve SOCKS5 inside
+    /// the transport like `handle_connection`.
+    pub async fn handle_obfs_connection<S>(
+        &self,
+        stream: S,
+        peer: PeerInfo,
+        config: &ObfsConfig,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match handshake(stream, &peer, config).await? {
+            Some(stream) => self.handle_connection(stream, peer).await,
+            None => Ok(()),
+        }
+    }
+}
+
+// The obfuscated handshake as a transport
+struct ObfsAcceptor {
+    config: ObfsConfig,
+}
+
+#[async_trait]
+impl Acceptor for ObfsAcceptor {
+    type Stream = ObfsStream<TcpStream>;
+
+    fn name(&self) -> &'static str {
+        "obfuscated"
+    }
+
+    async fn accept(
+        &self,
+        stream: TcpStream,
+        peer: &mut PeerInfo,
+    ) -> io::Result<Accepted<ObfsStream<TcpStream>>> {
+        Ok(match handshake(stream, peer, &self.config).await? {
+            Some(stream) => Accepted::Socks(stream),
+            None => Accepted::Handled,
+        })
+    }
+}
+
+// Read and check the client's salt and first chunk. `None` if the client
+// hung up before sending any.
+async fn handshake<S>(
+    stream: S,
+    peer: &PeerInfo,
+    config: &ObfsConfig,
+) -> io::Result<Option<ObfsStream<S>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = ObfsStream::accept(stream, config.clone())?;
+    let header = tokio::time::timeout(
+        config.handshake_timeout,
+        poll_fn(|cx| stream.poll_header(cx)),
+    )
+    .await;
+    let failure = match header {
+        Ok(Ok(true)) if config.salts.lock().unwrap().insert(stream.peer_salt) => None,
+        Ok(Ok(true)) => Some("Replayed obfuscated handshake"),
+        Ok(Ok(false)) => return Ok(None),
+        Ok(Err(_)) => Some("Obfuscated handshake failed"),
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Obfuscated handshake timed out",
+            ));
+        }
+    };
+
+    if let Some(reason) = failure {
+        warn!("{} from {:?}", reason, peer.addr);
+        // Behave like a server that ignores what it cannot parse
+        let mut inner = stream.into_inner();
+        let _ = tokio::time::timeout(
+            PROBE_HOLD,
+            tokio::io::copy(&mut inner, &mut tokio::io::sink()),
+        )
+        .await;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+    }
+
+    debug!("Obfuscated handshake complete");
+    Ok(Some(stream))
+}
+
+/// Connects to targets through a SOCKS5 proxy reached over the obfuscated
+/// transport.
+pub type ObfsClient = TransportClient<ObfsTransport>;
+
+impl ObfsClient {
+    /// Use `client`, with its credentials, timeouts, retries and socket
+    /// options, to reach the proxy's obfuscated listener.
+    pub fn new(client: Client, config: ObfsConfig) -> Self {
+        Self::from_parts(client, ObfsTransport::new(config))
+    }
+}
+
+/// The client side of the obfuscated transport.
+pub struct ObfsTransport {
+    config: ObfsConfig,
+}
+
+impl ObfsTransport {
+    /// Seal connections to the proxy with `config`.
+    pub fn new(config: ObfsConfig) -> Self {
+        ObfsTransport { config }
+    }
+}
+
+#[async_trait]
+impl Transport for ObfsTransport {
+    type Stream = ObfsStream<TcpStream>;
+
+    async fn dial(&self, client: &Client) -> io::Result<ObfsStream<TcpStream>> {
+        let stream = client.dial_tcp().await?;
+        ObfsStream::new(stream, self.config.clone())
+    }
+}
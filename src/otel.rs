@@ -0,0 +1,317 @@
+// OpenTelemetry export (`otel` feature).
+//
+// `Telemetry::init` sends traces and metrics to an OTLP/HTTP collector. Each
+// proxied connection becomes a `socks.connection` span, with child spans for
+// resolving the destination and connecting to it. Until `init` is called the
+// server records nothing.
+
+use std::future::Future;
+use std::io;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use opentelemetry::context::FutureExt;
+use opentelemetry::metrics::{Counter, Histogram, UpDownCounter};
+use opentelemetry::trace::{Span, SpanKind, Status, TraceContextExt, Tracer, TracerProvider};
+use opentelemetry::{Context, KeyValue, global};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+
+use crate::hook::{ConnectionInfo, ConnectionSummary};
+use crate::protocol::SocksAddr;
+
+static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub struct OtelOptions {
+    /// Collector base URL, e.g. "http://otel-collector:4318"; `/v1/traces`
+    /// and `/v1/metrics` are appended. `None` falls back to the standard
+    /// `OTEL_EXPORTER_OTLP_*` environment variables, then localhost:4318.
+    pub endpoint: Option<String>,
+    pub service_name: String,
+    /// How often metrics are pushed.
+    pub metrics_interval: Duration,
+    /// Deadline for each export request.
+    pub export_timeout: Duration,
+}
+
+impl Default for OtelOptions {
+    fn default() -> Self {
+        OtelOptions {
+            endpoint: None,
+            service_name: "charon".to_string(),
+            metrics_interval: Duration::from_secs(60),
+            export_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Installed exporters. Keep it alive for the life of the process and call
+/// `shutdown` before exiting so buffered spans and metrics are sent.
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Telemetry {
+    /// Start exporting and register the providers globally, so embedders'
+    /// own instrumentation goes to the same collector. May only be called
+    /// once per process.
+    pub fn init(options: OtelOptions) -> io::Result<Self> {
+        let resource = Resource::builder()
+            .with_service_name(options.service_name)
+            .build();
+        let endpoint = |path: &str| {
+            options
+                .endpoint
+                .as_ref()
+                .map(|base| format!("{}{}", base.trim_end_matches('/'), path))
+        };
+
+        let mut spans = SpanExporter::builder()
+            .with_http()
+            .with_timeout(options.export_timeout);
+        if let Some(url) = endpoint("/v1/traces") {
+            spans = spans.with_endpoint(url);
+        }
+        let spans = spans.build().map_err(io::Error::other)?;
+
+        let mut metrics = MetricExporter::builder()
+            .with_http()
+            .with_timeout(options.export_timeout);
+        if let Some(url) = endpoint("/v1/metrics") {
+            metrics = metrics.with_endpoint(url);
+        }
+        let metrics = metrics.build().map_err(io::Error::other)?;
+
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_resource(resource.clone())
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(
+                PeriodicReader::builder(metrics)
+                    .with_interval(options.metrics_interval)
+                    .build(),
+            )
+            .with_resource(resource)
+            .build();
+
+        let instruments = Instruments::new(&tracer_provider, &meter_provider);
+        if INSTRUMENTS.set(instruments).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "OpenTelemetry export is already initialized",
+            ));
+        }
+        global::set_tracer_provider(tracer_provider.clone());
+        global::set_meter_provider(meter_provider.clone());
+
+        Ok(Telemetry {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+
+    /// Flush and stop both exporters. Blocks until the final export
+    /// completes or times out.
+    pub fn shutdown(&self) -> io::Result<()> {
+        let traces = self.tracer_provider.shutdown();
+        let metrics = self.meter_provider.shutdown();
+        traces.map_err(io::Error::other)?;
+        metrics.map_err(io::Error::other)
+    }
+}
+
+struct Instruments {
+    tracer: SdkTracer,
+    connections: Counter<u64>,
+    active: UpDownCounter<i64>,
+    bytes: Counter<u64>,
+    duration: Histogram<f64>,
+    dns_duration: Histogram<f64>,
+    connect_duration: Histogram<f64>,
+}
+
+impl Instruments {
+    fn new(tracer_provider: &SdkTracerProvider, meter_provider: &SdkMeterProvider) -> Self {
+        use opentelemetry::metrics::MeterProvider;
+
+        let meter = meter_provider.meter("charon");
+        Instruments {
+            tracer: tracer_provider.tracer("charon"),
+            connections: meter
+                .u64_counter("charon.connections")
+                .with_description("Connections served, by outcome")
+                .build(),
+            active: meter
+                .i64_up_down_counter("charon.connections.active")
+                .with_description("Connections currently open")
+                .build(),
+            bytes: meter
+                .u64_counter("charon.bytes")
+                .with_description("Bytes relayed, by direction")
+                .with_unit("By")
+                .build(),
+            duration: meter
+                .f64_histogram("charon.connection.duration")
+                .with_unit("s")
+                .build(),
+            dns_duration: meter
+                .f64_histogram("charon.dns.duration")
+                .with_unit("s")
+                .build(),
+            connect_duration: meter
+                .f64_histogram("charon.connect.duration")
+                .with_unit("s")
+                .build(),
+        }
+    }
+}
+
+/// The span covering one client connection.
+pub(crate) struct ConnectionSpan {
+    cx: Option<Context>,
+}
+
+impl ConnectionSpan {
+    pub(crate) fn start(info: &ConnectionInfo) -> Self {
+        let Some(instruments) = INSTRUMENTS.get() else {
+            return ConnectionSpan { cx: None };
+        };
+
+        let mut attributes = vec![KeyValue::new("socks.connection_id", info.id as i64)];
+        if let Some(addr) = info.peer.addr {
+            attributes.push(KeyValue::new("client.address", addr.ip().to_string()));
+            attributes.push(KeyValue::new("client.port", i64::from(addr.port())));
+        }
+        let span = instruments
+            .tracer
+            .span_builder("socks.connection")
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start(&instruments.tracer);
+        instruments.active.add(1, &[]);
+
+        ConnectionSpan {
+            cx: Some(Context::current_with_span(span)),
+        }
+    }
+
+    /// Run `fut` with this span as the parent of any spans it starts.
+    pub(crate) fn scope<F: Future>(&self, fut: F) -> impl Future<Output = F::Output> {
+        fut.with_context(self.cx.clone().unwrap_or_else(Context::current))
+    }
+
+    pub(crate) fn end(mut self, info: &ConnectionInfo, summary: &ConnectionSummary<'_>) {
+        let (Some(instruments), Some(cx)) = (INSTRUMENTS.get(), self.cx.take()) else {
+            return;
+        };
+
+        let span = cx.span();
+        if let Some(identity) = &info.identity {
+            span.set_attribute(KeyValue::new("enduser.id", identity.username.clone()));
+        }
+        if let Some(target) = &info.target {
+            let (host, port) = match target {
+                SocksAddr::Ipv4(ip, port) => (ip.to_string(), port),
+                SocksAddr::Ipv6(ip, port) => (ip.to_string(), port),
+                SocksAddr::Domain(domain, port) => (domain.clone(), port),
+            };
+            span.set_attribute(KeyValue::new("server.address", host));
+            span.set_attribute(KeyValue::new("server.port", i64::from(*port)));
+        }
+        if let Some(name) = &info.server_name {
+            span.set_attribute(KeyValue::new("socks.server_name", name.name().to_string()));
+        }
+        span.set_attribute(KeyValue::new("socks.bytes.upload", summary.upload as i64));
+        span.set_attribute(KeyValue::new(
+            "socks.bytes.download",
+            summary.download as i64,
+        ));
+        if let Some(e) = summary.error {
+            span.set_status(Status::error(e.to_string()));
+        }
+        span.end();
+
+        let outcome = if summary.error.is_some() {
+            "error"
+        } else {
+            "ok"
+        };
+        instruments
+            .connections
+            .add(1, &[KeyValue::new("outcome", outcome)]);
+        instruments.active.add(-1, &[]);
+        instruments
+            .bytes
+            .add(summary.upload, &[KeyValue::new("direction", "upload")]);
+        instruments
+            .bytes
+            .add(summary.download, &[KeyValue::new("direction", "download")]);
+        instruments
+            .duration
+            .record(summary.duration.as_secs_f64(), &[]);
+    }
+}
+
+// A connection whose future is dropped before `end`, e.g. when shutdown cuts
+// it, still ends its span and leaves the active count
+impl Drop for ConnectionSpan {
+    fn drop(&mut self) {
+        let (Some(instruments), Some(cx)) = (INSTRUMENTS.get(), self.cx.take()) else {
+            return;
+        };
+        let span = cx.span();
+        span.set_status(Status::error("Connection dropped"));
+        span.end();
+        instruments
+            .connections
+            .add(1, &[KeyValue::new("outcome", "dropped")]);
+        instruments.active.add(-1, &[]);
+    }
+}
+
+/// A step of dialing the destination, traced as a child span.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    Dns,
+    Connect,
+}
+
+/// Run `fut` as a child span of the current connection and record its
+/// duration.
+pub(crate) async fn traced<T>(
+    phase: Phase,
+    fut: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let Some(instruments) = INSTRUMENTS.get() else {
+        return fut.await;
+    };
+
+    let (name, histogram) = match phase {
+        Phase::Dns => ("dns.resolve", &instruments.dns_duration),
+        Phase::Connect => ("connect", &instruments.connect_duration),
+    };
+    let mut span = instruments
+        .tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Client)
+        .start_with_context(&instruments.tracer, &Context::current());
+    let started = Instant::now();
+
+    let result = fut.await;
+
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    histogram.record(
+        started.elapsed().as_secs_f64(),
+        &[KeyValue::new("outcome", outcome)],
+    );
+    if let Err(e) = &result {
+        span.set_status(Status::error(e.to_string()));
+    }
+    span.end();
+    result
+}
@@ -0,0 +1,207 @@
+// Proxy auto-config (PAC) for browsers.
+//
+// An optional HTTP listener serves a PAC file at `/proxy.pac` and at
+// `/wpad.dat`, the path WPAD discovery fetches. The file points browsers at
+// the server's SOCKS5 listeners, and the routing table's direct routes become
+// DIRECT entries, evaluated in the same order. A route the script cannot
+// evaluate exactly, e.g. one restricted to certain users, sends whatever it
+// might match to the proxy, which then applies the real rule.
+
+use std::fmt::Write;
+use std::net::IpAddr;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::header::{CONTENT_TYPE, HOST, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use log::info;
+use tokio::io;
+use tokio::net::TcpListener;
+
+use crate::acl::AclTarget;
+use crate::health::text;
+use crate::routing::{Route, RouteAction};
+use crate::server::Server;
+
+/// Settings for the PAC listener started by `Server::run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacOptions {
+    pub bind_addr: String,
+    /// Host name or address browsers reach the proxy at. Defaults to the
+    /// listener's own address, or to the host the PAC file was requested
+    /// from when the listener binds a wildcard address.
+    pub proxy_host: Option<String>,
+}
+
+impl Default for PacOptions {
+    fn default() -> Self {
+        PacOptions {
+            bind_addr: "0.0.0.0:8081".to_string(),
+            proxy_host: None,
+        }
+    }
+}
+
+impl Server {
+    /// The PAC file for browsers that reach the proxy at `request_host` (the
+    /// host the file is fetched from, when `PacOptions::proxy_host` is
+    /// unset). Lists the listeners that do not require authentication,
+    /// since browsers cannot authenticate to SOCKS5 proxies.
+    pub fn pac_file(&self, request_host: &str) -> String {
+        let configured = self.pac_options().and_then(|pac| pac.proxy_host.as_deref());
+        let proxies: Vec<_> = self
+            .anonymous_listeners()
+            .iter()
+            .filter_map(|bind_addr| {
+                let (host, port) = bind_addr.rsplit_once(':')?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let host = match configured {
+                    Some(configured) => configured,
+                    None if host.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified()) => {
+                        request_host
+                    }
+                    None => host,
+                };
+                Some(match host.parse::<IpAddr>() {
+                    Ok(IpAddr::V6(ip)) => format!("SOCKS5 [{}]:{}", ip, port),
+                    _ => format!("SOCKS5 {}:{}", host, port),
+                })
+            })
+            .collect();
+
+        let mut script = String::from("function FindProxyForURL(url, host) {\n");
+        let _ = writeln!(
+            script,
+            "    var proxy = {};",
+            js_string(&proxies.join("; "))
+        );
+        script.push_str("    host = host.toLowerCase();\n");
+        script.push_str("    var ipv4 = /^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host);\n");
+        script.push_str("    var ipv6 = host.indexOf(\":\") >= 0;\n");
+
+        // Only routes up to the last one that goes direct make a difference;
+        // everything else goes to the proxy anyway
+        let routes = self.routing().map_or(&[][..], |routing| &routing.routes);
+        let significant = routes
+            .iter()
+            .rposition(is_exact_direct)
+            .map_or(0, |i| i + 1);
+        for route in &routes[..significant] {
+            let result = if is_exact_direct(route) {
+                "\"DIRECT\""
+            } else {
+                "proxy"
+            };
+            let _ = writeln!(
+                script,
+                "    if ({}) return {};",
+                condition(&route.target),
+                result
+            );
+        }
+
+        script.push_str("    return proxy;\n}\n");
+        script
+    }
+
+    /// Serve the PAC file on an already bound listener until the server
+    /// shuts down.
+    pub async fn run_pac_with_listener(&self, listener: TcpListener) -> io::Result<()> {
+        info!("PAC file served on {}", listener.local_addr()?);
+        self.serve_http(listener, |server, request| async move {
+            server.pac_response(&request)
+        })
+        .await
+    }
+
+    fn pac_response(&self, request: &Request<Incoming>) -> Response<Full<Bytes>> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed\n");
+        }
+        if !matches!(request.uri().path(), "/proxy.pac" | "/wpad.dat") {
+            return text(StatusCode::NOT_FOUND, "Not found\n");
+        }
+
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<hyper::http::uri::Authority>().ok())
+            .map(|authority| authority.host().to_string())
+            .unwrap_or_else(|| "localhost".to_string());
+        let mut response = text(StatusCode::OK, self.pac_file(&host));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ns-proxy-autoconfig"),
+        );
+        response
+    }
+}
+
+// A direct route the script can evaluate exactly: no user or port
+// restriction, and a target the PAC functions can test
+fn is_exact_direct(route: &Route) -> bool {
+    route.action == RouteAction::Direct
+        && route.users.is_none()
+        && route.ports.is_none()
+        && match &route.target {
+            AclTarget::Any | AclTarget::Domain(_) | AclTarget::DomainSuffix(_) => true,
+            AclTarget::Cidr(network, _) => network.is_ipv4(),
+            AclTarget::Asn { .. } => false,
+        }
+}
+
+// A JavaScript test for the hosts a route target matches. Inexact for IPv6
+// networks and ASNs, which it over-approximates with every IP literal of the
+// family.
+fn condition(target: &AclTarget) -> String {
+    match target {
+        AclTarget::Any => "true".to_string(),
+        AclTarget::Domain(name) => format!("host == {}", js_string(&normalize(name))),
+        AclTarget::DomainSuffix(suffix) => {
+            let suffix = normalize(suffix.trim_start_matches('.'));
+            format!(
+                "host == {} || dnsDomainIs(host, {})",
+                js_string(&suffix),
+                js_string(&format!(".{}", suffix))
+            )
+        }
+        AclTarget::Cidr(IpAddr::V4(network), prefix) => {
+            let prefix = u32::from(*prefix).min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            format!(
+                "ipv4 && isInNet(host, \"{}\", \"{}\")",
+                network,
+                std::net::Ipv4Addr::from(mask)
+            )
+        }
+        AclTarget::Cidr(IpAddr::V6(_), _) => "ipv6".to_string(),
+        AclTarget::Asn { .. } => "ipv4 || ipv6".to_string(),
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// A double-quoted JavaScript string literal
+fn js_string(value: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            c if c.is_ascii_graphic() || c == ' ' => literal.push(c),
+            c => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    let _ = write!(literal, "\\u{:04x}", unit);
+                }
+            }
+        }
+    }
+    literal.push('"');
+    literal
+}
@@ -0,0 +1,83 @@
+// Per-user policies applied after authentication.
+//
+// A policy is looked up by the policy name the authenticator assigned to the
+// identity, then by username; anonymous and unmatched connections get the
+// default policy.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::acl::Acl;
+use crate::auth::Identity;
+use crate::dialer::Dialer;
+use crate::ratelimit::RateLimiter;
+use crate::relay::RelayLimits;
+
+/// Bandwidth caps in bytes per second; `None` leaves a direction unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    /// Client -> destination
+    pub upload: Option<u64>,
+    /// Destination -> client
+    pub download: Option<u64>,
+}
+
+impl BandwidthLimit {
+    /// Convenience constructor taking KiB/s.
+    pub fn kib_per_sec(upload: Option<u64>, download: Option<u64>) -> Self {
+        BandwidthLimit {
+            upload: upload.map(|v| v * 1024),
+            download: download.map(|v| v * 1024),
+        }
+    }
+
+    /// Build relay limits for the configured directions using `make` to
+    /// create (or look up) each limiter.
+    pub fn limiters<F>(&self, make: F) -> RelayLimits
+    where
+        F: Fn(u64) -> Arc<RateLimiter>,
+    {
+        RelayLimits {
+            upload: self.upload.map(&make).into_iter().collect(),
+            download: self.download.map(&make).into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct UserPolicy {
+    /// Shared by all of a user's connections; applied per connection for
+    /// anonymous clients.
+    pub bandwidth: Option<BandwidthLimit>,
+    /// Total bytes (both directions) the user may relay; enforced through the
+    /// server's quota store.
+    pub quota_bytes: Option<u64>,
+    /// Simultaneous relays the user may hold; overrides the server-wide limit.
+    pub max_sessions: Option<usize>,
+    /// Destinations the user may reach, checked after the server-wide ACL.
+    pub acl: Option<Acl>,
+    /// How the user's connections egress (e.g. through an upstream proxy);
+    /// the server's dialer is used when unset.
+    pub dialer: Option<Arc<dyn Dialer>>,
+}
+
+#[derive(Clone, Default)]
+pub struct PolicyTable {
+    /// Policies keyed by policy name or username.
+    pub policies: HashMap<String, UserPolicy>,
+    /// Policy for anonymous connections and users without a matching entry.
+    pub default_policy: UserPolicy,
+}
+
+impl PolicyTable {
+    pub fn resolve(&self, identity: Option<&Identity>) -> &UserPolicy {
+        identity
+            .and_then(|id| {
+                id.policy
+                    .as_ref()
+                    .and_then(|name| self.policies.get(name))
+                    .or_else(|| self.policies.get(&id.username))
+            })
+            .unwrap_or(&self.default_policy)
+    }
+}
@@ -0,0 +1,128 @@
+// Warm connections to the proxy.
+//
+// Every CONNECT needs its own proxy connection, so a pool cannot reuse
+// tunnels. What it can do is dial (and for TLS, handshake) ahead of time: the
+// client takes an idle connection that is already established and starts the
+// SOCKS handshake right away, while the pool dials a replacement in the
+// background.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use socket2::SockRef;
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// Connections kept established and idle, ready for the next request.
+    pub idle: usize,
+    /// Discard idle connections older than this. Keep it below the proxy's
+    /// greeting timeout (10 seconds for charon), after which the proxy
+    /// closes connections that have not spoken.
+    pub max_age: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            idle: 4,
+            max_age: Duration::from_secs(5),
+        }
+    }
+}
+
+impl PoolOptions {
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if self.idle == 0 || self.max_age.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pool idle count and max_age must not be zero",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Idle connections of type `S`, refilled in the background.
+pub(crate) struct ProxyPool<S> {
+    options: PoolOptions,
+    idle: Mutex<VecDeque<(S, Instant)>>,
+    refilling: AtomicBool,
+}
+
+impl<S: Send + 'static> ProxyPool<S> {
+    pub(crate) fn new(options: PoolOptions) -> Self {
+        ProxyPool {
+            options,
+            idle: Mutex::new(VecDeque::new()),
+            refilling: AtomicBool::new(false),
+        }
+    }
+
+    /// The most recently dialed idle connection that is still usable.
+    /// `usable` must not consume data from the connection.
+    pub(crate) fn take(&self, usable: impl Fn(&S) -> bool) -> Option<S> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some((stream, dialed)) = idle.pop_back() {
+            if dialed.elapsed() < self.options.max_age && usable(&stream) {
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    /// Top the pool up to `PoolOptions::idle` with `dial`, unless a refill is
+    /// already running. Stops at the first dial error.
+    pub(crate) fn refill<F, Fut>(self: &Arc<Self>, dial: F)
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<S>> + Send,
+    {
+        if self.refilling.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let pool = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                {
+                    let mut idle = pool.idle.lock().unwrap();
+                    let max_age = pool.options.max_age;
+                    idle.retain(|(_, dialed)| dialed.elapsed() < max_age);
+                    if idle.len() >= pool.options.idle {
+                        break;
+                    }
+                }
+                match dial().await {
+                    Ok(stream) => pool
+                        .idle
+                        .lock()
+                        .unwrap()
+                        .push_back((stream, Instant::now())),
+                    Err(e) => {
+                        debug!("Failed to pre-dial proxy connection: {}", e);
+                        break;
+                    }
+                }
+            }
+            pool.refilling.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// Whether an idle TCP connection is still open. With `data_ok`, bytes
+/// waiting to be read (e.g. TLS session tickets) do not disqualify it.
+pub(crate) fn tcp_open(stream: &TcpStream, data_ok: bool) -> bool {
+    let mut buf = [MaybeUninit::uninit(); 1];
+    // The socket is non-blocking, so this only reports what has arrived
+    match SockRef::from(stream).peek(&mut buf) {
+        Ok(0) => false,
+        Ok(_) => data_ok,
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+    }
+}
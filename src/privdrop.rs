@@ -0,0 +1,123 @@
+// Dropping root privileges once the listening sockets are bound, so the proxy
+// can bind privileged ports (1080, 443) as root but relay traffic as an
+// unprivileged user.
+
+use std::io;
+use std::path::PathBuf;
+
+/// Who to run as after binding. Applied in order: chroot, supplementary
+/// groups, group, user.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrivilegeDrop {
+    /// User name or numeric uid to switch to.
+    pub user: String,
+    /// Group name or numeric gid; defaults to the user's primary group.
+    pub group: Option<String>,
+    /// Directory to chroot into. Name resolution, certificate reloading and
+    /// any other file access then happen relative to it.
+    pub chroot: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl PrivilegeDrop {
+    pub fn apply(&self) -> io::Result<()> {
+        use std::ffi::{CString, OsStr};
+        use std::os::unix::ffi::OsStrExt;
+
+        let (uid, primary_gid) = lookup_user(&self.user)?;
+        let gid = match &self.group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid,
+        };
+
+        if let Some(dir) = &self.chroot {
+            let path = CString::new(OsStr::as_bytes(dir.as_os_str()))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // SAFETY: `path` is a valid NUL-terminated string.
+            check(unsafe { libc::chroot(path.as_ptr()) })?;
+            std::env::set_current_dir("/")?;
+        }
+
+        // SAFETY: plain syscalls with valid arguments; the group list is a
+        // single gid that outlives the call.
+        unsafe {
+            check(libc::setgroups(1, &gid))?;
+            check(libc::setgid(gid))?;
+            check(libc::setuid(uid))?;
+        }
+
+        // Regaining root must now be impossible
+        // SAFETY: plain syscall.
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Privileges could be regained after dropping them",
+            ));
+        }
+
+        log::info!("Dropped privileges to uid {} gid {}", uid, gid);
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+impl PrivilegeDrop {
+    pub fn apply(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Dropping privileges is only supported on Unix",
+        ))
+    }
+}
+
+#[cfg(unix)]
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Resolve a user name or numeric uid to (uid, primary gid)
+#[cfg(unix)]
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let name =
+        std::ffi::CString::new(user).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `name` is NUL-terminated; the returned record is read before
+    // any other passwd call could overwrite it.
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !entry.is_null() {
+        // SAFETY: checked non-null above.
+        return Ok(unsafe { ((*entry).pw_uid, (*entry).pw_gid) });
+    }
+
+    let uid: libc::uid_t = user
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("Unknown user {}", user)))?;
+    // SAFETY: as above.
+    let entry = unsafe { libc::getpwuid(uid) };
+    let gid = if entry.is_null() {
+        uid
+    } else {
+        // SAFETY: checked non-null above.
+        unsafe { (*entry).pw_gid }
+    };
+    Ok((uid, gid))
+}
+
+// Resolve a group name or numeric gid
+#[cfg(unix)]
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    let name = std::ffi::CString::new(group)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `name` is NUL-terminated; the record is read immediately.
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !entry.is_null() {
+        // SAFETY: checked non-null above.
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+
+    group
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("Unknown group {}", group)))
+}
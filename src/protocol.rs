@@ -3,6 +3,7 @@
 
 use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 // SOCKS protocol version
@@ -35,13 +36,57 @@ pub const REP_TTL_EXPIRED: u8 = 6;
 pub const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
 pub const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
 
+/// The reply code a server sends for a failed outbound connection, from the
+/// error's kind. Dialers and resolvers report failures through these kinds.
+pub fn reply_code_for(error: &io::Error) -> u8 {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => REP_CONNECTION_NOT_ALLOWED,
+        io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable => REP_HOST_UNREACHABLE,
+        io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        io::ErrorKind::TimedOut => REP_TTL_EXPIRED,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+/// Human-readable meaning of a reply code, as worded in RFC 1928.
+pub fn reply_message(code: u8) -> &'static str {
+    match code {
+        REP_SUCCEEDED => "Succeeded",
+        REP_GENERAL_FAILURE => "General SOCKS server failure",
+        REP_CONNECTION_NOT_ALLOWED => "Connection not allowed by ruleset",
+        REP_NETWORK_UNREACHABLE => "Network unreachable",
+        REP_HOST_UNREACHABLE => "Host unreachable",
+        REP_CONNECTION_REFUSED => "Connection refused",
+        REP_TTL_EXPIRED => "TTL expired",
+        REP_COMMAND_NOT_SUPPORTED => "Command not supported",
+        REP_ADDRESS_TYPE_NOT_SUPPORTED => "Address type not supported",
+        _ => "Unknown reply code",
+    }
+}
+
+/// The error a client reports for a failure reply; the inverse of
+/// `reply_code_for`, so a chained proxy passes the original code on.
+pub fn reply_error(code: u8) -> io::Error {
+    let kind = match code {
+        REP_CONNECTION_NOT_ALLOWED => io::ErrorKind::PermissionDenied,
+        REP_NETWORK_UNREACHABLE => io::ErrorKind::NetworkUnreachable,
+        REP_HOST_UNREACHABLE => io::ErrorKind::HostUnreachable,
+        REP_CONNECTION_REFUSED => io::ErrorKind::ConnectionRefused,
+        REP_TTL_EXPIRED => io::ErrorKind::TimedOut,
+        REP_COMMAND_NOT_SUPPORTED | REP_ADDRESS_TYPE_NOT_SUPPORTED => io::ErrorKind::Unsupported,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, reply_message(code))
+}
+
 // Username/Password Authentication (RFC 1929)
 pub const AUTH_VERSION: u8 = 1;
 pub const AUTH_SUCCESS: u8 = 0;
 pub const AUTH_FAILURE: u8 = 1;
 
 // SOCKS address enum for different address types
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SocksAddr {
     Ipv4(Ipv4Addr, u16),
     Ipv6(Ipv6Addr, u16),
@@ -69,9 +114,15 @@ impl SocksAddr {
             }
             ATYP_DOMAIN => {
                 let len = r.read_u8().await? as usize;
-                let mut domain = vec![0u8; len];
-                r.read_exact(&mut domain).await?;
-                let domain = String::from_utf8(domain).map_err(|_| {
+                if len == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Empty domain name",
+                    ));
+                }
+                let mut domain = [0u8; 255];
+                r.read_exact(&mut domain[..len]).await?;
+                let domain = String::from_utf8(domain[..len].to_vec()).map_err(|_| {
                     io::Error::new(io::ErrorKind::InvalidData, "Invalid domain name")
                 })?;
                 let port = r.read_u16().await?;
@@ -100,10 +151,10 @@ impl SocksAddr {
                 w.write_u16(*port).await?;
             }
             SocksAddr::Domain(domain, port) => {
-                if domain.len() > 255 {
+                if domain.is_empty() || domain.len() > 255 {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidInput,
-                        "Domain too long",
+                        "Domain must be 1 to 255 bytes",
                     ));
                 }
                 w.write_u8(ATYP_DOMAIN).await?;
@@ -115,6 +166,40 @@ impl SocksAddr {
         Ok(())
     }
 
+    /// The address with an internationalized domain name converted to the
+    /// ASCII (punycode) form proxies and resolvers expect, e.g.
+    /// `bücher.de` to `xn--bcher-kva.de`. Without the `idna` feature, names
+    /// are returned unchanged.
+    pub fn into_ascii(self) -> io::Result<SocksAddr> {
+        let SocksAddr::Domain(domain, port) = self else {
+            return Ok(self);
+        };
+        if domain.is_ascii() {
+            return Ok(SocksAddr::Domain(domain, port));
+        }
+        #[cfg(feature = "idna")]
+        let domain = idna::domain_to_ascii(&domain).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid internationalized domain name {:?}", domain),
+            )
+        })?;
+        if domain.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Empty domain name",
+            ));
+        }
+        // Punycode is longer than the name it encodes
+        if domain.len() > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Domain too long",
+            ));
+        }
+        Ok(SocksAddr::Domain(domain, port))
+    }
+
     pub fn to_socket_addr(&self) -> Option<SocketAddr> {
         match self {
             SocksAddr::Ipv4(addr, port) => Some(SocketAddr::V4(SocketAddrV4::new(*addr, *port))),
@@ -124,6 +209,14 @@ impl SocksAddr {
             SocksAddr::Domain(_, _) => None, // Requires DNS resolution
         }
     }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            SocksAddr::Ipv4(_, port) | SocksAddr::Ipv6(_, port) | SocksAddr::Domain(_, port) => {
+                *port
+            }
+        }
+    }
 }
 
 impl fmt::Display for SocksAddr {
@@ -136,13 +229,49 @@ impl fmt::Display for SocksAddr {
     }
 }
 
+impl From<SocketAddr> for SocksAddr {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => SocksAddr::Ipv4(*addr.ip(), addr.port()),
+            SocketAddr::V6(addr) => SocksAddr::Ipv6(*addr.ip(), addr.port()),
+        }
+    }
+}
+
+impl FromStr for SocksAddr {
+    type Err = io::Error;
+
+    /// Parse `host:port`, `1.2.3.4:port` or `[::1]:port`, the forms
+    /// `Display` produces. Host names are kept as names, not resolved.
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid target {:?}, expected host:port", s),
+            )
+        };
+
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(addr.into());
+        }
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        if host.is_empty() || host.len() > 255 || host.contains([':', '[', ']']) {
+            return Err(invalid());
+        }
+        Ok(SocksAddr::Domain(host.to_string(), port))
+    }
+}
+
 // SOCKS handshake request structure
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HandshakeRequest {
     pub version: u8,
     pub methods: Vec<u8>,
 }
 
 // SOCKS connection request structure
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Request {
     pub version: u8,
     pub command: u8,
@@ -150,6 +279,7 @@ pub struct Request {
 }
 
 // SOCKS reply structure
+#[derive(Debug, Clone)]
 pub struct Reply {
     pub version: u8,
     pub reply: u8,
@@ -157,6 +287,7 @@ pub struct Reply {
 }
 
 // Username/Password Authentication structure
+#[derive(Clone, PartialEq, Eq)]
 pub struct UserPassAuth {
     pub username: String,
     pub password: String,
@@ -199,6 +330,19 @@ impl UserPassAuth {
     }
 
     pub async fn read_from<R>(r: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        Self::read_limited(r, 255, 255).await
+    }
+
+    /// Like `read_from`, failing with `ErrorKind::InvalidData` as soon as a
+    /// length byte exceeds its limit, before the field itself is read.
+    pub async fn read_limited<R>(
+        r: &mut R,
+        max_username_len: usize,
+        max_password_len: usize,
+    ) -> io::Result<Self>
     where
         R: AsyncRead + Unpin,
     {
@@ -212,16 +356,28 @@ impl UserPassAuth {
 
         // Username
         let username_len = r.read_u8().await? as usize;
-        let mut username_bytes = vec![0u8; username_len];
-        r.read_exact(&mut username_bytes).await?;
-        let username = String::from_utf8(username_bytes)
+        if username_len > max_username_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Username too long",
+            ));
+        }
+        let mut username_bytes = [0u8; 255];
+        r.read_exact(&mut username_bytes[..username_len]).await?;
+        let username = String::from_utf8(username_bytes[..username_len].to_vec())
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid username encoding"))?;
 
         // Password
         let password_len = r.read_u8().await? as usize;
-        let mut password_bytes = vec![0u8; password_len];
-        r.read_exact(&mut password_bytes).await?;
-        let password = String::from_utf8(password_bytes)
+        if password_len > max_password_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Password too long",
+            ));
+        }
+        let mut password_bytes = [0u8; 255];
+        r.read_exact(&mut password_bytes[..password_len]).await?;
+        let password = String::from_utf8(password_bytes[..password_len].to_vec())
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid password encoding"))?;
 
         Ok(UserPassAuth { username, password })
@@ -241,11 +397,37 @@ impl HandshakeRequest {
             ));
         }
 
-        let nmethods = r.read_u8().await?;
-        let mut methods = vec![0u8; nmethods as usize];
-        r.read_exact(&mut methods).await?;
+        let nmethods = r.read_u8().await? as usize;
+        if nmethods == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "No authentication methods offered",
+            ));
+        }
+        let mut methods = [0u8; 255];
+        r.read_exact(&mut methods[..nmethods]).await?;
 
-        Ok(HandshakeRequest { version, methods })
+        Ok(HandshakeRequest {
+            version,
+            methods: methods[..nmethods].to_vec(),
+        })
+    }
+
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if self.methods.is_empty() || self.methods.len() > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A handshake offers 1 to 255 methods",
+            ));
+        }
+        w.write_u8(self.version).await?;
+        w.write_u8(self.methods.len() as u8).await?;
+        w.write_all(&self.methods).await?;
+        w.flush().await?;
+        Ok(())
     }
 }
 
@@ -272,6 +454,18 @@ impl Request {
             addr,
         })
     }
+
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        w.write_u8(self.version).await?;
+        w.write_u8(self.command).await?;
+        w.write_u8(0x00).await?; // Reserved
+        self.addr.write_to(w).await?;
+        w.flush().await?;
+        Ok(())
+    }
 }
 
 impl Reply {
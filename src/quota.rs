@@ -0,0 +1,67 @@
+// Per-user data quotas.
+//
+// Relayed bytes (both directions) are added to a per-username counter kept in
+// a pluggable `QuotaStore`; once a user's counter reaches their quota, new
+// CONNECT requests are refused with REP_CONNECTION_NOT_ALLOWED and running
+// relays are closed. Running relays are charged every second.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+/// Storage for cumulative per-user byte counters.
+#[async_trait]
+pub trait QuotaStore: Send + Sync {
+    /// Bytes used so far by `username`.
+    async fn usage(&self, username: &str) -> io::Result<u64>;
+
+    /// Add `bytes` to the user's counter and return the new total.
+    async fn add_usage(&self, username: &str, bytes: u64) -> io::Result<u64>;
+
+    /// Reset the user's counter (e.g. at the start of a billing period).
+    async fn reset(&self, username: &str) -> io::Result<()>;
+
+    /// Quota recorded by the store itself, overriding the user's policy.
+    async fn limit(&self, _username: &str) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// Quota counters kept in memory; they are lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryQuotaStore {
+    usage: Mutex<HashMap<String, u64>>,
+}
+
+impl MemoryQuotaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl QuotaStore for MemoryQuotaStore {
+    async fn usage(&self, username: &str) -> io::Result<u64> {
+        Ok(self
+            .usage
+            .lock()
+            .unwrap()
+            .get(username)
+            .copied()
+            .unwrap_or(0))
+    }
+
+    async fn add_usage(&self, username: &str, bytes: u64) -> io::Result<u64> {
+        let mut usage = self.usage.lock().unwrap();
+        let total = usage.entry(username.to_string()).or_insert(0);
+        *total = total.saturating_add(bytes);
+        Ok(*total)
+    }
+
+    async fn reset(&self, username: &str) -> io::Result<()> {
+        self.usage.lock().unwrap().remove(username);
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+// Token-bucket rate limiting for relayed traffic.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// A token bucket measured in bytes. Consumers may overdraw the bucket and
+/// then sleep until the debt is repaid, so several connections sharing one
+/// limiter are slowed down proportionally to what they transfer.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `bytes_per_sec` with a burst of one second.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self::with_burst(bytes_per_sec, bytes_per_sec)
+    }
+
+    pub fn with_burst(bytes_per_sec: u64, burst: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        let burst = burst.max(1) as f64;
+        RateLimiter {
+            bytes_per_sec,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec as u64
+    }
+
+    /// Take `n` bytes from the bucket, waiting as long as needed to stay under the rate.
+    pub async fn consume(&self, n: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            self.refill(&mut state);
+            state.tokens -= n as f64;
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take `n` bytes from the bucket if they are there, without overdrawing
+    /// it; for traffic that is dropped rather than delayed when over the
+    /// rate.
+    pub fn try_consume(&self, n: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        if state.tokens < n as f64 {
+            return false;
+        }
+        state.tokens -= n as f64;
+        true
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.bytes_per_sec).min(self.burst);
+        state.last_refill = now;
+    }
+}
+
+/// Limiters shared by key (e.g. username) for as long as someone holds them.
+#[derive(Debug, Default)]
+pub struct KeyedLimiters {
+    limiters: Mutex<HashMap<String, Weak<RateLimiter>>>,
+}
+
+impl KeyedLimiters {
+    /// Return the live limiter for `key`, creating one if none exists or the
+    /// configured rate changed.
+    pub fn get(&self, key: &str, bytes_per_sec: u64) -> Arc<RateLimiter> {
+        let mut limiters = self.limiters.lock().unwrap();
+        if let Some(limiter) = limiters.get(key).and_then(Weak::upgrade)
+            && limiter.bytes_per_sec() == bytes_per_sec.max(1)
+        {
+            return limiter;
+        }
+
+        limiters.retain(|_, l| l.strong_count() > 0);
+        let limiter = Arc::new(RateLimiter::new(bytes_per_sec));
+        limiters.insert(key.to_string(), Arc::downgrade(&limiter));
+        limiter
+    }
+}
@@ -0,0 +1,276 @@
+// Redis-backed credential cache.
+//
+// Successful authentications from an inner backend (LDAP, SQLite, ...) are
+// cached in Redis so every proxy instance in a fleet shares them, and
+// revocations written by any instance (or an admin tool) take effect
+// everywhere on the next authentication attempt. Passwords are only stored
+// as HMAC-SHA256 tags keyed with a secret Redis never sees, so a dump of the
+// cache cannot be brute-forced without the secret as well.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use redis::AsyncCommands;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use ring::hmac;
+
+use crate::auth::{Authenticator, Identity};
+
+/// What to do when Redis cannot be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailMode {
+    /// Skip the cache (and revocation check) and ask the inner authenticator.
+    Open,
+    /// Reject the authentication attempt.
+    Closed,
+}
+
+pub struct RedisAuthOptions {
+    /// Connection URL, e.g. `redis://127.0.0.1:6379/0`.
+    pub url: String,
+    /// Prefix for every key written by the cache.
+    pub key_prefix: String,
+    /// How long a successful authentication stays cached.
+    pub ttl: Duration,
+    /// How long a revocation lasts; `None` keeps it until explicitly lifted.
+    pub revocation_ttl: Option<Duration>,
+    /// Key of the cached password digests; required, ideally 32 random
+    /// bytes. Must be the same on every instance sharing the cache, and
+    /// should not be stored in Redis.
+    pub secret: Vec<u8>,
+    /// Upper bound on each Redis round trip before `fail_mode` applies.
+    pub timeout: Duration,
+    pub fail_mode: FailMode,
+}
+
+impl Default for RedisAuthOptions {
+    fn default() -> Self {
+        RedisAuthOptions {
+            url: "redis://127.0.0.1:6379".to_string(),
+            key_prefix: "charon:".to_string(),
+            ttl: Duration::from_secs(300),
+            revocation_ttl: None,
+            secret: Vec::new(),
+            timeout: Duration::from_millis(500),
+            fail_mode: FailMode::Closed,
+        }
+    }
+}
+
+pub struct RedisAuthenticator {
+    options: RedisAuthOptions,
+    key: hmac::Key,
+    conn: ConnectionManager,
+    inner: Option<Arc<dyn Authenticator>>,
+}
+
+impl RedisAuthenticator {
+    /// Create a cache in front of `inner`. Without an inner authenticator, only
+    /// entries written with [`RedisAuthenticator::put`] are accepted.
+    ///
+    /// The connection is established lazily on first use. Fails if
+    /// `options.secret` is empty.
+    pub fn new(
+        options: RedisAuthOptions,
+        inner: Option<Arc<dyn Authenticator>>,
+    ) -> io::Result<Self> {
+        if options.secret.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Redis credential cache needs a secret",
+            ));
+        }
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &options.secret);
+        let client = redis::Client::open(options.url.as_str()).map_err(redis_error)?;
+        let config = ConnectionManagerConfig::new()
+            .set_connection_timeout(Some(options.timeout))
+            .set_response_timeout(Some(options.timeout));
+        let conn = client
+            .get_connection_manager_lazy(config)
+            .map_err(redis_error)?;
+
+        Ok(RedisAuthenticator {
+            options,
+            key,
+            conn,
+            inner,
+        })
+    }
+
+    fn user_key(&self, username: &str) -> String {
+        format!("{}auth:{}", self.options.key_prefix, username)
+    }
+
+    fn revoked_key(&self, username: &str) -> String {
+        format!("{}revoked:{}", self.options.key_prefix, username)
+    }
+
+    fn digest(&self, username: &str, password: &str) -> String {
+        hmac::sign(&self.key, &digest_input(username, password))
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    // Whether a cached digest is the password's, in constant time
+    fn digest_matches(&self, digest: &str, username: &str, password: &str) -> bool {
+        match decode_hex(digest) {
+            Some(tag) => hmac::verify(&self.key, &digest_input(username, password), &tag).is_ok(),
+            None => false,
+        }
+    }
+
+    async fn with_timeout<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: Future<Output = redis::RedisResult<T>>,
+    {
+        match tokio::time::timeout(self.options.timeout, f).await {
+            Ok(result) => result.map_err(redis_error),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Redis operation timed out",
+            )),
+        }
+    }
+
+    /// Write an identity and its password digest into the shared cache.
+    /// A `ttl` of `None` keeps the entry until it is revoked or overwritten.
+    pub async fn put(
+        &self,
+        identity: &Identity,
+        password: &str,
+        ttl: Option<Duration>,
+    ) -> io::Result<()> {
+        let key = self.user_key(&identity.username);
+        let fields = [
+            ("digest", self.digest(&identity.username, password)),
+            ("policy", identity.policy.clone().unwrap_or_default()),
+            ("groups", identity.groups.join("\n")),
+        ];
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key).ignore();
+        pipe.hset_multiple(&key, &fields).ignore();
+        if let Some(ttl) = ttl {
+            pipe.expire(&key, ttl.as_secs().max(1) as i64).ignore();
+        }
+
+        let mut conn = self.conn.clone();
+        self.with_timeout(pipe.query_async::<()>(&mut conn)).await
+    }
+
+    /// Revoke a user fleet-wide: drop their cached entry and reject them until
+    /// the revocation expires or is lifted.
+    pub async fn revoke(&self, username: &str) -> io::Result<()> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(self.user_key(username)).ignore();
+        match self.options.revocation_ttl {
+            Some(ttl) => pipe
+                .set_ex(self.revoked_key(username), 1, ttl.as_secs().max(1))
+                .ignore(),
+            None => pipe.set(self.revoked_key(username), 1).ignore(),
+        };
+
+        let mut conn = self.conn.clone();
+        self.with_timeout(pipe.query_async::<()>(&mut conn)).await
+    }
+
+    /// Lift a revocation written by [`RedisAuthenticator::revoke`].
+    pub async fn unrevoke(&self, username: &str) -> io::Result<()> {
+        let mut conn = self.conn.clone();
+        let key = self.revoked_key(username);
+        self.with_timeout(conn.del::<_, ()>(key)).await
+    }
+
+    // Ok(None) on a cache miss, Ok(Some(None)) when the user is revoked
+    async fn lookup(&self, username: &str, password: &str) -> io::Result<Option<Option<Identity>>> {
+        let mut conn = self.conn.clone();
+        let (revoked, fields): (bool, std::collections::HashMap<String, String>) = self
+            .with_timeout(
+                redis::pipe()
+                    .exists(self.revoked_key(username))
+                    .hgetall(self.user_key(username))
+                    .query_async(&mut conn),
+            )
+            .await?;
+
+        if revoked {
+            debug!("User {} is revoked", username);
+            return Ok(Some(None));
+        }
+
+        match fields.get("digest") {
+            Some(digest) if self.digest_matches(digest, username, password) => {
+                let policy = fields.get("policy").filter(|p| !p.is_empty()).cloned();
+                let groups = fields
+                    .get("groups")
+                    .map(|g| g.lines().map(str::to_string).collect())
+                    .unwrap_or_default();
+                Ok(Some(Some(Identity {
+                    username: username.to_string(),
+                    groups,
+                    policy,
+                })))
+            }
+            // Missing or stale entry (e.g. the password changed upstream)
+            _ => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for RedisAuthenticator {
+    async fn authenticate(&self, username: &str, password: &str) -> io::Result<Option<Identity>> {
+        let redis_up = match self.lookup(username, password).await {
+            Ok(Some(cached)) => return Ok(cached),
+            Ok(None) => true,
+            Err(e) => match self.options.fail_mode {
+                FailMode::Closed => return Err(e),
+                FailMode::Open => {
+                    warn!("Redis unavailable, failing open: {}", e);
+                    false
+                }
+            },
+        };
+
+        let Some(inner) = &self.inner else {
+            return Ok(None);
+        };
+        let identity = inner.authenticate(username, password).await?;
+
+        if redis_up
+            && let Some(identity) = &identity
+            && let Err(e) = self.put(identity, password, Some(self.options.ttl)).await
+        {
+            warn!("Failed to cache credentials for {}: {}", username, e);
+        }
+        Ok(identity)
+    }
+}
+
+// The username is length-prefixed so no two pairs share an input
+fn digest_input(username: &str, password: &str) -> Vec<u8> {
+    let mut input = Vec::with_capacity(8 + username.len() + password.len());
+    input.extend_from_slice(&(username.len() as u64).to_be_bytes());
+    input.extend_from_slice(username.as_bytes());
+    input.extend_from_slice(password.as_bytes());
+    input
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn redis_error(e: redis::RedisError) -> io::Error {
+    io::Error::other(format!("Redis error: {}", e))
+}
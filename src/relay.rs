@@ -0,0 +1,313 @@
+// Bidirectional relay between the SOCKS client and the destination.
+// Replaces `tokio::io::copy_bidirectional` so traffic can be shaped, counted
+// while in flight, timed out when idle and cancelled from outside; with the
+// `splice` feature, TCP-to-TCP relays on Linux skip userspace copies.
+
+use std::any::Any;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::dialer::ProxyStream;
+use crate::ratelimit::RateLimiter;
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Rate limiters applied to each direction of a relay. Every chunk must pass
+/// all limiters in the list before it is written.
+#[derive(Debug, Clone, Default)]
+pub struct RelayLimits {
+    /// Client -> destination
+    pub upload: Vec<Arc<RateLimiter>>,
+    /// Destination -> client
+    pub download: Vec<Arc<RateLimiter>>,
+}
+
+impl RelayLimits {
+    pub fn extend(&mut self, other: RelayLimits) {
+        self.upload.extend(other.upload);
+        self.download.extend(other.download);
+    }
+}
+
+/// Bytes moved so far, updated after every chunk so they can be read while
+/// the relay is still running.
+#[derive(Debug, Default)]
+pub struct RelayCounters {
+    upload: AtomicU64,
+    download: AtomicU64,
+    // Aggregates that every update is also added to
+    parents: Vec<Arc<RelayCounters>>,
+}
+
+impl RelayCounters {
+    /// Counters that also add every update to `parent`, e.g. per-connection
+    /// counters feeding a server-wide total.
+    pub fn with_parent(parent: Arc<RelayCounters>) -> Self {
+        Self::with_parents(vec![parent])
+    }
+
+    /// Counters that also add every update to each of `parents`.
+    pub fn with_parents(parents: Vec<Arc<RelayCounters>>) -> Self {
+        RelayCounters {
+            parents,
+            ..Default::default()
+        }
+    }
+
+    // Counters resuming from earlier totals, without parents
+    pub(crate) fn starting_at(upload: u64, download: u64) -> Self {
+        RelayCounters {
+            upload: AtomicU64::new(upload),
+            download: AtomicU64::new(download),
+            parents: Vec::new(),
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        self.upload.store(0, Ordering::Relaxed);
+        self.download.store(0, Ordering::Relaxed);
+    }
+
+    /// Bytes sent to the destination.
+    pub fn upload(&self) -> u64 {
+        self.upload.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent back to the client.
+    pub fn download(&self) -> u64 {
+        self.download.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.upload() + self.download()
+    }
+
+    pub(crate) fn add(&self, direction: Direction, n: u64) {
+        let counter = match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+        for parent in &self.parents {
+            parent.add(direction, n);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RelayOptions {
+    pub limits: RelayLimits,
+    /// End the relay with `ErrorKind::TimedOut` when no data has moved in
+    /// either direction for this long.
+    pub idle_timeout: Option<Duration>,
+    /// End the relay with `ErrorKind::ConnectionAborted` once cancelled.
+    pub cancel: Option<CancellationToken>,
+    /// Counters to update as data moves, e.g. for live metrics.
+    pub counters: Option<Arc<RelayCounters>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Direction {
+    Upload,
+    Download,
+}
+
+// Shared by both directions of one relay
+pub(crate) struct Progress {
+    counters: Arc<RelayCounters>,
+    started: Instant,
+    // Milliseconds since `started` at the last chunk
+    last_activity: AtomicU64,
+}
+
+impl Progress {
+    fn new(counters: Option<Arc<RelayCounters>>) -> Self {
+        Progress {
+            counters: counters.unwrap_or_default(),
+            started: Instant::now(),
+            last_activity: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, direction: Direction, n: usize) {
+        self.counters.add(direction, n as u64);
+        self.touch();
+    }
+
+    // Note activity without moving bytes yet, e.g. data read but waiting on
+    // a rate limiter
+    pub(crate) fn touch(&self) {
+        self.last_activity
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Resolves once nothing has moved for `timeout`
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let last =
+                self.started + Duration::from_millis(self.last_activity.load(Ordering::Relaxed));
+            let deadline = last + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// Copy data in both directions until both sides have closed, returning the
+/// bytes sent to the destination and the bytes sent back to the client.
+pub async fn relay<A, B>(client: A, server: B, limits: &RelayLimits) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let options = RelayOptions {
+        limits: limits.clone(),
+        ..Default::default()
+    };
+    relay_with(client, server, &options).await
+}
+
+/// Like `relay`, with an idle timeout, cancellation and live counters. A
+/// side that reaches EOF is shut down for writing on the other side while
+/// the opposite direction keeps flowing.
+pub async fn relay_with<A, B>(
+    client: A,
+    server: B,
+    options: &RelayOptions,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let progress = Progress::new(options.counters.clone());
+    let (mut client_read, mut client_write) = io::split(client);
+    let (mut server_read, mut server_write) = io::split(server);
+
+    let transfer = async {
+        tokio::try_join!(
+            copy_limited(
+                &mut client_read,
+                &mut server_write,
+                &options.limits.upload,
+                &progress,
+                Direction::Upload,
+            ),
+            copy_limited(
+                &mut server_read,
+                &mut client_write,
+                &options.limits.download,
+                &progress,
+                Direction::Download,
+            ),
+        )
+    };
+    supervise(transfer, options, &progress).await
+}
+
+/// Relay between the client and an outbound connection. Two plain TCP
+/// sockets are relayed with io_uring on the `run_uring` server, or inside the
+/// kernel with the `splice` feature, both on Linux only.
+pub(crate) async fn relay_outbound<S>(
+    client: &mut S,
+    server: Box<dyn ProxyStream>,
+    options: &RelayOptions,
+) -> io::Result<(u64, u64)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Any,
+{
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if crate::uring::in_uring_task()
+        && let (Some(client), Some(server)) = (
+            (&*client as &dyn Any).downcast_ref::<tokio::net::TcpStream>(),
+            (&*server as &dyn Any).downcast_ref::<tokio::net::TcpStream>(),
+        )
+    {
+        log::debug!("Relaying with io_uring");
+        let progress = Arc::new(Progress::new(options.counters.clone()));
+        let transfer =
+            crate::uring::relay(client, server, options.limits.clone(), progress.clone());
+        return supervise(transfer, options, &progress).await;
+    }
+
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    if let (Some(client), Some(server)) = (
+        (&*client as &dyn Any).downcast_ref::<tokio::net::TcpStream>(),
+        (&*server as &dyn Any).downcast_ref::<tokio::net::TcpStream>(),
+    ) {
+        log::debug!("Relaying with splice");
+        let progress = Progress::new(options.counters.clone());
+        let transfer = crate::splice::relay(client, server, &options.limits, &progress);
+        return supervise(transfer, options, &progress).await;
+    }
+
+    relay_with(client, server, options).await
+}
+
+// Run a transfer until it finishes, goes idle or is cancelled
+async fn supervise<F>(
+    transfer: F,
+    options: &RelayOptions,
+    progress: &Progress,
+) -> io::Result<(u64, u64)>
+where
+    F: Future<Output = io::Result<(u64, u64)>>,
+{
+    let idle = async {
+        match options.idle_timeout {
+            Some(timeout) => progress.idle(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    let cancelled = async {
+        match &options.cancel {
+            Some(token) => token.cancelled().await,
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        result = transfer => result,
+        _ = idle => Err(io::Error::new(io::ErrorKind::TimedOut, "Relay idle timeout")),
+        _ = cancelled => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Relay cancelled")),
+    }
+}
+
+async fn copy_limited<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limiters: &[Arc<RateLimiter>],
+    progress: &Progress,
+    direction: Direction,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    let mut total = 0u64;
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            // Propagate the half-close so the peer sees EOF
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+        progress.touch();
+
+        for limiter in limiters {
+            limiter.consume(n).await;
+        }
+
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        total += n as u64;
+        progress.record(direction, n);
+    }
+}
@@ -0,0 +1,56 @@
+// Name resolution for domain CONNECT targets.
+//
+// The direct dialer asks a `Resolver` for a domain's addresses. The default
+// `SystemResolver` uses the operating system (getaddrinfo); the
+// `encrypted-dns` feature adds DNS-over-HTTPS / DNS-over-TLS.
+
+use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+/// Addresses a domain resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    pub addrs: Vec<IpAddr>,
+    /// How long the answer may be reused, if the resolver knows.
+    pub ttl: Option<Duration>,
+}
+
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// Resolve `domain` to at least one address. Failures should use
+    /// `ErrorKind::HostUnreachable` so the client gets the matching reply.
+    async fn resolve(&self, domain: &str) -> io::Result<Resolved>;
+}
+
+/// Resolves through the operating system's resolver.
+#[derive(Debug, Clone, Default)]
+pub struct SystemResolver;
+
+impl SystemResolver {
+    pub fn new() -> Self {
+        SystemResolver
+    }
+}
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, domain: &str) -> io::Result<Resolved> {
+        let addrs: Vec<IpAddr> = tokio::net::lookup_host((domain, 0))
+            .await
+            .map_err(|_| unresolved())?
+            .map(|addr| addr.ip())
+            .collect();
+        if addrs.is_empty() {
+            return Err(unresolved());
+        }
+
+        Ok(Resolved { addrs, ttl: None })
+    }
+}
+
+pub(crate) fn unresolved() -> io::Error {
+    io::Error::new(io::ErrorKind::HostUnreachable, "Could not resolve domain")
+}
@@ -0,0 +1,334 @@
+// Reverse tunnels for servers behind NAT.
+//
+// An agent (a charon instance without a reachable address) dials out to a
+// public rendezvous over TLS and keeps that connection open. Roles then swap:
+// the rendezvous speaks HTTP/2 as the client and sends every CONNECT it
+// receives down the tunnel as an HTTP/2 CONNECT, and the agent serves it like
+// the HTTP/2 CONNECT gateway on its TLS listener, through its own ACLs and
+// limits. The rendezvous is a `Dialer`, so its server needs no other changes
+// to hand its traffic to the agents.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::client::conn::http2::{self, SendRequest};
+use hyper::{Method, Request, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use log::{debug, info, warn};
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+use crate::accept::{AcceptErrors, Backoff};
+use crate::dialer::{Dialer, Outbound};
+use crate::drain::{Drain, DrainReport};
+use crate::protocol::SocksAddr;
+use crate::server::{PeerInfo, Server};
+use crate::tls_client::TlsClient;
+
+/// Keepalive and reconnection timing of reverse tunnels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReverseOptions {
+    /// Ping the other end this often; a tunnel that stops answering is
+    /// dropped (and redialed by the agent). Keep it below the idle timeout
+    /// of any NAT or firewall on the path.
+    pub keepalive_interval: Duration,
+    /// Wait this long before redialing after the tunnel failed, doubling
+    /// after every failed attempt up to `max_reconnect_delay`.
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    /// How long the rendezvous waits for an agent to complete its TLS
+    /// handshake.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for ReverseOptions {
+    fn default() -> Self {
+        ReverseOptions {
+            keepalive_interval: Duration::from_secs(30),
+            reconnect_delay: Duration::from_secs(1),
+            max_reconnect_delay: Duration::from_secs(60),
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The NATed end: serves a server's CONNECT pipeline to a rendezvous over an
+/// outbound TLS connection.
+pub struct ReverseAgent {
+    server: Server,
+    client: TlsClient,
+    options: ReverseOptions,
+}
+
+impl ReverseAgent {
+    /// Serve `server` to the rendezvous `client` points at. Give the client
+    /// a certificate (`TlsClient::with_client_cert`) if the rendezvous
+    /// requires one. Requests from the rendezvous carry no credentials, so
+    /// `server` should not require authentication; restrict what is
+    /// reachable with its ACL instead.
+    pub fn new(server: Server, client: TlsClient) -> Self {
+        ReverseAgent {
+            server,
+            client,
+            options: ReverseOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: ReverseOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Keep a tunnel to the rendezvous open, redialing whenever it fails,
+    /// until the server shuts down.
+    pub async fn run(&self) -> io::Result<()> {
+        let drain = self.server.drain();
+        let mut delay = self.options.reconnect_delay;
+
+        loop {
+            match self.client.dial().await {
+                Ok(stream) => {
+                    info!("Reverse tunnel to rendezvous established");
+                    delay = self.options.reconnect_delay;
+                    let peer = PeerInfo {
+                        addr: stream.get_ref().0.peer_addr().ok(),
+                        identity: None,
+                        #[cfg(feature = "udp-aead")]
+                        udp_keys: None,
+                    };
+                    let keepalive = Some(self.options.keepalive_interval);
+                    match self.server.serve_h2(stream, peer, keepalive).await {
+                        Ok(()) => info!("Reverse tunnel closed"),
+                        Err(e) => warn!("Reverse tunnel failed: {}", e),
+                    }
+                }
+                Err(e) => warn!("Failed to reach rendezvous: {}", e),
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = drain.stopped() => return Ok(()),
+            }
+            delay = (delay * 2).min(self.options.max_reconnect_delay);
+        }
+    }
+}
+
+/// The public end: accepts agents and dials CONNECT targets through them.
+///
+/// Install it as the dialer of the server clients connect to
+/// (`ServerOptions::dialer` or a policy's dialer). With several agents
+/// connected, tunnels are spread across them in turn.
+#[derive(Clone)]
+pub struct Rendezvous {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    options: ReverseOptions,
+    agents: Mutex<Vec<Agent>>,
+    next_agent: AtomicUsize,
+    next_id: AtomicU64,
+    accept_errors: AcceptErrors,
+    drain: Arc<Drain>,
+}
+
+struct Agent {
+    id: u64,
+    sender: SendRequest<Empty<Bytes>>,
+}
+
+impl Default for Rendezvous {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rendezvous {
+    pub fn new() -> Self {
+        Self::with_options(ReverseOptions::default())
+    }
+
+    /// Only `keepalive_interval` and `handshake_timeout` apply to this end.
+    pub fn with_options(options: ReverseOptions) -> Self {
+        Rendezvous {
+            inner: Arc::new(Inner {
+                options,
+                agents: Mutex::new(Vec::new()),
+                next_agent: AtomicUsize::new(0),
+                next_id: AtomicU64::new(1),
+                accept_errors: AcceptErrors::new(),
+                drain: Arc::default(),
+            }),
+        }
+    }
+
+    /// Number of agents currently connected.
+    pub fn agents(&self) -> usize {
+        self.inner.agents.lock().unwrap().len()
+    }
+
+    /// Stop accepting agents and abandon handshakes in progress, give
+    /// connected agents `grace` to finish, then drop their tunnels.
+    pub async fn shutdown(&self, grace: Duration) -> DrainReport {
+        self.inner.drain.run(grace).await
+    }
+
+    /// Accept agents over TLS on an already bound listener. Anyone who can
+    /// connect becomes an agent and receives the server's traffic, so
+    /// `config` should require client certificates (see `load_tls_config`).
+    pub async fn run_with_listener(
+        &self,
+        listener: TcpListener,
+        config: Arc<ServerConfig>,
+    ) -> io::Result<()> {
+        info!(
+            "Reverse tunnel rendezvous listening on {}",
+            listener.local_addr()?
+        );
+        let acceptor = TlsAcceptor::from(config);
+        let drain = &self.inner.drain;
+        let _listening = drain.listen();
+        let mut backoff = Backoff::new();
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = drain.stopped() => return Ok(()),
+            };
+            let (stream, addr) = match accepted {
+                Ok(accepted) => {
+                    backoff.reset();
+                    accepted
+                }
+                Err(e) => {
+                    self.inner
+                        .accept_errors
+                        .recover(&e, &mut backoff, || listener.accept())
+                        .await;
+                    continue;
+                }
+            };
+
+            let rendezvous = self.clone();
+            let acceptor = acceptor.clone();
+            let timeout = self.inner.options.handshake_timeout;
+            tokio::spawn(drain.track(async move {
+                let handshake = tokio::time::timeout(timeout, acceptor.accept(stream));
+                let stream = tokio::select! {
+                    handshake = handshake => match handshake {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            warn!("TLS handshake with agent {} failed: {}", addr, e);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("TLS handshake with agent {} timed out", addr);
+                            return;
+                        }
+                    },
+                    _ = rendezvous.inner.drain.stopped() => return,
+                };
+                info!("Reverse tunnel agent connected from {}", addr);
+                match rendezvous.handle_agent(stream).await {
+                    Ok(()) => info!("Reverse tunnel agent {} disconnected", addr),
+                    Err(e) => warn!("Reverse tunnel agent {} failed: {}", addr, e),
+                }
+            }));
+        }
+    }
+
+    /// Use an established connection from an agent, e.g. after your own TLS
+    /// handshake, until the agent disconnects.
+    pub async fn handle_agent<S>(&self, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, connection) = http2::Builder::new(TokioExecutor::new())
+            .timer(TokioTimer::new())
+            .keep_alive_interval(self.inner.options.keepalive_interval)
+            .keep_alive_while_idle(true)
+            .handshake(TokioIo::new(stream))
+            .await
+            .map_err(io::Error::other)?;
+
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.agents.lock().unwrap().push(Agent { id, sender });
+        let result = connection.await.map_err(io::Error::other);
+        self.inner
+            .agents
+            .lock()
+            .unwrap()
+            .retain(|agent| agent.id != id);
+        result
+    }
+
+    // The next connected agent in turn
+    fn pick_agent(&self) -> Option<SendRequest<Empty<Bytes>>> {
+        let agents = self.inner.agents.lock().unwrap();
+        let ready: Vec<_> = agents
+            .iter()
+            .filter(|agent| !agent.sender.is_closed())
+            .collect();
+        if ready.is_empty() {
+            return None;
+        }
+        let next = self.inner.next_agent.fetch_add(1, Ordering::Relaxed);
+        Some(ready[next % ready.len()].sender.clone())
+    }
+}
+
+#[async_trait]
+impl Dialer for Rendezvous {
+    async fn dial(&self, target: &SocksAddr) -> io::Result<Outbound> {
+        let mut sender = self.pick_agent().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NetworkUnreachable,
+                "No reverse tunnel agent is connected",
+            )
+        })?;
+        debug!("Dialing {} through reverse tunnel", target);
+
+        let request = Request::builder()
+            .method(Method::CONNECT)
+            .uri(target.to_string())
+            .body(Empty::new())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        sender.ready().await.map_err(io::Error::other)?;
+        let mut response = sender
+            .send_request(request)
+            .await
+            .map_err(io::Error::other)?;
+        if response.status() != StatusCode::OK {
+            return Err(dial_error(response.status()));
+        }
+
+        let upgraded = hyper::upgrade::on(&mut response)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(Outbound {
+            stream: Box::new(TokioIo::new(upgraded)),
+            bound_addr: None,
+        })
+    }
+}
+
+// The error behind an agent's refusal, so the client gets a matching reply
+fn dial_error(status: StatusCode) -> io::Error {
+    let kind = match status {
+        StatusCode::FORBIDDEN | StatusCode::PROXY_AUTHENTICATION_REQUIRED => {
+            io::ErrorKind::PermissionDenied
+        }
+        StatusCode::GATEWAY_TIMEOUT => io::ErrorKind::TimedOut,
+        StatusCode::NOT_IMPLEMENTED => io::ErrorKind::Unsupported,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("Agent refused the tunnel: {}", status))
+}
@@ -0,0 +1,158 @@
+// Rule-based routing of CONNECT requests.
+//
+// A routing table picks how each allowed request egresses: straight to the
+// destination, through a named upstream (a proxy, a chain or a pool), or not
+// at all. Routes are evaluated in order against the destination, its port
+// and the authenticated user, and the first match decides. Requests no route
+// matches use the dialer they would use without the table: the user's
+// policy dialer or the server's. A domain no route names is resolved and
+// routed by its addresses when the table has CIDR or ASN routes.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use crate::acl::AclTarget;
+use crate::dialer::Dialer;
+use crate::protocol::SocksAddr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteAction {
+    /// Connect straight to the destination with the server's direct dialer,
+    /// even when the server or the user's policy has another dialer.
+    Direct,
+    /// Dial through the entry of `RoutingTable::upstreams` with this name.
+    Upstream(String),
+    /// Refuse the request as not allowed.
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// Destinations the route applies to. Domain rules match domain targets
+    /// and IP/CIDR/ASN rules match IP-literal targets and the addresses
+    /// domains resolve to, as in ACLs.
+    pub target: AclTarget,
+    /// Restrict the route to these destination ports; `None` matches any
+    /// port.
+    pub ports: Option<RangeInclusive<u16>>,
+    /// Restrict the route to these authenticated usernames; `None` matches
+    /// everyone, anonymous clients included.
+    pub users: Option<Vec<String>>,
+    pub action: RouteAction,
+}
+
+impl Route {
+    pub fn new(target: AclTarget, action: RouteAction) -> Self {
+        Route {
+            target,
+            ports: None,
+            users: None,
+            action,
+        }
+    }
+
+    pub fn direct(target: AclTarget) -> Self {
+        Self::new(target, RouteAction::Direct)
+    }
+
+    pub fn upstream(target: AclTarget, name: impl Into<String>) -> Self {
+        Self::new(target, RouteAction::Upstream(name.into()))
+    }
+
+    pub fn reject(target: AclTarget) -> Self {
+        Self::new(target, RouteAction::Reject)
+    }
+
+    pub fn with_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.ports = Some(ports);
+        self
+    }
+
+    pub fn with_users(mut self, users: Vec<String>) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    pub fn matches(&self, addr: &SocksAddr, username: Option<&str>) -> bool {
+        self.applies(addr, username) && self.target.matches(addr)
+    }
+
+    /// Whether the route matches `ip`, an address the domain target `addr`
+    /// resolved to.
+    pub fn matches_resolved(&self, addr: &SocksAddr, ip: IpAddr, username: Option<&str>) -> bool {
+        self.applies(addr, username) && self.target.matches_resolved(addr, ip)
+    }
+
+    // The port and user restrictions
+    fn applies(&self, addr: &SocksAddr, username: Option<&str>) -> bool {
+        if let Some(ports) = &self.ports
+            && !ports.contains(&addr.port())
+        {
+            return false;
+        }
+        if let Some(users) = &self.users
+            && !username.is_some_and(|username| users.iter().any(|user| user == username))
+        {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct RoutingTable {
+    pub routes: Vec<Route>,
+    /// Dialers that `RouteAction::Upstream` routes name, such as an
+    /// `UpstreamDialer` for a proxy or chain, or an `UpstreamPool`.
+    pub upstreams: HashMap<String, Arc<dyn Dialer>>,
+}
+
+impl RoutingTable {
+    /// The action of the first route matching the request, if any.
+    pub fn route(&self, addr: &SocksAddr, username: Option<&str>) -> Option<&RouteAction> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(addr, username))
+            .map(|route| &route.action)
+    }
+
+    /// The action of the first route matching `ip`, an address the domain
+    /// target `addr` resolved to, if any.
+    pub fn route_resolved(
+        &self,
+        addr: &SocksAddr,
+        ip: IpAddr,
+        username: Option<&str>,
+    ) -> Option<&RouteAction> {
+        self.routes
+            .iter()
+            .find(|route| route.matches_resolved(addr, ip, username))
+            .map(|route| &route.action)
+    }
+
+    // Whether any route matches by address, so domains need resolving to
+    // be routed
+    pub(crate) fn routes_addresses(&self) -> bool {
+        self.routes
+            .iter()
+            .any(|route| matches!(route.target, AclTarget::Cidr(..) | AclTarget::Asn { .. }))
+    }
+
+    /// Check that every upstream a route names exists.
+    pub fn validate(&self) -> io::Result<()> {
+        for route in &self.routes {
+            if let RouteAction::Upstream(name) = &route.action
+                && !self.upstreams.contains_key(name)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Route names unknown upstream {:?}", name),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
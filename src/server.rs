@@ -1,26 +1,309 @@
-use log::{debug, error, info};
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use socket2::SockRef;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::pin;
 use std::sync::Arc;
-use tokio::io::{self, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::time::{Duration, SystemTime};
+use tokio::io::{self, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 
+use crate::accept::{AcceptErrors, AcceptStats};
+use crate::accounting::Accounting;
+use crate::acl::Acl;
+use crate::audit::{AuditAction, AuditEvent, AuditSink};
+use crate::auth::{AuthLimits, Authenticator, Identity, StaticAuthenticator};
+use crate::ban::{BanList, BanOptions};
+use crate::capture::{self, CaptureOptions};
+#[cfg(feature = "chaos")]
+use crate::chaos::{self, Chaos, ChaosOptions, ChaosStream, FaultPoint};
+use crate::dialer::{
+    DEFAULT_CONNECT_TIMEOUT, Dialer, DirectDialer, Outbound, OutboundBind, ProxyStream,
+};
+use crate::drain::{Drain, DrainReport};
+use crate::health::HealthOptions;
+use crate::hook::{ConnectionHook, ConnectionInfo, ConnectionSummary};
+use crate::pac::PacOptions;
+use crate::policy::{BandwidthLimit, PolicyTable, UserPolicy};
+use crate::privdrop::PrivilegeDrop;
 use crate::protocol::{
-    AUTH_FAILURE, AUTH_NONE, AUTH_PASSWORD, AUTH_SUCCESS, AUTH_VERSION, CMD_CONNECT,
-    HandshakeRequest, REP_ADDRESS_TYPE_NOT_SUPPORTED, REP_COMMAND_NOT_SUPPORTED,
-    REP_CONNECTION_REFUSED, REP_HOST_UNREACHABLE, REP_NETWORK_UNREACHABLE, REP_SUCCEEDED, Reply,
-    Request, SOCKS_VERSION, SocksAddr, UserPassAuth,
+    AUTH_FAILURE, AUTH_NONE, AUTH_NOT_ACCEPTABLE, AUTH_PASSWORD, AUTH_SUCCESS, AUTH_VERSION,
+    CMD_CONNECT, CMD_UDP_ASSOCIATE, HandshakeRequest, REP_COMMAND_NOT_SUPPORTED,
+    REP_CONNECTION_NOT_ALLOWED, REP_GENERAL_FAILURE, REP_SUCCEEDED, Reply, Request, SOCKS_VERSION,
+    SocksAddr, UserPassAuth, reply_code_for,
 };
+use crate::quota::QuotaStore;
+use crate::ratelimit::{KeyedLimiters, RateLimiter};
+use crate::relay::{Direction, RelayCounters, RelayLimits, RelayOptions, relay_outbound};
+use crate::resolver::{Resolver, SystemResolver};
+use crate::routing::{RouteAction, RoutingTable};
+use crate::session::SessionTracker;
+use crate::sniff::{self, SniffOptions};
+use crate::sockopt::SocketOptions;
+use crate::socks6::{SOCKS6_VERSION, Socks6Request, Socks6Tunnel};
+use crate::stats::{ServerStats, StatsCounters};
+use crate::transport::Tcp;
+use crate::udp_relay::{AssociationEnd, UdpAssociations, UdpRelayOptions};
+
+/// How long a client may take to send each handshake message, measured from
+/// the end of the previous phase. Stops clients that open a connection and
+/// trickle bytes (slowloris) from holding it forever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTimeouts {
+    /// Version and method list.
+    pub greeting: Duration,
+    /// Username/password sub-negotiation.
+    pub auth: Duration,
+    /// The command and destination.
+    pub request: Duration,
+}
+
+impl Default for HandshakeTimeouts {
+    fn default() -> Self {
+        HandshakeTimeouts {
+            greeting: Duration::from_secs(10),
+            auth: Duration::from_secs(10),
+            request: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Default for `ServerOptions::drain_timeout`.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Same backlog `TcpListener::bind` uses
+const LISTEN_BACKLOG: u32 = 1024;
+
+// How often a relay's bytes are charged to a data quota while it runs, which
+// bounds how far a user can overshoot the quota
+const QUOTA_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the transport layer knows about a connection before the SOCKS handshake.
+#[derive(Debug, Clone, Default)]
+pub struct PeerInfo {
+    /// Remote address, if the transport has one.
+    pub addr: Option<SocketAddr>,
+    /// Identity already established by the transport, e.g. from a verified
+    /// TLS client certificate.
+    pub identity: Option<Identity>,
+    /// Keys for protecting UDP relay datagrams, exported from the TLS
+    /// session the connection arrived over.
+    #[cfg(feature = "udp-aead")]
+    pub udp_keys: Option<crate::udp_aead::UdpKeys>,
+}
+
+/// A CONNECT that arrived over another protocol than SOCKS5 (an HTTP/2
+/// CONNECT stream or a SOCKS6 request), served through the same pipeline as
+/// SOCKS5 requests.
+#[async_trait]
+pub(crate) trait Tunnel: Send {
+    /// Username and password sent with the request, if any.
+    fn credentials(&self) -> Option<UserPassAuth>;
+
+    /// Refuse the request for missing or invalid credentials.
+    async fn reject_auth(self: Box<Self>);
+
+    /// Refuse the request with a SOCKS reply code.
+    async fn refuse(self: Box<Self>, reply: Reply);
+
+    /// Report success; the stream to relay on.
+    async fn open(self: Box<Self>, reply: Reply) -> io::Result<Box<dyn ProxyStream>>;
+}
+
+// What `Server::tracked` serves as one connection
+enum Frontend<S> {
+    Socks(S),
+    Tunnel(Box<dyn Tunnel>, SocksAddr),
+}
+
+// Who the outcome of a CONNECT is reported to
+enum ConnectClient<S> {
+    Socks(S),
+    Tunnel(Box<dyn Tunnel>),
+}
+
+impl<S: AsyncWrite + Unpin> ConnectClient<S> {
+    // Answer a request that is not relayed
+    async fn refuse(self, reply: Reply) -> io::Result<()> {
+        match self {
+            ConnectClient::Socks(mut stream) => reply.write_to(&mut stream).await,
+            ConnectClient::Tunnel(tunnel) => {
+                tunnel.refuse(reply).await;
+                Ok(())
+            }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Server {
     bind_addr: String,
     auth_required: bool,
-    credentials: Option<Arc<Vec<(String, String)>>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    ban_list: Option<Arc<BanList>>,
+    policies: Arc<PolicyTable>,
+    upload_limiters: Arc<KeyedLimiters>,
+    download_limiters: Arc<KeyedLimiters>,
+    global_limits: RelayLimits,
+    connection_bandwidth: Option<BandwidthLimit>,
+    quota_store: Option<Arc<dyn QuotaStore>>,
+    sessions: Arc<SessionTracker>,
+    max_sessions_per_user: Option<usize>,
+    acl: Option<Arc<Acl>>,
+    dialer: Arc<dyn Dialer>,
+    direct: Arc<dyn Dialer>,
+    resolver: Arc<dyn Resolver>,
+    routing: Option<Arc<RoutingTable>>,
+    udp: Option<Arc<UdpAssociations>>,
+    socks6: bool,
+    sniff: Option<SniffOptions>,
+    capture: Option<CaptureOptions>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Arc<Chaos>>,
+    client_socket: SocketOptions,
+    idle_timeout: Option<Duration>,
+    acceptors: usize,
+    unix_socket_path: Option<PathBuf>,
+    unix_socket_mode: Option<u32>,
+    listeners: Vec<ListenerOptions>,
+    privileges: Option<PrivilegeDrop>,
+    accept_errors: Arc<AcceptErrors>,
+    drain: Arc<Drain>,
+    drain_timeout: Duration,
+    hooks: Arc<[Arc<dyn ConnectionHook>]>,
+    auth_limits: AuthLimits,
+    handshake_timeouts: HandshakeTimeouts,
+    handshake_slots: Option<Arc<Semaphore>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    health: Option<HealthOptions>,
+    pac: Option<PacOptions>,
+    stats: Arc<StatsCounters>,
+    accounting: Option<Arc<Accounting>>,
 }
 
 pub struct ServerOptions {
     pub bind_addr: String,
     pub auth_required: bool,
     pub credentials: Option<Vec<(String, String)>>, // username, password pairs
+    /// Custom authentication backend; takes precedence over `credentials`.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Temporarily ban source IPs that repeatedly fail authentication.
+    pub auth_ban: Option<BanOptions>,
+    /// Per-user policies (bandwidth caps, ...) applied after authentication.
+    pub policies: PolicyTable,
+    /// Ceiling on the combined throughput of every relay on this server.
+    pub global_bandwidth: Option<BandwidthLimit>,
+    /// Ceiling on each individual relay, independent of the user's limits.
+    pub connection_bandwidth: Option<BandwidthLimit>,
+    /// Where per-user byte counters live; quotas are only enforced when set.
+    pub quota_store: Option<Arc<dyn QuotaStore>>,
+    /// Simultaneous relays allowed per username unless the user's policy
+    /// sets its own limit.
+    pub max_sessions_per_user: Option<usize>,
+    /// Server-wide destination ACL, checked in addition to the user's.
+    pub acl: Option<Acl>,
+    /// How outbound connections are made for users whose policy does not
+    /// name a dialer; defaults to connecting directly.
+    pub dialer: Option<Arc<dyn Dialer>>,
+    /// Rules choosing per request whether to connect directly, through a
+    /// named upstream or not at all, ahead of `dialer` and the users'
+    /// policy dialers.
+    pub routing: Option<RoutingTable>,
+    /// Serve UDP ASSOCIATE. Datagrams are sent directly from the relay,
+    /// whatever the dialer, after the same ACL and routing checks as
+    /// CONNECT; routes through an upstream drop them.
+    pub udp_relay: Option<UdpRelayOptions>,
+    /// Also serve SOCKS6 (draft-olteanu-intarea-socks-6-11) on every
+    /// listener, told apart by its version byte. Only CONNECT is supported,
+    /// with username/password or no authentication.
+    pub socks6: bool,
+    /// Read the TLS SNI or HTTP Host from the start of each relay to log it
+    /// and check it against the ACLs, which must allow both the requested
+    /// destination and the sniffed host name.
+    pub sniff: Option<SniffOptions>,
+    /// Write the plaintext of selected relays to pcapng files for
+    /// troubleshooting. Off unless set, and then limited to the users or
+    /// destinations it names.
+    pub capture: Option<CaptureOptions>,
+    /// Inject faults (latency, resets, truncated replies, slow reads) to
+    /// test clients against. Never set this in production.
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<ChaosOptions>,
+    /// Resolver the default direct dialer uses for domain targets (e.g. an
+    /// `EncryptedResolver`); when `dialer` is set, only `RouteAction::Direct`
+    /// routes use it.
+    pub resolver: Option<Arc<dyn Resolver>>,
+    /// How long the default direct dialer waits on each resolved address
+    /// before trying the next.
+    pub connect_timeout: Duration,
+    /// Source address or interface for the default direct dialer's
+    /// connections; when `dialer` is set, only `RouteAction::Direct` routes
+    /// use it.
+    pub outbound_bind: Option<OutboundBind>,
+    /// Options applied to every accepted client socket.
+    pub client_socket: SocketOptions,
+    /// Options applied to the default direct dialer's outbound sockets;
+    /// ignored when `dialer` is set.
+    pub outbound_socket: SocketOptions,
+    /// Close relays that have carried no data in either direction for this
+    /// long.
+    pub idle_timeout: Option<Duration>,
+    /// Accept loops `run` starts. With more than one, each gets its own
+    /// `SO_REUSEPORT` listener on `bind_addr` and the kernel load-balances
+    /// new connections across them (Unix only).
+    pub acceptors: usize,
+    /// Also serve SOCKS5 on a Unix socket at this path (Unix only). A stale
+    /// socket file left by a previous run is replaced.
+    pub unix_socket_path: Option<PathBuf>,
+    /// Permission bits for the Unix socket file, e.g. `0o660`; left to the
+    /// umask when unset.
+    pub unix_socket_mode: Option<u32>,
+    /// Further addresses `run` listens on besides `bind_addr`, each with
+    /// optional overrides of the server-wide settings.
+    pub listeners: Vec<ListenerOptions>,
+    /// Switch to an unprivileged user (and optionally chroot) once every
+    /// listener is bound, so privileged ports can be bound as root (Unix
+    /// only).
+    pub privileges: Option<PrivilegeDrop>,
+    /// How long `shutdown` lets in-flight connections finish before cutting
+    /// them.
+    pub drain_timeout: Duration,
+    /// Lifecycle callbacks, run in order for every connection.
+    pub hooks: Vec<Arc<dyn ConnectionHook>>,
+    /// Field size caps and failure delay for username/password
+    /// authentication.
+    pub auth_limits: AuthLimits,
+    pub handshake_timeouts: HandshakeTimeouts,
+    /// Connections allowed to be in the SOCKS handshake at once, counted
+    /// separately from established relays. Connections beyond the cap are
+    /// closed straight away.
+    pub max_handshaking: Option<usize>,
+    /// Receives a record of every authentication attempt and of whether
+    /// each request was allowed.
+    pub audit: Option<Arc<dyn AuditSink>>,
+    /// HTTP listener for `/healthz` and `/readyz`, started by `run`.
+    pub health: Option<HealthOptions>,
+    /// HTTP listener serving a proxy auto-config file for browsers, started
+    /// by `run`.
+    pub pac: Option<PacOptions>,
+    /// Per-user byte counters that persist across restarts. `run` flushes
+    /// them periodically and `shutdown` once more at the end.
+    pub accounting: Option<Arc<Accounting>>,
+}
+
+/// An additional listening address. Unset fields fall back to the
+/// server-wide setting.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerOptions {
+    pub bind_addr: String,
+    pub auth_required: Option<bool>,
+    /// Destination ACL replacing the server-wide one on this listener.
+    pub acl: Option<Acl>,
+    pub client_socket: Option<SocketOptions>,
 }
 
 impl Default for ServerOptions {
@@ -29,317 +312,1634 @@ impl Default for ServerOptions {
             bind_addr: "127.0.0.1:1080".to_string(),
             auth_required: false,
             credentials: None,
+            authenticator: None,
+            auth_ban: None,
+            policies: PolicyTable::default(),
+            global_bandwidth: None,
+            connection_bandwidth: None,
+            quota_store: None,
+            max_sessions_per_user: None,
+            acl: None,
+            dialer: None,
+            routing: None,
+            udp_relay: None,
+            socks6: false,
+            sniff: None,
+            capture: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            resolver: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            outbound_bind: None,
+            client_socket: SocketOptions::default(),
+            outbound_socket: SocketOptions::default(),
+            idle_timeout: None,
+            acceptors: 1,
+            unix_socket_path: None,
+            unix_socket_mode: None,
+            listeners: Vec::new(),
+            privileges: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            hooks: Vec::new(),
+            auth_limits: AuthLimits::default(),
+            handshake_timeouts: HandshakeTimeouts::default(),
+            max_handshaking: None,
+            audit: None,
+            health: None,
+            pac: None,
+            accounting: None,
         }
     }
 }
 
+impl ServerOptions {
+    /// Check for settings that cannot work together, e.g. requiring
+    /// authentication without any way to authenticate. `Server::builder`
+    /// runs this at build time; `Server::from_options` does not.
+    pub fn validate(&self) -> io::Result<()> {
+        let has_auth = self.authenticator.is_some()
+            || self
+                .credentials
+                .as_ref()
+                .is_some_and(|creds| !creds.is_empty());
+
+        validate_bind_addr(&self.bind_addr)?;
+        if self.auth_required && !has_auth {
+            return Err(invalid(
+                "auth_required is set but no credentials or authenticator are configured",
+            ));
+        }
+        if self.acceptors == 0 {
+            return Err(invalid("acceptors must be at least 1"));
+        }
+        if self.connect_timeout.is_zero() {
+            return Err(invalid("connect_timeout must not be zero"));
+        }
+        if self.idle_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(invalid("idle_timeout must not be zero"));
+        }
+        let handshake = &self.handshake_timeouts;
+        if [handshake.greeting, handshake.auth, handshake.request]
+            .iter()
+            .any(Duration::is_zero)
+        {
+            return Err(invalid("handshake_timeouts must not be zero"));
+        }
+        if self.max_handshaking == Some(0) {
+            return Err(invalid("max_handshaking must be at least 1"));
+        }
+        if self.dialer.is_some()
+            && self.routing.is_none()
+            && (self.resolver.is_some() || self.outbound_bind.is_some())
+        {
+            return Err(invalid(
+                "resolver and outbound_bind only apply to the default dialer, but a dialer is set",
+            ));
+        }
+        if let Some(routing) = &self.routing {
+            routing.validate()?;
+        }
+        if let Some(udp_relay) = &self.udp_relay {
+            udp_relay.validate()?;
+        }
+        if let Some(capture) = &self.capture {
+            capture.validate()?;
+        }
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &self.chaos {
+            chaos.validate()?;
+        }
+        if let Some(mode) = self.unix_socket_mode {
+            if self.unix_socket_path.is_none() {
+                return Err(invalid("unix_socket_mode is set without unix_socket_path"));
+            }
+            if mode > 0o777 {
+                return Err(invalid(format!(
+                    "unix_socket_mode {:o} is not a permission mode",
+                    mode
+                )));
+            }
+        }
+        if self
+            .privileges
+            .as_ref()
+            .is_some_and(|privileges| privileges.user.is_empty())
+        {
+            return Err(invalid("privileges.user must not be empty"));
+        }
+
+        let mut addrs = vec![self.bind_addr.as_str()];
+        for listener in &self.listeners {
+            validate_bind_addr(&listener.bind_addr)?;
+            if addrs.contains(&listener.bind_addr.as_str()) {
+                return Err(invalid(format!(
+                    "{} is listed more than once",
+                    listener.bind_addr
+                )));
+            }
+            addrs.push(&listener.bind_addr);
+            if listener.auth_required == Some(true) && !has_auth {
+                return Err(invalid(format!(
+                    "Listener {} requires authentication but no credentials or authenticator are configured",
+                    listener.bind_addr
+                )));
+            }
+        }
+        if let Some(health) = &self.health {
+            validate_bind_addr(&health.bind_addr)?;
+            if addrs.contains(&health.bind_addr.as_str()) {
+                return Err(invalid(format!(
+                    "Health listener {} is also a SOCKS listener",
+                    health.bind_addr
+                )));
+            }
+            if health.probe_timeout.is_zero() {
+                return Err(invalid("health.probe_timeout must not be zero"));
+            }
+            addrs.push(&health.bind_addr);
+        }
+        if let Some(pac) = &self.pac {
+            validate_bind_addr(&pac.bind_addr)?;
+            if addrs.contains(&pac.bind_addr.as_str()) {
+                return Err(invalid(format!(
+                    "PAC listener {} is also used by another listener",
+                    pac.bind_addr
+                )));
+            }
+            let anonymous = !self.auth_required
+                || self
+                    .listeners
+                    .iter()
+                    .any(|listener| listener.auth_required == Some(false));
+            if !anonymous {
+                return Err(invalid(
+                    "pac is set but every listener requires authentication, which browsers cannot do",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Server {
     pub fn new(bind_addr: String) -> Self {
         Server {
             bind_addr,
             auth_required: false,
-            credentials: None,
+            authenticator: None,
+            ban_list: None,
+            policies: Arc::default(),
+            upload_limiters: Arc::default(),
+            download_limiters: Arc::default(),
+            global_limits: RelayLimits::default(),
+            connection_bandwidth: None,
+            quota_store: None,
+            sessions: Arc::default(),
+            max_sessions_per_user: None,
+            acl: None,
+            dialer: Arc::new(DirectDialer::new()),
+            direct: Arc::new(DirectDialer::new()),
+            resolver: Arc::new(SystemResolver::new()),
+            routing: None,
+            udp: None,
+            socks6: false,
+            sniff: None,
+            capture: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            client_socket: SocketOptions::default(),
+            idle_timeout: None,
+            acceptors: 1,
+            unix_socket_path: None,
+            unix_socket_mode: None,
+            listeners: Vec::new(),
+            privileges: None,
+            accept_errors: Arc::default(),
+            drain: Arc::default(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            hooks: Arc::new([]),
+            auth_limits: AuthLimits::default(),
+            handshake_timeouts: HandshakeTimeouts::default(),
+            handshake_slots: None,
+            audit: None,
+            health: None,
+            pac: None,
+            stats: Arc::default(),
+            accounting: None,
         }
     }
 
     pub fn from_options(options: ServerOptions) -> Self {
+        let authenticator = options.authenticator.or_else(|| {
+            options
+                .credentials
+                .map(|creds| Arc::new(StaticAuthenticator::new(creds)) as Arc<dyn Authenticator>)
+        });
+
+        let resolver = options
+            .resolver
+            .unwrap_or_else(|| Arc::new(SystemResolver::new()));
+        let direct = DirectDialer::with_resolver(resolver.clone());
+        let direct = direct
+            .with_connect_timeout(options.connect_timeout)
+            .with_socket_options(options.outbound_socket);
+        let direct: Arc<dyn Dialer> = Arc::new(match options.outbound_bind {
+            Some(bind) => direct.with_outbound_bind(bind),
+            None => direct,
+        });
+        let dialer = options.dialer.unwrap_or_else(|| direct.clone());
+
         Server {
             bind_addr: options.bind_addr,
             auth_required: options.auth_required,
-            credentials: options.credentials.map(Arc::new),
+            authenticator,
+            ban_list: options.auth_ban.map(|opts| Arc::new(BanList::new(opts))),
+            policies: Arc::new(options.policies),
+            upload_limiters: Arc::default(),
+            download_limiters: Arc::default(),
+            global_limits: options
+                .global_bandwidth
+                .map(|bandwidth| bandwidth.limiters(|rate| Arc::new(RateLimiter::new(rate))))
+                .unwrap_or_default(),
+            connection_bandwidth: options.connection_bandwidth,
+            quota_store: options.quota_store,
+            sessions: Arc::default(),
+            max_sessions_per_user: options.max_sessions_per_user,
+            acl: options.acl.map(Arc::new),
+            dialer,
+            direct,
+            resolver,
+            routing: options.routing.map(Arc::new),
+            udp: options
+                .udp_relay
+                .map(|options| Arc::new(UdpAssociations::new(options))),
+            socks6: options.socks6,
+            sniff: options.sniff,
+            capture: options.capture,
+            #[cfg(feature = "chaos")]
+            chaos: options.chaos.map(|options| Arc::new(Chaos::new(options))),
+            client_socket: options.client_socket,
+            idle_timeout: options.idle_timeout,
+            acceptors: options.acceptors,
+            unix_socket_path: options.unix_socket_path,
+            unix_socket_mode: options.unix_socket_mode,
+            listeners: options.listeners,
+            privileges: options.privileges,
+            accept_errors: Arc::default(),
+            drain: Arc::default(),
+            drain_timeout: options.drain_timeout,
+            hooks: options.hooks.into(),
+            auth_limits: options.auth_limits,
+            handshake_timeouts: options.handshake_timeouts,
+            handshake_slots: options
+                .max_handshaking
+                .map(|max| Arc::new(Semaphore::new(max))),
+            audit: options.audit,
+            health: options.health,
+            pac: options.pac,
+            stats: Arc::default(),
+            accounting: options.accounting,
         }
     }
 
-    pub async fn run(&self) -> io::Result<()> {
-        let listener = TcpListener::bind(&self.bind_addr).await?;
-        info!("SOCKS5 server listening on {}", self.bind_addr);
-
-        loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    info!("New connection from {}", addr);
-                    let auth_required = self.auth_required;
-                    let credentials = self.credentials.clone().map(|arc| (*arc).clone());
-
-                    tokio::spawn(async move {
-                        if let Err(e) = handle_client(stream, auth_required, credentials).await {
-                            error!("Error handling client: {}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Failed to accept connection: {}", e);
+    /// Per-user count of active relays.
+    pub fn sessions(&self) -> &Arc<SessionTracker> {
+        &self.sessions
+    }
+
+    /// Live UDP associations and the relay's counters, if `udp_relay` was
+    /// configured.
+    pub fn udp_associations(&self) -> Option<&Arc<UdpAssociations>> {
+        self.udp.as_ref()
+    }
+
+    /// Brute-force protection state, if `auth_ban` was configured.
+    pub fn ban_list(&self) -> Option<&Arc<BanList>> {
+        self.ban_list.as_ref()
+    }
+
+    /// Accept failure counters, shared by every listener of this server.
+    pub fn accept_stats(&self) -> AcceptStats {
+        self.accept_errors.stats()
+    }
+
+    pub(crate) fn accept_errors(&self) -> &AcceptErrors {
+        &self.accept_errors
+    }
+
+    /// Connections currently being served, from accept until close.
+    pub fn active_connections(&self) -> u64 {
+        self.drain.active()
+    }
+
+    /// Counters for connections, relayed bytes, authentication failures and
+    /// replies since the server was created.
+    pub fn stats(&self) -> ServerStats {
+        self.stats.snapshot()
+    }
+
+    pub(crate) fn drain(&self) -> &Arc<Drain> {
+        &self.drain
+    }
+
+    /// Whether `max_handshaking` connections are already mid-handshake.
+    pub(crate) fn handshakes_exhausted(&self) -> bool {
+        self.handshake_slots
+            .as_ref()
+            .is_some_and(|slots| slots.available_permits() == 0)
+    }
+
+    // One of the `max_handshaking` slots, to hold until a handshake is done
+    pub(crate) fn handshake_slot(&self) -> io::Result<Option<OwnedSemaphorePermit>> {
+        match &self.handshake_slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => {
+                    warn!("Too many connections in handshake, closing");
+                    Err(io::Error::other("Too many connections in handshake"))
                 }
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn handshake_timeouts(&self) -> &HandshakeTimeouts {
+        &self.handshake_timeouts
+    }
+
+    pub(crate) fn health_options(&self) -> HealthOptions {
+        self.health.clone().unwrap_or_default()
+    }
+
+    pub(crate) fn pac_options(&self) -> Option<&PacOptions> {
+        self.pac.as_ref()
+    }
+
+    pub(crate) fn routing(&self) -> Option<&RoutingTable> {
+        self.routing.as_deref()
+    }
+
+    // Bind addresses of the SOCKS listeners open to clients without
+    // credentials
+    pub(crate) fn anonymous_listeners(&self) -> Vec<&str> {
+        let mut addrs = Vec::new();
+        if !self.auth_required {
+            addrs.push(self.bind_addr.as_str());
+        }
+        for listener in &self.listeners {
+            if !listener.auth_required.unwrap_or(self.auth_required) {
+                addrs.push(listener.bind_addr.as_str());
             }
         }
+        addrs
+    }
+
+    /// Bind the health listener, if one is configured.
+    pub(crate) async fn bind_health(&self) -> io::Result<Option<TcpListener>> {
+        match &self.health {
+            Some(health) => Ok(Some(TcpListener::bind(&health.bind_addr).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stop accepting on every listener, so `run` returns, and give
+    /// in-flight connections up to `drain_timeout` to finish before the rest
+    /// are cut. A server that has been shut down cannot be run again.
+    pub async fn shutdown(&self) -> DrainReport {
+        let report = self.drain.run(self.drain_timeout).await;
+        if let Some(accounting) = &self.accounting
+            && let Err(e) = accounting.flush().await
+        {
+            error!("Failed to save bandwidth accounting: {}", e);
+        }
+        report
+    }
+
+    /// Flush the bandwidth accounting periodically until shutdown.
+    pub(crate) async fn run_accounting(&self) -> io::Result<()> {
+        if let Some(accounting) = &self.accounting {
+            tokio::select! {
+                _ = accounting.run() => {}
+                _ = self.drain.stopped() => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the configured client socket options to an accepted connection.
+    pub(crate) fn configure_client_socket(&self, stream: &TcpStream) {
+        if let Err(e) = self.client_socket.apply(SockRef::from(stream)) {
+            warn!("Failed to set client socket options: {}", e);
+        }
+    }
+
+    /// Address `run` binds to.
+    pub fn bind_addr(&self) -> &str {
+        &self.bind_addr
+    }
+
+    /// Number of accept loops `run` starts; see `ServerOptions::acceptors`.
+    pub(crate) fn acceptors(&self) -> usize {
+        self.acceptors
+    }
+
+    /// Drop privileges as configured; called once listeners are bound.
+    pub(crate) fn drop_privileges(&self) -> io::Result<()> {
+        match &self.privileges {
+            Some(privileges) => privileges.apply(),
+            None => Ok(()),
+        }
     }
 
-    // Generic handle_client method that works with any stream type
+    /// Listen on the bind address and every additional listener, plus the
+    /// Unix socket if one is configured.
+    pub async fn run(&self) -> io::Result<()> {
+        // Bind everything up front so a bad address fails before serving starts
+        let mut bound = vec![(
+            self.clone(),
+            bind_listeners(&self.bind_addr, self.acceptors).await?,
+        )];
+        for options in &self.listeners {
+            let listeners = bind_listeners(&options.bind_addr, self.acceptors).await?;
+            bound.push((self.with_listener_overrides(options), listeners));
+        }
+        #[cfg(unix)]
+        let unix = match &self.unix_socket_path {
+            Some(path) => Some(crate::unix::bind_unix(path, self.unix_socket_mode)?),
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if self.unix_socket_path.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            ));
+        }
+        let health = self.bind_health().await?;
+        let pac = match &self.pac {
+            Some(pac) => Some(TcpListener::bind(&pac.bind_addr).await?),
+            None => None,
+        };
+        self.drop_privileges()?;
+
+        let mut servers = JoinSet::new();
+        for (server, listeners) in bound {
+            servers.spawn(async move { server.run_with_listeners(listeners).await });
+        }
+        #[cfg(unix)]
+        if let Some(unix) = unix {
+            let server = self.clone();
+            servers.spawn(async move { server.run_with_unix_listener(unix).await });
+        }
+        if let Some(health) = health {
+            let server = self.clone();
+            servers.spawn(async move { server.run_health_with_listener(health).await });
+        }
+        if let Some(pac) = pac {
+            let server = self.clone();
+            servers.spawn(async move { server.run_pac_with_listener(pac).await });
+        }
+        let server = self.clone();
+        servers.spawn(async move { server.run_accounting().await });
+        join_all(servers).await
+    }
+
+    /// Serve SOCKS5 on an already bound listener.
+    pub async fn run_with_listener(&self, listener: TcpListener) -> io::Result<()> {
+        self.run_with_listeners(vec![listener]).await
+    }
+
+    /// Serve SOCKS5 on several already bound listeners, each with its own
+    /// accept loop.
+    pub async fn run_with_listeners(&self, listeners: Vec<TcpListener>) -> io::Result<()> {
+        let mut loops = JoinSet::new();
+        for listener in listeners {
+            let server = self.clone();
+            loops.spawn(async move { server.accept_loop(listener).await });
+        }
+        join_all(loops).await
+    }
+
+    // A view of this server with a listener's overrides applied; counters,
+    // limiters and sessions stay shared
+    fn with_listener_overrides(&self, options: &ListenerOptions) -> Server {
+        let mut server = self.clone();
+        server.bind_addr = options.bind_addr.clone();
+        if let Some(auth_required) = options.auth_required {
+            server.auth_required = auth_required;
+        }
+        if let Some(acl) = &options.acl {
+            server.acl = Some(Arc::new(acl.clone()));
+        }
+        if let Some(client_socket) = &options.client_socket {
+            server.client_socket = client_socket.clone();
+        }
+        server
+    }
+
+    async fn accept_loop(&self, listener: TcpListener) -> io::Result<()> {
+        self.run_with_acceptor(listener, Arc::new(Tcp)).await
+    }
+
+    /// `handle_connection` for a peer the server knows nothing about.
     pub async fn handle_client<S>(&self, stream: S) -> io::Result<()>
     where
         S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
     {
-        let auth_required = self.auth_required;
-        let credentials = self.credentials.clone();
+        self.handle_connection(stream, PeerInfo::default()).await
+    }
 
-        handle_stream(stream, auth_required, credentials).await
+    /// Serve one SOCKS5 connection from any byte stream: a socket from your
+    /// own listener, a tunnel, or a `tokio::io::duplex` pipe in tests.
+    /// Returns once the client disconnects or the relay ends. `peer.addr`
+    /// feeds the ban list and logs; with `peer.identity` set, the client may
+    /// pick "no authentication" even when authentication is required.
+    pub async fn handle_connection<S>(&self, stream: S, peer: PeerInfo) -> io::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &self.chaos {
+            let connection = chaos.connection();
+            let stream = ChaosStream::new(stream, Arc::clone(&connection));
+            let frontend = Frontend::Socks(stream);
+            return chaos::scope(connection, self.tracked(peer, frontend)).await;
+        }
+        self.tracked(peer, Frontend::Socks(stream)).await
     }
-}
 
-async fn handle_client(
-    mut stream: TcpStream,
-    auth_required: bool,
-    credentials: Option<Vec<(String, String)>>,
-) -> io::Result<()> {
-    // SOCKS5 handshake
-    let handshake = HandshakeRequest::read_from(&mut stream).await?;
-    debug!(
-        "Received handshake with {} methods",
-        handshake.methods.len()
-    );
-
-    // Authentication handling
-    if auth_required && handshake.methods.contains(&AUTH_PASSWORD) {
-        // Send back auth choice (username/password auth)
-        stream.write_all(&[SOCKS_VERSION, AUTH_PASSWORD]).await?;
-
-        // Read auth data
-        let auth = UserPassAuth::read_from(&mut stream).await?;
-
-        // Validate credentials
-        let auth_successful = if let Some(creds) = &credentials {
-            creds.iter().any(|(username, password)| {
-                username == &auth.username && password == &auth.password
-            })
-        } else {
-            // No credentials specified, but auth required - deny all
-            false
+    /// Serve a CONNECT that arrived over another protocol than SOCKS through
+    /// the same pipeline: ban list, hooks, authentication, ACLs, quotas and
+    /// the relay.
+    pub(crate) async fn handle_tunnel(
+        &self,
+        tunnel: Box<dyn Tunnel>,
+        target: SocksAddr,
+        peer: PeerInfo,
+    ) -> io::Result<()> {
+        let frontend = Frontend::<Box<dyn ProxyStream>>::Tunnel(tunnel, target);
+        self.tracked(peer, frontend).await
+    }
+
+    // Serve `frontend` as one connection: counted in the stats, traced, and
+    // reported to the hooks when it ends
+    async fn tracked<S>(&self, peer: PeerInfo, frontend: Frontend<S>) -> io::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let _active = self.stats.connection();
+        let mut info = ConnectionInfo::new(peer);
+        let counters = Arc::new(self.stats.relay_counters());
+        #[cfg(feature = "otel")]
+        let span = crate::otel::ConnectionSpan::start(&info);
+        #[cfg(feature = "otel")]
+        let result = span
+            .scope(self.serve_frontend(frontend, &mut info, &counters))
+            .await;
+        #[cfg(not(feature = "otel"))]
+        let result = self.serve_frontend(frontend, &mut info, &counters).await;
+
+        let summary = ConnectionSummary {
+            upload: counters.upload(),
+            download: counters.download(),
+            duration: info.started.elapsed(),
+            error: result.as_ref().err(),
         };
+        for hook in self.hooks.iter() {
+            hook.on_close(&info, &summary).await;
+        }
+        #[cfg(feature = "otel")]
+        span.end(&info, &summary);
+        result
+    }
 
-        if !auth_successful {
-            stream.write_all(&[AUTH_VERSION, AUTH_FAILURE]).await?;
-            return Err(io::Error::new(
-                io::ErrorKind::PermissionDenied,
-                "Authentication failed",
-            ));
+    async fn serve_frontend<S>(
+        &self,
+        frontend: Frontend<S>,
+        info: &mut ConnectionInfo,
+        counters: &Arc<RelayCounters>,
+    ) -> io::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        match frontend {
+            Frontend::Socks(stream) => self.serve(stream, info, counters).await,
+            Frontend::Tunnel(tunnel, target) => {
+                self.serve_tunnel(tunnel, target, info, counters).await
+            }
         }
+    }
 
-        // Notify success
-        stream.write_all(&[AUTH_VERSION, AUTH_SUCCESS]).await?;
-        debug!("Authentication successful for user: {}", auth.username);
-    } else if auth_required {
-        // Auth required but no acceptable auth methods
-        stream.write_all(&[SOCKS_VERSION, 0xFF]).await?;
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "No acceptable auth methods",
-        ));
-    } else if handshake.methods.contains(&AUTH_NONE) {
-        // No auth required
-        stream.write_all(&[SOCKS_VERSION, AUTH_NONE]).await?;
-    } else {
-        // No acceptable auth methods
-        stream.write_all(&[SOCKS_VERSION, 0xFF]).await?;
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "No acceptable auth methods",
-        ));
-    }
-
-    // Process the request
-    let request = Request::read_from(&mut stream).await?;
-    debug!("Received request for command {}", request.command);
-
-    // Handle based on command
-    match request.command {
-        CMD_CONNECT => connect_and_relay(stream, request.addr).await,
-        _ => {
-            // Command not supported
-            let reply = Reply::new(REP_COMMAND_NOT_SUPPORTED, request.addr);
-            reply.write_to(&mut stream).await?;
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Command not supported",
-            ))
+    async fn serve<S>(
+        &self,
+        mut stream: S,
+        info: &mut ConnectionInfo,
+        counters: &Arc<RelayCounters>,
+    ) -> io::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        self.check_banned(info).await?;
+        // Held until the request has been read
+        let handshake_slot = self.handshake_slot()?;
+        for hook in self.hooks.iter() {
+            hook.on_accept(info).await?;
+        }
+
+        // SOCKS5 handshake
+        #[cfg(feature = "chaos")]
+        chaos::inject(FaultPoint::Accept).await?;
+        let timeouts = &self.handshake_timeouts;
+        // One greeting deadline covers the version byte and the method list
+        let greeting_deadline = tokio::time::Instant::now() + timeouts.greeting;
+        let version = within(timeouts.greeting, "greeting", stream.read_u8()).await?;
+        if version == SOCKS6_VERSION && self.socks6 {
+            let request = within(
+                timeouts.request,
+                "request",
+                Socks6Request::read_after_version(&mut stream),
+            )
+            .await?;
+            drop(handshake_slot);
+            debug!("Received SOCKS6 request for command {}", request.command);
+            let (command, target) = (request.command, request.addr.clone());
+            let tunnel = Box::new(Socks6Tunnel::new(stream, request));
+            return self
+                .tunnel_request(tunnel, command, target, info, counters)
+                .await;
+        }
+        let handshake = within(
+            greeting_deadline.saturating_duration_since(tokio::time::Instant::now()),
+            "greeting",
+            HandshakeRequest::read_from(&mut (&[version][..]).chain(&mut stream)),
+        )
+        .await?;
+        debug!(
+            "Received handshake with {} methods",
+            handshake.methods.len()
+        );
+
+        #[cfg(feature = "chaos")]
+        chaos::inject(FaultPoint::MethodReply).await?;
+        info.identity = self.negotiate_auth(&mut stream, &handshake, info).await?;
+        for hook in self.hooks.iter() {
+            hook.on_auth(info).await?;
+        }
+
+        // Process the request
+        let request = within(timeouts.request, "request", Request::read_from(&mut stream)).await?;
+        drop(handshake_slot);
+        match &info.identity {
+            Some(identity) => debug!(
+                "Received request for command {} from user {}",
+                request.command, identity.username
+            ),
+            None => debug!("Received request for command {}", request.command),
+        }
+
+        #[cfg(feature = "chaos")]
+        chaos::inject(FaultPoint::ConnectReply).await?;
+        info.target = Some(request.addr.clone());
+        if let Err(e) = self.request_hooks(info, request.command).await {
+            Reply::new(REP_CONNECTION_NOT_ALLOWED, request.addr)
+                .write_to(&mut stream)
+                .await?;
+            return Err(e);
+        }
+
+        // Handle based on command
+        match request.command {
+            CMD_CONNECT => {
+                let client = ConnectClient::Socks(stream);
+                self.handle_connect(client, request.addr, info, counters)
+                    .await
+            }
+            CMD_UDP_ASSOCIATE if self.udp.is_some() => {
+                self.handle_udp_associate(stream, request.addr, info, counters)
+                    .await
+            }
+            _ => {
+                // Command not supported
+                self.stats.reply(REP_COMMAND_NOT_SUPPORTED);
+                let reply = Reply::new(REP_COMMAND_NOT_SUPPORTED, request.addr);
+                reply.write_to(&mut stream).await?;
+                Err(io::Error::other("Command not supported"))
+            }
         }
     }
-}
 
-async fn handle_stream<S>(
-    mut stream: S,
-    auth_required: bool,
-    credentials: Option<Arc<Vec<(String, String)>>>,
-) -> io::Result<()>
-where
-    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
-{
-    use tokio::io::AsyncReadExt;
-
-    // SOCKS5 handshake
-    let handshake = HandshakeRequest::read_from(&mut stream).await?;
-    debug!(
-        "Received handshake with {} methods",
-        handshake.methods.len()
-    );
-
-    // Authentication handling
-    if auth_required && handshake.methods.contains(&AUTH_PASSWORD) {
-        // Send back auth choice (username/password auth)
-        stream.write_all(&[SOCKS_VERSION, AUTH_PASSWORD]).await?;
-
-        // Read auth data
-        let auth = UserPassAuth::read_from(&mut stream).await?;
-
-        // Validate credentials
-        let auth_successful = if let Some(creds) = &credentials {
-            creds.iter().any(|(username, password)| {
-                username == &auth.username && password == &auth.password
-            })
-        } else {
-            // No credentials specified, but auth required - deny all
-            false
+    // `serve` for a request that has already been read by another protocol
+    async fn serve_tunnel(
+        &self,
+        tunnel: Box<dyn Tunnel>,
+        target: SocksAddr,
+        info: &mut ConnectionInfo,
+        counters: &Arc<RelayCounters>,
+    ) -> io::Result<()> {
+        self.check_banned(info).await?;
+        for hook in self.hooks.iter() {
+            hook.on_accept(info).await?;
+        }
+        self.tunnel_request(tunnel, CMD_CONNECT, target, info, counters)
+            .await
+    }
+
+    // The rest of `serve_tunnel`, also serving SOCKS6 requests, which arrive
+    // on connections already through the accept checks
+    async fn tunnel_request(
+        &self,
+        tunnel: Box<dyn Tunnel>,
+        command: u8,
+        target: SocksAddr,
+        info: &mut ConnectionInfo,
+        counters: &Arc<RelayCounters>,
+    ) -> io::Result<()> {
+        // Credentials are only looked at when they are needed, as in SOCKS
+        info.identity = match (&info.peer.identity, tunnel.credentials()) {
+            (Some(identity), _) => Some(identity.clone()),
+            (None, Some(auth)) if self.auth_required => {
+                match self.verify_credentials(info, &auth).await {
+                    Some(identity) => Some(identity),
+                    None => {
+                        self.auth_failed(info, Some(&auth.username), "Invalid credentials")
+                            .await;
+                        tunnel.reject_auth().await;
+                        return Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            "Authentication failed",
+                        ));
+                    }
+                }
+            }
+            (None, None) if self.auth_required => {
+                tunnel.reject_auth().await;
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Authentication required",
+                ));
+            }
+            (None, _) => None,
         };
+        for hook in self.hooks.iter() {
+            hook.on_auth(info).await?;
+        }
+
+        info.target = Some(target.clone());
+        if let Err(e) = self.request_hooks(info, command).await {
+            tunnel
+                .refuse(Reply::new(REP_CONNECTION_NOT_ALLOWED, target))
+                .await;
+            return Err(e);
+        }
+        if command != CMD_CONNECT {
+            self.stats.reply(REP_COMMAND_NOT_SUPPORTED);
+            tunnel
+                .refuse(Reply::new(REP_COMMAND_NOT_SUPPORTED, target))
+                .await;
+            return Err(io::Error::other("Command not supported"));
+        }
+        let client = ConnectClient::<Box<dyn ProxyStream>>::Tunnel(tunnel);
+        self.handle_connect(client, target, info, counters).await
+    }
 
-        if !auth_successful {
-            stream.write_all(&[AUTH_VERSION, AUTH_FAILURE]).await?;
+    // Refuse connections from banned source addresses
+    async fn check_banned(&self, info: &ConnectionInfo) -> io::Result<()> {
+        if let (Some(bans), Some(addr)) = (&self.ban_list, info.peer.addr)
+            && bans.check(addr.ip())
+        {
+            debug!("Rejecting connection from banned address {}", addr.ip());
+            self.audit(
+                info,
+                AuditAction::Denied,
+                None,
+                Some("Source address is banned"),
+            )
+            .await;
             return Err(io::Error::new(
                 io::ErrorKind::PermissionDenied,
-                "Authentication failed",
+                "Source address is banned",
             ));
         }
+        Ok(())
+    }
 
-        // Notify success
-        stream.write_all(&[AUTH_VERSION, AUTH_SUCCESS]).await?;
-        debug!("Authentication successful for user: {}", auth.username);
-    } else if auth_required {
-        // Auth required but no acceptable auth methods
-        stream.write_all(&[SOCKS_VERSION, 0xFF]).await?;
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "No acceptable auth methods",
-        ));
-    } else if handshake.methods.contains(&AUTH_NONE) {
-        // No auth required
-        stream.write_all(&[SOCKS_VERSION, AUTH_NONE]).await?;
-    } else {
-        // No acceptable auth methods
-        stream.write_all(&[SOCKS_VERSION, 0xFF]).await?;
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "No acceptable auth methods",
-        ));
-    }
-
-    // Process the request
-    let request = Request::read_from(&mut stream).await?;
-    debug!("Received request for command {}", request.command);
-
-    // Handle based on command
-    match request.command {
-        CMD_CONNECT => connect_and_relay(stream, request.addr).await,
-        _ => {
-            // Command not supported
-            let reply = Reply::new(REP_COMMAND_NOT_SUPPORTED, request.addr);
-            reply.write_to(&mut stream).await?;
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Command not supported",
-            ))
+    // Run the `on_request` hooks; a rejection is audited and counted, and
+    // the caller refuses the request
+    async fn request_hooks(&self, info: &ConnectionInfo, command: u8) -> io::Result<()> {
+        for hook in self.hooks.iter() {
+            if let Err(e) = hook.on_request(info, command).await {
+                self.audit(info, AuditAction::Denied, None, Some(&e.to_string()))
+                    .await;
+                self.stats.reply(REP_CONNECTION_NOT_ALLOWED);
+                return Err(e);
+            }
         }
+        Ok(())
     }
-}
 
-async fn connect_and_relay<S>(mut client: S, addr: SocksAddr) -> io::Result<()>
-where
-    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
-{
-    debug!("Connecting to {:?}", addr.to_string());
-
-    // Resolve domain name if necessary
-    let dest_addr = match &addr {
-        SocksAddr::Domain(domain, port) => {
-            match tokio::net::lookup_host(format!("{}:{}", domain, port)).await {
-                Ok(mut addresses) => {
-                    if let Some(addr) = addresses.next() {
-                        addr
-                    } else {
-                        let reply = Reply::new(REP_HOST_UNREACHABLE, addr.clone());
-                        reply.write_to(&mut client).await?;
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            "Could not resolve domain",
-                        ));
+    async fn handle_connect<S>(
+        &self,
+        client: ConnectClient<S>,
+        addr: SocksAddr,
+        info: &mut ConnectionInfo,
+        counters: &Arc<RelayCounters>,
+    ) -> io::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let identity = info.identity.clone();
+        let identity = identity.as_ref();
+        let policy = self.policies.resolve(identity);
+
+        // Both the server-wide and the user's ACL must allow the destination
+        let allowed = self.acl.as_ref().is_none_or(|acl| acl.allows(&addr))
+            && policy.acl.as_ref().is_none_or(|acl| acl.allows(&addr));
+        if !allowed {
+            return self
+                .deny(client, addr, info, "Destination denied by ACL")
+                .await;
+        }
+
+        // The first matching route overrides the policy's and the server's
+        // dialer
+        let username = identity.map(|identity| identity.username.as_str());
+        let routing = self.routing.as_deref();
+        let mut route = routing.and_then(|routing| routing.route(&addr, username));
+        // A domain no route names may still resolve into a CIDR or ASN route.
+        // Lookup failures are left for the dialer to report.
+        if route.is_none()
+            && let Some(routing) = routing
+            && routing.routes_addresses()
+            && let SocksAddr::Domain(domain, _) = &addr
+            && let Ok(resolved) = self.resolver.resolve(domain).await
+        {
+            route = resolved
+                .addrs
+                .iter()
+                .find_map(|ip| routing.route_resolved(&addr, ip.to_canonical(), username));
+        }
+        let dialer = match route {
+            None => policy.dialer.as_ref().unwrap_or(&self.dialer),
+            Some(RouteAction::Direct) => &self.direct,
+            Some(RouteAction::Upstream(name)) => {
+                match routing.and_then(|routing| routing.upstreams.get(name)) {
+                    Some(dialer) => dialer,
+                    None => {
+                        let msg = format!("Route names unknown upstream {:?}", name);
+                        return self.deny(client, addr, info, &msg).await;
                     }
                 }
-                Err(_) => {
-                    let reply = Reply::new(REP_HOST_UNREACHABLE, addr.clone());
-                    reply.write_to(&mut client).await?;
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Could not resolve domain",
-                    ));
+            }
+            Some(RouteAction::Reject) => {
+                return self
+                    .deny(client, addr, info, "Destination rejected by routing rule")
+                    .await;
+            }
+        };
+
+        // Looked up once, before dialing, and charged against while relaying
+        let quota = match self.quota(identity).await {
+            Ok(Some((_, true))) => {
+                let msg = format!("Data quota exhausted for user {}", username.unwrap_or(""));
+                return self.deny(client, addr, info, &msg).await;
+            }
+            Ok(quota) => quota.map(|(limit, _)| limit),
+            Err(e) => {
+                return self
+                    .fail(client, addr, "Failed to look up data quota", e)
+                    .await;
+            }
+        };
+
+        // Held until the relay ends
+        let _session = match identity {
+            Some(identity) => {
+                let limit = policy.max_sessions.or(self.max_sessions_per_user);
+                match self.sessions.try_acquire(&identity.username, limit) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        let msg = format!("Session limit reached for user {}", identity.username);
+                        return self.deny(client, addr, info, &msg).await;
+                    }
                 }
             }
+            None => None,
+        };
+
+        // A domain is checked again against each address it resolves to, in
+        // case the name points into a denied network. Addresses another
+        // route claims are skipped too.
+        let check = |resolved: SocketAddr| {
+            let ip = resolved.ip().to_canonical();
+            self.acl
+                .as_ref()
+                .is_none_or(|acl| acl.allows_resolved(&addr, ip))
+                && policy
+                    .acl
+                    .as_ref()
+                    .is_none_or(|acl| acl.allows_resolved(&addr, ip))
+                && routing.is_none_or(|routing| {
+                    routing
+                        .route_resolved(&addr, ip, username)
+                        .is_none_or(|action| Some(action) == route)
+                })
+        };
+
+        self.audit(info, AuditAction::Allowed, None, None).await;
+        debug!("Connecting to {:?}", addr.to_string());
+        let mut outbound = dialer.dial_checked(&addr, &check).await;
+        if let Some(capture) = &self.capture
+            && capture.matches(&addr, username)
+            && let Ok(tapped) = outbound
+        {
+            outbound = Ok(capture::tap(capture, info, &addr, tapped).await);
         }
-        _ => {
-            // If it's an IP address, just convert it to a socket address
-            if let Some(socket_addr) = addr.to_socket_addr() {
-                socket_addr
-            } else {
-                let reply = Reply::new(REP_ADDRESS_TYPE_NOT_SUPPORTED, addr.clone());
-                reply.write_to(&mut client).await?;
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "Address type not supported",
-                ));
+        for hook in self.hooks.iter() {
+            let result = match &outbound {
+                Ok(outbound) => Ok(outbound.bound_addr),
+                Err(e) => Err(e),
+            };
+            hook.on_connect_result(info, result).await;
+        }
+        self.stats.reply(match &outbound {
+            Ok(_) => REP_SUCCEEDED,
+            Err(e) => reply_code_for(e),
+        });
+
+        let cut = self.drain.cut_token().child_token();
+        let options = RelayOptions {
+            limits: self.relay_limits(identity),
+            idle_timeout: self.idle_timeout,
+            cancel: Some(cut.clone()),
+            counters: Some(self.user_counters(identity, counters)),
+        };
+        let relay = self.reply_and_relay(client, addr, outbound, &options, info, policy);
+        match self.metered(identity, quota, counters, &cut, relay).await {
+            (Err(_), true) => Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                "Connection closed: data quota exhausted",
+            )),
+            (result, _) => result.map(|_| ()),
+        }
+    }
+
+    // Set up a UDP association and relay its datagrams until the control
+    // connection closes, the association idles out or the server shuts down.
+    // `addr` is where the client said it would send datagrams from.
+    async fn handle_udp_associate<S>(
+        &self,
+        mut stream: S,
+        addr: SocksAddr,
+        info: &mut ConnectionInfo,
+        counters: &Arc<RelayCounters>,
+    ) -> io::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        let udp = self.udp.as_ref().expect("UDP relay is enabled");
+        let identity = info.identity.clone();
+        let identity = identity.as_ref();
+        let policy = self.policies.resolve(identity);
+
+        let quota = match self.quota(identity).await {
+            Ok(Some((_, true))) => {
+                let username = identity.map_or("", |identity| identity.username.as_str());
+                let msg = format!("Data quota exhausted for user {}", username);
+                return self
+                    .deny(ConnectClient::Socks(stream), addr, info, &msg)
+                    .await;
+            }
+            Ok(quota) => quota.map(|(limit, _)| limit),
+            Err(e) => {
+                let client = ConnectClient::Socks(stream);
+                return self
+                    .fail(client, addr, "Failed to look up data quota", e)
+                    .await;
             }
+        };
+        // Held until the association ends
+        let _session = match identity {
+            Some(identity) => {
+                let limit = policy.max_sessions.or(self.max_sessions_per_user);
+                match self.sessions.try_acquire(&identity.username, limit) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        let msg = format!("Session limit reached for user {}", identity.username);
+                        return self
+                            .deny(ConnectClient::Socks(stream), addr, info, &msg)
+                            .await;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "udp-aead")]
+        if udp.options().require_encryption && info.peer.udp_keys.is_none() {
+            let msg = "UDP relay requires a TLS control connection with exported keys";
+            return self
+                .deny(ConnectClient::Socks(stream), addr, info, msg)
+                .await;
         }
-    };
 
-    // Connect to the destination
-    match TcpStream::connect(dest_addr).await {
-        Ok(mut server) => {
-            // Send success reply
-            let bind_addr = match server.local_addr() {
-                Ok(addr) => {
-                    if addr.is_ipv4() {
-                        SocksAddr::Ipv4(addr.ip().to_string().parse().unwrap(), addr.port())
-                    } else {
-                        SocksAddr::Ipv6(addr.ip().to_string().parse().unwrap(), addr.port())
+        let username = identity.map(|identity| identity.username.clone());
+        let association = match udp.open(&addr, info.peer.addr, username).await {
+            Ok(association) => association,
+            Err(e) => {
+                warn!("Refusing UDP ASSOCIATE: {}", e);
+                self.stats.reply(REP_GENERAL_FAILURE);
+                Reply::new(REP_GENERAL_FAILURE, addr)
+                    .write_to(&mut stream)
+                    .await?;
+                return Err(e);
+            }
+        };
+        #[cfg(feature = "udp-aead")]
+        let association = match &info.peer.udp_keys {
+            Some(keys) => association.with_protection(keys),
+            None => association,
+        };
+        self.audit(info, AuditAction::Allowed, None, None).await;
+        self.stats.reply(REP_SUCCEEDED);
+        Reply::new(REP_SUCCEEDED, association.relay_addr().into())
+            .write_to(&mut stream)
+            .await?;
+
+        // Each datagram's destination goes through the same checks as a
+        // CONNECT target; only direct routes can carry UDP
+        let username = identity.map(|identity| identity.username.as_str());
+        let allows = |target: &SocksAddr, resolved: Option<IpAddr>| {
+            let acl_allows = |acl: &Acl| match resolved {
+                Some(ip) => acl.allows_resolved(target, ip),
+                None => acl.allows(target),
+            };
+            self.acl.as_ref().is_none_or(|acl| acl_allows(acl))
+                && policy.acl.as_ref().is_none_or(acl_allows)
+                && match self.routing.as_deref() {
+                    Some(routing) => matches!(
+                        match resolved {
+                            Some(ip) => routing.route_resolved(target, ip, username),
+                            None => routing.route(target, username),
+                        },
+                        None | Some(RouteAction::Direct)
+                    ),
+                    None => true,
+                }
+        };
+        let relay_counters = self.user_counters(identity, counters);
+        let cut = self.drain.cut_token().child_token();
+        let relay = association.run(
+            &mut stream,
+            allows,
+            self.resolver.as_ref(),
+            &relay_counters,
+            cut.clone(),
+        );
+        let (end, exhausted) = self.metered(identity, quota, counters, &cut, relay).await;
+        drop(association);
+
+        match end {
+            AssociationEnd::Cancelled if exhausted => Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                "UDP association closed: data quota exhausted",
+            )),
+            AssociationEnd::ControlClosed => Ok(()),
+            AssociationEnd::Idle => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "UDP association idle",
+            )),
+            AssociationEnd::Cancelled => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "UDP association closed by shutdown",
+            )),
+        }
+    }
+
+    // Counters for a relay that feed the connection's totals and, for
+    // authenticated users, their accounting counters
+    fn user_counters(
+        &self,
+        identity: Option<&Identity>,
+        counters: &Arc<RelayCounters>,
+    ) -> Arc<RelayCounters> {
+        match (identity, &self.accounting) {
+            (Some(identity), Some(accounting)) => Arc::new(RelayCounters::with_parents(vec![
+                counters.clone(),
+                accounting.counters(&identity.username),
+            ])),
+            _ => counters.clone(),
+        }
+    }
+
+    // The user's data quota, if they have one, and whether it is already used
+    // up. The store's own limit takes precedence over the policy's.
+    async fn quota(&self, identity: Option<&Identity>) -> io::Result<Option<(u64, bool)>> {
+        let (Some(identity), Some(store)) = (identity, &self.quota_store) else {
+            return Ok(None);
+        };
+        let limit = match store.limit(&identity.username).await? {
+            Some(limit) => limit,
+            None => match self.policies.resolve(Some(identity)).quota_bytes {
+                Some(limit) => limit,
+                None => return Ok(None),
+            },
+        };
+        let used = store.usage(&identity.username).await?;
+        Ok(Some((limit, used >= limit)))
+    }
+
+    // Run a relay, charging what `counters` count to the user's data quota
+    // as it goes and cancelling `cut` once `quota` is used up; the flag says
+    // whether that happened. Whatever is left is charged when the relay
+    // ends, even in an error. Failed charges are logged and otherwise
+    // ignored, so they never cut a relay short or replace its result.
+    async fn metered<T>(
+        &self,
+        identity: Option<&Identity>,
+        quota: Option<u64>,
+        counters: &RelayCounters,
+        cut: &CancellationToken,
+        relay: impl Future<Output = T>,
+    ) -> (T, bool) {
+        let (Some(identity), Some(store)) = (identity, &self.quota_store) else {
+            return (relay.await, false);
+        };
+        let username = &identity.username;
+        let mut charged = 0;
+        let mut exhausted = false;
+        let result = match quota {
+            None => relay.await,
+            Some(limit) => {
+                let mut relay = pin!(relay);
+                let mut ticks = tokio::time::interval(QUOTA_INTERVAL);
+                ticks.tick().await;
+                loop {
+                    tokio::select! {
+                        result = &mut relay => break result,
+                        _ = ticks.tick(), if !exhausted => {
+                            let total = counters.total();
+                            match store.add_usage(username, total - charged).await {
+                                Ok(used) => {
+                                    charged = total;
+                                    if used >= limit {
+                                        info!("Data quota of {} exhausted, closing", username);
+                                        exhausted = true;
+                                        cut.cancel();
+                                    }
+                                }
+                                Err(e) => warn!("Failed to charge quota of {}: {}", username, e),
+                            }
+                        }
                     }
                 }
-                Err(_) => addr.clone(), // Fallback to original address
+            }
+        };
+        if let Err(e) = store.add_usage(username, counters.total() - charged).await {
+            warn!("Failed to charge quota of {}: {}", username, e);
+        }
+        (result, exhausted)
+    }
+
+    // Build the rate limiters for a relay: the server-wide ceiling, the
+    // per-connection ceiling and the user's bandwidth policy. Authenticated
+    // users share one bucket per direction across all their connections;
+    // anonymous connections each get their own.
+    fn relay_limits(&self, identity: Option<&Identity>) -> RelayLimits {
+        let mut limits = self.global_limits.clone();
+
+        if let Some(bandwidth) = self.connection_bandwidth {
+            limits.extend(bandwidth.limiters(|rate| Arc::new(RateLimiter::new(rate))));
+        }
+
+        if let Some(bandwidth) = self.policies.resolve(identity).bandwidth {
+            let user = match identity {
+                Some(identity) => RelayLimits {
+                    upload: bandwidth
+                        .upload
+                        .map(|rate| self.upload_limiters.get(&identity.username, rate))
+                        .into_iter()
+                        .collect(),
+                    download: bandwidth
+                        .download
+                        .map(|rate| self.download_limiters.get(&identity.username, rate))
+                        .into_iter()
+                        .collect(),
+                },
+                None => bandwidth.limiters(|rate| Arc::new(RateLimiter::new(rate))),
             };
+            limits.extend(user);
+        }
 
-            let reply = Reply::new(REP_SUCCEEDED, bind_addr);
-            reply.write_to(&mut client).await?;
-
-            // Proxy data between client and server
-            match tokio::io::copy_bidirectional(&mut client, &mut server).await {
-                Ok((bytes_to_server, bytes_to_client)) => {
-                    debug!(
-                        "Connection closed: {} bytes sent, {} bytes received",
-                        bytes_to_server, bytes_to_client
-                    );
-                    Ok(())
+        limits
+    }
+
+    // Select an auth method and run the sub-negotiation, returning the
+    // authenticated identity (if any)
+    async fn negotiate_auth<S>(
+        &self,
+        stream: &mut S,
+        handshake: &HandshakeRequest,
+        info: &ConnectionInfo,
+    ) -> io::Result<Option<Identity>>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        // The transport already authenticated the client (e.g. mTLS)
+        if let Some(identity) = &info.peer.identity
+            && handshake.methods.contains(&AUTH_NONE)
+        {
+            stream.write_all(&[SOCKS_VERSION, AUTH_NONE]).await?;
+            debug!("Client authenticated by transport as {}", identity.username);
+            self.audit(
+                info,
+                AuditAction::AuthSuccess,
+                Some(&identity.username),
+                Some("Authenticated by transport"),
+            )
+            .await;
+            return Ok(Some(identity.clone()));
+        }
+
+        if self.auth_required && handshake.methods.contains(&AUTH_PASSWORD) {
+            // Send back auth choice (username/password auth)
+            stream.write_all(&[SOCKS_VERSION, AUTH_PASSWORD]).await?;
+
+            // Read auth data; malformed or oversized credentials fail the
+            // attempt like wrong ones
+            let limits = &self.auth_limits;
+            let read = UserPassAuth::read_limited(
+                stream,
+                limits.max_username_len,
+                limits.max_password_len,
+            );
+            let auth = match within(self.handshake_timeouts.auth, "authentication", read).await {
+                Ok(auth) => auth,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    debug!("Rejecting malformed authentication: {}", e);
+                    let reason = e.to_string();
+                    return Err(self.reject_auth(stream, info, None, &reason).await);
                 }
-                Err(e) => {
-                    error!("Error during data transfer: {}", e);
-                    Err(e)
+                Err(e) => return Err(e),
+            };
+            #[cfg(feature = "chaos")]
+            chaos::inject(FaultPoint::AuthReply).await?;
+
+            let Some(identity) = self.verify_credentials(info, &auth).await else {
+                let username = Some(auth.username.as_str());
+                return Err(self
+                    .reject_auth(stream, info, username, "Invalid credentials")
+                    .await);
+            };
+
+            // Notify success
+            stream.write_all(&[AUTH_VERSION, AUTH_SUCCESS]).await?;
+            Ok(Some(identity))
+        } else if self.auth_required {
+            // Auth required but no acceptable auth methods
+            stream
+                .write_all(&[SOCKS_VERSION, AUTH_NOT_ACCEPTABLE])
+                .await?;
+            Err(io::Error::other("No acceptable auth methods"))
+        } else if handshake.methods.contains(&AUTH_NONE) {
+            // No auth required
+            stream.write_all(&[SOCKS_VERSION, AUTH_NONE]).await?;
+            Ok(None)
+        } else {
+            // No acceptable auth methods
+            stream
+                .write_all(&[SOCKS_VERSION, AUTH_NOT_ACCEPTABLE])
+                .await?;
+            Err(io::Error::other("No acceptable auth methods"))
+        }
+    }
+
+    // Check a username and password with the authenticator. Success is
+    // audited and clears the client's failures on the ban list.
+    async fn verify_credentials(
+        &self,
+        info: &ConnectionInfo,
+        auth: &UserPassAuth,
+    ) -> Option<Identity> {
+        let identity = match &self.authenticator {
+            Some(authenticator) => authenticator
+                .authenticate(&auth.username, &auth.password)
+                .await
+                .unwrap_or_else(|e| {
+                    error!("Authentication backend error: {}", e);
+                    None
+                }),
+            // No credentials specified, but auth required - deny all
+            None => None,
+        }?;
+
+        if let (Some(bans), Some(addr)) = (&self.ban_list, info.peer.addr) {
+            bans.record_success(addr.ip());
+        }
+        debug!("Authentication successful for user: {}", identity.username);
+        self.audit(
+            info,
+            AuditAction::AuthSuccess,
+            Some(&identity.username),
+            None,
+        )
+        .await;
+        Some(identity)
+    }
+
+    // Record a failed authentication attempt: audit it, count it towards a
+    // ban and wait out the configured delay
+    async fn auth_failed(&self, info: &ConnectionInfo, username: Option<&str>, reason: &str) {
+        self.audit(info, AuditAction::AuthFailure, username, Some(reason))
+            .await;
+        self.stats.auth_failure();
+        if let (Some(bans), Some(addr)) = (&self.ban_list, info.peer.addr) {
+            bans.record_failure(addr.ip());
+        }
+        if !self.auth_limits.failure_delay.is_zero() {
+            tokio::time::sleep(self.auth_limits.failure_delay).await;
+        }
+    }
+
+    // Fail the connection's only authentication attempt: record it, answer
+    // with the failure status and stop reading from the client
+    async fn reject_auth<S>(
+        &self,
+        stream: &mut S,
+        info: &ConnectionInfo,
+        username: Option<&str>,
+        reason: &str,
+    ) -> io::Error
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        self.auth_failed(info, username, reason).await;
+        if let Err(e) = stream.write_all(&[AUTH_VERSION, AUTH_FAILURE]).await {
+            return e;
+        }
+        let _ = stream.shutdown().await;
+        io::Error::new(io::ErrorKind::PermissionDenied, "Authentication failed")
+    }
+
+    // Refuse a request with REP_CONNECTION_NOT_ALLOWED
+    async fn deny<S: AsyncWrite + Unpin>(
+        &self,
+        client: ConnectClient<S>,
+        addr: SocksAddr,
+        info: &ConnectionInfo,
+        reason: &str,
+    ) -> io::Result<()> {
+        self.audit(info, AuditAction::Denied, None, Some(reason))
+            .await;
+        self.stats.reply(REP_CONNECTION_NOT_ALLOWED);
+        client
+            .refuse(Reply::new(REP_CONNECTION_NOT_ALLOWED, addr))
+            .await?;
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, reason))
+    }
+
+    // Refuse a request the server could not serve because of its own
+    // failure, such as an unreachable store
+    async fn fail<S: AsyncWrite + Unpin>(
+        &self,
+        client: ConnectClient<S>,
+        addr: SocksAddr,
+        context: &str,
+        error: io::Error,
+    ) -> io::Result<()> {
+        warn!("{}: {}", context, error);
+        self.stats.reply(REP_GENERAL_FAILURE);
+        client.refuse(Reply::new(REP_GENERAL_FAILURE, addr)).await?;
+        Err(io::Error::new(
+            error.kind(),
+            format!("{}: {}", context, error),
+        ))
+    }
+
+    // Hand an event to the audit sink, if any. `username` defaults to the
+    // connection's identity. A failed write is logged and otherwise ignored.
+    async fn audit(
+        &self,
+        info: &ConnectionInfo,
+        action: AuditAction,
+        username: Option<&str>,
+        detail: Option<&str>,
+    ) {
+        let Some(sink) = &self.audit else {
+            return;
+        };
+        let username = username.or(info.identity.as_ref().map(|id| id.username.as_str()));
+        let event = AuditEvent {
+            time: SystemTime::now(),
+            connection: info.id,
+            peer: info.peer.addr,
+            username: username.map(str::to_string),
+            action,
+            target: info.target.as_ref().map(|target| target.to_string()),
+            detail: detail.map(str::to_string),
+        };
+        if let Err(e) = sink.record(&event).await {
+            error!("Failed to write audit record: {}", e);
+        }
+    }
+
+    // Report the outcome of dialing to the client and relay on success
+    async fn reply_and_relay<S>(
+        &self,
+        client: ConnectClient<S>,
+        addr: SocksAddr,
+        outbound: io::Result<Outbound>,
+        options: &RelayOptions,
+        info: &mut ConnectionInfo,
+        policy: &UserPolicy,
+    ) -> io::Result<(u64, u64)>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        match outbound {
+            Ok(mut outbound) => {
+                // Send success reply
+                let bind_addr = match outbound.bound_addr {
+                    Some(SocketAddr::V4(addr)) => SocksAddr::Ipv4(*addr.ip(), addr.port()),
+                    Some(SocketAddr::V6(addr)) => SocksAddr::Ipv6(*addr.ip(), addr.port()),
+                    None => addr.clone(), // Fallback to original address
+                };
+
+                let reply = Reply::new(REP_SUCCEEDED, bind_addr);
+                let relayed = match client {
+                    ConnectClient::Socks(mut stream) => {
+                        reply.write_to(&mut stream).await?;
+                        #[cfg(feature = "chaos")]
+                        chaos::inject(FaultPoint::Relay).await?;
+                        self.sniff(
+                            &mut stream,
+                            &mut outbound.stream,
+                            &addr,
+                            info,
+                            policy,
+                            options,
+                        )
+                        .await?;
+                        relay_outbound(&mut stream, outbound.stream, options).await
+                    }
+                    ConnectClient::Tunnel(tunnel) => {
+                        let mut stream = tunnel.open(reply).await?;
+                        self.sniff(
+                            &mut stream,
+                            &mut outbound.stream,
+                            &addr,
+                            info,
+                            policy,
+                            options,
+                        )
+                        .await?;
+                        relay_outbound(&mut stream, outbound.stream, options).await
+                    }
+                };
+
+                // Proxy data between client and server
+                match relayed {
+                    Ok((bytes_to_server, bytes_to_client)) => {
+                        debug!(
+                            "Connection closed: {} bytes sent, {} bytes received",
+                            bytes_to_server, bytes_to_client
+                        );
+                        Ok((bytes_to_server, bytes_to_client))
+                    }
+                    Err(e) => {
+                        error!("Error during data transfer: {}", e);
+                        Err(e)
+                    }
                 }
             }
+            Err(e) => {
+                let reply_code = reply_code_for(&e);
+                client.refuse(Reply::new(reply_code, addr)).await?;
+                Err(e)
+            }
+        }
+    }
+
+    // With sniffing enabled, read the host name the client is after from its
+    // first bytes, check it against the ACLs and pass the bytes on
+    async fn sniff<C>(
+        &self,
+        client: &mut C,
+        outbound: &mut Box<dyn ProxyStream>,
+        addr: &SocksAddr,
+        info: &mut ConnectionInfo,
+        policy: &UserPolicy,
+        options: &RelayOptions,
+    ) -> io::Result<()>
+    where
+        C: tokio::io::AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(sniff) = &self.sniff else {
+            return Ok(());
+        };
+        let counters = options.counters.as_deref();
+        let (name, data) = sniff::sniff(client, outbound, sniff.timeout, counters).await?;
+
+        if let Some(name) = name {
+            info!("Connection {} to {} is for {}", info.id, addr, name);
+            let sniffed = SocksAddr::Domain(name.name().to_string(), addr.port());
+            let allowed = self.acl.as_ref().is_none_or(|acl| acl.allows(&sniffed))
+                && policy.acl.as_ref().is_none_or(|acl| acl.allows(&sniffed));
+            let reason = format!("{} denied by ACL", name);
+            info.server_name = Some(name);
+            if !allowed {
+                self.audit(info, AuditAction::Denied, None, Some(&reason))
+                    .await;
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+            }
         }
-        Err(e) => {
-            let reply_code = match e.kind() {
-                io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
-                io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
-                io::ErrorKind::HostUnreachable => REP_HOST_UNREACHABLE,
-                _ => REP_NETWORK_UNREACHABLE,
-            };
 
-            let reply = Reply::new(reply_code, addr);
-            reply.write_to(&mut client).await?;
-            Err(e)
+        outbound.write_all(&data).await?;
+        if let Some(counters) = counters {
+            counters.add(Direction::Upload, data.len() as u64);
         }
+        Ok(())
     }
 }
+
+/// Check that `addr` has the `host:port` form `run` binds to.
+pub(crate) fn validate_bind_addr(addr: &str) -> io::Result<()> {
+    let valid = addr
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if !valid {
+        return Err(invalid(format!(
+            "Invalid bind address {:?}, expected host:port",
+            addr
+        )));
+    }
+    Ok(())
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+// Read one handshake message within its phase's deadline
+pub(crate) async fn within<T>(
+    limit: Duration,
+    phase: &str,
+    read: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    tokio::time::timeout(limit, read).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Client took too long to send its {}", phase),
+        )
+    })?
+}
+
+// Wait for every task, returning the first error
+pub(crate) async fn join_all(mut tasks: JoinSet<io::Result<()>>) -> io::Result<()> {
+    while let Some(result) = tasks.join_next().await {
+        result.map_err(io::Error::other)??;
+    }
+    Ok(())
+}
+
+/// Bind `addr` once, or `acceptors` times with `SO_REUSEPORT` so the kernel
+/// spreads incoming connections across the accept loops.
+pub(crate) async fn bind_listeners(addr: &str, acceptors: usize) -> io::Result<Vec<TcpListener>> {
+    if acceptors <= 1 {
+        return Ok(vec![TcpListener::bind(addr).await?]);
+    }
+
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid bind address"))?;
+    let first = reuseport_listener(addr)?;
+    // With port 0 the remaining listeners must share the port the first got
+    let addr = first.local_addr()?;
+
+    let mut listeners = vec![first];
+    for _ in 1..acceptors {
+        listeners.push(reuseport_listener(addr)?);
+    }
+    Ok(listeners)
+}
+
+#[cfg(unix)]
+fn reuseport_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+#[cfg(not(unix))]
+fn reuseport_listener(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Multiple acceptors need SO_REUSEPORT, which this platform lacks",
+    ))
+}
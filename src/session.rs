@@ -0,0 +1,69 @@
+// Per-user concurrent session accounting.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Counts the relays each username currently holds.
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    active: Mutex<HashMap<String, usize>>,
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new session for `username` unless that would exceed `limit`.
+    /// The session ends when the returned guard is dropped.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        username: &str,
+        limit: Option<usize>,
+    ) -> Option<SessionGuard> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.get(username).copied().unwrap_or(0);
+        if limit.is_some_and(|limit| count >= limit) {
+            return None;
+        }
+        active.insert(username.to_string(), count + 1);
+
+        Some(SessionGuard {
+            tracker: Arc::clone(self),
+            username: username.to_string(),
+        })
+    }
+
+    /// Number of sessions `username` currently holds.
+    pub fn active(&self, username: &str) -> usize {
+        self.active
+            .lock()
+            .unwrap()
+            .get(username)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn release(&self, username: &str) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(username) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(username);
+            }
+        }
+    }
+}
+
+/// Holds one of a user's session slots for as long as it lives.
+#[derive(Debug)]
+pub struct SessionGuard {
+    tracker: Arc<SessionTracker>,
+    username: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.tracker.release(&self.username);
+    }
+}
@@ -0,0 +1,303 @@
+// Passive detection of the host name a relayed connection is for.
+//
+// Clients often CONNECT to bare IP addresses, which tells the proxy little
+// about what they are reaching. With sniffing enabled, the server reads the
+// client's first bytes after the CONNECT reply and looks for the server name
+// in a TLS ClientHello (SNI) or in the Host header of an HTTP/1 request.
+// Nothing is decrypted or altered: the bytes go on to the destination as they
+// are once the name has been checked. If the destination speaks first (SMTP,
+// FTP, ...) or the client stays silent until the sniff timeout, the relay
+// starts without a name.
+
+use std::fmt;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use crate::relay::{Direction, RelayCounters};
+
+// Stop looking once this much client data is buffered without a verdict
+const MAX_SNIFF_BYTES: usize = 16 * 1024;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"TRACE ",
+    b"CONNECT ",
+];
+
+/// Settings for host name sniffing (`ServerOptions::sniff`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SniffOptions {
+    /// How long to wait for the client's first bytes. Only clients that
+    /// stay silent while their destination is silent too wait this long.
+    pub timeout: Duration,
+}
+
+impl Default for SniffOptions {
+    fn default() -> Self {
+        SniffOptions {
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A host name found at the start of a client's data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerName {
+    /// The server_name extension of a TLS ClientHello.
+    Tls(String),
+    /// The Host header of an HTTP/1 request, without the port.
+    Http(String),
+}
+
+impl ServerName {
+    pub fn name(&self) -> &str {
+        match self {
+            ServerName::Tls(name) | ServerName::Http(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for ServerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerName::Tls(name) => write!(f, "SNI {}", name),
+            ServerName::Http(name) => write!(f, "Host {}", name),
+        }
+    }
+}
+
+/// The host name in `data`, the first bytes a client sent, if they start a
+/// TLS ClientHello with SNI or an HTTP/1 request with a Host header.
+pub fn server_name(data: &[u8]) -> Option<ServerName> {
+    match scan(data) {
+        Scan::Found(name) => Some(name),
+        Scan::Incomplete | Scan::NotFound => None,
+    }
+}
+
+enum Scan {
+    Found(ServerName),
+    Incomplete,
+    NotFound,
+}
+
+// Read the client's first bytes until they reveal a host name, prove not to
+// have one, or the destination speaks first (which is passed on to the
+// client). Returns the name and the client bytes still to be forwarded.
+pub(crate) async fn sniff<C, S>(
+    client: &mut C,
+    server: &mut S,
+    timeout: Duration,
+    counters: Option<&RelayCounters>,
+) -> io::Result<(Option<ServerName>, Vec<u8>)>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + Unpin,
+{
+    let deadline = Instant::now() + timeout;
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let mut reply = [0u8; 4096];
+
+    loop {
+        tokio::select! {
+            read = client.read(&mut chunk) => {
+                let n = read?;
+                if n == 0 {
+                    break;
+                }
+                data.extend_from_slice(&chunk[..n]);
+                match scan(&data) {
+                    Scan::Found(name) => return Ok((Some(name), data)),
+                    Scan::Incomplete if data.len() < MAX_SNIFF_BYTES => {}
+                    Scan::Incomplete | Scan::NotFound => break,
+                }
+            }
+            read = server.read(&mut reply) => {
+                let n = read?;
+                if n > 0 {
+                    client.write_all(&reply[..n]).await?;
+                    if let Some(counters) = counters {
+                        counters.add(Direction::Download, n as u64);
+                    }
+                }
+                break;
+            }
+            _ = tokio::time::sleep_until(deadline) => break,
+        }
+    }
+    Ok((None, data))
+}
+
+fn scan(data: &[u8]) -> Scan {
+    match data.first() {
+        None => Scan::Incomplete,
+        Some(&CONTENT_TYPE_HANDSHAKE) => scan_tls(data),
+        Some(_) => scan_http(data),
+    }
+}
+
+// Reassemble the first handshake message from its records and read the SNI
+// from it if it is a ClientHello
+fn scan_tls(mut data: &[u8]) -> Scan {
+    let mut handshake = Vec::new();
+    loop {
+        if let Some(&kind) = handshake.first()
+            && kind != HANDSHAKE_CLIENT_HELLO
+        {
+            return Scan::NotFound;
+        }
+        if handshake.len() >= 4 {
+            let len = 4
+                + (usize::from(handshake[1]) << 16
+                    | usize::from(handshake[2]) << 8
+                    | usize::from(handshake[3]));
+            if handshake.len() >= len {
+                return client_hello_sni(&handshake[4..len])
+                    .map_or(Scan::NotFound, |name| Scan::Found(ServerName::Tls(name)));
+            }
+        }
+
+        // Record header: content type, legacy version, length
+        if data.len() < 5 {
+            return Scan::Incomplete;
+        }
+        if data[0] != CONTENT_TYPE_HANDSHAKE || data[1] != 3 {
+            return Scan::NotFound;
+        }
+        let len = usize::from(u16::from_be_bytes([data[3], data[4]]));
+        let Some(fragment) = data.get(5..5 + len) else {
+            return Scan::Incomplete;
+        };
+        handshake.extend_from_slice(fragment);
+        data = &data[5 + len..];
+    }
+}
+
+fn client_hello_sni(body: &[u8]) -> Option<String> {
+    let mut hello = Cursor(body);
+    hello.take(2 + 32)?; // legacy version, random
+    hello.vec8()?; // session id
+    hello.vec16()?; // cipher suites
+    hello.vec8()?; // compression methods
+    let mut extensions = Cursor(hello.vec16()?);
+
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Cursor(Cursor(data).vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return host_name(std::str::from_utf8(name).ok()?);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+// Find the Host header in the head of an HTTP/1 request
+fn scan_http(data: &[u8]) -> Scan {
+    let method = HTTP_METHODS.iter().find(|method| {
+        let n = method.len().min(data.len());
+        method[..n] == data[..n]
+    });
+    match method {
+        None => return Scan::NotFound,
+        Some(method) if data.len() < method.len() => return Scan::Incomplete,
+        Some(_) => {}
+    }
+
+    // Complete lines only, past the request line
+    let Some(end) = data.iter().rposition(|&b| b == b'\n') else {
+        return Scan::Incomplete;
+    };
+    for line in data[..end].split(|&b| b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return Scan::NotFound;
+        }
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            continue;
+        };
+        if line[..colon].eq_ignore_ascii_case(b"host") {
+            let value = std::str::from_utf8(&line[colon + 1..]).ok();
+            return value
+                .and_then(|value| host_name(strip_port(value.trim())))
+                .map_or(Scan::NotFound, |name| Scan::Found(ServerName::Http(name)));
+        }
+    }
+    Scan::Incomplete
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(bracketed) = host.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or(bracketed);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+// A plausible host name, lowercased. IP literals and anything else are
+// ignored rather than logged or matched.
+fn host_name(name: &str) -> Option<String> {
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && name.parse::<std::net::IpAddr>().is_err()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'));
+    valid.then(|| name.to_ascii_lowercase())
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.take(usize::from(len))
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(usize::from(len))
+    }
+}
@@ -0,0 +1,57 @@
+// TCP socket tuning for accepted client sockets and outbound connections.
+
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm so small writes (interactive protocols such
+    /// as SSH) are sent immediately.
+    pub nodelay: bool,
+    /// Idle time before the first keepalive probe; keepalive is off when
+    /// unset.
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes once they have started.
+    pub keepalive_interval: Option<Duration>,
+    /// `SO_RCVBUF` size in bytes; the kernel default when unset.
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_SNDBUF` size in bytes; the kernel default when unset.
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            keepalive_interval: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Apply the options to a socket. Buffer sizes affect the TCP window
+    /// scale only when set before the connection is established.
+    pub fn apply(&self, socket: SockRef<'_>) -> io::Result<()> {
+        socket.set_tcp_nodelay(self.nodelay)?;
+
+        if let Some(time) = self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,620 @@
+// SOCKS6 (draft-olteanu-intarea-socks-6-11), for interoperability testing.
+//
+// With `ServerOptions::socks6` set, a connection whose first byte is 6 is
+// served as SOCKS6 on the same listener. The client sends its request in
+// one go: the command and destination, a list of options (authentication
+// methods it supports and, with them, its credentials) and optionally the
+// first bytes for the destination. The server answers with an
+// authentication reply, then an operation reply once it has acted on the
+// request. Requests go through the same pipeline as SOCKS5 CONNECTs, with
+// username/password as the only method and CONNECT as the only command.
+// Other options (stack options, sessions, idempotence tokens) are parsed
+// and ignored, which the draft allows.
+//
+// Every option is a 2-byte kind and a 2-byte length covering the whole
+// option, padded to a multiple of 4 bytes. Addresses come after the port,
+// and domain names are padded the same way.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::dialer::ProxyStream;
+use crate::protocol::{
+    ATYP_DOMAIN, ATYP_IPV4, ATYP_IPV6, AUTH_PASSWORD, AUTH_VERSION, CMD_CONNECT, Reply, SocksAddr,
+    UserPassAuth,
+};
+use crate::server::Tunnel;
+
+pub const SOCKS6_VERSION: u8 = 6;
+
+pub const CMD_NOOP: u8 = 0;
+
+/// Stack options (IP TOS, TFO, MPTCP, ...), kept as `Socks6Option::Other`.
+pub const OPT_STACK: u16 = 1;
+pub const OPT_AUTH_METHOD_ADVERTISEMENT: u16 = 2;
+pub const OPT_AUTH_METHOD_SELECTION: u16 = 3;
+pub const OPT_AUTH_DATA: u16 = 4;
+
+/// Authentication reply types.
+pub const AUTH_REPLY_SUCCESS: u8 = 0;
+pub const AUTH_REPLY_FAILURE: u8 = 1;
+
+/// Most initial data a request may carry.
+pub const MAX_INITIAL_DATA: usize = 16384;
+
+// Kind and length of an option
+const OPTION_HEADER_LEN: usize = 4;
+
+/// An option other than the authentication method advertisement, which
+/// `Socks6Request` holds in fields of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Socks6Option {
+    /// The method the server chose, in authentication replies.
+    AuthMethodSelection(u8),
+    /// Data for an authentication method; for username/password, an
+    /// RFC 1929 request.
+    AuthData { method: u8, data: Vec<u8> },
+    /// Any other option, with its data as received, padding included.
+    Other { kind: u16, data: Vec<u8> },
+}
+
+/// A SOCKS6 request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks6Request {
+    pub command: u8,
+    pub addr: SocksAddr,
+    /// Authentication methods advertised besides "no authentication",
+    /// which is always implied.
+    pub methods: Vec<u8>,
+    /// Bytes for the destination sent along with the request, at most
+    /// `MAX_INITIAL_DATA`.
+    pub initial_data: Vec<u8>,
+    pub options: Vec<Socks6Option>,
+}
+
+/// The server's answer to the authentication in a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks6AuthReply {
+    pub success: bool,
+    pub options: Vec<Socks6Option>,
+}
+
+/// The server's answer to the command in a request, with the address it
+/// connected from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks6Reply {
+    pub reply: u8,
+    pub addr: SocksAddr,
+    pub options: Vec<Socks6Option>,
+}
+
+impl Socks6Request {
+    pub fn connect(addr: SocksAddr) -> Self {
+        Socks6Request {
+            command: CMD_CONNECT,
+            addr,
+            methods: Vec::new(),
+            initial_data: Vec::new(),
+            options: Vec::new(),
+        }
+    }
+
+    /// Advertise username/password and send `auth` with the request.
+    pub fn with_credentials(mut self, auth: &UserPassAuth) -> io::Result<Self> {
+        let mut data = vec![AUTH_VERSION];
+        for field in [&auth.username, &auth.password] {
+            if field.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Username and password must be at most 255 bytes",
+                ));
+            }
+            data.push(field.len() as u8);
+            data.extend_from_slice(field.as_bytes());
+        }
+        if !self.methods.contains(&AUTH_PASSWORD) {
+            self.methods.push(AUTH_PASSWORD);
+        }
+        self.options.push(Socks6Option::AuthData {
+            method: AUTH_PASSWORD,
+            data,
+        });
+        Ok(self)
+    }
+
+    /// Send `data` to the destination along with the request.
+    pub fn with_initial_data(mut self, data: Vec<u8>) -> Self {
+        self.initial_data = data;
+        self
+    }
+
+    /// The username and password sent in an authentication data option.
+    pub fn credentials(&self) -> Option<UserPassAuth> {
+        self.options.iter().find_map(|option| match option {
+            Socks6Option::AuthData { method, data } if *method == AUTH_PASSWORD => user_pass(data),
+            _ => None,
+        })
+    }
+
+    pub async fn read_from<R>(r: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        if r.read_u8().await? != SOCKS6_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported SOCKS version",
+            ));
+        }
+        Self::read_after_version(r).await
+    }
+
+    // The request once its version byte has been read
+    pub(crate) async fn read_after_version<R>(r: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let command = r.read_u8().await?;
+        let options_len = r.read_u16().await? as usize;
+        let addr = read_addr(r).await?;
+        let mut options = vec![0u8; options_len];
+        r.read_exact(&mut options).await?;
+
+        let mut methods = Vec::new();
+        let mut initial_data_len = 0;
+        let mut others = Vec::new();
+        for (kind, data) in parse_options(&options)? {
+            match kind {
+                OPT_AUTH_METHOD_ADVERTISEMENT => {
+                    if data.len() < 2 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Truncated authentication method advertisement",
+                        ));
+                    }
+                    initial_data_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+                    // Zeros are padding; "no authentication" is implied
+                    methods.extend(data[2..].iter().filter(|&&method| method != 0));
+                }
+                kind => others.push(option_from(kind, data)),
+            }
+        }
+        if initial_data_len > MAX_INITIAL_DATA {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Initial data longer than 16 KiB",
+            ));
+        }
+        let mut initial_data = vec![0u8; initial_data_len];
+        r.read_exact(&mut initial_data).await?;
+
+        Ok(Socks6Request {
+            command,
+            addr,
+            methods,
+            initial_data,
+            options: others,
+        })
+    }
+
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if self.initial_data.len() > MAX_INITIAL_DATA {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Initial data longer than 16 KiB",
+            ));
+        }
+        let mut options = Vec::new();
+        if !self.methods.is_empty() || !self.initial_data.is_empty() {
+            let mut data = (self.initial_data.len() as u16).to_be_bytes().to_vec();
+            data.extend_from_slice(&self.methods);
+            write_option(&mut options, OPT_AUTH_METHOD_ADVERTISEMENT, &data)?;
+        }
+        encode_options(&mut options, &self.options)?;
+
+        let mut buf = vec![SOCKS6_VERSION, self.command];
+        buf.extend_from_slice(&options_len(&options)?.to_be_bytes());
+        write_addr(&mut buf, &self.addr)?;
+        buf.extend_from_slice(&options);
+        buf.extend_from_slice(&self.initial_data);
+        w.write_all(&buf).await?;
+        w.flush().await
+    }
+}
+
+impl Socks6AuthReply {
+    pub async fn read_from<R>(r: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        read_version(r).await?;
+        let success = match r.read_u8().await? {
+            AUTH_REPLY_SUCCESS => true,
+            AUTH_REPLY_FAILURE => false,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unknown authentication reply type",
+                ));
+            }
+        };
+        let options = read_options(r).await?;
+        Ok(Socks6AuthReply { success, options })
+    }
+
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        w.write_all(&self.encode()?).await?;
+        w.flush().await
+    }
+
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut options = Vec::new();
+        encode_options(&mut options, &self.options)?;
+        let reply_type = if self.success {
+            AUTH_REPLY_SUCCESS
+        } else {
+            AUTH_REPLY_FAILURE
+        };
+        let mut buf = vec![SOCKS6_VERSION, reply_type];
+        buf.extend_from_slice(&options_len(&options)?.to_be_bytes());
+        buf.extend_from_slice(&options);
+        Ok(buf)
+    }
+}
+
+impl Socks6Reply {
+    pub async fn read_from<R>(r: &mut R) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        read_version(r).await?;
+        let reply = r.read_u8().await?;
+        let options_len = r.read_u16().await? as usize;
+        let addr = read_addr(r).await?;
+        let mut options = vec![0u8; options_len];
+        r.read_exact(&mut options).await?;
+        let options = parse_options(&options)?
+            .into_iter()
+            .map(|(kind, data)| option_from(kind, data))
+            .collect();
+        Ok(Socks6Reply {
+            reply,
+            addr,
+            options,
+        })
+    }
+
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        w.write_all(&self.encode()?).await?;
+        w.flush().await
+    }
+
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut options = Vec::new();
+        encode_options(&mut options, &self.options)?;
+        let mut buf = vec![SOCKS6_VERSION, self.reply];
+        buf.extend_from_slice(&options_len(&options)?.to_be_bytes());
+        write_addr(&mut buf, &self.addr)?;
+        buf.extend_from_slice(&options);
+        Ok(buf)
+    }
+}
+
+/// Send `request` on `stream`, a connection to a SOCKS6 server, and wait
+/// for its replies. Fails with `ErrorKind::PermissionDenied` if the server
+/// refuses the authentication; otherwise returns the operation reply,
+/// whatever its code. After a successful CONNECT, `stream` carries the
+/// destination's data.
+pub async fn request<S>(stream: &mut S, request: &Socks6Request) -> io::Result<Socks6Reply>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    request.write_to(stream).await?;
+    let auth = Socks6AuthReply::read_from(stream).await?;
+    if !auth.success {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS6 authentication failed",
+        ));
+    }
+    Socks6Reply::read_from(stream).await
+}
+
+async fn read_version<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<()> {
+    if r.read_u8().await? != SOCKS6_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported SOCKS version",
+        ));
+    }
+    Ok(())
+}
+
+// Port, padding, address type and address
+async fn read_addr<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<SocksAddr> {
+    let port = r.read_u16().await?;
+    let _padding = r.read_u8().await?;
+    match r.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            r.read_exact(&mut octets).await?;
+            Ok(SocksAddr::Ipv4(Ipv4Addr::from(octets), port))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            r.read_exact(&mut octets).await?;
+            Ok(SocksAddr::Ipv6(Ipv6Addr::from(octets), port))
+        }
+        ATYP_DOMAIN => {
+            let len = r.read_u8().await? as usize;
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Empty domain name",
+                ));
+            }
+            // The name and its padding
+            let mut domain = [0u8; 255 + 3];
+            r.read_exact(&mut domain[..len + padding(1 + len)]).await?;
+            let domain = String::from_utf8(domain[..len].to_vec())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid domain name"))?;
+            Ok(SocksAddr::Domain(domain, port))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unsupported address type",
+        )),
+    }
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: &SocksAddr) -> io::Result<()> {
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf.push(0);
+    match addr {
+        SocksAddr::Ipv4(ip, _) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        SocksAddr::Ipv6(ip, _) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+        SocksAddr::Domain(domain, _) => {
+            if domain.is_empty() || domain.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Domain must be 1 to 255 bytes",
+                ));
+            }
+            buf.push(ATYP_DOMAIN);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain.as_bytes());
+            buf.resize(buf.len() + padding(1 + domain.len()), 0);
+        }
+    }
+    Ok(())
+}
+
+async fn read_options<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Vec<Socks6Option>> {
+    let len = r.read_u16().await? as usize;
+    let mut options = vec![0u8; len];
+    r.read_exact(&mut options).await?;
+    Ok(parse_options(&options)?
+        .into_iter()
+        .map(|(kind, data)| option_from(kind, data))
+        .collect())
+}
+
+// The kind and data of each option in `buf`
+fn parse_options(mut buf: &[u8]) -> io::Result<Vec<(u16, &[u8])>> {
+    let mut options = Vec::new();
+    while !buf.is_empty() {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed SOCKS6 option");
+        if buf.len() < OPTION_HEADER_LEN {
+            return Err(invalid());
+        }
+        let kind = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if len < OPTION_HEADER_LEN || !len.is_multiple_of(4) || len > buf.len() {
+            return Err(invalid());
+        }
+        options.push((kind, &buf[OPTION_HEADER_LEN..len]));
+        buf = &buf[len..];
+    }
+    Ok(options)
+}
+
+fn option_from(kind: u16, data: &[u8]) -> Socks6Option {
+    match kind {
+        OPT_AUTH_METHOD_SELECTION if !data.is_empty() => Socks6Option::AuthMethodSelection(data[0]),
+        OPT_AUTH_DATA if !data.is_empty() => Socks6Option::AuthData {
+            method: data[0],
+            data: data[1..].to_vec(),
+        },
+        kind => Socks6Option::Other {
+            kind,
+            data: data.to_vec(),
+        },
+    }
+}
+
+fn encode_options(buf: &mut Vec<u8>, options: &[Socks6Option]) -> io::Result<()> {
+    for option in options {
+        match option {
+            Socks6Option::AuthMethodSelection(method) => {
+                write_option(buf, OPT_AUTH_METHOD_SELECTION, &[*method])?
+            }
+            Socks6Option::AuthData { method, data } => {
+                let mut option = vec![*method];
+                option.extend_from_slice(data);
+                write_option(buf, OPT_AUTH_DATA, &option)?
+            }
+            Socks6Option::Other { kind, data } => write_option(buf, *kind, data)?,
+        }
+    }
+    Ok(())
+}
+
+fn write_option(buf: &mut Vec<u8>, kind: u16, data: &[u8]) -> io::Result<()> {
+    let len = OPTION_HEADER_LEN + data.len();
+    let padded = len + padding(len);
+    let padded_len = u16::try_from(padded)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "SOCKS6 option too long"))?;
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&padded_len.to_be_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + padding(len), 0);
+    Ok(())
+}
+
+fn options_len(options: &[u8]) -> io::Result<u16> {
+    u16::try_from(options.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "SOCKS6 options too long"))
+}
+
+// Zero bytes bringing `len` to a multiple of 4
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+// An RFC 1929 request; trailing padding is ignored
+fn user_pass(data: &[u8]) -> Option<UserPassAuth> {
+    let (&version, rest) = data.split_first()?;
+    if version != AUTH_VERSION {
+        return None;
+    }
+    let (&ulen, rest) = rest.split_first()?;
+    let (username, rest) = rest.split_at_checked(ulen as usize)?;
+    let (&plen, rest) = rest.split_first()?;
+    let (password, _) = rest.split_at_checked(plen as usize)?;
+    Some(UserPassAuth::new(
+        String::from_utf8(username.to_vec()).ok()?,
+        String::from_utf8(password.to_vec()).ok()?,
+    ))
+}
+
+// A SOCKS6 request waiting for the pipeline's answer. Once the pipeline
+// refuses or opens the request, authentication has passed, so both replies
+// go out together.
+pub(crate) struct Socks6Tunnel<S> {
+    stream: S,
+    request: Socks6Request,
+}
+
+impl<S> Socks6Tunnel<S> {
+    pub(crate) fn new(stream: S, request: Socks6Request) -> Self {
+        Socks6Tunnel { stream, request }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Socks6Tunnel<S> {
+    async fn reply(&mut self, reply: Reply) -> io::Result<()> {
+        let auth = Socks6AuthReply {
+            success: true,
+            options: Vec::new(),
+        };
+        let mut buf = auth.encode()?;
+        let reply = Socks6Reply {
+            reply: reply.reply,
+            addr: reply.addr,
+            options: Vec::new(),
+        };
+        buf.extend_from_slice(&reply.encode()?);
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await
+    }
+}
+
+#[async_trait]
+impl<S> Tunnel for Socks6Tunnel<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn credentials(&self) -> Option<UserPassAuth> {
+        self.request.credentials()
+    }
+
+    async fn reject_auth(mut self: Box<Self>) {
+        // Point the client at username/password if it can do it
+        let options = if self.request.methods.contains(&AUTH_PASSWORD) {
+            vec![Socks6Option::AuthMethodSelection(AUTH_PASSWORD)]
+        } else {
+            Vec::new()
+        };
+        let reply = Socks6AuthReply {
+            success: false,
+            options,
+        };
+        let _ = reply.write_to(&mut self.stream).await;
+    }
+
+    async fn refuse(mut self: Box<Self>, reply: Reply) {
+        let _ = self.reply(reply).await;
+    }
+
+    async fn open(mut self: Box<Self>, reply: Reply) -> io::Result<Box<dyn ProxyStream>> {
+        self.reply(reply).await?;
+        let Socks6Tunnel { stream, request } = *self;
+        Ok(Box::new(InitialData {
+            data: request.initial_data,
+            pos: 0,
+            stream,
+        }))
+    }
+}
+
+// The client stream, read back starting with the request's initial data
+struct InitialData<S> {
+    data: Vec<u8>,
+    pos: usize,
+    stream: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for InitialData<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.data.len() {
+            let n = buf.remaining().min(this.data.len() - this.pos);
+            buf.put_slice(&this.data[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for InitialData<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,130 @@
+// Zero-copy relay between two TCP sockets with splice(2).
+//
+// Each direction moves data socket -> pipe -> socket inside the kernel, so
+// payload bytes never reach userspace. Rate limits still apply: a chunk is
+// accounted for after it lands in the pipe and before it is forwarded.
+
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+
+use socket2::SockRef;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+use crate::ratelimit::RateLimiter;
+use crate::relay::{Direction, Progress, RelayLimits};
+
+// Default Linux pipe capacity
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Relay between two TCP sockets until both directions have closed,
+/// returning the bytes sent to `server` and the bytes sent back to `client`.
+pub(crate) async fn relay(
+    client: &TcpStream,
+    server: &TcpStream,
+    limits: &RelayLimits,
+    progress: &Progress,
+) -> io::Result<(u64, u64)> {
+    tokio::try_join!(
+        splice_one(client, server, &limits.upload, progress, Direction::Upload),
+        splice_one(
+            server,
+            client,
+            &limits.download,
+            progress,
+            Direction::Download
+        ),
+    )
+}
+
+async fn splice_one(
+    src: &TcpStream,
+    dst: &TcpStream,
+    limiters: &[Arc<RateLimiter>],
+    progress: &Progress,
+    direction: Direction,
+) -> io::Result<u64> {
+    let pipe = Pipe::new()?;
+    let mut total = 0u64;
+
+    loop {
+        // The pipe is empty here, so EAGAIN can only mean the socket has no data
+        let n = loop {
+            src.readable().await?;
+            match src.try_io(Interest::READABLE, || {
+                splice(src.as_raw_fd(), pipe.write.as_raw_fd(), PIPE_SIZE)
+            }) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if n == 0 {
+            // Propagate the half-close so the peer sees EOF
+            SockRef::from(dst).shutdown(Shutdown::Write)?;
+            return Ok(total);
+        }
+        progress.touch();
+
+        for limiter in limiters {
+            limiter.consume(n).await;
+        }
+
+        let mut remaining = n;
+        while remaining > 0 {
+            dst.writable().await?;
+            match dst.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), dst.as_raw_fd(), remaining)
+            }) {
+                Ok(written) => remaining -= written,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total += n as u64;
+        progress.record(direction, n);
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both descriptors are open for the duration of the call and
+    // null offsets are valid for sockets and pipes.
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both descriptors are open and owned by us.
+        unsafe {
+            Ok(Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
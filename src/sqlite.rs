@@ -0,0 +1,453 @@
+// SQLite-backed credential and policy store.
+//
+// Keeps users (with Argon2 password hashes), their policy name, data quota and
+// ACL assignments in a single database file, so small deployments get
+// persistent accounts without running an external service.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use argon2::Argon2;
+use argon2::password_hash::phc::PasswordHash;
+use argon2::password_hash::{PasswordHasher, PasswordVerifier};
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::accounting::{AccountingStore, Usage};
+use crate::auth::{Authenticator, Identity};
+use crate::quota::QuotaStore;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        username      TEXT PRIMARY KEY NOT NULL,
+        password_hash TEXT NOT NULL,
+        policy        TEXT,
+        quota_bytes   INTEGER,
+        enabled       INTEGER NOT NULL DEFAULT 1
+    );
+    CREATE TABLE IF NOT EXISTS acl_assignments (
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+        acl      TEXT NOT NULL,
+        PRIMARY KEY (username, acl)
+    );
+    CREATE TABLE IF NOT EXISTS usage (
+        username TEXT PRIMARY KEY NOT NULL,
+        bytes    INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS accounting (
+        username TEXT PRIMARY KEY NOT NULL,
+        upload   INTEGER NOT NULL DEFAULT 0,
+        download INTEGER NOT NULL DEFAULT 0
+    );
+";
+
+// Verified against when a user does not exist, so unknown and known names
+// take the same time to reject
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    Argon2::default()
+        .hash_password(b"charon-dummy-password")
+        .expect("hashing a fixed password succeeds")
+        .to_string()
+});
+
+/// A user record as stored in the database (without the password hash).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserRecord {
+    pub username: String,
+    pub policy: Option<String>,
+    pub quota_bytes: Option<u64>,
+    pub enabled: bool,
+}
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path` and make sure the schema exists.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::init(Connection::open(path).map_err(sqlite_error)?)
+    }
+
+    /// Create a store backed by a private in-memory database.
+    pub fn open_in_memory() -> io::Result<Self> {
+        Self::init(Connection::open_in_memory().map_err(sqlite_error)?)
+    }
+
+    fn init(conn: Connection) -> io::Result<Self> {
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .map_err(sqlite_error)?;
+        conn.execute_batch(SCHEMA).map_err(sqlite_error)?;
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Run a blocking closure against the connection off the async runtime
+    async fn with_conn<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> io::Result<T> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || {
+            let conn = conn
+                .lock()
+                .map_err(|_| io::Error::other("SQLite connection poisoned"))?;
+            f(&conn)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    /// Add a user, or replace the password and policy of an existing one.
+    pub async fn add_user(
+        &self,
+        username: &str,
+        password: &str,
+        policy: Option<&str>,
+    ) -> io::Result<()> {
+        let username = username.to_string();
+        let hash = hash_password(password).await?;
+        let policy = policy.map(str::to_string);
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO users (username, password_hash, policy) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(username) DO UPDATE SET password_hash = ?2, policy = ?3",
+                params![username, hash, policy],
+            )
+            .map_err(sqlite_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Remove a user along with their ACL assignments, quota usage and
+    /// accounting totals, so a user later added under the same name starts
+    /// afresh.
+    pub async fn remove_user(&self, username: &str) -> io::Result<bool> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction().map_err(sqlite_error)?;
+            for table in ["usage", "accounting"] {
+                tx.execute(
+                    &format!("DELETE FROM {} WHERE username = ?1", table),
+                    params![username],
+                )
+                .map_err(sqlite_error)?;
+            }
+            let n = tx
+                .execute("DELETE FROM users WHERE username = ?1", params![username])
+                .map_err(sqlite_error)?;
+            tx.commit().map_err(sqlite_error)?;
+            Ok(n > 0)
+        })
+        .await
+    }
+
+    pub async fn set_password(&self, username: &str, password: &str) -> io::Result<bool> {
+        let username = username.to_string();
+        let hash = hash_password(password).await?;
+        self.update_user(
+            "UPDATE users SET password_hash = ?2 WHERE username = ?1",
+            username,
+            hash,
+        )
+        .await
+    }
+
+    pub async fn set_enabled(&self, username: &str, enabled: bool) -> io::Result<bool> {
+        let username = username.to_string();
+        self.update_user(
+            "UPDATE users SET enabled = ?2 WHERE username = ?1",
+            username,
+            enabled,
+        )
+        .await
+    }
+
+    pub async fn set_policy(&self, username: &str, policy: Option<&str>) -> io::Result<bool> {
+        let username = username.to_string();
+        let policy = policy.map(str::to_string);
+        self.update_user(
+            "UPDATE users SET policy = ?2 WHERE username = ?1",
+            username,
+            policy,
+        )
+        .await
+    }
+
+    /// Set the user's data quota in bytes (`None` for unlimited).
+    pub async fn set_quota(&self, username: &str, quota_bytes: Option<u64>) -> io::Result<bool> {
+        let username = username.to_string();
+        let quota = quota_bytes.map(|q| q.min(i64::MAX as u64) as i64);
+        self.update_user(
+            "UPDATE users SET quota_bytes = ?2 WHERE username = ?1",
+            username,
+            quota,
+        )
+        .await
+    }
+
+    async fn update_user<V>(
+        &self,
+        sql: &'static str,
+        username: String,
+        value: V,
+    ) -> io::Result<bool>
+    where
+        V: rusqlite::ToSql + Send + 'static,
+    {
+        self.with_conn(move |conn| {
+            let n = conn
+                .execute(sql, params![username, value])
+                .map_err(sqlite_error)?;
+            Ok(n > 0)
+        })
+        .await
+    }
+
+    pub async fn user(&self, username: &str) -> io::Result<Option<UserRecord>> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT username, policy, quota_bytes, enabled FROM users WHERE username = ?1",
+                params![username],
+                |row| {
+                    Ok(UserRecord {
+                        username: row.get(0)?,
+                        policy: row.get(1)?,
+                        quota_bytes: row.get::<_, Option<i64>>(2)?.map(|q| q.max(0) as u64),
+                        enabled: row.get(3)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(sqlite_error)
+        })
+        .await
+    }
+
+    pub async fn quota(&self, username: &str) -> io::Result<Option<u64>> {
+        Ok(self.user(username).await?.and_then(|u| u.quota_bytes))
+    }
+
+    pub async fn assign_acl(&self, username: &str, acl: &str) -> io::Result<()> {
+        let username = username.to_string();
+        let acl = acl.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO acl_assignments (username, acl) VALUES (?1, ?2)",
+                params![username, acl],
+            )
+            .map_err(sqlite_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn unassign_acl(&self, username: &str, acl: &str) -> io::Result<bool> {
+        let username = username.to_string();
+        let acl = acl.to_string();
+        self.with_conn(move |conn| {
+            let n = conn
+                .execute(
+                    "DELETE FROM acl_assignments WHERE username = ?1 AND acl = ?2",
+                    params![username, acl],
+                )
+                .map_err(sqlite_error)?;
+            Ok(n > 0)
+        })
+        .await
+    }
+
+    /// Names of the ACLs assigned to a user, in name order.
+    pub async fn acls(&self, username: &str) -> io::Result<Vec<String>> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn
+                .prepare("SELECT acl FROM acl_assignments WHERE username = ?1 ORDER BY acl")
+                .map_err(sqlite_error)?;
+            let rows = stmt
+                .query_map(params![username], |row| row.get(0))
+                .map_err(sqlite_error)?;
+            rows.collect::<Result<Vec<String>, _>>()
+                .map_err(sqlite_error)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Authenticator for SqliteStore {
+    async fn authenticate(&self, username: &str, password: &str) -> io::Result<Option<Identity>> {
+        let user = username.to_string();
+        let row = self
+            .with_conn(move |conn| {
+                conn.query_row(
+                    "SELECT password_hash, policy FROM users WHERE username = ?1 AND enabled = 1",
+                    params![user],
+                    |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)),
+                )
+                .optional()
+                .map_err(sqlite_error)
+            })
+            .await?;
+
+        // Argon2 verification is deliberately expensive; keep it off the
+        // runtime. A missing user is checked against a dummy hash so the
+        // rejection takes as long as a wrong password would.
+        let (hash, policy) = row.unzip();
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || {
+            verify_password(&password, hash.as_deref().unwrap_or(&DUMMY_HASH))
+        })
+        .await
+        .map_err(io::Error::other)??;
+        let Some(policy) = policy.filter(|_| valid) else {
+            return Ok(None);
+        };
+
+        // ACL assignments are reported as the identity's groups
+        let groups = self.acls(username).await?;
+        Ok(Some(Identity {
+            username: username.to_string(),
+            groups,
+            policy,
+        }))
+    }
+}
+
+#[async_trait]
+impl QuotaStore for SqliteStore {
+    async fn usage(&self, username: &str) -> io::Result<u64> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            let bytes: Option<i64> = conn
+                .query_row(
+                    "SELECT bytes FROM usage WHERE username = ?1",
+                    params![username],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(sqlite_error)?;
+            Ok(bytes.unwrap_or(0).max(0) as u64)
+        })
+        .await
+    }
+
+    async fn add_usage(&self, username: &str, bytes: u64) -> io::Result<u64> {
+        let username = username.to_string();
+        let bytes = bytes.min(i64::MAX as u64) as i64;
+        self.with_conn(move |conn| {
+            let total: i64 = conn
+                .query_row(
+                    "INSERT INTO usage (username, bytes) VALUES (?1, ?2)
+                     ON CONFLICT(username) DO UPDATE SET bytes = bytes + ?2
+                     RETURNING bytes",
+                    params![username, bytes],
+                    |row| row.get(0),
+                )
+                .map_err(sqlite_error)?;
+            Ok(total.max(0) as u64)
+        })
+        .await
+    }
+
+    async fn reset(&self, username: &str) -> io::Result<()> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM usage WHERE username = ?1", params![username])
+                .map_err(sqlite_error)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn limit(&self, username: &str) -> io::Result<Option<u64>> {
+        self.quota(username).await
+    }
+}
+
+#[async_trait]
+impl AccountingStore for SqliteStore {
+    async fn load(&self) -> io::Result<HashMap<String, Usage>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn
+                .prepare("SELECT username, upload, download FROM accounting")
+                .map_err(sqlite_error)?;
+            let rows = stmt
+                .query_map([], |row| {
+                    let upload: i64 = row.get(1)?;
+                    let download: i64 = row.get(2)?;
+                    Ok((
+                        row.get(0)?,
+                        Usage {
+                            upload: upload.max(0) as u64,
+                            download: download.max(0) as u64,
+                        },
+                    ))
+                })
+                .map_err(sqlite_error)?;
+            rows.collect::<Result<_, _>>().map_err(sqlite_error)
+        })
+        .await
+    }
+
+    async fn save(&self, usage: &HashMap<String, Usage>) -> io::Result<()> {
+        let usage = usage.clone();
+        self.with_conn(move |conn| {
+            let tx = conn.unchecked_transaction().map_err(sqlite_error)?;
+            tx.execute("DELETE FROM accounting", [])
+                .map_err(sqlite_error)?;
+            {
+                let mut insert = tx
+                    .prepare(
+                        "INSERT INTO accounting (username, upload, download) VALUES (?1, ?2, ?3)",
+                    )
+                    .map_err(sqlite_error)?;
+                for (username, usage) in &usage {
+                    let upload = usage.upload.min(i64::MAX as u64) as i64;
+                    let download = usage.download.min(i64::MAX as u64) as i64;
+                    insert
+                        .execute(params![username, upload, download])
+                        .map_err(sqlite_error)?;
+                }
+            }
+            tx.commit().map_err(sqlite_error)
+        })
+        .await
+    }
+}
+
+// Argon2 is deliberately expensive, so hashing runs off the runtime too
+async fn hash_password(password: &str) -> io::Result<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        Argon2::default()
+            .hash_password(password.as_bytes())
+            .map(|hash| hash.to_string())
+            .map_err(|e| io::Error::other(format!("Password hashing failed: {}", e)))
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+fn verify_password(password: &str, hash: &str) -> io::Result<bool> {
+    let parsed = PasswordHash::new(hash).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bad password hash: {}", e),
+        )
+    })?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok())
+}
+
+fn sqlite_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(format!("SQLite error: {}", e))
+}
@@ -0,0 +1,95 @@
+// Server-wide counters, read through `Server::stats`.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::relay::RelayCounters;
+
+/// A point-in-time snapshot of a server's activity since it was created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerStats {
+    /// Connections handled, including ones that failed the handshake.
+    pub total_connections: u64,
+    pub active_connections: u64,
+    /// Bytes relayed to destinations, including relays still running.
+    pub bytes_uploaded: u64,
+    /// Bytes relayed back to clients, including relays still running.
+    pub bytes_downloaded: u64,
+    /// Failed username/password attempts, malformed ones included.
+    pub auth_failures: u64,
+    /// Replies sent to requests, indexed by reply code (`REP_SUCCEEDED` =
+    /// 0 through `REP_ADDRESS_TYPE_NOT_SUPPORTED` = 8).
+    pub replies: [u64; 9],
+    pub uptime: Duration,
+}
+
+#[derive(Debug)]
+pub(crate) struct StatsCounters {
+    started: Instant,
+    connections: AtomicU64,
+    active: AtomicU64,
+    auth_failures: AtomicU64,
+    replies: [AtomicU64; 9],
+    bytes: Arc<RelayCounters>,
+}
+
+impl StatsCounters {
+    /// Count a connection as active until the guard is dropped.
+    pub(crate) fn connection(self: &Arc<Self>) -> ActiveConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(Arc::clone(self))
+    }
+
+    /// Byte counters for one relay that also add to the server totals.
+    pub(crate) fn relay_counters(&self) -> RelayCounters {
+        RelayCounters::with_parent(Arc::clone(&self.bytes))
+    }
+
+    pub(crate) fn auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reply(&self, code: u8) {
+        if let Some(counter) = self.replies.get(code as usize) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> ServerStats {
+        ServerStats {
+            total_connections: self.connections.load(Ordering::Relaxed),
+            active_connections: self.active.load(Ordering::Relaxed),
+            bytes_uploaded: self.bytes.upload(),
+            bytes_downloaded: self.bytes.download(),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            replies: self
+                .replies
+                .each_ref()
+                .map(|counter| counter.load(Ordering::Relaxed)),
+            uptime: self.started.elapsed(),
+        }
+    }
+}
+
+impl Default for StatsCounters {
+    fn default() -> Self {
+        StatsCounters {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            replies: Default::default(),
+            bytes: Arc::default(),
+        }
+    }
+}
+
+pub(crate) struct ActiveConnection(Arc<StatsCounters>);
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,415 @@
+// Syslog output for the `log` facade.
+//
+// `SyslogLogger` formats every record as an RFC 5424 message and sends it to
+// the local syslog socket, or to a remote collector over UDP (RFC 5426) or
+// TCP with octet-counting framing (RFC 6587). Install it instead of
+// env_logger to route the server's access and error logs to syslog.
+//
+// `log()` runs on whatever thread logs, tokio workers included, so it only
+// queues the message. A thread of the logger's own does the I/O, and when
+// the collector falls behind or is down, messages beyond the queue are
+// dropped rather than stalling the server.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Syslog facility messages are filed under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Facility {
+    User = 1,
+    Daemon = 3,
+    Auth = 4,
+    AuthPriv = 10,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// Where messages are sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogTransport {
+    /// A local datagram socket such as `/dev/log` (Unix only).
+    Unix(std::path::PathBuf),
+    /// `host:port` of a remote collector, usually port 514.
+    Udp(String),
+    /// `host:port` of a remote collector, usually port 601.
+    Tcp(String),
+}
+
+impl FromStr for SyslogTransport {
+    type Err = io::Error;
+
+    /// Parse `unix:/dev/log`, `udp://host:514` or `tcp://host:601`.
+    fn from_str(s: &str) -> io::Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            Ok(SyslogTransport::Unix(path.into()))
+        } else if let Some(addr) = s.strip_prefix("udp://") {
+            Ok(SyslogTransport::Udp(addr.to_string()))
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            Ok(SyslogTransport::Tcp(addr.to_string()))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid syslog destination {:?}, expected unix:PATH, udp://HOST:PORT or tcp://HOST:PORT",
+                    s
+                ),
+            ))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogOptions {
+    pub transport: SyslogTransport,
+    pub facility: Facility,
+    /// APP-NAME field of every message.
+    pub app_name: String,
+    /// HOSTNAME field; defaults to the system host name.
+    pub hostname: Option<String>,
+    /// Records below this level are dropped.
+    pub level: LevelFilter,
+}
+
+impl Default for SyslogOptions {
+    fn default() -> Self {
+        SyslogOptions {
+            transport: SyslogTransport::Unix("/dev/log".into()),
+            facility: Facility::Daemon,
+            app_name: "charon".to_string(),
+            hostname: None,
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+// Messages waiting for the sender thread; more are dropped
+const QUEUE_LEN: usize = 1024;
+
+// Per attempt to connect to, or write to, a TCP collector
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Wait between attempts to reach a TCP collector that is down, doubling up
+// to the maximum
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+enum Connection {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpConnection),
+}
+
+// A TCP collector, reconnected with backoff after a failure. Messages sent
+// while waiting to retry are dropped.
+struct TcpConnection {
+    addr: String,
+    stream: Option<TcpStream>,
+    retry_at: Option<Instant>,
+    backoff: Duration,
+}
+
+enum Command {
+    Send(String),
+    // Answered once everything queued before it is sent
+    Flush(SyncSender<()>),
+}
+
+pub struct SyslogLogger {
+    options: SyslogOptions,
+    hostname: String,
+    queue: SyncSender<Command>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl SyslogLogger {
+    /// Connect to the syslog destination and start the thread sending to it.
+    /// Remote TCP collectors that are down are retried with backoff.
+    pub fn new(options: SyslogOptions) -> io::Result<Self> {
+        let connection = match &options.transport {
+            #[cfg(unix)]
+            SyslogTransport::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Connection::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogTransport::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unix syslog sockets are not supported on this platform",
+                ));
+            }
+            SyslogTransport::Udp(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0").or_else(|_| UdpSocket::bind("[::]:0"))?;
+                socket.connect(addr)?;
+                Connection::Udp(socket)
+            }
+            SyslogTransport::Tcp(addr) => Connection::Tcp(TcpConnection {
+                addr: addr.clone(),
+                stream: None,
+                retry_at: None,
+                backoff: MIN_BACKOFF,
+            }),
+        };
+
+        let hostname = options
+            .hostname
+            .clone()
+            .or_else(system_hostname)
+            .unwrap_or_else(|| "-".to_string());
+        let (queue, commands) = mpsc::sync_channel(QUEUE_LEN);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let thread_dropped = dropped.clone();
+        thread::Builder::new()
+            .name("syslog".to_string())
+            .spawn(move || run(connection, commands, &thread_dropped))?;
+        Ok(SyslogLogger {
+            options,
+            hostname,
+            queue,
+            dropped,
+        })
+    }
+
+    /// Install as the global logger.
+    pub fn init(options: SyslogOptions) -> io::Result<()> {
+        let level = options.level;
+        let logger = SyslogLogger::new(options)?;
+        log::set_boxed_logger(Box::new(logger)).map_err(io::Error::other)?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    fn format(&self, record: &Record) -> String {
+        let severity = match record.level() {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        let priority = self.options.facility as u8 * 8 + severity;
+        let msg_id: String = record
+            .target()
+            .chars()
+            .filter(|c| c.is_ascii_graphic())
+            .take(32)
+            .collect();
+
+        let mut message = format!(
+            "<{}>1 {} {} {} {} {} - ",
+            priority,
+            timestamp(SystemTime::now()),
+            header_field(&self.hostname, 255),
+            header_field(&self.options.app_name, 48),
+            std::process::id(),
+            if msg_id.is_empty() { "-" } else { &msg_id },
+        );
+        let _ = write!(message, "{}", record.args());
+        message
+    }
+}
+
+// The sender thread: runs until the logger is dropped
+fn run(mut connection: Connection, commands: Receiver<Command>, dropped: &AtomicU64) {
+    for command in commands {
+        match command {
+            Command::Send(message) => match connection.send(&message) {
+                Ok(()) => {
+                    let missed = dropped.swap(0, Ordering::Relaxed);
+                    if missed > 0 {
+                        eprintln!("syslog: dropped {} messages", missed);
+                    }
+                }
+                Err(e) => {
+                    // Nowhere else to report it
+                    if dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                        eprintln!("syslog: {}", e);
+                    }
+                }
+            },
+            Command::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Connection::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Connection::Tcp(tcp) => tcp.send(message),
+        }
+    }
+}
+
+impl TcpConnection {
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        if self.stream.is_none() {
+            if let Some(retry_at) = self.retry_at
+                && Instant::now() < retry_at
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "Syslog collector is down",
+                ));
+            }
+            match connect(&self.addr) {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.retry_at = None;
+                    self.backoff = MIN_BACKOFF;
+                }
+                Err(e) => {
+                    self.failed();
+                    return Err(e);
+                }
+            }
+        }
+        let framed = format!("{} {}", message.len(), message);
+        let result = self
+            .stream
+            .as_mut()
+            .expect("connected above")
+            .write_all(framed.as_bytes());
+        if result.is_err() {
+            self.stream = None;
+            self.failed();
+        }
+        result
+    }
+
+    fn failed(&mut self) {
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Try each address of `addr` in turn, each under the timeout
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match connect_to(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No address for syslog collector {}", addr),
+        )
+    }))
+}
+
+fn connect_to(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, TCP_TIMEOUT)?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+    Ok(stream)
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.options.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match self.queue.try_send(Command::Send(self.format(record))) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // The sender thread is gone; nothing to do
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    // Wait, briefly, for the messages queued so far to go out
+    fn flush(&self) {
+        let (done, flushed) = mpsc::sync_channel(1);
+        if self.queue.try_send(Command::Flush(done)).is_ok() {
+            let _ = flushed.recv_timeout(TCP_TIMEOUT);
+        }
+    }
+}
+
+// Header fields are printable ASCII without spaces, "-" when empty
+fn header_field(value: &str, max_len: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if value.is_empty() {
+        "-".to_string()
+    } else {
+        value
+    }
+}
+
+// RFC 3339 UTC timestamp with microseconds
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+#[cfg(unix)]
+fn system_hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if result != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn system_hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
@@ -0,0 +1,170 @@
+// In-memory plumbing for testing proxy behaviour without real sockets.
+//
+// `MemoryNet` is a `Dialer` whose destinations live in the process: register
+// a handler per target (`echo` covers the common case) and CONNECTs to it get
+// one end of a `tokio::io::duplex` pipe while the handler runs on the other.
+// `TestProxy` runs a `Server` that dials into such a network and opens client
+// connections to it over duplex pipes too, which `Client::connect_with_stream`
+// (or `TestProxy::connect`) drives like a real connection. Nothing binds a
+// port, so tests can run in parallel. UDP ASSOCIATE and BIND need real
+// sockets and are not covered.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+use crate::client::{Client, SocksStream};
+use crate::dialer::{Dialer, Outbound};
+use crate::protocol::SocksAddr;
+use crate::server::{PeerInfo, Server, ServerOptions};
+
+/// Capacity of each direction of the in-memory pipes.
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+type Handler = Arc<dyn Fn(DuplexStream) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Destinations reachable through an in-memory `Dialer`. Clones share the
+/// same destinations. CONNECTs to unregistered targets are refused.
+#[derive(Clone, Default)]
+pub struct MemoryNet {
+    destinations: Arc<Mutex<HashMap<String, Handler>>>,
+}
+
+impl MemoryNet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve each connection to `target` (`host:port`, `1.2.3.4:port` or
+    /// `[::1]:port`) with `handler`, in a task of its own. Replaces any
+    /// handler registered for the same target.
+    pub fn listen<F, Fut>(&self, target: &str, handler: F) -> io::Result<()>
+    where
+        F: Fn(DuplexStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let key = key(&target.parse()?);
+        let handler: Handler = Arc::new(move |stream| Box::pin(handler(stream)));
+        self.destinations.lock().unwrap().insert(key, handler);
+        Ok(())
+    }
+
+    /// Echo everything sent to `target` back to the sender.
+    pub fn echo(&self, target: &str) -> io::Result<()> {
+        self.listen(target, |mut stream| async move {
+            let (mut reader, mut writer) = tokio::io::split(&mut stream);
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        })
+    }
+
+    /// Make `target` unreachable again.
+    pub fn remove(&self, target: &str) -> io::Result<()> {
+        let key = key(&target.parse()?);
+        self.destinations.lock().unwrap().remove(&key);
+        Ok(())
+    }
+}
+
+// Domain names match case-insensitively, like DNS
+fn key(target: &SocksAddr) -> String {
+    target.to_string().to_ascii_lowercase()
+}
+
+#[async_trait]
+impl Dialer for MemoryNet {
+    async fn dial(&self, target: &SocksAddr) -> io::Result<Outbound> {
+        let handler = self.destinations.lock().unwrap().get(&key(target)).cloned();
+        let Some(handler) = handler else {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("Nothing listening on {} in memory", target),
+            ));
+        };
+        let (local, remote) = tokio::io::duplex(PIPE_CAPACITY);
+        tokio::spawn(handler(remote));
+        Ok(Outbound {
+            stream: Box::new(local),
+            bound_addr: None,
+        })
+    }
+}
+
+/// A `Server` reached over in-memory pipes that dials into its own
+/// `MemoryNet`.
+#[derive(Clone)]
+pub struct TestProxy {
+    server: Server,
+    net: MemoryNet,
+}
+
+impl TestProxy {
+    /// A proxy configured with `options`, except that its dialer is a new,
+    /// empty `MemoryNet`. The bind address is not used.
+    pub fn new(mut options: ServerOptions) -> Self {
+        let net = MemoryNet::new();
+        options.dialer = Some(Arc::new(net.clone()));
+        TestProxy {
+            server: Server::from_options(options),
+            net,
+        }
+    }
+
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// The destinations the proxy can reach.
+    pub fn net(&self) -> &MemoryNet {
+        &self.net
+    }
+
+    /// A new connection to the proxy from a client without an address.
+    pub fn open(&self) -> DuplexStream {
+        self.open_from(PeerInfo::default())
+    }
+
+    /// A new connection to the proxy from `peer`, e.g. with an address to
+    /// exercise the ban list and per-IP limits. The server side runs in a
+    /// task of its own until either end closes.
+    pub fn open_from(&self, peer: PeerInfo) -> DuplexStream {
+        let (client, server_end) = tokio::io::duplex(PIPE_CAPACITY);
+        let server = self.server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = server.handle_connection(server_end, peer).await {
+                debug!("In-memory proxy connection ended: {}", e);
+            }
+        });
+        client
+    }
+
+    /// Have `client` CONNECT to `target` (`host:port`, `1.2.3.4:port` or
+    /// `[::1]:port`) through a new in-memory connection. The client's
+    /// credentials and handshake timeout apply; its proxy address does not.
+    pub async fn connect(
+        &self,
+        client: &Client,
+        target: &str,
+    ) -> io::Result<SocksStream<DuplexStream>> {
+        client
+            .connect_with_stream(self.open(), target.parse()?)
+            .await
+    }
+}
+
+/// Write `data` to `stream` and read back as many bytes, e.g. through a
+/// tunnel to an `echo` destination.
+pub async fn round_trip<S>(stream: &mut S, data: &[u8]) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(data).await?;
+    let mut reply = vec![0u8; data.len()];
+    stream.read_exact(&mut reply).await?;
+    Ok(reply)
+}
@@ -1,21 +1,192 @@
-use std::fs::File;
-use std::io::{self, BufReader};
+use std::io;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use log::{debug, error, info};
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use rustls_pemfile::{certs, pkcs8_private_keys};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio_rustls::TlsAcceptor;
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{
+    Acceptor, NoServerSessionStorage, ProducesTickets, ServerSessionMemoryCache,
+    WebPkiClientVerifier,
+};
+use rustls::{RootCertStore, ServerConfig};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio_rustls::LazyConfigAcceptor;
 
-use crate::server::{Server, ServerOptions};
+use crate::auth::Identity;
+use crate::dialer::ProxyStream;
+use crate::h2::{ALPN_H2, offer_h2};
+use crate::server::{PeerInfo, Server, ServerOptions, bind_listeners, join_all};
+use crate::transport::{self, Accepted};
+
+// ALPN protocol of ACME TLS-ALPN-01 validation handshakes (RFC 8737)
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+// The selected crypto provider's ticketer, which rotates its key every six
+// hours and accepts tickets for up to twelve
+fn ticketer() -> io::Result<Arc<dyn ProducesTickets>> {
+    #[cfg(feature = "aws-lc-rs")]
+    let ticketer = rustls::crypto::aws_lc_rs::Ticketer::new();
+    #[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+    let ticketer = rustls::crypto::ring::Ticketer::new();
+
+    ticketer.map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Which part of a verified client certificate becomes the SOCKS username.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientCertIdentity {
+    /// The subject's common name.
+    CommonName,
+    /// The first DNS name or email address in the subject alternative names.
+    SubjectAltName,
+}
+
+/// A TLS protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+/// Session resumption, which lets returning clients skip the full handshake.
+#[derive(Debug, Clone)]
+pub struct ResumptionSettings {
+    /// Sessions kept in memory: by session ID on the server, per proxy name
+    /// on the client. 0 disables stateful resumption.
+    pub cache_size: usize,
+    /// Server only: issue stateless session tickets. Their key rotates every
+    /// six hours, and tickets are accepted for up to twelve.
+    pub tickets: bool,
+}
+
+impl Default for ResumptionSettings {
+    fn default() -> Self {
+        ResumptionSettings {
+            cache_size: 256,
+            tickets: true,
+        }
+    }
+}
+
+/// Protocol parameters shared by the TLS server and client.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSettings {
+    /// ALPN protocols in preference order; empty disables ALPN.
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Lowest TLS version to negotiate (default TLS 1.2).
+    pub min_version: Option<TlsVersion>,
+    /// Highest TLS version to negotiate (default TLS 1.3).
+    pub max_version: Option<TlsVersion>,
+    /// Cipher suites in preference order; empty uses the crypto provider's
+    /// defaults.
+    pub cipher_suites: Vec<rustls::CipherSuite>,
+    /// Append session secrets to the file named by the `SSLKEYLOGFILE`
+    /// environment variable so captures can be decrypted (e.g. in
+    /// Wireshark). For troubleshooting only.
+    pub key_log: bool,
+    /// Session cache and ticket parameters (rustls only).
+    pub resumption: ResumptionSettings,
+}
+
+impl TlsSettings {
+    /// The crypto provider restricted to the configured cipher suites.
+    pub(crate) fn provider(&self) -> io::Result<Arc<CryptoProvider>> {
+        let mut provider = Arc::unwrap_or_clone(crypto_provider());
+        if !self.cipher_suites.is_empty() {
+            let available = std::mem::take(&mut provider.cipher_suites);
+            provider.cipher_suites = self
+                .cipher_suites
+                .iter()
+                .filter_map(|wanted| available.iter().find(|s| s.suite() == *wanted).copied())
+                .collect();
+            if provider.cipher_suites.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "None of the configured cipher suites is supported",
+                ));
+            }
+        }
+        Ok(Arc::new(provider))
+    }
+
+    pub(crate) fn key_log(&self) -> Arc<dyn rustls::KeyLog> {
+        if self.key_log {
+            warn!("TLS key logging is enabled; session secrets are written to SSLKEYLOGFILE");
+            Arc::new(rustls::KeyLogFile::new())
+        } else {
+            Arc::new(rustls::NoKeyLog)
+        }
+    }
+
+    fn apply_server_resumption(&self, config: &mut ServerConfig) -> io::Result<()> {
+        let resumption = &self.resumption;
+        config.session_storage = if resumption.cache_size == 0 {
+            Arc::new(NoServerSessionStorage {})
+        } else {
+            ServerSessionMemoryCache::new(resumption.cache_size)
+        };
+        if resumption.tickets {
+            config.ticketer = ticketer()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn protocol_versions(
+        &self,
+    ) -> io::Result<Vec<&'static rustls::SupportedProtocolVersion>> {
+        let min = self.min_version.unwrap_or(TlsVersion::Tls12);
+        let max = self.max_version.unwrap_or(TlsVersion::Tls13);
+        let versions: Vec<_> = [
+            (TlsVersion::Tls13, &rustls::version::TLS13),
+            (TlsVersion::Tls12, &rustls::version::TLS12),
+        ]
+        .into_iter()
+        .filter(|(version, _)| (min..=max).contains(version))
+        .map(|(_, supported)| supported)
+        .collect();
+
+        if versions.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Minimum TLS version is above the maximum",
+            ));
+        }
+        Ok(versions)
+    }
+}
 
 pub struct TlsServerOptions {
+    /// Options of the wrapped SOCKS5 server; the TLS listener binds to
+    /// `server_options.bind_addr`.
     pub server_options: ServerOptions,
     pub cert_path: String,
     pub key_path: String,
+    /// ALPN, protocol versions and cipher suites.
+    pub tls: TlsSettings,
+    /// CA bundle used to verify client certificates. When set, clients must
+    /// present a certificate signed by one of these CAs (mutual TLS).
+    pub client_ca_path: Option<String>,
+    /// Map the client certificate to a SOCKS identity, so it flows into
+    /// policies, ACLs and logs. Clients with a mapped certificate may then
+    /// skip username/password authentication.
+    pub client_cert_identity: Option<ClientCertIdentity>,
+    /// Poll the certificate, key and client CA files at this interval and
+    /// reload them when they change (e.g. after a certbot renewal). Existing
+    /// sessions keep running; new handshakes use the new certificate.
+    pub reload_interval: Option<Duration>,
+    /// Also accept plaintext SOCKS5 on this address, served by the same
+    /// server (shared authentication, limits and session accounting).
+    pub plaintext_bind_addr: Option<String>,
+    /// Also accept HTTP/2 CONNECT tunnels from clients that negotiate "h2",
+    /// which is added to the ALPN protocols. SOCKS5 clients that offer no
+    /// ALPN or another protocol are served as before.
+    pub http2_connect: bool,
 }
 
 impl Default for TlsServerOptions {
@@ -24,126 +195,552 @@ impl Default for TlsServerOptions {
             server_options: ServerOptions::default(),
             cert_path: "cert.pem".to_string(),
             key_path: "key.pem".to_string(),
+            tls: TlsSettings::default(),
+            client_ca_path: None,
+            client_cert_identity: None,
+            reload_interval: None,
+            plaintext_bind_addr: None,
+            http2_connect: false,
+        }
+    }
+}
+
+/// A connection that completed the TLS handshake.
+pub struct TlsAccepted {
+    pub stream: Box<dyn ProxyStream>,
+    /// DER-encoded certificate presented by the client, if any.
+    pub peer_certificate: Option<Vec<u8>>,
+    /// ALPN protocol agreed on, if any.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Keys for protecting UDP relay datagrams, if the backend supports TLS
+    /// exporters.
+    #[cfg(feature = "udp-aead")]
+    pub udp_keys: Option<crate::udp_aead::UdpKeys>,
+}
+
+/// The TLS implementation behind a `TlsServer`.
+///
+/// rustls is always available; other stacks (e.g. the system's via the
+/// `native-tls` feature) implement this trait as well.
+#[async_trait]
+pub trait TlsBackend: Send + Sync {
+    /// Run the server side of the handshake. `Ok(None)` means the connection
+    /// was fully handled during the handshake (e.g. an ACME challenge).
+    async fn accept(&self, stream: TcpStream) -> io::Result<Option<TlsAccepted>>;
+
+    /// Reload certificates from their source for all new handshakes.
+    fn reload(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS configuration was not loaded from files",
+        ))
+    }
+
+    /// Files whose modification should trigger `reload`.
+    fn watched_files(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+// Files and settings the TLS configuration is loaded from
+#[derive(Clone)]
+struct TlsFiles {
+    cert_path: String,
+    key_path: String,
+    client_ca_path: Option<String>,
+    settings: TlsSettings,
+}
+
+impl TlsFiles {
+    fn load(&self) -> io::Result<Arc<ServerConfig>> {
+        load_tls_config(
+            &self.cert_path,
+            &self.key_path,
+            self.client_ca_path.as_deref(),
+            &self.settings,
+        )
+    }
+}
+
+// The rustls backend
+pub(crate) struct RustlsBackend {
+    files: Option<TlsFiles>,
+    tls_config: RwLock<Arc<ServerConfig>>,
+    // Served to ACME TLS-ALPN-01 validation connections
+    challenge_config: Option<Arc<ServerConfig>>,
+}
+
+impl RustlsBackend {
+    pub(crate) fn with_config(config: Arc<ServerConfig>) -> Self {
+        RustlsBackend {
+            files: None,
+            tls_config: RwLock::new(config),
+            challenge_config: None,
         }
     }
+
+    // Answer ACME TLS-ALPN-01 challenges with `config`
+    #[cfg(feature = "acme")]
+    pub(crate) fn with_challenge_config(mut self, config: Arc<ServerConfig>) -> Self {
+        self.challenge_config = Some(config);
+        self
+    }
 }
 
+#[async_trait]
+impl TlsBackend for RustlsBackend {
+    async fn accept(&self, stream: TcpStream) -> io::Result<Option<TlsAccepted>> {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+
+        // ACME validation connections only need the handshake itself
+        if let Some(challenge_config) = &self.challenge_config
+            && start
+                .client_hello()
+                .alpn()
+                .into_iter()
+                .flatten()
+                .eq([ACME_TLS_ALPN])
+        {
+            debug!("Answering ACME TLS-ALPN-01 challenge");
+            let mut tls_stream = start.into_stream(Arc::clone(challenge_config)).await?;
+            let _ = tls_stream.shutdown().await;
+            return Ok(None);
+        }
+
+        let config = Arc::clone(&self.tls_config.read().unwrap());
+        let tls_stream = start.into_stream(config).await?;
+        let peer_certificate = tls_stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.to_vec());
+        let alpn_protocol = tls_stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+        #[cfg(feature = "udp-aead")]
+        let udp_keys = crate::udp_aead::UdpKeys::from_rustls(tls_stream.get_ref().1).ok();
+
+        Ok(Some(TlsAccepted {
+            stream: Box::new(tls_stream),
+            peer_certificate,
+            alpn_protocol,
+            #[cfg(feature = "udp-aead")]
+            udp_keys,
+        }))
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let Some(files) = &self.files else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS configuration was not loaded from files",
+            ));
+        };
+        *self.tls_config.write().unwrap() = files.load()?;
+        Ok(())
+    }
+
+    fn watched_files(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .flat_map(|files| {
+                [&files.cert_path, &files.key_path]
+                    .into_iter()
+                    .chain(&files.client_ca_path)
+                    .cloned()
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
 pub struct TlsServer {
     server: Server,
-    tls_config: Arc<ServerConfig>,
+    backend: Arc<dyn TlsBackend>,
+    client_cert_identity: Option<ClientCertIdentity>,
+    reload_interval: Option<Duration>,
+    plaintext_bind_addr: Option<String>,
+    http2_connect: bool,
 }
 
 impl TlsServer {
-    pub fn new(options: TlsServerOptions) -> io::Result<Self> {
+    pub fn new(mut options: TlsServerOptions) -> io::Result<Self> {
+        if options.http2_connect {
+            offer_h2(&mut options.tls.alpn_protocols);
+        }
+        let files = TlsFiles {
+            cert_path: options.cert_path,
+            key_path: options.key_path,
+            client_ca_path: options.client_ca_path,
+            settings: options.tls,
+        };
+        let backend = RustlsBackend {
+            tls_config: RwLock::new(files.load()?),
+            files: Some(files),
+            challenge_config: None,
+        };
         let server = Server::from_options(options.server_options);
-        let tls_config = load_tls_config(&options.cert_path, &options.key_path)?;
 
-        Ok(TlsServer { server, tls_config })
+        Ok(TlsServer {
+            server,
+            backend: Arc::new(backend),
+            client_cert_identity: options.client_cert_identity,
+            reload_interval: options.reload_interval,
+            plaintext_bind_addr: options.plaintext_bind_addr,
+            http2_connect: options.http2_connect,
+        })
     }
 
-    pub async fn run(&self, bind_addr: &str) -> io::Result<()> {
-        let listener = TcpListener::bind(bind_addr).await?;
-        info!("SOCKS5 TLS server listening on {}", bind_addr);
+    /// Wrap `server` in a TLS layer using a caller-built rustls configuration,
+    /// e.g. one backed by a secrets vault or a SPIFFE workload API. Use a
+    /// custom `ResolvesServerCert` in `config` to rotate certificates.
+    pub fn with_config(server: Server, config: Arc<ServerConfig>) -> Self {
+        Self::with_backend(server, Arc::new(RustlsBackend::with_config(config)))
+    }
 
-        let acceptor = TlsAcceptor::from(Arc::clone(&self.tls_config));
+    /// Wrap `server` in a TLS layer provided by `backend`.
+    pub fn with_backend(server: Server, backend: Arc<dyn TlsBackend>) -> Self {
+        TlsServer {
+            server,
+            backend,
+            client_cert_identity: None,
+            reload_interval: None,
+            plaintext_bind_addr: None,
+            http2_connect: false,
+        }
+    }
+
+    /// Map verified client certificates to SOCKS identities. Only useful if
+    /// the configuration requests client certificates.
+    pub fn with_client_cert_identity(mut self, source: ClientCertIdentity) -> Self {
+        self.client_cert_identity = Some(source);
+        self
+    }
+
+    /// Also serve plaintext SOCKS5 on `bind_addr` when running.
+    pub fn with_plaintext_listener(mut self, bind_addr: String) -> Self {
+        self.plaintext_bind_addr = Some(bind_addr);
+        self
+    }
+
+    /// Serve HTTP/2 CONNECT to clients that negotiate "h2". The TLS
+    /// configuration must offer "h2" in its ALPN protocols.
+    pub fn with_http2_connect(mut self) -> Self {
+        self.http2_connect = true;
+        self
+    }
+
+    /// Poll the certificate files at `interval` and reload on change.
+    pub fn with_reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = Some(interval);
+        self
+    }
+
+    /// Re-read the certificate, key and client CA files and use them for all
+    /// new handshakes. On error the current configuration stays in place.
+    pub fn reload(&self) -> io::Result<()> {
+        self.backend.reload()?;
+        info!("Reloaded TLS certificate");
+        Ok(())
+    }
+
+    /// Listen for TLS on the server's bind address, plus plaintext SOCKS5 on
+    /// the plaintext address if one is configured.
+    pub async fn run(&self) -> io::Result<()> {
+        let acceptors = self.server.acceptors();
+        let listeners = bind_listeners(self.server.bind_addr(), acceptors).await?;
+        let plaintext = match &self.plaintext_bind_addr {
+            Some(plaintext_addr) => Some(bind_listeners(plaintext_addr, acceptors).await?),
+            None => None,
+        };
+        let health = self.server.bind_health().await?;
+        self.server.drop_privileges()?;
+
+        let plaintext = async {
+            match plaintext {
+                Some(plaintext) => self.server.run_with_listeners(plaintext).await,
+                None => Ok(()),
+            }
+        };
+        let health = async {
+            match health {
+                Some(health) => self.server.run_health_with_listener(health).await,
+                None => Ok(()),
+            }
+        };
+        tokio::try_join!(
+            self.run_with_listeners(listeners),
+            plaintext,
+            health,
+            self.server.run_accounting()
+        )?;
+        Ok(())
+    }
+
+    /// Serve TLS-wrapped SOCKS5 on an already bound listener.
+    pub async fn run_with_listener(&self, listener: TcpListener) -> io::Result<()> {
+        self.run_with_listeners(vec![listener]).await
+    }
+
+    /// Serve TLS-wrapped SOCKS5 on several already bound listeners, each
+    /// with its own accept loop.
+    pub async fn run_with_listeners(&self, listeners: Vec<TcpListener>) -> io::Result<()> {
+        let watched = self.backend.watched_files();
+        if let Some(interval) = self.reload_interval
+            && !watched.is_empty()
+        {
+            tokio::spawn(self.clone().watch_files(watched, interval));
+        }
+
+        let acceptor = Arc::new(TlsAcceptor {
+            backend: Arc::clone(&self.backend),
+            client_cert_identity: self.client_cert_identity,
+            http2_connect: self.http2_connect,
+        });
+        let mut loops = JoinSet::new();
+        for listener in listeners {
+            let server = self.server.clone();
+            let acceptor = Arc::clone(&acceptor);
+            loops.spawn(async move { server.run_with_acceptor(listener, acceptor).await });
+        }
+        join_all(loops).await
+    }
+
+    // Poll the TLS files and reload when any of them changes, until shutdown
+    async fn watch_files(self, files: Vec<String>, interval: Duration) {
+        // Latest modification time across all files, used to detect rotation
+        let modified = || {
+            files
+                .iter()
+                .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+                .max()
+        };
+        let mut last_modified = modified();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
 
         loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    info!("Accepted connection from: {}", addr);
-                    let acceptor = acceptor.clone();
-                    let server = self.server.clone();
-
-                    tokio::spawn(async move {
-                        match acceptor.accept(stream).await {
-                            Ok(tls_stream) => {
-                                if let Err(e) = server.handle_client(tls_stream).await {
-                                    error!("Error handling TLS client: {}", e);
-                                }
-                            }
-                            Err(e) => {
-                                error!("TLS handshake failed: {}", e);
-                            }
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Failed to accept connection: {}", e);
-                }
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = self.server.drain().stopped() => return,
+            }
+            let current = modified();
+            if current == last_modified {
+                continue;
+            }
+            // Keep the old timestamp on failure so a half-written pair is
+            // retried on the next tick
+            match self.reload() {
+                Ok(()) => last_modified = current,
+                Err(e) => warn!("Failed to reload TLS certificate: {}", e),
             }
         }
     }
 }
 
-// Load certificates and private key from files
-fn load_tls_config(cert_path: &str, key_path: &str) -> io::Result<Arc<ServerConfig>> {
-    // Load certificates
-    let cert_file = File::open(cert_path)?;
-    let mut cert_reader = BufReader::new(cert_file);
-    let certs = certs(&mut cert_reader)?
-        .into_iter()
-        .map(Certificate)
-        .collect();
+// The TLS handshake as a transport, with the options of the `TlsServer` it
+// came from
+struct TlsAcceptor {
+    backend: Arc<dyn TlsBackend>,
+    client_cert_identity: Option<ClientCertIdentity>,
+    http2_connect: bool,
+}
 
-    // Load private key
-    let key_file = File::open(key_path)?;
-    let mut key_reader = BufReader::new(key_file);
-    let keys = pkcs8_private_keys(&mut key_reader)?
-        .into_iter()
-        .map(PrivateKey)
-        .collect::<Vec<_>>();
+#[async_trait]
+impl transport::Acceptor for TlsAcceptor {
+    type Stream = Box<dyn ProxyStream>;
 
-    if keys.is_empty() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "No private keys found in file",
-        ));
+    fn name(&self) -> &'static str {
+        "TLS"
     }
 
+    async fn accept(
+        &self,
+        stream: TcpStream,
+        peer: &mut PeerInfo,
+    ) -> io::Result<Accepted<Box<dyn ProxyStream>>> {
+        let accepted = match self.backend.accept(stream).await {
+            Ok(Some(accepted)) => accepted,
+            Ok(None) => return Ok(Accepted::Handled),
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("TLS handshake failed: {}", e),
+                ));
+            }
+        };
+
+        peer.identity = self
+            .client_cert_identity
+            .and_then(|source| identity_from_cert(accepted.peer_certificate.as_deref()?, source));
+        if let (Some(addr), Some(identity)) = (peer.addr, &peer.identity) {
+            info!(
+                "Client {} authenticated by certificate as {}",
+                addr, identity.username
+            );
+        }
+
+        #[cfg(feature = "udp-aead")]
+        {
+            peer.udp_keys = accepted.udp_keys;
+        }
+
+        if self.http2_connect && accepted.alpn_protocol.as_deref() == Some(ALPN_H2) {
+            Ok(Accepted::Http2(accepted.stream))
+        } else {
+            Ok(Accepted::Socks(accepted.stream))
+        }
+    }
+}
+
+/// The rustls crypto provider selected by cargo features (`aws-lc-rs` takes
+/// precedence over `ring` when both are enabled).
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    #[cfg(feature = "aws-lc-rs")]
+    let provider = rustls::crypto::aws_lc_rs::default_provider();
+    #[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+    let provider = rustls::crypto::ring::default_provider();
+    #[cfg(not(any(feature = "ring", feature = "aws-lc-rs")))]
+    compile_error!("enable either the `ring` or the `aws-lc-rs` feature");
+
+    Arc::new(provider)
+}
+
+/// Load a rustls server configuration from PEM files the way
+/// `TlsServer::new` does. With `client_ca_path`, clients must present a
+/// certificate signed by one of those CAs.
+pub fn load_tls_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+    settings: &TlsSettings,
+) -> io::Result<Arc<ServerConfig>> {
+    // Load certificates
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(pem_error)?;
+
+    // Load private key
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(pem_error)?;
+
     // Configure server
-    let mut config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, keys[0].clone())
+    let provider = settings.provider()?;
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_protocol_versions(&settings.protocol_versions()?)
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
-    // Configure ALPN protocols if needed
-    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    let builder = match client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca_path).map_err(pem_error)? {
+                roots
+                    .add(cert.map_err(pem_error)?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    config.alpn_protocols = settings.alpn_protocols.clone();
+    config.key_log = settings.key_log();
+    settings.apply_server_resumption(&mut config)?;
 
     Ok(Arc::new(config))
 }
 
+// Extract the SOCKS identity from a (verified) client certificate
+fn identity_from_cert(cert: &[u8], source: ClientCertIdentity) -> Option<Identity> {
+    use x509_parser::extensions::GeneralName;
+    use x509_parser::prelude::{FromDer, X509Certificate};
+
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let username = match source {
+        ClientCertIdentity::CommonName => cert
+            .subject()
+            .iter_common_name()
+            .next()?
+            .as_str()
+            .ok()?
+            .to_string(),
+        ClientCertIdentity::SubjectAltName => cert
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => {
+                    Some(name.to_string())
+                }
+                _ => None,
+            })?,
+    };
+
+    Some(Identity::new(username))
+}
+
+pub(crate) fn pem_error(e: rustls::pki_types::pem::Error) -> io::Error {
+    match e {
+        rustls::pki_types::pem::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+    }
+}
+
+/// A freshly generated self-signed certificate and its private key.
+pub struct SelfSignedCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub cert_der: Vec<u8>,
+    /// PKCS#8-encoded private key
+    pub key_der: Vec<u8>,
+}
+
+impl SelfSignedCert {
+    /// Write the PEM-encoded certificate and key to the given paths.
+    pub fn write_to<P: AsRef<Path>>(&self, cert_path: P, key_path: P) -> io::Result<()> {
+        std::fs::write(cert_path, &self.cert_pem)?;
+        std::fs::write(key_path, &self.key_pem)?;
+        Ok(())
+    }
+}
+
+/// Generate a self-signed certificate in-process for the given hostnames.
+/// Each entry becomes a subject alternative name (IP SAN for IP literals,
+/// DNS SAN otherwise); the first one is also used as the common name.
+pub fn generate_self_signed_cert_for(hostnames: &[&str]) -> io::Result<SelfSignedCert> {
+    use rcgen::{CertificateParams, DnType, KeyPair};
+
+    let names: Vec<String> = hostnames.iter().map(|h| h.to_string()).collect();
+    let mut params = CertificateParams::new(names)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if let Some(first) = hostnames.first() {
+        params.distinguished_name.push(DnType::CommonName, *first);
+    }
+
+    let key_pair = KeyPair::generate().map_err(io::Error::other)?;
+    let cert = params.self_signed(&key_pair).map_err(io::Error::other)?;
+
+    Ok(SelfSignedCert {
+        cert_pem: cert.pem(),
+        key_pem: key_pair.serialize_pem(),
+        cert_der: cert.der().to_vec(),
+        key_der: key_pair.serialize_der(),
+    })
+}
+
 // Helper function to generate test certificates
-#[allow(dead_code)]
 pub fn generate_self_signed_cert() -> io::Result<()> {
-    use std::process::Command;
-
     println!("Generating self-signed certificate...");
 
-    let status = Command::new("openssl")
-        .args(&[
-            "req",
-            "-x509",
-            "-newkey",
-            "rsa:4096",
-            "-keyout",
-            "key.pem",
-            "-out",
-            "cert.pem",
-            "-days",
-            "365",
-            "-nodes",
-            "-subj",
-            "/CN=localhost",
-        ])
-        .status()?;
-
-    if !status.success() {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Failed to generate certificate",
-        ));
-    }
+    let cert = generate_self_signed_cert_for(&["localhost", "127.0.0.1", "::1"])?;
+    cert.write_to("cert.pem", "key.pem")?;
 
     println!("Certificate generated: cert.pem");
     println!("Key generated: key.pem");
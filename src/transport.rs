@@ -0,0 +1,230 @@
+// Transports that carry the SOCKS5 byte stream between client and proxy.
+//
+// Plain TCP, TLS, WebSocket and the obfuscated transport only differ in how a
+// connection is set up: a handshake, an upgrade, a key exchange. Afterwards
+// SOCKS5 runs over the resulting stream unchanged. `Acceptor` is the server
+// side of that setup and `Transport` the client side. `Server::
+// run_with_acceptor` supplies everything else on the server (accept loop,
+// drain, backoff, socket options) and `TransportClient` on the client
+// (deadlines, retries, DNS mode), so a new transport such as QUIC only needs
+// to implement the pair.
+
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{error, info};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::accept::Backoff;
+use crate::client::{Client, SocksListener, SocksStream, first_addr};
+use crate::protocol::SocksAddr;
+use crate::server::{PeerInfo, Server, within};
+
+/// What an `Acceptor` made of an accepted connection.
+pub enum Accepted<S> {
+    /// Serve SOCKS5 over the stream.
+    Socks(S),
+    /// Serve HTTP/2 CONNECT over the stream, e.g. after ALPN chose "h2".
+    Http2(S),
+    /// The acceptor dealt with the connection itself, e.g. an ACME
+    /// validation handshake.
+    Handled,
+}
+
+/// Server side of a transport: sets it up on each accepted TCP connection.
+/// Run one with `Server::run_with_acceptor`.
+#[async_trait]
+pub trait Acceptor: Send + Sync + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Short name for logs, e.g. "TLS".
+    fn name(&self) -> &'static str;
+
+    /// Set up the transport on `stream`. `peer` holds the socket's address;
+    /// the acceptor may replace it (an address forwarded by a reverse proxy)
+    /// or add an identity (from a client certificate).
+    async fn accept(
+        &self,
+        stream: TcpStream,
+        peer: &mut PeerInfo,
+    ) -> io::Result<Accepted<Self::Stream>>;
+}
+
+/// Client side of a transport: opens connections to the proxy. Use it with
+/// `TransportClient`.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    /// A new connection to the proxy `client` is configured for, typically
+    /// `Client::dial_tcp` followed by the transport's own handshake.
+    async fn dial(&self, client: &Client) -> io::Result<Self::Stream>;
+}
+
+/// Plain TCP, the transport `Server::run` and `Client` use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tcp;
+
+#[async_trait]
+impl Acceptor for Tcp {
+    type Stream = TcpStream;
+
+    fn name(&self) -> &'static str {
+        "TCP"
+    }
+
+    async fn accept(
+        &self,
+        stream: TcpStream,
+        _peer: &mut PeerInfo,
+    ) -> io::Result<Accepted<TcpStream>> {
+        Ok(Accepted::Socks(stream))
+    }
+}
+
+#[async_trait]
+impl Transport for Tcp {
+    type Stream = TcpStream;
+
+    async fn dial(&self, client: &Client) -> io::Result<TcpStream> {
+        client.dial_tcp().await
+    }
+}
+
+impl Server {
+    /// Serve SOCKS5 over `acceptor`'s transport on an already bound
+    /// listener.
+    pub async fn run_with_acceptor<A: Acceptor>(
+        &self,
+        listener: TcpListener,
+        acceptor: Arc<A>,
+    ) -> io::Result<()> {
+        info!(
+            "SOCKS5 {} server listening on {}",
+            acceptor.name(),
+            listener.local_addr()?
+        );
+        let _listening = self.drain().listen();
+        let mut backoff = Backoff::new();
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.drain().stopped() => return Ok(()),
+            };
+            match accepted {
+                Ok((stream, addr)) => {
+                    backoff.reset();
+                    info!("New {} connection from {}", acceptor.name(), addr);
+                    self.configure_client_socket(&stream);
+                    let server = self.clone();
+                    let acceptor = Arc::clone(&acceptor);
+
+                    tokio::spawn(self.drain().track(async move {
+                        let mut peer = PeerInfo {
+                            addr: Some(addr),
+                            identity: None,
+                            #[cfg(feature = "udp-aead")]
+                            udp_keys: None,
+                        };
+                        // The transport's handshake counts towards
+                        // `max_handshaking` and has the greeting's deadline
+                        let phase = format!("{} handshake", acceptor.name());
+                        let accepted = match server.handshake_slot() {
+                            Ok(_slot) => {
+                                let greeting = server.handshake_timeouts().greeting;
+                                within(greeting, &phase, acceptor.accept(stream, &mut peer)).await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        let result = match accepted {
+                            Ok(Accepted::Socks(stream)) => {
+                                server.handle_connection(stream, peer).await
+                            }
+                            Ok(Accepted::Http2(stream)) => {
+                                server.handle_h2_connection(stream, peer).await
+                            }
+                            Ok(Accepted::Handled) => Ok(()),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = result {
+                            error!("Error handling client: {}", e);
+                        }
+                    }));
+                }
+                Err(e) => {
+                    self.accept_errors()
+                        .recover(&e, &mut backoff, || listener.accept())
+                        .await;
+                }
+            }
+        }
+    }
+}
+
+/// Connects to targets through a SOCKS5 proxy reached over `T`. The
+/// client's credentials, timeouts, retries, DNS mode and socket options all
+/// apply; `T` only decides how each connection to the proxy is opened.
+pub struct TransportClient<T> {
+    client: Client,
+    transport: T,
+}
+
+impl<T: Transport> TransportClient<T> {
+    /// Reach the proxy `client` is configured for over `transport`.
+    pub fn from_parts(client: Client, transport: T) -> Self {
+        TransportClient { client, transport }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn into_parts(self) -> (Client, T) {
+        (self.client, self.transport)
+    }
+
+    /// Connect to `target`, given as `host:port`, `1.2.3.4:port` or
+    /// `[::1]:port`; see `Client::connect`.
+    pub async fn connect(&self, target: &str) -> io::Result<SocksStream<T::Stream>> {
+        self.connect_addr(target.parse()?).await
+    }
+
+    pub async fn connect_to_domain(
+        &self,
+        domain: &str,
+        port: u16,
+    ) -> io::Result<SocksStream<T::Stream>> {
+        self.connect_addr(SocksAddr::Domain(domain.to_string(), port))
+            .await
+    }
+
+    /// Connect to an address resolved locally; see
+    /// `Client::connect_to_target`.
+    pub async fn connect_to_target<A: std::net::ToSocketAddrs>(
+        &self,
+        target_addr: A,
+    ) -> io::Result<SocksStream<T::Stream>> {
+        self.connect_addr(first_addr(target_addr)?).await
+    }
+
+    /// Have the proxy accept one inbound connection from `peer`; see
+    /// `Client::bind`.
+    pub async fn bind(&self, peer: SocksAddr) -> io::Result<SocksListener<T::Stream>> {
+        self.client
+            .bind_via(peer, || self.transport.dial(&self.client))
+            .await
+    }
+
+    async fn connect_addr(&self, addr: SocksAddr) -> io::Result<SocksStream<T::Stream>> {
+        self.client
+            .connect_via(addr, || self.transport.dial(&self.client))
+            .await
+    }
+}
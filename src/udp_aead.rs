@@ -0,0 +1,237 @@
+// AEAD protection for UDP relay datagrams (`udp-aead` feature).
+//
+// A UDP association set up over a TLS control connection still carries its
+// datagrams in plaintext. With this feature both ends derive keys from the
+// control connection's TLS session through an exporter (RFC 5705, RFC 8446
+// section 7.5), one per direction, and seal every datagram between client
+// and relay with ChaCha20-Poly1305. A protected datagram is an 8-byte
+// sequence number, which is also the nonce, followed by the sealed SOCKS UDP
+// datagram, header included. Receivers drop sequence numbers they have seen
+// or that fall behind a 64-datagram window, as DTLS does.
+//
+// Exporter output is unique to each TLS connection, and each association
+// has a control connection of its own, so keys are never shared between
+// associations. The relay protects an association from the first client
+// datagram that opens with its keys on, and refuses plaintext from then on.
+// Until then a client that sends plaintext gets plaintext, unless the server
+// requires protection.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+
+/// Exporter label both ends derive the keys with, without a context value.
+pub const EXPORTER_LABEL: &[u8] = b"EXPORTER-charon-udp-aead";
+
+/// Bytes of exporter output `UdpKeys::from_exporter` takes.
+pub const EXPORTER_LEN: usize = 2 * KEY_LEN;
+
+/// Bytes a protected datagram adds: the sequence number and the tag.
+pub const OVERHEAD: usize = SEQ_LEN + TAG_LEN;
+
+const KEY_LEN: usize = 32;
+const SEQ_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const REPLAY_WINDOW: u64 = 64;
+
+/// Keys protecting one association's datagrams, derived from the TLS
+/// session of its control connection.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UdpKeys {
+    client_to_relay: [u8; KEY_LEN],
+    relay_to_client: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for UdpKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UdpKeys(..)")
+    }
+}
+
+impl UdpKeys {
+    /// Keys from `EXPORTER_LEN` bytes a TLS stack exported with
+    /// `EXPORTER_LABEL` and no context, e.g. for a custom `TlsBackend`.
+    pub fn from_exporter(material: [u8; EXPORTER_LEN]) -> Self {
+        let mut client_to_relay = [0u8; KEY_LEN];
+        let mut relay_to_client = [0u8; KEY_LEN];
+        client_to_relay.copy_from_slice(&material[..KEY_LEN]);
+        relay_to_client.copy_from_slice(&material[KEY_LEN..]);
+        UdpKeys {
+            client_to_relay,
+            relay_to_client,
+        }
+    }
+
+    /// Keys for a rustls connection, client or server side.
+    pub fn from_rustls<D>(connection: &rustls::ConnectionCommon<D>) -> io::Result<Self> {
+        let material = connection
+            .export_keying_material([0u8; EXPORTER_LEN], EXPORTER_LABEL, None)
+            .map_err(io::Error::other)?;
+        Ok(Self::from_exporter(material))
+    }
+
+    // What the client seals with and opens with
+    pub(crate) fn client_cipher(&self) -> DatagramCipher {
+        DatagramCipher::new(&self.client_to_relay, &self.relay_to_client)
+    }
+
+    // What the relay seals with and opens with
+    pub(crate) fn relay_cipher(&self) -> DatagramCipher {
+        DatagramCipher::new(&self.relay_to_client, &self.client_to_relay)
+    }
+}
+
+// One end's keys for an association
+pub(crate) struct DatagramCipher {
+    seal: LessSafeKey,
+    open: LessSafeKey,
+    next_seq: AtomicU64,
+    window: Mutex<ReplayWindow>,
+}
+
+impl DatagramCipher {
+    fn new(seal: &[u8; KEY_LEN], open: &[u8; KEY_LEN]) -> Self {
+        let key = |bytes: &[u8; KEY_LEN]| {
+            LessSafeKey::new(UnboundKey::new(&aead::CHACHA20_POLY1305, bytes).expect("key length"))
+        };
+        DatagramCipher {
+            seal: key(seal),
+            open: key(open),
+            next_seq: AtomicU64::new(0),
+            window: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn seal(&self, datagram: &[u8]) -> io::Result<Vec<u8>> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        if seq == u64::MAX {
+            return Err(io::Error::other("UDP sequence numbers exhausted"));
+        }
+        let mut packet = Vec::with_capacity(datagram.len() + OVERHEAD);
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(datagram);
+        let tag = self
+            .seal
+            .seal_in_place_separate_tag(nonce(seq), Aad::empty(), &mut packet[SEQ_LEN..])
+            .map_err(|_| io::Error::other("Failed to seal UDP datagram"))?;
+        packet.extend_from_slice(tag.as_ref());
+        Ok(packet)
+    }
+
+    pub(crate) fn open(&self, packet: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "UDP datagram did not open");
+        if packet.len() < OVERHEAD {
+            return Err(invalid());
+        }
+        let seq = u64::from_be_bytes(packet[..SEQ_LEN].try_into().unwrap());
+        if !self.window.lock().unwrap().fresh(seq) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Replayed UDP datagram",
+            ));
+        }
+        let mut datagram = packet[SEQ_LEN..].to_vec();
+        let len = self
+            .open
+            .open_in_place(nonce(seq), Aad::empty(), &mut datagram)
+            .map_err(|_| invalid())?
+            .len();
+        datagram.truncate(len);
+        // Only authentic datagrams move the window
+        self.window.lock().unwrap().accept(seq);
+        Ok(datagram)
+    }
+}
+
+fn nonce(seq: u64) -> Nonce {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    nonce[aead::NONCE_LEN - SEQ_LEN..].copy_from_slice(&seq.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+// Sequence numbers seen lately: bit i of `seen` stands for `highest - i`
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn fresh(&self, seq: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if seq > highest => true,
+            Some(highest) => {
+                let age = highest - seq;
+                age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+            }
+        }
+    }
+
+    fn accept(&mut self, seq: u64) {
+        match self.highest {
+            Some(highest) if seq <= highest => self.seen |= 1 << (highest - seq),
+            Some(highest) => {
+                let shift = seq - highest;
+                self.seen = if shift >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.seen << shift
+                } | 1;
+                self.highest = Some(seq);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(seq);
+            }
+        }
+    }
+}
+
+// The relay's side of an association whose control connection has keys.
+// Plaintext never switches protection off again, so a spoofed datagram
+// cannot downgrade the association.
+pub(crate) struct RelayProtection {
+    cipher: DatagramCipher,
+    protected: AtomicBool,
+}
+
+impl RelayProtection {
+    pub(crate) fn new(keys: &UdpKeys, required: bool) -> Self {
+        RelayProtection {
+            cipher: keys.relay_cipher(),
+            protected: AtomicBool::new(required),
+        }
+    }
+
+    pub(crate) fn is_protected(&self) -> bool {
+        self.protected.load(Ordering::Relaxed)
+    }
+
+    // A datagram from the client with the protection removed
+    pub(crate) fn open<'a>(&self, packet: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        if self.is_protected() {
+            return self.cipher.open(packet).map(Cow::Owned);
+        }
+        match self.cipher.open(packet) {
+            Ok(datagram) => {
+                self.protected.store(true, Ordering::Relaxed);
+                Ok(Cow::Owned(datagram))
+            }
+            Err(_) => Ok(Cow::Borrowed(packet)),
+        }
+    }
+
+    // A datagram for the client, protected if the client protects its own
+    pub(crate) fn seal(&self, datagram: Vec<u8>) -> io::Result<Vec<u8>> {
+        if self.is_protected() {
+            self.cipher.seal(&datagram)
+        } else {
+            Ok(datagram)
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// UDP through a SOCKS5 proxy (RFC 1928 section 7).
+//
+// `Client::udp_associate` sets up the association over a TCP control
+// connection, and the proxy relays datagrams for as long as that connection
+// stays open. `SocksUdpSocket` owns it: a background task watches it and
+// marks the socket closed when the proxy hangs up, and dropping the socket
+// closes it. Every datagram to and from the relay carries a small header
+// naming the remote end, and with the `udp-aead` feature may be sealed with
+// keys from a TLS control connection (see `udp_aead`).
+
+use std::borrow::Cow;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use crate::client::SocksStream;
+use crate::protocol::SocksAddr;
+#[cfg(feature = "udp-aead")]
+use crate::udp_aead::{DatagramCipher, UdpKeys};
+
+// RSV (2 bytes), FRAG, then the address
+const HEADER_PREFIX: [u8; 3] = [0, 0, 0];
+
+struct Inner {
+    socket: UdpSocket,
+    // Where datagrams go; follows the relay if it answers from another port
+    relay: Mutex<SocketAddr>,
+    closed: CancellationToken,
+    #[cfg(feature = "udp-aead")]
+    cipher: std::sync::OnceLock<DatagramCipher>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // Stops the control task, which closes the association
+        self.closed.cancel();
+    }
+}
+
+/// A UDP socket whose datagrams go through the proxy's relay.
+pub struct SocksUdpSocket {
+    inner: Arc<Inner>,
+}
+
+/// Sending half of a `SocksUdpSocket`, from `split`.
+pub struct SocksUdpSendHalf {
+    inner: Arc<Inner>,
+}
+
+/// Receiving half of a `SocksUdpSocket`, from `split`.
+pub struct SocksUdpRecvHalf {
+    inner: Arc<Inner>,
+}
+
+impl SocksUdpSocket {
+    // `proxy` is the address the control connection reached
+    pub(crate) async fn new<S>(control: SocksStream<S>, proxy: SocketAddr) -> io::Result<Self>
+    where
+        S: AsyncRead + Unpin + Send + 'static,
+    {
+        let relay = match control.bound_addr() {
+            SocksAddr::Domain(host, port) => tokio::net::lookup_host((host.as_str(), *port))
+                .await?
+                .next()
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Could not resolve UDP relay {}", host),
+                    )
+                })?,
+            addr => addr.to_socket_addr().expect("IP address"),
+        };
+        // A relay on "any address" means the proxy's own address
+        let relay = if relay.ip().is_unspecified() {
+            SocketAddr::new(proxy.ip(), relay.port())
+        } else {
+            relay
+        };
+
+        let local: SocketAddr = if relay.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        debug!("UDP association through relay {}", relay);
+
+        let closed = CancellationToken::new();
+        tokio::spawn(watch_control(control.into_inner(), closed.clone()));
+        Ok(SocksUdpSocket {
+            inner: Arc::new(Inner {
+                socket,
+                relay: Mutex::new(relay),
+                closed,
+                #[cfg(feature = "udp-aead")]
+                cipher: std::sync::OnceLock::new(),
+            }),
+        })
+    }
+
+    // Seal datagrams to the relay with `keys` and accept only sealed ones
+    // from it; set before the socket is handed out
+    #[cfg(feature = "udp-aead")]
+    pub(crate) fn protect(&self, keys: &UdpKeys) {
+        let _ = self.inner.cipher.set(keys.client_cipher());
+    }
+
+    /// Send `buf` to `target` through the relay. Domain targets are
+    /// resolved by the proxy; internationalized names are sent as punycode
+    /// with the `idna` feature.
+    pub async fn send_to(&self, buf: &[u8], target: &SocksAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, target).await
+    }
+
+    /// Receive the next datagram, with the address it came from.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocksAddr)> {
+        self.inner.recv_from(buf).await
+    }
+
+    /// The relay datagrams are currently sent to.
+    pub fn relay_addr(&self) -> SocketAddr {
+        *self.inner.relay.lock().unwrap()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    /// Whether the proxy has closed the association.
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.is_cancelled()
+    }
+
+    /// Wait until the proxy closes the association.
+    pub async fn closed(&self) {
+        self.inner.closed.cancelled().await
+    }
+
+    /// Separate halves for sending and receiving from different tasks.
+    /// The association lasts until both are dropped.
+    pub fn split(self) -> (SocksUdpSendHalf, SocksUdpRecvHalf) {
+        let send = SocksUdpSendHalf {
+            inner: Arc::clone(&self.inner),
+        };
+        (send, SocksUdpRecvHalf { inner: self.inner })
+    }
+}
+
+impl SocksUdpSendHalf {
+    pub async fn send_to(&self, buf: &[u8], target: &SocksAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, target).await
+    }
+}
+
+impl SocksUdpRecvHalf {
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocksAddr)> {
+        self.inner.recv_from(buf).await
+    }
+}
+
+impl Inner {
+    fn check_open(&self) -> io::Result<()> {
+        if self.closed.is_cancelled() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "UDP association closed by the proxy",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn send_to(&self, buf: &[u8], target: &SocksAddr) -> io::Result<usize> {
+        self.check_open()?;
+        let mut datagram = HEADER_PREFIX.to_vec();
+        target.clone().into_ascii()?.write_to(&mut datagram).await?;
+        datagram.extend_from_slice(buf);
+        #[cfg(feature = "udp-aead")]
+        if let Some(cipher) = self.cipher.get() {
+            datagram = cipher.seal(&datagram)?;
+        }
+
+        let relay = *self.relay.lock().unwrap();
+        self.socket.send_to(&datagram, relay).await?;
+        Ok(buf.len())
+    }
+
+    // A datagram from the relay without its protection, if it opens
+    fn unprotect<'a>(&self, datagram: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        #[cfg(feature = "udp-aead")]
+        if let Some(cipher) = self.cipher.get() {
+            return match cipher.open(datagram) {
+                Ok(opened) => Some(Cow::Owned(opened)),
+                Err(e) => {
+                    debug!("Dropping UDP datagram from the relay: {}", e);
+                    None
+                }
+            };
+        }
+        Some(Cow::Borrowed(datagram))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocksAddr)> {
+        // Room for the largest header (a 255-byte domain) and sealing on
+        // top of `buf`
+        let mut datagram = vec![0u8; buf.len() + 262 + 24];
+        loop {
+            self.check_open()?;
+            let (len, from) = tokio::select! {
+                result = self.socket.recv_from(&mut datagram) => result?,
+                _ = self.closed.cancelled() => continue,
+            };
+
+            // Only the relay may send to us; it may move to another port
+            let relay = *self.relay.lock().unwrap();
+            if from.ip() != relay.ip() {
+                debug!("Dropping UDP datagram from {}, not the relay", from);
+                continue;
+            }
+
+            let Some(opened) = self.unprotect(&datagram[..len]) else {
+                continue;
+            };
+            let mut packet = &opened[..];
+            if packet.len() < HEADER_PREFIX.len() || packet[2] != 0 {
+                // Truncated, or a fragment, which we do not reassemble
+                debug!("Dropping malformed or fragmented UDP datagram");
+                continue;
+            }
+            packet = &packet[HEADER_PREFIX.len()..];
+            let Ok(source) = SocksAddr::read_from(&mut packet).await else {
+                debug!("Dropping UDP datagram with a bad address");
+                continue;
+            };
+            if from != relay {
+                debug!("UDP relay moved from {} to {}", relay, from);
+                *self.relay.lock().unwrap() = from;
+            }
+
+            let n = packet.len().min(buf.len());
+            buf[..n].copy_from_slice(&packet[..n]);
+            return Ok((n, source));
+        }
+    }
+}
+
+// The association ends when the proxy closes the control connection, or
+// when we drop it after the socket goes away
+async fn watch_control<S: AsyncRead + Unpin>(mut control: S, closed: CancellationToken) {
+    let mut buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            result = control.read(&mut buf) => match result {
+                Ok(0) | Err(_) => break,
+                // Nothing is expected here after the reply
+                Ok(_) => continue,
+            },
+            _ = closed.cancelled() => break,
+        }
+    }
+    debug!("UDP association control connection closed");
+    closed.cancel();
+}
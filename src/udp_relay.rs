@@ -0,0 +1,828 @@
+// UDP ASSOCIATE on the server (RFC 1928 section 7).
+//
+// Each association gets a relay socket of its own, bound when the request is
+// accepted and named in the reply. Datagrams from the client carry a header
+// naming their destination; the relay strips it and sends the payload on from
+// the same socket, and wraps whatever comes back in a header naming the
+// sender. `UdpAssociations` tracks every live association: which client
+// endpoint uses which relay socket, when each last carried a datagram, and
+// server-wide counters. An association ends, and its socket is closed, when
+// the client closes the TCP control connection, when it has been idle for
+// `UdpRelayOptions::idle_timeout`, or when the server shuts down.
+//
+// Clients may split a datagram into fragments (a non-zero FRAG field). Each
+// association has one reassembly queue, as RFC 1928 describes: fragments
+// with rising positions are collected until the one marked last arrives, and
+// the queue starts over when a lower position arrives, when the destination
+// changes or when the reassembly timer runs out. With `FragmentPolicy::Drop`
+// fragments are dropped instead, and counted, so a client that fragments is
+// easy to spot.
+//
+// So the relay cannot be used as a reflector, it only takes datagrams from
+// the client endpoint named in the request (its IP defaulting to the control
+// connection's and its port, if 0, learned from the first datagram), only
+// passes on replies from destinations the client has sent to, and caps the
+// rate of replies per association. Everything else is dropped and counted.
+//
+// With the `udp-aead` feature, datagrams of associations requested over TLS
+// can be sealed with keys from the control connection (see `udp_aead`).
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use crate::protocol::SocksAddr;
+use crate::ratelimit::RateLimiter;
+use crate::relay::{Direction, RelayCounters};
+use crate::resolver::Resolver;
+#[cfg(feature = "udp-aead")]
+use crate::udp_aead::{RelayProtection, UdpKeys};
+
+/// Default for `UdpRelayOptions::idle_timeout`.
+pub const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Default for `UdpRelayOptions::max_associations`.
+pub const DEFAULT_MAX_UDP_ASSOCIATIONS: usize = 1024;
+
+/// Default for `UdpRelayOptions::max_reply_rate`, in bytes per second.
+pub const DEFAULT_UDP_REPLY_RATE: u64 = 8 * 1024 * 1024;
+
+/// Default for `UdpRelayOptions::max_total_reply_rate`, in bytes per second.
+pub const DEFAULT_UDP_TOTAL_REPLY_RATE: u64 = 64 * 1024 * 1024;
+
+// Destinations remembered per association for filtering replies
+const MAX_CONTACTED: usize = 1024;
+
+// Longest a datagram's domain lookup may hold up its association, which
+// handles one datagram at a time
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Largest UDP payload, plus room for a header naming a 255-byte domain
+const DATAGRAM_BUFFER_SIZE: usize = 65535 + 262;
+
+// RSV (2 bytes), FRAG, then the address
+const HEADER_PREFIX: [u8; 3] = [0, 0, 0];
+
+// High bit of FRAG: the last fragment of a datagram
+const FRAG_END: u8 = 0x80;
+
+/// Default reassembly timer of `FragmentPolicy::Reassemble`; RFC 1928 asks
+/// for at least 5 seconds.
+pub const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the relay does with fragmented datagrams from clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentPolicy {
+    /// Drop every fragment, counting it in
+    /// `UdpRelayStats::fragments_dropped`.
+    Drop,
+    /// Reassemble fragments into datagrams of up to `max_size` payload
+    /// bytes, abandoning a datagram whose fragments take longer than
+    /// `timeout` to arrive.
+    Reassemble { timeout: Duration, max_size: usize },
+}
+
+impl Default for FragmentPolicy {
+    fn default() -> Self {
+        FragmentPolicy::Reassemble {
+            timeout: DEFAULT_REASSEMBLY_TIMEOUT,
+            // Largest UDP payload over IPv4
+            max_size: 65507,
+        }
+    }
+}
+
+/// Settings for the UDP relay (`ServerOptions::udp_relay`). Without them,
+/// UDP ASSOCIATE requests are refused as unsupported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpRelayOptions {
+    /// Address relay sockets bind to, each on a port of its own. The
+    /// default, `0.0.0.0`, is sent to clients as is, and they use the
+    /// proxy's address in its place.
+    pub bind_ip: IpAddr,
+    /// End associations that have carried no datagram in either direction
+    /// for this long.
+    pub idle_timeout: Duration,
+    /// Associations allowed at once across the server. Requests beyond the
+    /// cap are refused with a general failure.
+    pub max_associations: usize,
+    pub fragments: FragmentPolicy,
+    /// Bytes per second of replies relayed to each client, with a burst of
+    /// one second. Replies over the rate are dropped, not delayed.
+    pub max_reply_rate: Option<u64>,
+    /// Bytes per second of replies relayed across all associations, with a
+    /// burst of one second, so that many associations together cannot
+    /// flood clients either. Replies over the rate are dropped.
+    pub max_total_reply_rate: Option<u64>,
+    /// Relay datagrams from any sender to the client, not only replies from
+    /// destinations the client has sent to. Some peer-to-peer protocols need
+    /// this; it lets anyone who learns the relay's port reach the client.
+    pub unsolicited_replies: bool,
+    /// Refuse UDP ASSOCIATE on control connections that have no keys for
+    /// protecting datagrams (plain TCP, or a TLS backend without exporters),
+    /// and drop every datagram that does not open with the keys.
+    #[cfg(feature = "udp-aead")]
+    pub require_encryption: bool,
+}
+
+impl Default for UdpRelayOptions {
+    fn default() -> Self {
+        UdpRelayOptions {
+            bind_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            idle_timeout: DEFAULT_UDP_IDLE_TIMEOUT,
+            max_associations: DEFAULT_MAX_UDP_ASSOCIATIONS,
+            fragments: FragmentPolicy::default(),
+            max_reply_rate: Some(DEFAULT_UDP_REPLY_RATE),
+            max_total_reply_rate: Some(DEFAULT_UDP_TOTAL_REPLY_RATE),
+            unsolicited_replies: false,
+            #[cfg(feature = "udp-aead")]
+            require_encryption: false,
+        }
+    }
+}
+
+impl UdpRelayOptions {
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if self.idle_timeout.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp_relay.idle_timeout must not be zero",
+            ));
+        }
+        if self.max_associations == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp_relay.max_associations must be at least 1",
+            ));
+        }
+        if let FragmentPolicy::Reassemble { timeout, max_size } = self.fragments
+            && (timeout.is_zero() || max_size == 0)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp_relay.fragments timeout and max_size must not be zero",
+            ));
+        }
+        if self.max_reply_rate == Some(0) || self.max_total_reply_rate == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "udp_relay.max_reply_rate and max_total_reply_rate must not be zero",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the UDP relay's counters since the server was created.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UdpRelayStats {
+    pub active_associations: u64,
+    /// Associations set up, including ones still active.
+    pub total_associations: u64,
+    /// Requests refused because `max_associations` were active.
+    pub rejected_associations: u64,
+    /// Associations ended by the idle timeout.
+    pub expired_associations: u64,
+    /// Datagrams relayed from clients to destinations.
+    pub datagrams_uploaded: u64,
+    /// Datagrams relayed from destinations back to clients.
+    pub datagrams_downloaded: u64,
+    /// Datagrams dropped: malformed, from unexpected senders, to denied or
+    /// unresolvable destinations, or failing to send. Fragments are counted
+    /// separately.
+    pub datagrams_dropped: u64,
+    /// Fragments received from clients.
+    pub fragments_received: u64,
+    /// Fragments dropped by `FragmentPolicy::Drop`, or abandoned in an
+    /// incomplete or oversized datagram.
+    pub fragments_dropped: u64,
+    /// Datagrams put back together from fragments.
+    pub datagrams_reassembled: u64,
+    /// Datagrams dropped for coming from neither the client endpoint nor a
+    /// destination the client has sent to.
+    pub datagrams_rejected: u64,
+    /// Replies dropped by `max_reply_rate` or `max_total_reply_rate`.
+    pub replies_rate_limited: u64,
+}
+
+/// One live association, as listed by `UdpAssociations::list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssociationInfo {
+    /// The client endpoint datagrams are accepted from, once its first
+    /// datagram has arrived.
+    pub client: Option<SocketAddr>,
+    /// The relay socket serving the association.
+    pub relay: SocketAddr,
+    pub username: Option<String>,
+    /// Time since the last datagram in either direction, or since the
+    /// association was set up.
+    pub idle: Duration,
+}
+
+/// The server's live UDP associations and the relay's counters, from
+/// `Server::udp_associations`.
+#[derive(Debug)]
+pub struct UdpAssociations {
+    options: UdpRelayOptions,
+    entries: Mutex<HashMap<SocketAddr, Arc<Entry>>>,
+    total: AtomicU64,
+    rejected: AtomicU64,
+    expired: AtomicU64,
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    dropped: AtomicU64,
+    fragments: AtomicU64,
+    fragments_dropped: AtomicU64,
+    reassembled: AtomicU64,
+    rejected_datagrams: AtomicU64,
+    rate_limited: AtomicU64,
+    // Shared by every association's replies
+    reply_limiter: Option<RateLimiter>,
+}
+
+// What the table knows about one association; updated by its relay task
+#[derive(Debug)]
+struct Entry {
+    client: Mutex<Option<SocketAddr>>,
+    username: Option<String>,
+    last_active: Mutex<Instant>,
+}
+
+impl Entry {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn idle(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+}
+
+impl UdpAssociations {
+    pub(crate) fn new(options: UdpRelayOptions) -> Self {
+        UdpAssociations {
+            entries: Mutex::default(),
+            total: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            fragments: AtomicU64::new(0),
+            fragments_dropped: AtomicU64::new(0),
+            reassembled: AtomicU64::new(0),
+            rejected_datagrams: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            reply_limiter: options.max_total_reply_rate.map(RateLimiter::new),
+            options,
+        }
+    }
+
+    pub fn options(&self) -> &UdpRelayOptions {
+        &self.options
+    }
+
+    /// Every live association.
+    pub fn list(&self) -> Vec<AssociationInfo> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(relay, entry)| AssociationInfo {
+                client: *entry.client.lock().unwrap(),
+                relay: *relay,
+                username: entry.username.clone(),
+                idle: entry.idle(),
+            })
+            .collect()
+    }
+
+    /// The relay socket serving the client endpoint `client`, if any.
+    pub fn relay_for(&self, client: SocketAddr) -> Option<SocketAddr> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, entry)| *entry.client.lock().unwrap() == Some(client))
+            .map(|(relay, _)| *relay)
+    }
+
+    pub fn stats(&self) -> UdpRelayStats {
+        UdpRelayStats {
+            active_associations: self.entries.lock().unwrap().len() as u64,
+            total_associations: self.total.load(Ordering::Relaxed),
+            rejected_associations: self.rejected.load(Ordering::Relaxed),
+            expired_associations: self.expired.load(Ordering::Relaxed),
+            datagrams_uploaded: self.uploaded.load(Ordering::Relaxed),
+            datagrams_downloaded: self.downloaded.load(Ordering::Relaxed),
+            datagrams_dropped: self.dropped.load(Ordering::Relaxed),
+            fragments_received: self.fragments.load(Ordering::Relaxed),
+            fragments_dropped: self.fragments_dropped.load(Ordering::Relaxed),
+            datagrams_reassembled: self.reassembled.load(Ordering::Relaxed),
+            datagrams_rejected: self.rejected_datagrams.load(Ordering::Relaxed),
+            replies_rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Bind a relay socket for a new association, unless `max_associations`
+    /// are already active (`ErrorKind::QuotaExceeded`). `declared` is the
+    /// address the client said it would send from and `peer` the control
+    /// connection's address, if known. Datagrams are only taken from the
+    /// peer's IP: one in `declared` is used only when the peer is unknown.
+    /// Port 0 in `declared` stands for whichever port the first datagram
+    /// comes from.
+    pub(crate) async fn open(
+        self: &Arc<Self>,
+        declared: &SocksAddr,
+        peer: Option<SocketAddr>,
+        username: Option<String>,
+    ) -> io::Result<Association> {
+        if self.entries.lock().unwrap().len() >= self.options.max_associations {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::new(
+                io::ErrorKind::QuotaExceeded,
+                "Too many UDP associations",
+            ));
+        }
+        let socket = UdpSocket::bind(SocketAddr::new(self.options.bind_ip, 0)).await?;
+        let relay = socket.local_addr()?;
+
+        // Trusting another IP would let a client aim replies at a third
+        // party; clients behind NAT cannot know their public IP anyway
+        let declared_ip = declared
+            .to_socket_addr()
+            .map(|addr| addr.ip().to_canonical())
+            .filter(|ip| !ip.is_unspecified());
+        let peer_ip = peer.map(|addr| addr.ip().to_canonical());
+        if let (Some(declared_ip), Some(peer_ip)) = (declared_ip, peer_ip)
+            && declared_ip != peer_ip
+        {
+            debug!(
+                "Ignoring UDP client address {} declared by peer {}",
+                declared_ip, peer_ip
+            );
+        }
+        let client_ip = peer_ip.or(declared_ip);
+        let client_port = Some(declared.port()).filter(|&port| port != 0);
+        let client = match (client_ip, client_port) {
+            (Some(ip), Some(port)) => Some(SocketAddr::new(socket_ip(ip, relay), port)),
+            _ => None,
+        };
+
+        let entry = Arc::new(Entry {
+            client: Mutex::new(client),
+            username,
+            last_active: Mutex::new(Instant::now()),
+        });
+        {
+            // Checked again, as other associations may have opened meanwhile
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= self.options.max_associations {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(io::Error::new(
+                    io::ErrorKind::QuotaExceeded,
+                    "Too many UDP associations",
+                ));
+            }
+            entries.insert(relay, Arc::clone(&entry));
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+        debug!("UDP association opened on relay {}", relay);
+
+        Ok(Association {
+            table: Arc::clone(self),
+            socket,
+            relay,
+            entry,
+            client_ip,
+            client_port,
+            contacted: Mutex::default(),
+            reply_limiter: self.options.max_reply_rate.map(RateLimiter::new),
+            reassembly: Mutex::new(None),
+            warned_fragments: AtomicBool::new(false),
+            #[cfg(feature = "udp-aead")]
+            protection: None,
+        })
+    }
+
+    fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Why an association ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AssociationEnd {
+    ControlClosed,
+    Idle,
+    Cancelled,
+}
+
+// A registered association and its relay socket; leaving the table when
+// dropped closes the socket
+pub(crate) struct Association {
+    table: Arc<UdpAssociations>,
+    socket: UdpSocket,
+    relay: SocketAddr,
+    entry: Arc<Entry>,
+    // What is known of the client endpoint before its first datagram
+    client_ip: Option<IpAddr>,
+    client_port: Option<u16>,
+    // Destinations the client has sent to, and when it last did
+    contacted: Mutex<HashMap<SocketAddr, Instant>>,
+    reply_limiter: Option<RateLimiter>,
+    reassembly: Mutex<Option<Reassembly>>,
+    // Whether a dropped fragment has been logged at warning level yet
+    warned_fragments: AtomicBool,
+    #[cfg(feature = "udp-aead")]
+    protection: Option<RelayProtection>,
+}
+
+// The fragments of one datagram received so far
+struct Reassembly {
+    target: SocksAddr,
+    payload: Vec<u8>,
+    // Position of the latest fragment
+    position: u8,
+    fragments: u64,
+    started: Instant,
+}
+
+impl Drop for Association {
+    fn drop(&mut self) {
+        self.table.entries.lock().unwrap().remove(&self.relay);
+        debug!("UDP association on relay {} closed", self.relay);
+    }
+}
+
+impl Association {
+    pub(crate) fn relay_addr(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /// Seal datagrams between client and relay with `keys`, once the client
+    /// does, or from the start with `require_encryption`.
+    #[cfg(feature = "udp-aead")]
+    pub(crate) fn with_protection(mut self, keys: &UdpKeys) -> Self {
+        let required = self.table.options.require_encryption;
+        self.protection = Some(RelayProtection::new(keys, required));
+        self
+    }
+
+    /// Relay datagrams until the control connection closes, the association
+    /// idles out or `cancel` fires. Datagrams to destinations `allows`
+    /// refuses are dropped: it is asked about the destination without an
+    /// address first, then about the address a domain resolves to.
+    pub(crate) async fn run<C, F>(
+        &self,
+        control: &mut C,
+        allows: F,
+        resolver: &dyn Resolver,
+        counters: &RelayCounters,
+        cancel: CancellationToken,
+    ) -> AssociationEnd
+    where
+        C: AsyncRead + Unpin,
+        F: Fn(&SocksAddr, Option<IpAddr>) -> bool,
+    {
+        let idle_timeout = self.table.options.idle_timeout;
+        let mut buf = vec![0u8; DATAGRAM_BUFFER_SIZE];
+        let mut control_buf = [0u8; 64];
+        loop {
+            let deadline = *self.entry.last_active.lock().unwrap() + idle_timeout;
+            tokio::select! {
+                read = control.read(&mut control_buf) => match read {
+                    Ok(0) | Err(_) => return AssociationEnd::ControlClosed,
+                    // Nothing is expected after the request; ignore it
+                    Ok(_) => {}
+                },
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => {
+                        self.datagram(&buf[..len], from, &allows, resolver, counters)
+                            .await;
+                    }
+                    // E.g. ICMP port unreachable reported for an earlier send
+                    Err(e) => debug!("UDP relay {} receive error: {}", self.relay, e),
+                },
+                _ = tokio::time::sleep_until(deadline.into()) => {
+                    if self.entry.idle() >= idle_timeout {
+                        self.table.expired.fetch_add(1, Ordering::Relaxed);
+                        return AssociationEnd::Idle;
+                    }
+                }
+                _ = cancel.cancelled() => return AssociationEnd::Cancelled,
+            }
+        }
+    }
+
+    async fn datagram<F>(
+        &self,
+        datagram: &[u8],
+        from: SocketAddr,
+        allows: &F,
+        resolver: &dyn Resolver,
+        counters: &RelayCounters,
+    ) where
+        F: Fn(&SocksAddr, Option<IpAddr>) -> bool,
+    {
+        let client = *self.entry.client.lock().unwrap();
+        let from_client = match client {
+            Some(client) => from == client,
+            // Until the first datagram from the client establishes its
+            // endpoint, anything matching what the request declared
+            None => {
+                self.client_ip
+                    .is_none_or(|ip| ip == from.ip().to_canonical())
+                    && self.client_port.is_none_or(|port| port == from.port())
+            }
+        };
+        let result = match client {
+            _ if from_client => {
+                self.upload(datagram, from, allows, resolver, counters)
+                    .await
+            }
+            Some(client) if self.is_reply(from) => {
+                self.download(datagram, from, client, counters).await
+            }
+            _ => {
+                debug!("Rejecting UDP datagram from unexpected sender {}", from);
+                self.table
+                    .rejected_datagrams
+                    .fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        if let Err(e) = result {
+            debug!("Dropping UDP datagram from {}: {}", from, e);
+            self.table.dropped();
+        }
+    }
+
+    // Client -> destination
+    async fn upload<F>(
+        &self,
+        datagram: &[u8],
+        from: SocketAddr,
+        allows: &F,
+        resolver: &dyn Resolver,
+        counters: &RelayCounters,
+    ) -> io::Result<()>
+    where
+        F: Fn(&SocksAddr, Option<IpAddr>) -> bool,
+    {
+        let datagram = self.unprotect(datagram)?;
+        let (frag, target, payload) = parse_header(&datagram).await?;
+        let (target, payload) = match frag {
+            0 => (target, Cow::Borrowed(payload)),
+            _ => match self.fragment(frag, target, payload) {
+                Some((target, payload)) => (target, Cow::Owned(payload)),
+                None => return Ok(()),
+            },
+        };
+        let denied = || {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Destination {} denied", target),
+            )
+        };
+        if !allows(&target, None) {
+            return Err(denied());
+        }
+        let resolved = self.resolve(&target, resolver).await?;
+        if !allows(&target, Some(resolved.ip().to_canonical())) {
+            return Err(denied());
+        }
+
+        {
+            let mut client = self.entry.client.lock().unwrap();
+            if client.is_none() {
+                debug!(
+                    "UDP association on relay {} serves client {}",
+                    self.relay, from
+                );
+                *client = Some(from);
+            }
+        }
+
+        self.socket.send_to(&payload, resolved).await?;
+        self.contacted(resolved);
+        self.entry.touch();
+        counters.add(Direction::Upload, payload.len() as u64);
+        self.table.uploaded.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Queue a fragment; the whole datagram once its last fragment is in
+    fn fragment(
+        &self,
+        frag: u8,
+        target: SocksAddr,
+        payload: &[u8],
+    ) -> Option<(SocksAddr, Vec<u8>)> {
+        let table = &self.table;
+        table.fragments.fetch_add(1, Ordering::Relaxed);
+        let FragmentPolicy::Reassemble { timeout, max_size } = table.options.fragments else {
+            self.drop_fragments(1, "fragments are not accepted");
+            return None;
+        };
+        let position = frag & !FRAG_END;
+        if position == 0 {
+            self.drop_fragments(1, "invalid fragment position 0");
+            return None;
+        }
+
+        let mut queue = self.reassembly.lock().unwrap();
+        // Start over on a lower position, another destination or when the
+        // reassembly timer has run out
+        if let Some(pending) = queue.as_ref()
+            && (position <= pending.position
+                || pending.target != target
+                || pending.started.elapsed() >= timeout)
+        {
+            let abandoned = queue.take().expect("pending reassembly").fragments;
+            self.drop_fragments(abandoned, "incomplete datagram abandoned");
+        }
+        let pending = queue.get_or_insert_with(|| Reassembly {
+            target,
+            payload: Vec::new(),
+            position: 0,
+            fragments: 0,
+            started: Instant::now(),
+        });
+        pending.position = position;
+        pending.fragments += 1;
+        pending.payload.extend_from_slice(payload);
+        if pending.payload.len() > max_size {
+            let abandoned = queue.take().expect("pending reassembly").fragments;
+            self.drop_fragments(abandoned, "reassembled datagram too large");
+            return None;
+        }
+
+        if frag & FRAG_END == 0 {
+            return None;
+        }
+        let done = queue.take().expect("pending reassembly");
+        table.reassembled.fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "Reassembled a {}-byte datagram from {} fragments on relay {}",
+            done.payload.len(),
+            done.fragments,
+            self.relay
+        );
+        Some((done.target, done.payload))
+    }
+
+    // Count dropped fragments, warning about the first on this association
+    fn drop_fragments(&self, count: u64, reason: &str) {
+        self.table
+            .fragments_dropped
+            .fetch_add(count, Ordering::Relaxed);
+        if self.warned_fragments.swap(true, Ordering::Relaxed) {
+            debug!(
+                "Dropping {} UDP fragment(s) on relay {}: {}",
+                count, self.relay, reason
+            );
+        } else {
+            warn!(
+                "Dropping {} UDP fragment(s) on relay {}: {}; further drops are logged at debug level",
+                count, self.relay, reason
+            );
+        }
+    }
+
+    // Whether a datagram from `from` counts as a reply to the client
+    fn is_reply(&self, from: SocketAddr) -> bool {
+        self.table.options.unsolicited_replies || self.contacted.lock().unwrap().contains_key(&from)
+    }
+
+    // Remember that the client sent to `target`, forgetting destinations it
+    // has not sent to within the idle timeout once the list is full
+    fn contacted(&self, target: SocketAddr) {
+        let mut contacted = self.contacted.lock().unwrap();
+        if contacted.len() >= MAX_CONTACTED && !contacted.contains_key(&target) {
+            let idle_timeout = self.table.options.idle_timeout;
+            contacted.retain(|_, last| last.elapsed() < idle_timeout);
+            if contacted.len() >= MAX_CONTACTED
+                && let Some(oldest) = contacted
+                    .iter()
+                    .min_by_key(|(_, last)| **last)
+                    .map(|(addr, _)| *addr)
+            {
+                contacted.remove(&oldest);
+            }
+        }
+        contacted.insert(target, Instant::now());
+    }
+
+    // Destination -> client
+    async fn download(
+        &self,
+        payload: &[u8],
+        from: SocketAddr,
+        client: SocketAddr,
+        counters: &RelayCounters,
+    ) -> io::Result<()> {
+        let limiters = [&self.reply_limiter, &self.table.reply_limiter];
+        if !limiters
+            .into_iter()
+            .flatten()
+            .all(|limiter| limiter.try_consume(payload.len()))
+        {
+            self.table.rate_limited.fetch_add(1, Ordering::Relaxed);
+            debug!("Rate limiting UDP reply from {} to {}", from, client);
+            return Ok(());
+        }
+        let source = SocketAddr::new(from.ip().to_canonical(), from.port());
+        let mut datagram = HEADER_PREFIX.to_vec();
+        SocksAddr::from(source).write_to(&mut datagram).await?;
+        datagram.extend_from_slice(payload);
+        let datagram = self.protect(datagram)?;
+
+        self.socket.send_to(&datagram, client).await?;
+        self.entry.touch();
+        counters.add(Direction::Download, payload.len() as u64);
+        self.table.downloaded.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // A datagram from the client without its protection, if it has any
+    fn unprotect<'a>(&self, datagram: &'a [u8]) -> io::Result<Cow<'a, [u8]>> {
+        #[cfg(feature = "udp-aead")]
+        if let Some(protection) = &self.protection {
+            return protection.open(datagram);
+        }
+        Ok(Cow::Borrowed(datagram))
+    }
+
+    // A datagram for the client, protected if the association is
+    fn protect(&self, datagram: Vec<u8>) -> io::Result<Vec<u8>> {
+        #[cfg(feature = "udp-aead")]
+        if let Some(protection) = &self.protection {
+            return protection.seal(datagram);
+        }
+        Ok(datagram)
+    }
+
+    // Where to send datagrams for `target` from the relay socket
+    async fn resolve(&self, target: &SocksAddr, resolver: &dyn Resolver) -> io::Result<SocketAddr> {
+        let ipv4 = self.relay.is_ipv4();
+        let candidates = match target {
+            SocksAddr::Domain(domain, _) => {
+                let lookup = tokio::time::timeout(RESOLVE_TIMEOUT, resolver.resolve(domain));
+                match lookup.await {
+                    Ok(resolved) => resolved?.addrs,
+                    Err(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("Resolving {} timed out", domain),
+                        ));
+                    }
+                }
+            }
+            addr => vec![addr.to_socket_addr().expect("IP address").ip()],
+        };
+        let ip = candidates
+            .into_iter()
+            .map(|ip| ip.to_canonical())
+            .find(|ip| ip.is_ipv4() || !ipv4)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NetworkUnreachable,
+                    format!("No IPv4 address for {}", target),
+                )
+            })?;
+        Ok(SocketAddr::new(socket_ip(ip, self.relay), target.port()))
+    }
+}
+
+// `ip` as the relay socket bound to `relay` sends to and receives from it: an
+// IPv6 socket reaches IPv4 hosts through mapped addresses
+fn socket_ip(ip: IpAddr, relay: SocketAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) if relay.is_ipv6() => IpAddr::V6(v4.to_ipv6_mapped()),
+        ip => ip,
+    }
+}
+
+// The FRAG field, destination and payload of a datagram from the client
+async fn parse_header(datagram: &[u8]) -> io::Result<(u8, SocksAddr, &[u8])> {
+    if datagram.len() < HEADER_PREFIX.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Truncated UDP header",
+        ));
+    }
+    let frag = datagram[2];
+    let mut rest = &datagram[HEADER_PREFIX.len()..];
+    let target = SocksAddr::read_from(&mut rest).await?;
+    Ok((frag, target, rest))
+}
@@ -0,0 +1,122 @@
+// SOCKS5 over Unix domain sockets, so local applications can reach the proxy
+// without a TCP port being opened.
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+use log::{debug, error, info};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::accept::Backoff;
+use crate::client::{Client, SocksStream};
+use crate::protocol::SocksAddr;
+use crate::server::{PeerInfo, Server};
+
+impl Server {
+    /// Serve SOCKS5 on an already bound Unix socket listener.
+    pub async fn run_with_unix_listener(&self, listener: UnixListener) -> io::Result<()> {
+        info!(
+            "SOCKS5 server listening on unix:{}",
+            listener
+                .local_addr()?
+                .as_pathname()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        );
+        let _listening = self.drain().listen();
+        let mut backoff = Backoff::new();
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.drain().stopped() => return Ok(()),
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    backoff.reset();
+                    info!("New connection on Unix socket");
+                    let server = self.clone();
+
+                    tokio::spawn(self.drain().track(async move {
+                        if let Err(e) = server.handle_connection(stream, PeerInfo::default()).await
+                        {
+                            error!("Error handling client: {}", e);
+                        }
+                    }));
+                }
+                Err(e) => {
+                    self.accept_errors()
+                        .recover(&e, &mut backoff, || listener.accept())
+                        .await;
+                }
+            }
+        }
+    }
+}
+
+/// Bind a Unix socket at `path`, replacing a stale socket file left by a
+/// previous run, and apply `mode` to it if given.
+///
+/// With a mode, the socket is bound in a private directory next to `path`
+/// and only renamed into place once it has its mode, so nobody can connect
+/// while it still has the umask's permissions.
+pub(crate) fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let Some(mode) = mode else {
+        return UnixListener::bind(path);
+    };
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a socket path", path.display()),
+        )
+    })?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}.bind",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let staged = staging.join(name);
+    // Left over from a run of the same pid that died mid-bind
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    DirBuilder::new().mode(0o700).create(&staging)?;
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    result
+}
+
+impl Client {
+    /// Connect to `target` through a proxy listening on the Unix socket at
+    /// `path`, authenticating with this client's credentials. The proxy's
+    /// TCP address is not used.
+    pub async fn connect_unix<P: AsRef<Path>>(
+        &self,
+        path: P,
+        target: SocksAddr,
+    ) -> io::Result<SocksStream<UnixStream>> {
+        let stream = UnixStream::connect(path.as_ref()).await?;
+        debug!(
+            "Connected to SOCKS5 proxy at unix:{}",
+            path.as_ref().display()
+        );
+
+        self.connect_with_stream(stream, target).await
+    }
+}
@@ -0,0 +1,572 @@
+// Load balancing across upstream proxies.
+//
+// `UpstreamPool` is a `Dialer` that spreads CONNECTs over several upstream
+// SOCKS5 proxies, so egress capacity grows by adding proxies. Each tunnel
+// picks one upstream by the pool's strategy and keeps it for its lifetime;
+// the pool counts the tunnels open through every upstream until their
+// streams are dropped.
+//
+// Upstreams that fail several probes (or dials) in a row leave the rotation
+// until probes succeed again, or until a dial tried after a cooldown gets
+// through, so pools without health checks recover too. A tunnel whose
+// upstream cannot be reached moves on to the next healthy one, and finally
+// to the fallback dialer if the pool has one, or else to the upstreams that
+// are down.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::JoinSet;
+
+use crate::client::{Client, ConnectError, ConnectPhase};
+use crate::dialer::{Dialer, Outbound, ProxyStream, UpstreamDialer};
+use crate::protocol::SocksAddr;
+
+/// How `UpstreamPool` picks the upstream for a tunnel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each upstream in turn, ignoring weights.
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest open tunnels relative to its weight.
+    LeastConnections,
+    /// In turn, in proportion to the weights (smooth weighted round-robin).
+    Weighted,
+    /// Always the same upstream for the same destination host, spread in
+    /// proportion to the weights (rendezvous hashing). Sites that tie
+    /// sessions to the client address see a single egress address.
+    StickyByDestination,
+}
+
+/// An upstream proxy in an `UpstreamPool`.
+#[derive(Clone)]
+pub struct Upstream {
+    pub client: Client,
+    /// Share of the traffic relative to the other upstreams; must not be
+    /// zero. Defaults to 1.
+    pub weight: u32,
+}
+
+impl Upstream {
+    pub fn new(client: Client) -> Self {
+        Upstream { client, weight: 1 }
+    }
+
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+}
+
+impl From<Client> for Upstream {
+    fn from(client: Client) -> Self {
+        Upstream::new(client)
+    }
+}
+
+/// How `UpstreamPool` decides whether an upstream is healthy. A probe
+/// connects to the proxy and checks that it answers the SOCKS greeting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeOptions {
+    /// Probe every upstream this often in `run_health_checks`.
+    pub interval: Duration,
+    /// How long a probe may take, connect included.
+    pub timeout: Duration,
+    /// Failed probes, or dials that could not reach the upstream, in a row
+    /// before the upstream leaves the rotation.
+    pub unhealthy_after: u32,
+    /// Successful probes in a row before it rejoins.
+    pub healthy_after: u32,
+    /// How long an upstream out of the rotation waits before a dial tries
+    /// it again; a dial that gets through puts it back.
+    pub retry_after: Duration,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        ProbeOptions {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(3),
+            unhealthy_after: 3,
+            healthy_after: 2,
+            retry_after: Duration::from_secs(30),
+        }
+    }
+}
+
+/// An upstream's health and load, from `UpstreamPool::status`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamStatus {
+    /// `host:port` of the proxy.
+    pub addr: String,
+    /// Whether the upstream is in the rotation.
+    pub healthy: bool,
+    pub active_tunnels: usize,
+    /// Failed probes or dials since the last success.
+    pub failures: u32,
+    /// Times the upstream left the rotation.
+    pub times_down: u64,
+}
+
+/// Told whenever an upstream leaves or rejoins the rotation, e.g. to alert
+/// or to export metrics. Called from the probing or dialing task, so it
+/// should not block.
+pub trait HealthListener: Send + Sync {
+    fn on_change(&self, status: &UpstreamStatus);
+}
+
+/// Connects through one of several upstream SOCKS5 proxies.
+#[derive(Clone)]
+pub struct UpstreamPool {
+    inner: Arc<Inner>,
+    probe: ProbeOptions,
+    fallback: Option<Arc<dyn Dialer>>,
+    listener: Option<Arc<dyn HealthListener>>,
+}
+
+struct Inner {
+    strategy: Strategy,
+    members: Vec<Member>,
+    next: AtomicUsize,
+    // Smooth weighted round-robin state, one entry per member
+    current_weights: Mutex<Vec<i64>>,
+}
+
+struct Member {
+    client: Client,
+    dialer: UpstreamDialer,
+    weight: u32,
+    active: Arc<AtomicUsize>,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    down: bool,
+    failures: u32,
+    successes: u32,
+    times_down: u64,
+    // When a dial may next try the upstream while it is down
+    retry_at: Option<Instant>,
+}
+
+impl Member {
+    // In the rotation, or down with a retry due
+    fn is_available(&self) -> bool {
+        let health = self.health.lock().unwrap();
+        !health.down || health.retry_at.is_none_or(|at| at <= Instant::now())
+    }
+
+    // Take the due retry of a down upstream, so concurrent dials do not all
+    // pile onto it
+    fn claim_retry(&self, retry_after: Duration) -> bool {
+        let mut health = self.health.lock().unwrap();
+        let now = Instant::now();
+        match health.retry_at {
+            _ if !health.down => true,
+            Some(at) if at > now => false,
+            _ => {
+                health.retry_at = Some(now + retry_after);
+                true
+            }
+        }
+    }
+
+    fn status(&self) -> UpstreamStatus {
+        let health = self.health.lock().unwrap();
+        UpstreamStatus {
+            addr: self.client.proxy_label(),
+            healthy: !health.down,
+            active_tunnels: self.active.load(Ordering::Relaxed),
+            failures: health.failures,
+            times_down: health.times_down,
+        }
+    }
+}
+
+impl UpstreamPool {
+    /// Balance over `upstreams`; there must be at least one, and no weight
+    /// may be zero.
+    pub fn new(upstreams: Vec<Upstream>, strategy: Strategy) -> io::Result<Self> {
+        if upstreams.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "An upstream pool needs at least one upstream",
+            ));
+        }
+        if upstreams.iter().any(|upstream| upstream.weight == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Upstream weights must not be zero",
+            ));
+        }
+
+        let members: Vec<_> = upstreams
+            .into_iter()
+            .map(|upstream| Member {
+                dialer: UpstreamDialer::new(upstream.client.clone()),
+                client: upstream.client,
+                weight: upstream.weight,
+                active: Arc::new(AtomicUsize::new(0)),
+                health: Mutex::new(Health::default()),
+            })
+            .collect();
+        Ok(UpstreamPool {
+            inner: Arc::new(Inner {
+                strategy,
+                current_weights: Mutex::new(vec![0; members.len()]),
+                members,
+                next: AtomicUsize::new(0),
+            }),
+            probe: ProbeOptions::default(),
+            fallback: None,
+            listener: None,
+        })
+    }
+
+    pub fn with_probe_options(mut self, options: ProbeOptions) -> Self {
+        self.probe = options;
+        self
+    }
+
+    /// Dial with `dialer` (e.g. a `DirectDialer`) when no upstream is
+    /// healthy or none of them could be reached. Without a fallback those
+    /// connections fail.
+    pub fn with_fallback(mut self, dialer: Arc<dyn Dialer>) -> Self {
+        self.fallback = Some(dialer);
+        self
+    }
+
+    pub fn with_health_listener(mut self, listener: Arc<dyn HealthListener>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.inner.strategy
+    }
+
+    /// Health and load of each upstream, in the order the upstreams were
+    /// given.
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        self.inner.members.iter().map(Member::status).collect()
+    }
+
+    /// Probe every upstream once, concurrently, and update their health.
+    pub async fn check_health(&self) {
+        let mut probes = JoinSet::new();
+        for index in 0..self.inner.members.len() {
+            let pool = self.clone();
+            probes.spawn(async move {
+                let client = &pool.inner.members[index].client;
+                let result = tokio::time::timeout(pool.probe.timeout, client.probe())
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(io::ErrorKind::TimedOut, "Probe timed out"))
+                    });
+                pool.record(index, result.as_ref().err(), Source::Probe);
+            });
+        }
+        while probes.join_next().await.is_some() {}
+    }
+
+    /// Probe every probe interval, forever. Spawn it next to the server
+    /// using the pool.
+    pub async fn run_health_checks(&self) {
+        let mut ticks = tokio::time::interval(self.probe.interval);
+        loop {
+            ticks.tick().await;
+            self.check_health().await;
+        }
+    }
+
+    // Count a probe or dial outcome against an upstream, moving it in or out
+    // of the rotation once the threshold is reached. A tunnel through a down
+    // upstream is proof enough that it is back.
+    fn record(&self, index: usize, error: Option<&io::Error>, source: Source) {
+        let member = &self.inner.members[index];
+        let changed = {
+            let mut health = member.health.lock().unwrap();
+            match error {
+                None if health.down => {
+                    health.successes += 1;
+                    if source == Source::Dial || health.successes >= self.probe.healthy_after {
+                        health.down = false;
+                        health.failures = 0;
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => {
+                    health.failures = 0;
+                    false
+                }
+                Some(_) => {
+                    health.successes = 0;
+                    health.failures += 1;
+                    if health.down {
+                        health.retry_at = Some(Instant::now() + self.probe.retry_after);
+                        false
+                    } else if health.failures >= self.probe.unhealthy_after {
+                        health.down = true;
+                        health.times_down += 1;
+                        health.retry_at = Some(Instant::now() + self.probe.retry_after);
+                        true
+                    } else {
+                        false
+                    }
+                }
+            }
+        };
+        if !changed {
+            return;
+        }
+
+        let status = member.status();
+        match error {
+            Some(e) => warn!("Upstream {} left the rotation: {}", status.addr, e),
+            None => info!("Upstream {} rejoined the rotation", status.addr),
+        }
+        if let Some(listener) = &self.listener {
+            listener.on_change(&status);
+        }
+    }
+
+    /// Tunnels open (or being dialed) through each upstream, in the order
+    /// the upstreams were given.
+    pub fn active_tunnels(&self) -> Vec<usize> {
+        self.inner
+            .members
+            .iter()
+            .map(|member| member.active.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    // Index of the member to dial `target` through, among the eligible ones
+    fn pick(&self, target: &SocksAddr, eligible: impl Fn(usize) -> bool) -> Option<usize> {
+        let members = &self.inner.members;
+        match self.inner.strategy {
+            Strategy::RoundRobin => {
+                let candidates: Vec<_> = (0..members.len()).filter(|&i| eligible(i)).collect();
+                if candidates.is_empty() {
+                    return None;
+                }
+                let next = self.inner.next.fetch_add(1, Ordering::Relaxed);
+                Some(candidates[next % candidates.len()])
+            }
+            Strategy::LeastConnections => {
+                // Start the scan in turn so idle upstreams share the ties
+                let start = self.inner.next.fetch_add(1, Ordering::Relaxed);
+                (0..members.len())
+                    .map(|offset| (start + offset) % members.len())
+                    .filter(|&i| eligible(i))
+                    .min_by(|&a, &b| {
+                        let load = |index: usize| {
+                            let member = &members[index];
+                            member.active.load(Ordering::Relaxed) as u64 * u64::from(u32::MAX)
+                                / u64::from(member.weight)
+                        };
+                        load(a).cmp(&load(b))
+                    })
+            }
+            Strategy::Weighted => {
+                let mut current = self.inner.current_weights.lock().unwrap();
+                let mut total = 0;
+                for (index, member) in members.iter().enumerate() {
+                    if eligible(index) {
+                        current[index] += i64::from(member.weight);
+                        total += i64::from(member.weight);
+                    }
+                }
+                let best = (0..members.len())
+                    .filter(|&i| eligible(i))
+                    .max_by(|&a, &b| current[a].cmp(&current[b]).then(b.cmp(&a)))?;
+                current[best] -= total;
+                Some(best)
+            }
+            Strategy::StickyByDestination => {
+                let host = destination_host(target);
+                (0..members.len())
+                    .filter(|&i| eligible(i))
+                    .max_by(|&a, &b| {
+                        let score =
+                            |index: usize| rendezvous_score(&host, index, members[index].weight);
+                        score(a).total_cmp(&score(b))
+                    })
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for UpstreamPool {
+    async fn dial(&self, target: &SocksAddr) -> io::Result<Outbound> {
+        let members = &self.inner.members;
+        let mut tried = vec![false; members.len()];
+        let mut last_error = None;
+
+        loop {
+            let index = match self.pick(target, |i| !tried[i] && members[i].is_available()) {
+                Some(index) => index,
+                // With no fallback, upstreams that are down are better than
+                // failing outright
+                None if self.fallback.is_none() => match self.pick(target, |i| !tried[i]) {
+                    Some(index) => index,
+                    None => break,
+                },
+                None => break,
+            };
+            tried[index] = true;
+            let member = &members[index];
+            if !member.claim_retry(self.probe.retry_after) && self.fallback.is_some() {
+                continue;
+            }
+            debug!(
+                "Dialing {} through upstream {}",
+                target,
+                member.client.proxy_label()
+            );
+            let guard = ActiveGuard::new(&member.active);
+            match member.dialer.dial(target).await {
+                Ok(outbound) => {
+                    self.record(index, None, Source::Dial);
+                    return Ok(Outbound {
+                        stream: Box::new(Counted {
+                            stream: outbound.stream,
+                            _guard: guard,
+                        }),
+                        bound_addr: outbound.bound_addr,
+                    });
+                }
+                Err(e) if unreachable(&e) => {
+                    drop(guard);
+                    warn!(
+                        "Upstream {} unreachable, trying the next: {}",
+                        member.client.proxy_label(),
+                        e
+                    );
+                    self.record(index, Some(&e), Source::Dial);
+                    last_error = Some(e);
+                }
+                // The upstream answered; the target or the credentials failed
+                Err(e) => return Err(e),
+            }
+        }
+
+        if let Some(fallback) = &self.fallback {
+            debug!("No upstream available for {}, using the fallback", target);
+            return fallback.dial(target).await;
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NetworkUnreachable,
+                "No healthy upstream proxy",
+            )
+        }))
+    }
+}
+
+// What an outcome passed to `UpstreamPool::record` comes from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Probe,
+    Dial,
+}
+
+// Whether a dial failed for want of a working upstream rather than because
+// of the target, so another upstream may do better
+fn unreachable(error: &io::Error) -> bool {
+    ConnectError::of(error).is_some_and(|e| {
+        matches!(e.phase(), ConnectPhase::Connect | ConnectPhase::Greeting) && e.is_transient()
+    })
+}
+
+// The host part of a destination, so all ports of a site stick together
+fn destination_host(target: &SocksAddr) -> String {
+    match target {
+        SocksAddr::Ipv4(ip, _) => ip.to_string(),
+        SocksAddr::Ipv6(ip, _) => ip.to_string(),
+        SocksAddr::Domain(domain, _) => domain.to_ascii_lowercase(),
+    }
+}
+
+// Weighted rendezvous hashing: the member with the highest score wins, and a
+// member leaving only moves the hosts it had
+fn rendezvous_score(host: &str, index: usize, weight: u32) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    (host, index).hash(&mut hasher);
+    // Map the hash into (0, 1)
+    let unit = (hasher.finish() as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+    f64::from(weight) / -unit.ln()
+}
+
+// Counts a tunnel against its upstream until dropped
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard(Arc::clone(active))
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Counted {
+    stream: Box<dyn ProxyStream>,
+    _guard: ActiveGuard,
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
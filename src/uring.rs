@@ -0,0 +1,228 @@
+// io_uring accept loop and relay (Linux, `io-uring` feature).
+//
+// Connections are accepted and, once the SOCKS handshake is done, relayed
+// with io_uring operations on a tokio-uring runtime. The handshake, policy
+// checks and dialing run through the same code as the epoll server: the
+// runtime also drives a regular Tokio reactor, so accepted sockets are handed
+// to the protocol layer as ordinary Tokio streams and only the relay, where
+// the bulk of the syscalls happen, switches to io_uring.
+
+use std::io;
+use std::net::{Shutdown, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use log::{error, info};
+use socket2::SockRef;
+use tokio::task::JoinHandle;
+use tokio_uring::buf::BoundedBuf;
+
+use crate::accept::Backoff;
+use crate::ratelimit::RateLimiter;
+use crate::relay::{Direction, Progress, RelayLimits};
+use crate::server::{PeerInfo, Server};
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+
+tokio::task_local! {
+    // Set for connections accepted by `run_uring`, whose relays may use
+    // io_uring
+    static URING_TASK: ();
+}
+
+/// Whether the current task runs on the io_uring server.
+pub(crate) fn in_uring_task() -> bool {
+    URING_TASK.try_with(|_| ()).is_ok()
+}
+
+impl Server {
+    /// Serve SOCKS5 using io_uring for accepting and relaying. Starts its own
+    /// tokio-uring runtime and blocks until the listener fails or `shutdown`
+    /// is called, so it must not be called from within a Tokio runtime.
+    pub fn run_uring(&self) -> io::Result<()> {
+        let addr =
+            self.bind_addr().to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid bind address")
+            })?;
+
+        tokio_uring::start(async {
+            let listener = tokio_uring::net::TcpListener::bind(addr)?;
+            self.drop_privileges()?;
+            info!(
+                "SOCKS5 server listening on {} (io_uring)",
+                listener.local_addr()?
+            );
+            let _listening = self.drain().listen();
+            let mut backoff = Backoff::new();
+
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = self.drain().stopped() => return Ok(()),
+                };
+                match accepted {
+                    Ok((stream, addr)) => {
+                        backoff.reset();
+                        info!("New connection from {}", addr);
+                        let stream = match to_tokio(&stream) {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                error!("Failed to register connection: {}", e);
+                                continue;
+                            }
+                        };
+                        self.configure_client_socket(&stream);
+                        let server = self.clone();
+
+                        let connection = async move {
+                            let peer = PeerInfo {
+                                addr: Some(addr),
+                                identity: None,
+                                #[cfg(feature = "udp-aead")]
+                                udp_keys: None,
+                            };
+                            if let Err(e) = server.handle_connection(stream, peer).await {
+                                error!("Error handling client: {}", e);
+                            }
+                        };
+                        tokio_uring::spawn(URING_TASK.scope((), self.drain().track(connection)));
+                    }
+                    Err(e) => {
+                        self.accept_errors()
+                            .recover(&e, &mut backoff, || listener.accept())
+                            .await;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Relay between two TCP sockets with io_uring until both directions have
+/// closed. The sockets stay owned by the caller; the relay works on
+/// duplicated descriptors in a task of its own, which is aborted if this
+/// future is dropped (idle timeout, cancellation).
+pub(crate) async fn relay(
+    client: &tokio::net::TcpStream,
+    server: &tokio::net::TcpStream,
+    limits: RelayLimits,
+    progress: Arc<Progress>,
+) -> io::Result<(u64, u64)> {
+    let (client_socket, server_socket) = (client, server);
+    let client = dup(client.as_raw_fd())?;
+    let server = dup(server.as_raw_fd())?;
+
+    // io_uring streams are not Send, so they live in a local task and the
+    // caller's future stays Send
+    let task = tokio_uring::spawn(async move {
+        // SAFETY: both descriptors were just duplicated and are owned here.
+        let (client, server) = unsafe {
+            (
+                tokio_uring::net::TcpStream::from_raw_fd(client.into_raw_fd()),
+                tokio_uring::net::TcpStream::from_raw_fd(server.into_raw_fd()),
+            )
+        };
+        tokio::try_join!(
+            copy(
+                &client,
+                &server,
+                &limits.upload,
+                &progress,
+                Direction::Upload
+            ),
+            copy(
+                &server,
+                &client,
+                &limits.download,
+                &progress,
+                Direction::Download
+            ),
+        )
+    });
+    RelayTask {
+        task,
+        sockets: [client_socket, server_socket],
+    }
+    .await?
+}
+
+// Aborts the relay task if dropped before it finishes
+struct RelayTask<'a, T> {
+    task: JoinHandle<T>,
+    sockets: [&'a tokio::net::TcpStream; 2],
+}
+
+impl<T> Future for RelayTask<'_, T> {
+    type Output = io::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx).map_err(io::Error::other)
+    }
+}
+
+impl<T> Drop for RelayTask<'_, T> {
+    fn drop(&mut self) {
+        if self.task.is_finished() {
+            return;
+        }
+        self.task.abort();
+        // The duplicated descriptors are only closed once their pending reads
+        // complete, so shut the sockets down to end them
+        for socket in self.sockets {
+            let _ = SockRef::from(socket).shutdown(Shutdown::Both);
+        }
+    }
+}
+
+async fn copy(
+    src: &tokio_uring::net::TcpStream,
+    dst: &tokio_uring::net::TcpStream,
+    limiters: &[Arc<RateLimiter>],
+    progress: &Progress,
+    direction: Direction,
+) -> io::Result<u64> {
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    let mut total = 0u64;
+
+    loop {
+        let (result, returned) = src.read(buf).await;
+        buf = returned;
+        let n = result?;
+        if n == 0 {
+            // Propagate the half-close so the peer sees EOF
+            dst.shutdown(Shutdown::Write)?;
+            return Ok(total);
+        }
+        progress.touch();
+
+        for limiter in limiters {
+            limiter.consume(n).await;
+        }
+
+        let (result, returned) = dst.write_all(buf.slice(..n)).await;
+        buf = returned.into_inner();
+        result?;
+        total += n as u64;
+        progress.record(direction, n);
+    }
+}
+
+// Hand an io_uring-accepted socket to the Tokio reactor
+fn to_tokio(stream: &tokio_uring::net::TcpStream) -> io::Result<tokio::net::TcpStream> {
+    let socket = std::net::TcpStream::from(dup(stream.as_raw_fd())?);
+    socket.set_nonblocking(true)?;
+    tokio::net::TcpStream::from_std(socket)
+}
+
+fn dup(fd: RawFd) -> io::Result<OwnedFd> {
+    // SAFETY: fcntl has no memory safety requirements; failure is reported
+    // through the return value.
+    let fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a freshly duplicated descriptor nobody else owns.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
@@ -0,0 +1,352 @@
+// SOCKS5 over WebSocket (`ws` feature).
+//
+// The SOCKS5 byte stream is carried in binary WebSocket messages, so the
+// proxy can sit behind HTTP-only middleboxes, reverse proxies and CDNs that
+// pass WebSocket upgrades through. `Server::run_with_ws_listener` answers the
+// upgrade and hands the tunnel to the usual SOCKS5 pipeline; `WsClient`
+// dials it. Terminate TLS in front of the server (or wrap the stream yourself
+// and use `handle_ws_connection`) for `wss://`.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use futures_util::{Sink, Stream};
+use log::debug;
+use rustls::ClientConfig;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{StatusCode, Uri};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+use crate::client::{Client, HandshakeStream};
+use crate::server::{PeerInfo, Server};
+use crate::transport::{Accepted, Acceptor, Transport, TransportClient};
+
+/// Settings for the WebSocket listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsOptions {
+    /// Request path the upgrade must use; other paths get 404.
+    pub path: String,
+    /// Take the client address from the last `X-Forwarded-For` entry, the
+    /// one added by the reverse proxy in front. Only enable this when every
+    /// connection comes through such a proxy, since clients can set the
+    /// header themselves.
+    pub trust_forwarded_for: bool,
+    /// How long the HTTP upgrade may take.
+    pub upgrade_timeout: Duration,
+}
+
+impl Default for WsOptions {
+    fn default() -> Self {
+        WsOptions {
+            path: "/".to_string(),
+            trust_forwarded_for: false,
+            upgrade_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A WebSocket connection read and written as a byte stream. Every write
+/// becomes one binary message; text messages are a protocol error.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    // Rest of the last binary message, not yet read
+    pending: Bytes,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        WsStream {
+            inner,
+            pending: Bytes::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> WebSocketStream<S> {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.pending.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.pending = data,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected WebSocket text message",
+                    )));
+                }
+                // Pings are answered by tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(ws_error(e))),
+            }
+        }
+        let len = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..len]);
+        self.pending.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(ws_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(ws_error)?;
+        // Send it right away like a socket would, since callers such as the
+        // SOCKS handshake do not always flush. A message that cannot go out
+        // yet is sent by the next write or flush.
+        if let Poll::Ready(Err(e)) = Pin::new(&mut self.inner).poll_flush(cx) {
+            return Poll::Ready(Err(ws_error(e)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.inner).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(ws_error(e))),
+        }
+    }
+}
+
+fn ws_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::BrokenPipe, "WebSocket closed")
+        }
+        WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake) => io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "WebSocket closed without a close message",
+        ),
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl Server {
+    /// Serve SOCKS5 over WebSocket on an already bound listener.
+    pub async fn run_with_ws_listener(
+        &self,
+        listener: TcpListener,
+        options: WsOptions,
+    ) -> io::Result<()> {
+        self.run_with_acceptor(listener, Arc::new(WsAcceptor { options }))
+            .await
+    }
+
+    /// Answer the WebSocket upgrade on `stream`, e.g. after your own TLS
+    /// handshake, then serve SOCKS5 inside it like `handle_connection`.
+    pub async fn handle_ws_connection<S>(
+        &self,
+        stream: S,
+        mut peer: PeerInfo,
+        options: &WsOptions,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let stream = upgrade(stream, &mut peer, options).await?;
+        self.handle_connection(stream, peer).await
+    }
+}
+
+// The WebSocket upgrade as a transport
+struct WsAcceptor {
+    options: WsOptions,
+}
+
+#[async_trait]
+impl Acceptor for WsAcceptor {
+    type Stream = WsStream<TcpStream>;
+
+    fn name(&self) -> &'static str {
+        "WebSocket"
+    }
+
+    async fn accept(
+        &self,
+        stream: TcpStream,
+        peer: &mut PeerInfo,
+    ) -> io::Result<Accepted<WsStream<TcpStream>>> {
+        upgrade(stream, peer, &self.options)
+            .await
+            .map(Accepted::Socks)
+    }
+}
+
+// Answer the upgrade request, taking the client's address from
+// X-Forwarded-For if trusted
+async fn upgrade<S>(stream: S, peer: &mut PeerInfo, options: &WsOptions) -> io::Result<WsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut forwarded = None;
+    // The callback's signature is tungstenite's
+    #[allow(clippy::result_large_err)]
+    let check = |request: &Request, response: Response| {
+        if request.uri().path() != options.path {
+            return Err(reject(StatusCode::NOT_FOUND));
+        }
+        if options.trust_forwarded_for {
+            forwarded = forwarded_for(request);
+        }
+        Ok(response)
+    };
+    let upgrade = tokio_tungstenite::accept_hdr_async(stream, check);
+    let ws = tokio::time::timeout(options.upgrade_timeout, upgrade)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "WebSocket upgrade timed out"))?
+        .map_err(ws_error)?;
+
+    if let Some(ip) = forwarded {
+        // The proxy in front does not pass the client's port on
+        peer.addr = Some(SocketAddr::new(ip, 0));
+    }
+    debug!("WebSocket upgrade complete");
+    Ok(WsStream::new(ws))
+}
+
+fn reject(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(None);
+    *response.status_mut() = status;
+    response
+}
+
+// The address the reverse proxy saw, i.e. the last X-Forwarded-For entry
+fn forwarded_for(request: &Request) -> Option<IpAddr> {
+    let header = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .next_back()?;
+    header
+        .to_str()
+        .ok()?
+        .rsplit(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Connects to targets through a SOCKS5 proxy reached over WebSocket
+/// (`ws://`) or WebSocket over TLS (`wss://`).
+pub type WsClient = TransportClient<WsTransport>;
+
+impl WsClient {
+    /// A client for the proxy's WebSocket endpoint, e.g.
+    /// `wss://proxy.example.com/socks`. `wss://` verifies the server
+    /// against the default trust anchors; see `with_tls_config`.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid WebSocket URL"))?;
+        let tls = match uri.scheme_str() {
+            Some("ws") => false,
+            Some("wss") => true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "WebSocket URL must start with ws:// or wss://",
+                ));
+            }
+        };
+        let host = uri.host().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "WebSocket URL has no host")
+        })?;
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        Self::from_client(Client::new(host.to_string(), port), url)
+    }
+
+    /// Use `client`, with its credentials, timeouts, retries and socket
+    /// options, to reach the WebSocket endpoint at `url`. The client's
+    /// proxy address is where the connection goes; `url` supplies the
+    /// scheme, `Host` header and path.
+    pub fn from_client(client: Client, url: &str) -> io::Result<Self> {
+        Ok(Self::from_parts(client, WsTransport::new(url)))
+    }
+
+    /// Verify the `wss://` server with this configuration instead of the
+    /// default one.
+    pub fn with_tls_config(self, config: Arc<ClientConfig>) -> Self {
+        let (client, transport) = self.into_parts();
+        Self::from_parts(client, transport.with_tls_config(config))
+    }
+}
+
+/// The client side of SOCKS5 over WebSocket: TCP (and TLS for `wss://`) to
+/// the proxy, then the upgrade.
+pub struct WsTransport {
+    url: String,
+    tls_config: Option<Arc<ClientConfig>>,
+}
+
+impl WsTransport {
+    /// Upgrade to WebSocket at `url`; see `WsClient::from_client`.
+    pub fn new(url: &str) -> Self {
+        let tls_config = if url.starts_with("wss://") {
+            Some(crate::tls_client::default_tls_config())
+        } else {
+            None
+        };
+        WsTransport {
+            url: url.to_string(),
+            tls_config,
+        }
+    }
+
+    /// Verify the `wss://` server with this configuration instead of the
+    /// default one.
+    pub fn with_tls_config(mut self, config: Arc<ClientConfig>) -> Self {
+        if self.tls_config.is_some() {
+            self.tls_config = Some(config);
+        }
+        self
+    }
+}
+
+#[async_trait]
+impl Transport for WsTransport {
+    type Stream = WsStream<Box<dyn HandshakeStream>>;
+
+    async fn dial(&self, client: &Client) -> io::Result<WsStream<Box<dyn HandshakeStream>>> {
+        let tcp_stream: TcpStream = client.dial_tcp().await?;
+        let stream: Box<dyn HandshakeStream> = match &self.tls_config {
+            Some(config) => Box::new(
+                crate::tls_client::tls_handshake(tcp_stream, client.proxy_host(), config).await?,
+            ),
+            None => Box::new(tcp_stream),
+        };
+        let (ws, _) = tokio_tungstenite::client_async(self.url.as_str(), stream)
+            .await
+            .map_err(ws_error)?;
+        debug!("WebSocket connection established to {}", self.url);
+        Ok(WsStream::new(ws))
+    }
+}
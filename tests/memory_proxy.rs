@@ -0,0 +1,69 @@
+// End-to-end checks of the proxy pipeline over the in-memory harness.
+
+use socks5_rs::client::{Client, ConnectError};
+use socks5_rs::protocol::REP_CONNECTION_REFUSED;
+use socks5_rs::server::ServerOptions;
+use socks5_rs::testing::{TestProxy, round_trip};
+
+fn authenticated_proxy() -> TestProxy {
+    TestProxy::new(ServerOptions {
+        auth_required: true,
+        credentials: Some(vec![("alice".to_string(), "secret".to_string())]),
+        ..Default::default()
+    })
+}
+
+#[tokio::test]
+async fn echo_round_trip() {
+    let proxy = authenticated_proxy();
+    proxy.net().echo("echo.test:7").unwrap();
+    // The proxy address is not used in memory
+    let client = Client::with_auth(
+        "proxy.test".to_string(),
+        1080,
+        "alice".to_string(),
+        "secret".to_string(),
+    );
+
+    let mut stream = proxy.connect(&client, "echo.test:7").await.unwrap();
+    let reply = round_trip(&mut stream, b"hello through the proxy")
+        .await
+        .unwrap();
+    assert_eq!(reply, b"hello through the proxy");
+}
+
+#[tokio::test]
+async fn wrong_password_is_rejected() {
+    let proxy = authenticated_proxy();
+    proxy.net().echo("echo.test:7").unwrap();
+    let client = Client::with_auth(
+        "proxy.test".to_string(),
+        1080,
+        "alice".to_string(),
+        "wrong".to_string(),
+    );
+
+    let error = proxy.connect(&client, "echo.test:7").await.unwrap_err();
+    assert!(
+        matches!(
+            ConnectError::of(&error),
+            Some(ConnectError::AuthFailed { .. })
+        ),
+        "unexpected error: {error}"
+    );
+}
+
+#[tokio::test]
+async fn unknown_target_is_refused() {
+    let proxy = TestProxy::new(ServerOptions::default());
+    let client = Client::new("proxy.test".to_string(), 1080);
+
+    let error = proxy.connect(&client, "nowhere.test:80").await.unwrap_err();
+    assert!(
+        matches!(
+            ConnectError::of(&error),
+            Some(ConnectError::Rejected(REP_CONNECTION_REFUSED))
+        ),
+        "unexpected error: {error}"
+    );
+}